@@ -5,6 +5,8 @@ use ark_client::wallet::Balance;
 use ark_client::wallet::BoardingWallet;
 use ark_client::wallet::OnchainWallet;
 use ark_client::wallet::Persistence;
+use ark_core::descriptor;
+use ark_core::server;
 use ark_core::BoardingOutput;
 use bdk_esplora::EsploraAsyncExt;
 use bdk_wallet::KeychainKind;
@@ -180,17 +182,11 @@ impl<DB> BoardingWallet for Wallet<DB>
 where
     DB: Persistence,
 {
-    fn new_boarding_output(
-        &self,
-        server_pk: XOnlyPublicKey,
-        exit_delay: bitcoin::Sequence,
-        network: Network,
-    ) -> Result<BoardingOutput, Error> {
+    fn new_boarding_output(&self, server_info: &server::Info) -> Result<BoardingOutput, Error> {
         let sk = self.kp.secret_key();
         let (owner_pk, _) = sk.public_key(&self.secp).x_only_public_key();
 
-        let boarding_output =
-            BoardingOutput::new(&self.secp, server_pk, owner_pk, exit_delay, network)?;
+        let boarding_output = descriptor::boarding_output(&self.secp, server_info, owner_pk)?;
 
         self.db
             .save_boarding_output(sk, boarding_output.clone())
@@ -1,5 +1,7 @@
+use ark_core::server::RoundFailedEvent;
 use std::error::Error as StdError;
 use std::fmt;
+use std::time::Duration;
 
 type Source = Box<dyn StdError + Send + Sync + 'static>;
 
@@ -26,6 +28,30 @@ enum Kind {
     CoinSelect(CoinSelectError),
     /// An error related to actions within the wallet.
     Wallet(WalletError),
+    /// The Ark server reported that a round we registered for failed.
+    RoundFailed(RoundFailedError),
+    /// The Ark server's script policy is not one we can build outputs for.
+    ServerPolicy(ServerPolicyError),
+    /// A round we registered for was not finalized in time.
+    RoundTimeout(RoundTimeoutError),
+}
+
+/// The broad category of an [`Error`], for callers that need to react to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    AdHoc,
+    /// Talking to the Ark server failed, or it did not follow the protocol.
+    ArkServer,
+    Core(ark_core::ErrorKind),
+    /// Not enough funds to pay for what was asked.
+    CoinSelect,
+    Wallet,
+    RoundFailed,
+    /// The descriptor templates of the Ark server describe outputs that we cannot build.
+    ServerPolicy,
+    /// A round we registered for was not finalized in time. This is our own deadline, not an
+    /// error reported by the Ark server.
+    RoundTimeout,
 }
 
 #[derive(Debug)]
@@ -53,6 +79,21 @@ struct WalletError {
     source: Source,
 }
 
+#[derive(Debug)]
+struct RoundFailedError {
+    event: RoundFailedEvent,
+}
+
+#[derive(Debug)]
+struct ServerPolicyError {
+    source: ark_core::Error,
+}
+
+#[derive(Debug)]
+struct RoundTimeoutError {
+    timeout: Duration,
+}
+
 impl Error {
     fn new(kind: Kind) -> Self {
         Self {
@@ -83,6 +124,51 @@ impl Error {
             source: source.into(),
         }))
     }
+
+    pub(crate) fn server_policy(source: ark_core::Error) -> Self {
+        Error::new(Kind::ServerPolicy(ServerPolicyError { source }))
+    }
+
+    pub(crate) fn round_failed(event: RoundFailedEvent) -> Self {
+        Error::new(Kind::RoundFailed(RoundFailedError { event }))
+    }
+
+    pub(crate) fn round_timeout(timeout: Duration) -> Self {
+        Error::new(Kind::RoundTimeout(RoundTimeoutError { timeout }))
+    }
+
+    /// The failed round event behind this error, if it was caused by one.
+    pub fn round_failure(&self) -> Option<&RoundFailedEvent> {
+        let mut err = self;
+        loop {
+            if let Kind::RoundFailed(ref failed) = err.inner.kind {
+                return Some(&failed.event);
+            }
+            err = err.inner.cause.as_ref()?;
+        }
+    }
+
+    /// The kind of the first error in the causal chain that is not ad-hoc context, or
+    /// [`ErrorKind::AdHoc`] if there is none.
+    pub fn kind(&self) -> ErrorKind {
+        let mut err = self;
+        loop {
+            let kind = match err.inner.kind {
+                Kind::AdHoc(_) => ErrorKind::AdHoc,
+                Kind::ArkServer(_) => return ErrorKind::ArkServer,
+                Kind::Core(ref err) => return ErrorKind::Core(err.source.kind()),
+                Kind::CoinSelect(_) => return ErrorKind::CoinSelect,
+                Kind::Wallet(_) => return ErrorKind::Wallet,
+                Kind::RoundFailed(_) => return ErrorKind::RoundFailed,
+                Kind::ServerPolicy(_) => return ErrorKind::ServerPolicy,
+                Kind::RoundTimeout(_) => return ErrorKind::RoundTimeout,
+            };
+            err = match err.inner.cause.as_ref() {
+                None => return kind,
+                Some(err) => err,
+            };
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -108,6 +194,9 @@ impl fmt::Display for Kind {
             Kind::Core(ref err) => err.fmt(f),
             Kind::CoinSelect(ref err) => err.fmt(f),
             Kind::Wallet(ref err) => err.fmt(f),
+            Kind::RoundFailed(ref err) => err.fmt(f),
+            Kind::ServerPolicy(ref err) => err.fmt(f),
+            Kind::RoundTimeout(ref err) => err.fmt(f),
         }
     }
 }
@@ -142,6 +231,28 @@ impl fmt::Display for WalletError {
     }
 }
 
+impl fmt::Display for RoundFailedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed registering in round {}: {}",
+            self.event.id, self.event.reason
+        )
+    }
+}
+
+impl fmt::Display for ServerPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported Ark server script policy: {}", self.source)
+    }
+}
+
+impl fmt::Display for RoundTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "round was not finalized within {:?}", self.timeout)
+    }
+}
+
 impl From<ark_core::Error> for Error {
     fn from(value: ark_core::Error) -> Self {
         Self::new(Kind::Core(CoreError { source: value }))
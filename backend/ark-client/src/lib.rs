@@ -1,5 +1,6 @@
 use crate::wallet::BoardingWallet;
 use crate::wallet::OnchainWallet;
+use ark_core::descriptor;
 use ark_core::generate_incoming_vtxo_transaction_history;
 use ark_core::generate_outgoing_vtxo_transaction_history;
 use ark_core::server;
@@ -30,6 +31,10 @@ mod unilateral_exit;
 mod utils;
 
 pub use error::Error;
+pub use error::ErrorKind;
+pub use send_vtxo::BatchTransaction;
+pub use send_vtxo::SendPreview;
+pub use unilateral_exit::UnilateralExit;
 
 /// A client to interact with Ark Server
 ///
@@ -125,10 +130,7 @@ pub use error::Error;
 /// # {
 /// #     fn new_boarding_output(
 /// #         &self,
-/// #         server_pk: XOnlyPublicKey,
-/// #         exit_delay: bitcoin::Sequence,
-/// #         descriptor_template: &str,
-/// #         network: Network,
+/// #         server_info: &ark_core::server::Info,
 /// #     ) -> Result<BoardingOutput, Error> {
 /// #         unimplemented!()
 /// #     }
@@ -243,6 +245,36 @@ impl OffChainBalance {
     }
 }
 
+/// The on-chain funds held by our boarding outputs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BoardingBalance {
+    pending: Amount,
+    confirmed: Amount,
+    expired: Amount,
+}
+
+impl BoardingBalance {
+    /// Boarding outputs that have not been confirmed yet.
+    pub fn pending(&self) -> Amount {
+        self.pending
+    }
+
+    /// Confirmed boarding outputs that can still be used to board the Ark.
+    pub fn confirmed(&self) -> Amount {
+        self.confirmed
+    }
+
+    /// Confirmed boarding outputs whose exit path is already active. These can only be spent
+    /// unilaterally by their owner.
+    pub fn expired(&self) -> Amount {
+        self.expired
+    }
+
+    pub fn total(&self) -> Amount {
+        self.pending + self.confirmed + self.expired
+    }
+}
+
 pub trait Blockchain {
     fn find_outpoints(
         &self,
@@ -293,6 +325,12 @@ where
         self.network_client.connect().await?;
         let server_info = self.network_client.get_info().await?;
 
+        // Refuse to connect if we cannot build outputs which the Ark server accepts as ours.
+        let (user, _) = self.kp.x_only_public_key();
+        descriptor::boarding_output(&self.secp, &server_info, user)
+            .and_then(|_| descriptor::default_vtxo(&self.secp, &server_info, user))
+            .map_err(Error::server_policy)?;
+
         tracing::debug!(
             name = self.name,
             ark_server_url = ?self.network_client,
@@ -313,19 +351,10 @@ where
 {
     // At the moment we are always generating the same address.
     pub fn get_offchain_address(&self) -> Result<(ArkAddress, Vtxo), Error> {
-        let server_info = &self.server_info;
-
-        let (server, _) = server_info.pk.x_only_public_key();
         let (owner, _) = self.inner.kp.public_key().x_only_public_key();
 
-        let vtxo = Vtxo::new(
-            self.secp(),
-            server,
-            owner,
-            vec![],
-            server_info.unilateral_exit_delay,
-            server_info.network,
-        )?;
+        let vtxo = descriptor::default_vtxo(self.secp(), &self.server_info, owner)
+            .map_err(Error::server_policy)?;
 
         let ark_address = vtxo.to_ark_address();
 
@@ -340,13 +369,7 @@ where
 
     // At the moment we are always generating the same address.
     pub fn get_boarding_address(&self) -> Result<Address, Error> {
-        let server_info = &self.server_info;
-
-        let boarding_output = self.inner.wallet.new_boarding_output(
-            server_info.pk.x_only_public_key().0,
-            server_info.unilateral_exit_delay,
-            server_info.network,
-        )?;
+        let boarding_output = self.inner.wallet.new_boarding_output(&self.server_info)?;
 
         Ok(boarding_output.address().clone())
     }
@@ -443,6 +466,35 @@ where
         Ok(sum)
     }
 
+    pub async fn boarding_balance(&self) -> Result<BoardingBalance, Error> {
+        let now = Timestamp::now();
+
+        let mut balance = BoardingBalance::default();
+        for boarding_output in self.inner.wallet.get_boarding_outputs()? {
+            let outpoints = self
+                .blockchain()
+                .find_outpoints(boarding_output.address())
+                .await?;
+
+            for o in outpoints.iter().filter(|o| !o.is_spent) {
+                match o.confirmation_blocktime {
+                    Some(confirmation_blocktime)
+                        if boarding_output.can_be_claimed_unilaterally_by_owner(
+                            now.as_duration().try_into().map_err(Error::ad_hoc)?,
+                            std::time::Duration::from_secs(confirmation_blocktime),
+                        ) =>
+                    {
+                        balance.expired += o.amount;
+                    }
+                    Some(_) => balance.confirmed += o.amount,
+                    None => balance.pending += o.amount,
+                }
+            }
+        }
+
+        Ok(balance)
+    }
+
     pub async fn transaction_history(&self) -> Result<Vec<ArkTransaction>, Error> {
         let mut boarding_transactions = Vec::new();
         let mut boarding_round_transactions = Vec::new();
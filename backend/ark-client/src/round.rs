@@ -14,6 +14,7 @@ use ark_core::round::sign_round_psbt;
 use ark_core::round::sign_vtxo_tree;
 use ark_core::round::NonceTree;
 use ark_core::round::PubNonceTree;
+use ark_core::server;
 use ark_core::server::RoundInput;
 use ark_core::server::RoundOutput;
 use ark_core::server::RoundStreamEvent;
@@ -24,17 +25,26 @@ use backon::Retryable;
 use bitcoin::key::Keypair;
 use bitcoin::secp256k1;
 use bitcoin::secp256k1::schnorr;
+use bitcoin::secp256k1::All;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::secp256k1::Secp256k1;
 use bitcoin::Address;
 use bitcoin::Amount;
 use bitcoin::Psbt;
 use bitcoin::Txid;
 use bitcoin::XOnlyPublicKey;
+use futures::stream::BoxStream;
+use futures::Future;
 use futures::FutureExt;
 use futures::StreamExt;
 use jiff::Timestamp;
 use rand::CryptoRng;
 use rand::Rng;
 use std::collections::HashMap;
+use std::time::Duration;
+use tracing::Instrument;
+use zkp::MusigPartialSignature;
+use zkp::MusigPubNonce;
 
 impl<B, W> Client<B, W>
 where
@@ -50,6 +60,27 @@ where
         // Get off-chain address and send all funds to this address, no change output 🦄
         let (to_address, _) = self.get_offchain_address()?;
 
+        self.settle_to(rng, to_address, 0).await?;
+
+        Ok(())
+    }
+
+    /// Like [`Client::board`], but send all pending VTXOs and boarding outputs to `to_address`,
+    /// which need not belong to us.
+    ///
+    /// A round that fails, e.g. because another participant dropped out, is joined again up to
+    /// `max_retries` times. Other errors are returned straight away.
+    ///
+    /// Returns the TXID of the round transaction, or `None` if there was nothing to settle.
+    pub async fn settle_to<R>(
+        &self,
+        rng: &mut R,
+        to_address: ArkAddress,
+        max_retries: usize,
+    ) -> Result<Option<Txid>, Error>
+    where
+        R: Rng + CryptoRng + Clone,
+    {
         let (boarding_inputs, vtxo_inputs, total_amount) =
             self.fetch_round_transaction_inputs().await?;
 
@@ -62,7 +93,7 @@ where
 
         if boarding_inputs.is_empty() && vtxo_inputs.is_empty() {
             tracing::debug!("No transactions to board");
-            return Ok(());
+            return Ok(None);
         }
 
         let join_next_ark_round = || async {
@@ -78,22 +109,19 @@ where
             .await
         };
 
-        // Joining a round can fail depending on the timing, so we try a few times.
-        let txid = join_next_ark_round
-            .retry(ExponentialBuilder::default().with_max_times(0))
-            .sleep(sleep)
-            // TODO: Use `when` to only retry certain errors.
-            .notify(|err: &Error, dur: std::time::Duration| {
-                tracing::warn!("Retrying joining next Ark round after {dur:?}. Error: {err}",);
-            })
+        let txid = retry_failed_rounds(max_retries, join_next_ark_round)
             .await
             .context("Failed to join round")?;
 
         tracing::info!(%txid, "Boarding success");
 
-        Ok(())
+        Ok(Some(txid))
     }
 
+    /// Send `to_amount` of our funds on-chain to `to_address` by joining the next round, and the
+    /// rest back to our own off-chain address.
+    ///
+    /// Fails if either output would be below the dust limit.
     // In go client: CollaborativeRedeem.
     pub async fn off_board<R>(
         &self,
@@ -101,6 +129,36 @@ where
         to_address: Address,
         to_amount: Amount,
     ) -> Result<Txid, Error>
+    where
+        R: Rng + CryptoRng + Clone,
+    {
+        self.off_board_amount(rng, to_address, Some(to_amount))
+            .await?
+            .ok_or_else(|| Error::coin_select(format!("cannot afford to send {to_amount}")))
+    }
+
+    /// Like [`Client::off_board`], but send all of our funds on-chain to `to_address`, without
+    /// change.
+    ///
+    /// Returns the TXID of the round transaction, or `None` if there was nothing to off-board.
+    pub async fn off_board_all<R>(
+        &self,
+        rng: &mut R,
+        to_address: Address,
+    ) -> Result<Option<Txid>, Error>
+    where
+        R: Rng + CryptoRng + Clone,
+    {
+        self.off_board_amount(rng, to_address, None).await
+    }
+
+    /// Off-board `to_amount`, or everything if `None`. Returns `None` if there are no funds.
+    async fn off_board_amount<R>(
+        &self,
+        rng: &mut R,
+        to_address: Address,
+        to_amount: Option<Amount>,
+    ) -> Result<Option<Txid>, Error>
     where
         R: Rng + CryptoRng + Clone,
     {
@@ -109,10 +167,30 @@ where
         let (boarding_inputs, vtxo_inputs, total_amount) =
             self.fetch_round_transaction_inputs().await?;
 
+        if boarding_inputs.is_empty() && vtxo_inputs.is_empty() {
+            tracing::debug!("No funds to off-board");
+            return Ok(None);
+        }
+
+        let to_amount = to_amount.unwrap_or(total_amount);
         let change_amount = total_amount.checked_sub(to_amount).ok_or_else(|| {
-            Error::coin_select("cannot afford to send {to_amount}, only have {total_amount}")
+            Error::coin_select(format!(
+                "cannot afford to send {to_amount}, only have {total_amount}"
+            ))
         })?;
 
+        let dust = self.server_info.dust;
+        if to_amount < dust {
+            return Err(Error::coin_select(format!(
+                "cannot send {to_amount}, below the dust limit of {dust}"
+            )));
+        }
+        if change_amount > Amount::ZERO && change_amount < dust {
+            return Err(Error::coin_select(format!(
+                "change of {change_amount} would be below the dust limit of {dust}"
+            )));
+        }
+
         tracing::info!(
             %to_address,
             %to_amount,
@@ -150,7 +228,7 @@ where
 
         tracing::info!(%txid, "Off-boarding success");
 
-        Ok(txid)
+        Ok(Some(txid))
     }
 
     /// Get all the [`round::OnChainInput`]s and [`round::VtxoInput`]s that can be used to join an
@@ -221,6 +299,9 @@ where
         Ok((boarding_inputs, vtxo_inputs, total_amount))
     }
 
+    /// Join the next Ark round within an `ark_round` span, which records whether the round was
+    /// `finalized`, `failed` or ended in an `error`. Every step of the round protocol gets an
+    /// `ark_round_step` span of its own, so that subscribers can time them.
     async fn join_next_ark_round<R>(
         &self,
         rng: &mut R,
@@ -231,38 +312,33 @@ where
     where
         R: Rng + CryptoRng,
     {
-        if onchain_inputs.is_empty() && vtxo_inputs.is_empty() {
-            return Err(Error::ad_hoc("cannot join round without inputs"));
-        }
-
-        let server_info = &self.server_info;
+        let span = tracing::info_span!("ark_round", outcome = tracing::field::Empty);
 
-        // Generate an (ephemeral) cosigner keypair.
-        let own_cosigner_kp = Keypair::new(self.secp(), rng);
-
-        let inputs = {
-            let boarding_inputs = onchain_inputs
-                .clone()
-                .into_iter()
-                .map(|o| RoundInput::new(o.outpoint(), o.boarding_output().tapscripts()));
+        let result = self
+            .run_ark_round(rng, onchain_inputs, vtxo_inputs, output_type)
+            .instrument(span.clone())
+            .await;
 
-            let vtxo_inputs = vtxo_inputs
-                .clone()
-                .into_iter()
-                .map(|v| RoundInput::new(v.outpoint(), v.vtxo().tapscripts()));
-
-            boarding_inputs.chain(vtxo_inputs).collect::<Vec<_>>()
+        let outcome = match &result {
+            Ok(_) => "finalized",
+            Err(e) if e.round_failure().is_some() => "failed",
+            Err(_) => "error",
         };
+        span.record("outcome", outcome);
 
-        let payment_id = self
-            .network_client()
-            .register_inputs_for_next_round(&inputs)
-            .await
-            .map_err(Error::from)
-            .context("failed to register round inputs")?;
-
-        tracing::debug!(payment_id, "Registered for round");
+        result
+    }
 
+    async fn run_ark_round<R>(
+        &self,
+        rng: &mut R,
+        onchain_inputs: Vec<round::OnChainInput>,
+        vtxo_inputs: Vec<round::VtxoInput>,
+        output_type: RoundOutputType,
+    ) -> Result<Txid, Error>
+    where
+        R: Rng + CryptoRng,
+    {
         let mut outputs = vec![];
 
         match output_type {
@@ -277,258 +353,512 @@ where
                 change_amount,
             } => {
                 outputs.push(RoundOutput::new_on_chain(to_address, to_amount));
-                outputs.push(RoundOutput::new_virtual(change_address, change_amount));
+
+                // Off-boarding everything leaves nothing to send back.
+                if change_amount > Amount::ZERO {
+                    outputs.push(RoundOutput::new_virtual(change_address, change_amount));
+                }
             }
         }
 
-        let own_cosigner_kps = [own_cosigner_kp];
-        let own_cosigner_pks = own_cosigner_kps
+        let sign_for_pk_fn = |pk: &XOnlyPublicKey,
+                              msg: &secp256k1::Message|
+         -> Result<schnorr::Signature, ark_core::Error> {
+            self.inner
+                .wallet
+                .sign_for_pk(pk, msg)
+                .map_err(|e| ark_core::Error::ad_hoc(e.to_string()))
+        };
+
+        run_round(
+            &self.network_client(),
+            rng,
+            self.secp(),
+            &self.server_info,
+            self.kp(),
+            sign_for_pk_fn,
+            RoundParticipation {
+                onchain_inputs: &onchain_inputs,
+                vtxo_inputs: &vtxo_inputs,
+                outputs: &outputs,
+            },
+            round_timeout(&self.server_info),
+        )
+        .await
+    }
+}
+
+/// How many round intervals of the Ark server a round may take, from registering for it until
+/// it is finalized.
+const ROUND_TIMEOUT_INTERVALS: u64 = 10;
+
+/// How long to wait for a round to be finalized before giving up on it.
+fn round_timeout(server_info: &server::Info) -> Duration {
+    Duration::from_secs(server_info.round_interval.max(1) as u64 * ROUND_TIMEOUT_INTERVALS)
+}
+
+/// Join `join` again after a backoff every time it ends in a failed round, at most `max_retries`
+/// times.
+async fn retry_failed_rounds<F, Fut>(max_retries: usize, join: F) -> Result<Txid, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Txid, Error>>,
+{
+    join.retry(ExponentialBuilder::default().with_max_times(max_retries))
+        .sleep(sleep)
+        .when(|err: &Error| err.round_failure().is_some())
+        .notify(|err: &Error, dur: std::time::Duration| {
+            tracing::warn!("Retrying joining next Ark round after {dur:?}. Error: {err}");
+        })
+        .await
+}
+
+/// The calls to the Ark server that make up the round protocol.
+///
+/// Implemented for the gRPC client, and by a scripted server in tests.
+pub(crate) trait RoundTransport: Clone + Send + 'static {
+    fn register_inputs_for_next_round(
+        &self,
+        inputs: &[RoundInput],
+    ) -> impl Future<Output = Result<String, Error>> + Send;
+
+    fn register_outputs_for_next_round(
+        &self,
+        payment_id: String,
+        outputs: &[RoundOutput],
+        cosigner_pks: &[PublicKey],
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn ping(&self, payment_id: String) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn get_event_stream(
+        &self,
+    ) -> impl Future<Output = Result<BoxStream<'_, Result<RoundStreamEvent, Error>>, Error>> + Send;
+
+    fn submit_tree_nonces(
+        &self,
+        round_id: &str,
+        cosigner_pk: PublicKey,
+        pub_nonce_tree: Vec<Vec<Option<MusigPubNonce>>>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn submit_tree_signatures(
+        &self,
+        round_id: &str,
+        cosigner_pk: PublicKey,
+        partial_sig_tree: Vec<Vec<Option<MusigPartialSignature>>>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn submit_signed_forfeit_txs(
+        &self,
+        signed_forfeit_txs: Vec<Psbt>,
+        signed_round_psbt: Option<Psbt>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+impl RoundTransport for ark_grpc::Client {
+    async fn register_inputs_for_next_round(&self, inputs: &[RoundInput]) -> Result<String, Error> {
+        ark_grpc::Client::register_inputs_for_next_round(self, inputs)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn register_outputs_for_next_round(
+        &self,
+        payment_id: String,
+        outputs: &[RoundOutput],
+        cosigner_pks: &[PublicKey],
+    ) -> Result<(), Error> {
+        ark_grpc::Client::register_outputs_for_next_round(
+            self,
+            payment_id,
+            outputs,
+            cosigner_pks,
+            false,
+        )
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn ping(&self, payment_id: String) -> Result<(), Error> {
+        ark_grpc::Client::ping(self, payment_id)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn get_event_stream(
+        &self,
+    ) -> Result<BoxStream<'_, Result<RoundStreamEvent, Error>>, Error> {
+        let stream = ark_grpc::Client::get_event_stream(self).await?;
+
+        Ok(stream.map(|event| event.map_err(Error::ark_server)).boxed())
+    }
+
+    async fn submit_tree_nonces(
+        &self,
+        round_id: &str,
+        cosigner_pk: PublicKey,
+        pub_nonce_tree: Vec<Vec<Option<MusigPubNonce>>>,
+    ) -> Result<(), Error> {
+        ark_grpc::Client::submit_tree_nonces(self, round_id, cosigner_pk, pub_nonce_tree)
+            .await
+            .map_err(Error::ark_server)
+    }
+
+    async fn submit_tree_signatures(
+        &self,
+        round_id: &str,
+        cosigner_pk: PublicKey,
+        partial_sig_tree: Vec<Vec<Option<MusigPartialSignature>>>,
+    ) -> Result<(), Error> {
+        ark_grpc::Client::submit_tree_signatures(self, round_id, cosigner_pk, partial_sig_tree)
+            .await
+            .map_err(Error::ark_server)
+    }
+
+    async fn submit_signed_forfeit_txs(
+        &self,
+        signed_forfeit_txs: Vec<Psbt>,
+        signed_round_psbt: Option<Psbt>,
+    ) -> Result<(), Error> {
+        ark_grpc::Client::submit_signed_forfeit_txs(self, signed_forfeit_txs, signed_round_psbt)
+            .await
+            .map_err(Error::from)
+    }
+}
+
+/// What we bring to a round: the boarding outputs and VTXOs we spend, and the outputs we want.
+struct RoundParticipation<'a> {
+    onchain_inputs: &'a [round::OnChainInput],
+    vtxo_inputs: &'a [round::VtxoInput],
+    outputs: &'a [RoundOutput],
+}
+
+/// Register for the next round and follow it until it is finalized, giving up after `timeout`.
+///
+/// VTXO inputs are forfeited with `kp`; boarding inputs are signed with `sign_for_pk_fn`.
+#[allow(clippy::too_many_arguments)]
+async fn run_round<T, R, F>(
+    network_client: &T,
+    rng: &mut R,
+    secp: &Secp256k1<All>,
+    server_info: &server::Info,
+    kp: &Keypair,
+    sign_for_pk_fn: F,
+    participation: RoundParticipation<'_>,
+    timeout: Duration,
+) -> Result<Txid, Error>
+where
+    T: RoundTransport,
+    R: Rng + CryptoRng,
+    F: Fn(&XOnlyPublicKey, &secp256k1::Message) -> Result<schnorr::Signature, ark_core::Error>,
+{
+    let round = follow_round(
+        network_client,
+        rng,
+        secp,
+        server_info,
+        kp,
+        sign_for_pk_fn,
+        participation,
+    );
+
+    // Dropping the round on timeout also stops its ping task.
+    match futures::future::select(std::pin::pin!(round), std::pin::pin!(sleep(timeout))).await {
+        futures::future::Either::Left((result, _)) => result,
+        futures::future::Either::Right(_) => Err(Error::round_timeout(timeout)),
+    }
+}
+
+/// The body of [`run_round`], without the timeout.
+async fn follow_round<T, R, F>(
+    network_client: &T,
+    rng: &mut R,
+    secp: &Secp256k1<All>,
+    server_info: &server::Info,
+    kp: &Keypair,
+    sign_for_pk_fn: F,
+    participation: RoundParticipation<'_>,
+) -> Result<Txid, Error>
+where
+    T: RoundTransport,
+    R: Rng + CryptoRng,
+    F: Fn(&XOnlyPublicKey, &secp256k1::Message) -> Result<schnorr::Signature, ark_core::Error>,
+{
+    let RoundParticipation {
+        onchain_inputs,
+        vtxo_inputs,
+        outputs,
+    } = participation;
+
+    if onchain_inputs.is_empty() && vtxo_inputs.is_empty() {
+        return Err(Error::ad_hoc("cannot join round without inputs"));
+    }
+
+    // Generate an (ephemeral) cosigner keypair.
+    let own_cosigner_kp = Keypair::new(secp, rng);
+
+    let inputs = {
+        let boarding_inputs = onchain_inputs
             .iter()
-            .map(|k| k.public_key())
-            .collect::<Vec<_>>();
-        self.network_client()
-            .register_outputs_for_next_round(payment_id.clone(), &outputs, &own_cosigner_pks, false)
-            .await?;
-
-        let network_client = self.network_client();
-
-        // The protocol expects us to ping the Ark server every 5 seconds to let the server know
-        // that we are still interested in joining the round.
-        //
-        // We generate a `RemoteHandle` so that the ping task is cancelled when the parent function
-        // ends.
-        let (ping_task, _ping_handle) = {
-            let network_client = network_client.clone();
-            async move {
-                loop {
-                    if let Err(e) = network_client.ping(payment_id.clone()).await {
-                        tracing::warn!("Error via ping: {e:?}");
-                    }
+            .map(|o| RoundInput::new(o.outpoint(), o.boarding_output().tapscripts()));
 
-                    sleep(std::time::Duration::from_millis(5000)).await
+        let vtxo_inputs = vtxo_inputs
+            .iter()
+            .map(|v| RoundInput::new(v.outpoint(), v.vtxo().tapscripts()));
+
+        boarding_inputs.chain(vtxo_inputs).collect::<Vec<_>>()
+    };
+
+    let registration = tracing::info_span!("ark_round_step", step = "registration");
+
+    let payment_id = network_client
+        .register_inputs_for_next_round(&inputs)
+        .await
+        .context("failed to register round inputs")?;
+
+    tracing::debug!(payment_id, "Registered for round");
+
+    let own_cosigner_kps = [own_cosigner_kp];
+    let own_cosigner_pks = own_cosigner_kps
+        .iter()
+        .map(|k| k.public_key())
+        .collect::<Vec<_>>();
+    network_client
+        .register_outputs_for_next_round(payment_id.clone(), outputs, &own_cosigner_pks)
+        .await?;
+
+    drop(registration);
+
+    // The protocol expects us to ping the Ark server every 5 seconds to let the server know
+    // that we are still interested in joining the round.
+    //
+    // We generate a `RemoteHandle` so that the ping task is cancelled when the parent function
+    // ends.
+    let (ping_task, _ping_handle) = {
+        let network_client = network_client.clone();
+        async move {
+            loop {
+                if let Err(e) = network_client.ping(payment_id.clone()).await {
+                    tracing::warn!("Error via ping: {e:?}");
                 }
+
+                sleep(std::time::Duration::from_millis(5000)).await
             }
         }
-        .remote_handle();
+    }
+    .remote_handle();
 
-        spawn(ping_task);
+    spawn(ping_task);
 
-        let mut stream = network_client.get_event_stream().await?;
+    let mut stream = network_client.get_event_stream().await?;
 
-        let mut step = RoundStep::Start;
+    let mut step = RoundStep::Start;
 
-        let (ark_server_pk, _) = server_info.pk.x_only_public_key();
+    let (ark_server_pk, _) = server_info.pk.x_only_public_key();
 
-        let mut round_id: Option<String> = None;
-        let mut unsigned_round_tx: Option<Psbt> = None;
-        let mut vtxo_tree: Option<TxTree> = None;
-        let mut our_nonce_trees: Option<HashMap<Keypair, NonceTree>> = None;
-        loop {
-            match stream.next().await {
-                Some(Ok(event)) => match event {
-                    RoundStreamEvent::RoundSigning(e) => {
-                        if step != RoundStep::Start {
-                            continue;
-                        }
+    let mut round_id: Option<String> = None;
+    let mut unsigned_round_tx: Option<Psbt> = None;
+    let mut vtxo_tree: Option<TxTree> = None;
+    let mut our_nonce_trees: Option<HashMap<Keypair, NonceTree>> = None;
+    loop {
+        match stream.next().await {
+            Some(Ok(event)) => match event {
+                RoundStreamEvent::RoundSigning(e) => {
+                    if step != RoundStep::Start {
+                        continue;
+                    }
 
-                        tracing::info!(round_id = e.id, "Round signing started");
+                    // Not entered, since it is held across `.await`s; it is timed from
+                    // creation until it is dropped at the end of the step.
+                    let _step = tracing::info_span!("ark_round_step", step = "nonces");
 
-                        round_id = Some(e.id.clone());
+                    tracing::info!(round_id = e.id, "Round signing started");
 
-                        let unsigned_vtxo_tree =
-                            e.unsigned_vtxo_tree.expect("to have an unsigned vtxo tree");
+                    round_id = Some(e.id.clone());
 
-                        for own_cosigner_pk in own_cosigner_pks.iter() {
-                            if !&e.cosigners_pubkeys.iter().any(|p| p == own_cosigner_pk) {
-                                return Err(Error::ark_server(format!(
-                                    "own cosigner PK is not present in cosigner PKs: {own_cosigner_pk}"
-                                )));
-                            }
-                        }
+                    let unsigned_vtxo_tree =
+                        e.unsigned_vtxo_tree.expect("to have an unsigned vtxo tree");
 
-                        // We generate and submit a nonce tree for every cosigner key we provide.
-                        let mut our_nonce_tree_map = HashMap::new();
-                        for own_cosigner_kp in own_cosigner_kps {
-                            let own_cosigner_pk = own_cosigner_kp.public_key();
-                            let nonce_tree =
-                                generate_nonce_tree(rng, &unsigned_vtxo_tree, own_cosigner_pk)
-                                    .map_err(Error::from)
-                                    .context("failed to generate VTXO nonce tree")?;
-
-                            tracing::info!(
-                                cosigner_pk = %own_cosigner_pk,
-                                "Submitting nonce tree for cosigner PK"
-                            );
-
-                            network_client
-                                .submit_tree_nonces(
-                                    &e.id,
-                                    own_cosigner_pk,
-                                    nonce_tree.to_pub_nonce_tree().into_inner(),
-                                )
-                                .await
-                                .map_err(Error::ark_server)
-                                .context("failed to submit VTXO nonce tree")?;
-
-                            our_nonce_tree_map.insert(own_cosigner_kp, nonce_tree);
+                    for own_cosigner_pk in own_cosigner_pks.iter() {
+                        if !&e.cosigners_pubkeys.iter().any(|p| p == own_cosigner_pk) {
+                            return Err(Error::ark_server(format!(
+                                "own cosigner PK is not present in cosigner PKs: {own_cosigner_pk}"
+                            )));
                         }
+                    }
 
-                        our_nonce_trees = Some(our_nonce_tree_map);
-
-                        vtxo_tree = Some(unsigned_vtxo_tree);
+                    // We generate and submit a nonce tree for every cosigner key we provide.
+                    let mut our_nonce_tree_map = HashMap::new();
+                    for own_cosigner_kp in own_cosigner_kps {
+                        let own_cosigner_pk = own_cosigner_kp.public_key();
+                        let nonce_tree =
+                            generate_nonce_tree(rng, &unsigned_vtxo_tree, own_cosigner_pk)
+                                .map_err(Error::from)
+                                .context("failed to generate VTXO nonce tree")?;
+
+                        tracing::info!(
+                            cosigner_pk = %own_cosigner_pk,
+                            "Submitting nonce tree for cosigner PK"
+                        );
 
-                        unsigned_round_tx = Some(e.unsigned_round_tx);
+                        network_client
+                            .submit_tree_nonces(
+                                &e.id,
+                                own_cosigner_pk,
+                                nonce_tree.to_pub_nonce_tree().into_inner(),
+                            )
+                            .await
+                            .context("failed to submit VTXO nonce tree")?;
 
-                        step = step.next();
-                        continue;
+                        our_nonce_tree_map.insert(own_cosigner_kp, nonce_tree);
                     }
-                    RoundStreamEvent::RoundSigningNoncesGenerated(e) => {
-                        if step != RoundStep::RoundSigningStarted {
-                            continue;
-                        }
 
-                        let agg_pub_nonce_tree = PubNonceTree::from(e.tree_nonces);
+                    our_nonce_trees = Some(our_nonce_tree_map);
 
-                        tracing::debug!(
-                            round_id = e.id,
-                            ?agg_pub_nonce_tree,
-                            "Round combined nonces generated"
-                        );
+                    vtxo_tree = Some(unsigned_vtxo_tree);
 
-                        let unsigned_round_tx = unsigned_round_tx
-                            .as_ref()
-                            .ok_or(Error::ark_server("missing round TX during round protocol"))?;
-
-                        let vtxo_tree = vtxo_tree
-                            .as_ref()
-                            .ok_or(Error::ark_server("missing vtxo tree during round protocol"))?;
-                        let our_nonce_trees = our_nonce_trees.take().ok_or(Error::ark_server(
-                            "missing nonce tree during round protocol",
-                        ))?;
-
-                        for (cosigner_kp, our_nonce_tree) in our_nonce_trees {
-                            let partial_sig_tree = sign_vtxo_tree(
-                                server_info.vtxo_tree_expiry,
-                                ark_server_pk,
-                                &cosigner_kp,
-                                vtxo_tree,
-                                unsigned_round_tx,
-                                our_nonce_tree,
-                                &agg_pub_nonce_tree,
-                            )
-                            .map_err(Error::from)
-                            .context("failed to sign VTXO tree")?;
-
-                            network_client
-                                .submit_tree_signatures(
-                                    &e.id,
-                                    cosigner_kp.public_key(),
-                                    partial_sig_tree.into_inner(),
-                                )
-                                .await
-                                .map_err(Error::ark_server)
-                                .context("failed to submit VTXO tree signatures")?;
-                        }
+                    unsigned_round_tx = Some(e.unsigned_round_tx);
 
-                        step = step.next();
+                    step = step.next();
+                    continue;
+                }
+                RoundStreamEvent::RoundSigningNoncesGenerated(e) => {
+                    if step != RoundStep::RoundSigningStarted {
+                        continue;
                     }
-                    RoundStreamEvent::RoundFinalization(e) => {
-                        if step != RoundStep::RoundSigningNoncesGenerated {
-                            continue;
-                        }
-                        tracing::debug!(round_id = e.id, "Round finalization started");
-
-                        let signed_forfeit_psbts = create_and_sign_forfeit_txs(
-                            self.kp(),
-                            vtxo_inputs.as_slice(),
-                            e.connector_tree,
-                            &e.connectors_index,
-                            e.min_relay_fee_rate,
-                            &server_info.forfeit_address,
-                            server_info.dust,
+
+                    let _step = tracing::info_span!("ark_round_step", step = "signing");
+
+                    let agg_pub_nonce_tree = PubNonceTree::from(e.tree_nonces);
+
+                    tracing::debug!(
+                        round_id = e.id,
+                        ?agg_pub_nonce_tree,
+                        "Round combined nonces generated"
+                    );
+
+                    let unsigned_round_tx = unsigned_round_tx
+                        .as_ref()
+                        .ok_or(Error::ark_server("missing round TX during round protocol"))?;
+
+                    let vtxo_tree = vtxo_tree
+                        .as_ref()
+                        .ok_or(Error::ark_server("missing vtxo tree during round protocol"))?;
+                    let our_nonce_trees = our_nonce_trees.take().ok_or(Error::ark_server(
+                        "missing nonce tree during round protocol",
+                    ))?;
+
+                    for (cosigner_kp, our_nonce_tree) in our_nonce_trees {
+                        let partial_sig_tree = sign_vtxo_tree(
+                            server_info.vtxo_tree_expiry,
+                            ark_server_pk,
+                            &cosigner_kp,
+                            vtxo_tree,
+                            unsigned_round_tx,
+                            our_nonce_tree,
+                            &agg_pub_nonce_tree,
                         )
-                        .map_err(Error::from)?;
-
-                        let round_psbt = if onchain_inputs.is_empty() {
-                            None
-                        } else {
-                            let mut round_psbt = e.round_tx;
-
-                            let sign_for_pk_fn = |pk: &XOnlyPublicKey,
-                                                  msg: &secp256k1::Message|
-                             -> Result<
-                                schnorr::Signature,
-                                ark_core::Error,
-                            > {
-                                self.inner
-                                    .wallet
-                                    .sign_for_pk(pk, msg)
-                                    .map_err(|e| ark_core::Error::ad_hoc(e.to_string()))
-                            };
-
-                            sign_round_psbt(sign_for_pk_fn, &mut round_psbt, &onchain_inputs)
-                                .map_err(Error::from)?;
-
-                            Some(round_psbt)
-                        };
+                        .map_err(Error::from)
+                        .context("failed to sign VTXO tree")?;
 
                         network_client
-                            .submit_signed_forfeit_txs(signed_forfeit_psbts, round_psbt)
-                            .await?;
+                            .submit_tree_signatures(
+                                &e.id,
+                                cosigner_kp.public_key(),
+                                partial_sig_tree.into_inner(),
+                            )
+                            .await
+                            .context("failed to submit VTXO tree signatures")?;
+                    }
 
-                        step = step.next();
+                    step = step.next();
+                }
+                RoundStreamEvent::RoundFinalization(e) => {
+                    if step != RoundStep::RoundSigningNoncesGenerated {
+                        continue;
                     }
-                    RoundStreamEvent::RoundFinalized(e) => {
-                        if step != RoundStep::RoundFinalization {
-                            continue;
-                        }
+                    let _step = tracing::info_span!("ark_round_step", step = "finalization");
 
-                        let round_txid = e.round_txid;
+                    tracing::debug!(round_id = e.id, "Round finalization started");
 
-                        tracing::info!(round_id = e.id, %round_txid, "Round finalized");
+                    let signed_forfeit_psbts = create_and_sign_forfeit_txs(
+                        kp,
+                        vtxo_inputs,
+                        e.connector_tree,
+                        &e.connectors_index,
+                        e.min_relay_fee_rate,
+                        &server_info.forfeit_address,
+                        server_info.dust,
+                    )
+                    .map_err(Error::from)?;
 
-                        return Ok(round_txid);
-                    }
-                    RoundStreamEvent::RoundFailed(e) => {
-                        if Some(&e.id) == round_id.as_ref() {
-                            return Err(Error::ark_server(format!(
-                                "failed registering in round {}: {}",
-                                e.id, e.reason
-                            )));
-                        }
+                    let round_psbt = if onchain_inputs.is_empty() {
+                        None
+                    } else {
+                        let mut round_psbt = e.round_tx;
 
-                        tracing::debug!("Unrelated round failed: {e:?}");
+                        sign_round_psbt(&sign_for_pk_fn, &mut round_psbt, onchain_inputs)
+                            .map_err(Error::from)?;
 
+                        Some(round_psbt)
+                    };
+
+                    network_client
+                        .submit_signed_forfeit_txs(signed_forfeit_psbts, round_psbt)
+                        .await?;
+
+                    step = step.next();
+                }
+                RoundStreamEvent::RoundFinalized(e) => {
+                    if step != RoundStep::RoundFinalization {
                         continue;
                     }
-                },
-                Some(Err(e)) => {
-                    return Err(Error::ark_server(e));
+
+                    let round_txid = e.round_txid;
+
+                    tracing::info!(round_id = e.id, %round_txid, "Round finalized");
+
+                    return Ok(round_txid);
                 }
-                None => {
-                    return Err(Error::ark_server("dropped round event stream"));
+                RoundStreamEvent::RoundFailed(e) => {
+                    // Until signing starts we do not know the ID of our round, but a round failing
+                    // then is the one we registered for, and our registration is gone with it.
+                    if round_id.is_none() || Some(&e.id) == round_id.as_ref() {
+                        return Err(Error::round_failed(e));
+                    }
+
+                    tracing::debug!("Unrelated round failed: {e:?}");
+
+                    continue;
                 }
+            },
+            Some(Err(e)) => {
+                return Err(e);
+            }
+            None => {
+                return Err(Error::ark_server("dropped round event stream"));
             }
         }
+    }
 
-        #[derive(Debug, PartialEq, Eq)]
-        enum RoundStep {
-            Start,
-            RoundSigningStarted,
-            RoundSigningNoncesGenerated,
-            RoundFinalization,
-            Finalized,
-        }
+    #[derive(Debug, PartialEq, Eq)]
+    enum RoundStep {
+        Start,
+        RoundSigningStarted,
+        RoundSigningNoncesGenerated,
+        RoundFinalization,
+        Finalized,
+    }
 
-        impl RoundStep {
-            fn next(&self) -> RoundStep {
-                match self {
-                    RoundStep::Start => RoundStep::RoundSigningStarted,
-                    RoundStep::RoundSigningStarted => RoundStep::RoundSigningNoncesGenerated,
-                    RoundStep::RoundSigningNoncesGenerated => RoundStep::RoundFinalization,
-                    RoundStep::RoundFinalization => RoundStep::Finalized,
-                    RoundStep::Finalized => RoundStep::Finalized, // we can't go further
-                }
+    impl RoundStep {
+        fn next(&self) -> RoundStep {
+            match self {
+                RoundStep::Start => RoundStep::RoundSigningStarted,
+                RoundStep::RoundSigningStarted => RoundStep::RoundSigningNoncesGenerated,
+                RoundStep::RoundSigningNoncesGenerated => RoundStep::RoundFinalization,
+                RoundStep::RoundFinalization => RoundStep::Finalized,
+                RoundStep::Finalized => RoundStep::Finalized, // we can't go further
             }
         }
     }
@@ -546,3 +876,483 @@ enum RoundOutputType {
         change_amount: Amount,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+    use ark_core::server::Info;
+    use ark_core::server::RoundFailedEvent;
+    use ark_core::server::RoundFinalizationEvent;
+    use ark_core::server::RoundFinalizedEvent;
+    use ark_core::server::RoundSigningEvent;
+    use ark_core::server::RoundSigningNoncesGeneratedEvent;
+    use ark_core::server::TxTreeLevel;
+    use ark_core::server::TxTreeNode;
+    use ark_core::BoardingOutput;
+    use ark_core::Vtxo;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::transaction::Version;
+    use bitcoin::Network;
+    use bitcoin::OutPoint;
+    use bitcoin::Sequence;
+    use bitcoin::Transaction;
+    use bitcoin::TxIn;
+    use bitcoin::TxOut;
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    /// A step of the scripted event stream.
+    enum Scripted {
+        Event(Box<RoundStreamEvent>),
+        /// A signing event for a round that includes the most recently registered cosigner PK.
+        Signing(&'static str),
+        /// No further event, without closing the stream.
+        Stall,
+    }
+
+    #[derive(Default)]
+    struct Recorded {
+        registrations: usize,
+        cosigner_pk: Option<PublicKey>,
+        nonce_submissions: Vec<String>,
+        signature_submissions: Vec<String>,
+        forfeits: Vec<Psbt>,
+        round_psbt: Option<Psbt>,
+    }
+
+    /// An Ark server that plays back a script of round events, shared by every event stream
+    /// opened on it, and records what the client submits.
+    #[derive(Clone)]
+    struct FakeServer {
+        script: Arc<Mutex<VecDeque<Scripted>>>,
+        recorded: Arc<Mutex<Recorded>>,
+    }
+
+    impl FakeServer {
+        fn new(script: Vec<Scripted>) -> Self {
+            Self {
+                script: Arc::new(Mutex::new(script.into())),
+                recorded: Arc::default(),
+            }
+        }
+    }
+
+    impl RoundTransport for FakeServer {
+        async fn register_inputs_for_next_round(&self, _: &[RoundInput]) -> Result<String, Error> {
+            let mut recorded = self.recorded.lock().unwrap();
+            recorded.registrations += 1;
+
+            Ok(format!("payment-{}", recorded.registrations))
+        }
+
+        async fn register_outputs_for_next_round(
+            &self,
+            _: String,
+            _: &[RoundOutput],
+            cosigner_pks: &[PublicKey],
+        ) -> Result<(), Error> {
+            self.recorded.lock().unwrap().cosigner_pk = Some(cosigner_pks[0]);
+            Ok(())
+        }
+
+        async fn ping(&self, _: String) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn get_event_stream(
+            &self,
+        ) -> Result<BoxStream<'_, Result<RoundStreamEvent, Error>>, Error> {
+            let script = self.script.clone();
+            let recorded = self.recorded.clone();
+
+            // Events are materialized lazily, so that signing events can name the cosigner PK
+            // registered just before them.
+            let stream = futures::stream::unfold((), move |()| {
+                let next = script.lock().unwrap().pop_front();
+                let stall = matches!(next, Some(Scripted::Stall));
+                let event = next.and_then(|scripted| match scripted {
+                    Scripted::Event(event) => Some(*event),
+                    Scripted::Signing(id) => {
+                        Some(RoundStreamEvent::RoundSigning(RoundSigningEvent {
+                            id: id.to_string(),
+                            cosigners_pubkeys: vec![recorded.lock().unwrap().cosigner_pk.unwrap()],
+                            unsigned_vtxo_tree: Some(TxTree { levels: vec![] }),
+                            unsigned_round_tx: psbt(vec![], vec![]),
+                        }))
+                    }
+                    Scripted::Stall => None,
+                });
+
+                async move {
+                    if stall {
+                        futures::future::pending::<()>().await;
+                    }
+
+                    event.map(|event| (Ok(event), ()))
+                }
+            });
+
+            Ok(stream.boxed())
+        }
+
+        async fn submit_tree_nonces(
+            &self,
+            round_id: &str,
+            _: PublicKey,
+            _: Vec<Vec<Option<MusigPubNonce>>>,
+        ) -> Result<(), Error> {
+            let mut recorded = self.recorded.lock().unwrap();
+            recorded.nonce_submissions.push(round_id.to_string());
+            Ok(())
+        }
+
+        async fn submit_tree_signatures(
+            &self,
+            round_id: &str,
+            _: PublicKey,
+            _: Vec<Vec<Option<MusigPartialSignature>>>,
+        ) -> Result<(), Error> {
+            let mut recorded = self.recorded.lock().unwrap();
+            recorded.signature_submissions.push(round_id.to_string());
+            Ok(())
+        }
+
+        async fn submit_signed_forfeit_txs(
+            &self,
+            signed_forfeit_txs: Vec<Psbt>,
+            signed_round_psbt: Option<Psbt>,
+        ) -> Result<(), Error> {
+            let mut recorded = self.recorded.lock().unwrap();
+            recorded.forfeits = signed_forfeit_txs;
+            recorded.round_psbt = signed_round_psbt;
+            Ok(())
+        }
+    }
+
+    fn psbt(inputs: Vec<OutPoint>, outputs: Vec<TxOut>) -> Psbt {
+        Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs,
+        })
+        .unwrap()
+    }
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint {
+            txid: Txid::from_byte_array([n; 32]),
+            vout: 0,
+        }
+    }
+
+    struct Fixture {
+        secp: Secp256k1<All>,
+        server_info: Info,
+        owner: Keypair,
+        onchain_inputs: Vec<round::OnChainInput>,
+        vtxo_inputs: Vec<round::VtxoInput>,
+        outputs: Vec<RoundOutput>,
+        boarding_output: BoardingOutput,
+        vtxo: Vtxo,
+    }
+
+    fn fixture() -> Fixture {
+        let secp = Secp256k1::new();
+        let mut rng = rand::thread_rng();
+
+        let server = Keypair::new(&secp, &mut rng);
+        let owner = Keypair::new(&secp, &mut rng);
+        let exit_delay = Sequence::from_512_second_intervals(2);
+
+        let server_xonly = server.x_only_public_key().0;
+        let owner_xonly = owner.x_only_public_key().0;
+
+        let server_info = Info {
+            pk: server.public_key(),
+            vtxo_tree_expiry: Sequence::from_512_second_intervals(4),
+            unilateral_exit_delay: exit_delay,
+            round_interval: 10,
+            network: Network::Regtest,
+            dust: Amount::from_sat(330),
+            boarding_descriptor_template: String::new(),
+            vtxo_descriptor_templates: vec![],
+            forfeit_address: Address::p2tr(&secp, server_xonly, None, Network::Regtest),
+        };
+
+        let boarding_output = BoardingOutput::new(
+            &secp,
+            server_xonly,
+            owner_xonly,
+            exit_delay,
+            Network::Regtest,
+        )
+        .unwrap();
+        let vtxo = Vtxo::new(
+            &secp,
+            server_xonly,
+            owner_xonly,
+            vec![],
+            exit_delay,
+            Network::Regtest,
+        )
+        .unwrap();
+
+        Fixture {
+            server_info,
+            owner,
+            onchain_inputs: vec![round::OnChainInput::new(
+                boarding_output.clone(),
+                outpoint(1),
+            )],
+            vtxo_inputs: vec![round::VtxoInput::new(
+                vtxo.clone(),
+                Amount::from_sat(50_000),
+                outpoint(2),
+            )],
+            outputs: vec![RoundOutput::new_virtual(
+                vtxo.to_ark_address(),
+                Amount::from_sat(150_000),
+            )],
+            boarding_output,
+            vtxo,
+            secp,
+        }
+    }
+
+    /// Join rounds on `server` as the owner of the fixture, retrying failed rounds.
+    async fn join(
+        fixture: &Fixture,
+        server: &FakeServer,
+        max_retries: usize,
+    ) -> Result<Txid, Error> {
+        join_within(fixture, server, max_retries, Duration::from_secs(5)).await
+    }
+
+    /// [`join`], giving up on every round after `timeout`.
+    async fn join_within(
+        fixture: &Fixture,
+        server: &FakeServer,
+        max_retries: usize,
+        timeout: Duration,
+    ) -> Result<Txid, Error> {
+        let sign_for_pk_fn = |pk: &XOnlyPublicKey,
+                              msg: &secp256k1::Message|
+         -> Result<schnorr::Signature, ark_core::Error> {
+            assert_eq!(*pk, fixture.owner.x_only_public_key().0);
+            Ok(fixture.secp.sign_schnorr_no_aux_rand(msg, &fixture.owner))
+        };
+
+        retry_failed_rounds(max_retries, || async {
+            run_round(
+                server,
+                &mut rand::thread_rng(),
+                &fixture.secp,
+                &fixture.server_info,
+                &fixture.owner,
+                sign_for_pk_fn,
+                RoundParticipation {
+                    onchain_inputs: &fixture.onchain_inputs,
+                    vtxo_inputs: &fixture.vtxo_inputs,
+                    outputs: &fixture.outputs,
+                },
+                timeout,
+            )
+            .await
+        })
+        .await
+    }
+
+    /// A finalization event whose round transaction spends our boarding output and whose
+    /// connector tree has a connector for our VTXO.
+    fn finalization(fixture: &Fixture, id: &str) -> Scripted {
+        let mut round_tx = psbt(
+            vec![outpoint(1)],
+            vec![TxOut {
+                value: Amount::from_sat(150_000),
+                script_pubkey: fixture.vtxo.script_pubkey(),
+            }],
+        );
+        round_tx.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: fixture.boarding_output.script_pubkey(),
+        });
+
+        let connector = psbt(
+            vec![outpoint(3)],
+            vec![TxOut {
+                value: fixture.server_info.dust,
+                script_pubkey: fixture.server_info.forfeit_address.script_pubkey(),
+            }],
+        );
+        let connector_txid = connector.unsigned_tx.compute_txid();
+
+        Scripted::Event(Box::new(RoundStreamEvent::RoundFinalization(
+            RoundFinalizationEvent {
+                id: id.to_string(),
+                round_tx,
+                vtxo_tree: TxTree { levels: vec![] },
+                connector_tree: TxTree {
+                    levels: vec![TxTreeLevel {
+                        nodes: vec![TxTreeNode {
+                            txid: connector_txid,
+                            tx: connector,
+                            parent_txid: outpoint(3).txid,
+                        }],
+                    }],
+                },
+                connectors_index: HashMap::from([(
+                    outpoint(2),
+                    OutPoint {
+                        txid: connector_txid,
+                        vout: 0,
+                    },
+                )]),
+                min_relay_fee_rate: 1_000,
+            },
+        )))
+    }
+
+    fn nonces(id: &str) -> Scripted {
+        Scripted::Event(Box::new(RoundStreamEvent::RoundSigningNoncesGenerated(
+            RoundSigningNoncesGeneratedEvent {
+                id: id.to_string(),
+                tree_nonces: vec![],
+            },
+        )))
+    }
+
+    fn failed(id: &str) -> Scripted {
+        Scripted::Event(Box::new(RoundStreamEvent::RoundFailed(RoundFailedEvent {
+            id: id.to_string(),
+            reason: "not enough participants".to_string(),
+        })))
+    }
+
+    fn finalized(id: &str, txid: Txid) -> Scripted {
+        Scripted::Event(Box::new(RoundStreamEvent::RoundFinalized(
+            RoundFinalizedEvent {
+                id: id.to_string(),
+                round_txid: txid,
+            },
+        )))
+    }
+
+    #[tokio::test]
+    async fn completes_round_despite_unrelated_events_and_a_failed_round() {
+        let fixture = fixture();
+        let round_txid = Txid::from_byte_array([9; 32]);
+
+        let server = FakeServer::new(vec![
+            Scripted::Signing("round-1"),
+            failed("unrelated"),
+            finalized("unrelated", Txid::from_byte_array([8; 32])),
+            failed("round-1"),
+            // Events for the failed round arriving late must not confuse the next attempt.
+            nonces("round-1"),
+            Scripted::Signing("round-2"),
+            finalized("round-2", round_txid),
+            nonces("round-2"),
+            finalization(&fixture, "round-2"),
+            finalized("round-2", round_txid),
+        ]);
+
+        let txid = join(&fixture, &server, 1).await.unwrap();
+
+        assert_eq!(txid, round_txid);
+
+        let recorded = server.recorded.lock().unwrap();
+        assert_eq!(recorded.registrations, 2);
+        assert_eq!(recorded.nonce_submissions, ["round-1", "round-2"]);
+        assert_eq!(recorded.signature_submissions, ["round-2"]);
+
+        // One signed forfeit per VTXO input.
+        assert_eq!(recorded.forfeits.len(), 1);
+        assert_eq!(
+            recorded.forfeits[0].unsigned_tx.input[1].previous_output,
+            outpoint(2)
+        );
+        assert!(!recorded.forfeits[0].inputs[1].tap_script_sigs.is_empty());
+
+        // The boarding input of the round transaction is signed.
+        let round_psbt = recorded.round_psbt.as_ref().unwrap();
+        assert!(!round_psbt.inputs[0].tap_script_sigs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_repeated_round_failures() {
+        let fixture = fixture();
+        let max_retries = 2;
+
+        let script = (0..=max_retries)
+            .flat_map(|_| [Scripted::Signing("round"), failed("round")])
+            .collect();
+        let server = FakeServer::new(script);
+
+        let err = join(&fixture, &server, max_retries).await.unwrap_err();
+
+        assert!(err.round_failure().is_some());
+        assert_eq!(
+            server.recorded.lock().unwrap().registrations,
+            max_retries + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn failure_before_signing_is_retried() {
+        let fixture = fixture();
+        let round_txid = Txid::from_byte_array([9; 32]);
+
+        let server = FakeServer::new(vec![
+            failed("round-1"),
+            Scripted::Signing("round-2"),
+            nonces("round-2"),
+            finalization(&fixture, "round-2"),
+            finalized("round-2", round_txid),
+        ]);
+
+        let txid = join(&fixture, &server, 1).await.unwrap();
+
+        assert_eq!(txid, round_txid);
+
+        let recorded = server.recorded.lock().unwrap();
+        assert_eq!(recorded.registrations, 2);
+        assert_eq!(recorded.nonce_submissions, ["round-2"]);
+    }
+
+    #[tokio::test]
+    async fn stalled_round_times_out() {
+        let fixture = fixture();
+        let server = FakeServer::new(vec![Scripted::Signing("round"), Scripted::Stall]);
+
+        let err = join_within(&fixture, &server, 2, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+
+        // A round that times out is not joined again.
+        assert_eq!(err.kind(), ErrorKind::RoundTimeout);
+        assert!(err.round_failure().is_none());
+        assert_eq!(server.recorded.lock().unwrap().registrations, 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_is_an_error() {
+        let fixture = fixture();
+        let server = FakeServer::new(vec![Scripted::Signing("round")]);
+
+        let err = join(&fixture, &server, 2).await.unwrap_err();
+
+        // Only failed rounds are joined again.
+        assert!(err.round_failure().is_none());
+        assert_eq!(server.recorded.lock().unwrap().registrations, 1);
+    }
+}
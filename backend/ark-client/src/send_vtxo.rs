@@ -5,16 +5,65 @@ use crate::Blockchain;
 use crate::Client;
 use crate::Error;
 use ark_core::coin_select::select_vtxos;
+use ark_core::coin_select::VtxoOutPoint;
 use ark_core::redeem;
 use ark_core::redeem::build_redeem_transaction;
+use ark_core::redeem::redeem_transaction_fee;
 use ark_core::redeem::sign_redeem_transaction;
+use ark_core::redeem::MAX_REDEEM_INPUTS;
+use ark_core::redeem::MAX_REDEEM_OUTPUTS;
 use ark_core::ArkAddress;
+use ark_core::Vtxo;
+use bitcoin::key::Keypair;
 use bitcoin::key::Secp256k1;
 use bitcoin::secp256k1;
 use bitcoin::secp256k1::schnorr;
 use bitcoin::Amount;
+use bitcoin::OutPoint;
 use bitcoin::Psbt;
 use bitcoin::XOnlyPublicKey;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+/// What [`Client::send_vtxo`] would do, worked out without signing or submitting anything.
+#[derive(Debug, Clone)]
+pub struct SendPreview {
+    /// The VTXOs that would be spent, oldest expiry first.
+    pub selected: Vec<VtxoOutPoint>,
+    /// The unsigned redeem transaction.
+    pub psbt: Psbt,
+    /// The fee of the redeem transaction.
+    pub fee: Amount,
+    /// What the recipient would get. Less than the amount sent if there is no change output to
+    /// pay the fee from.
+    pub sent: Amount,
+    /// What would come back to our own address, after the fee.
+    pub change: Amount,
+    /// Outputs below this amount are dust.
+    pub dust: Amount,
+}
+
+/// One of the redeem transactions of [`Client::send_vtxos`].
+#[derive(Debug)]
+pub struct BatchTransaction {
+    /// The positions of the outputs it pays in the list given to [`Client::send_vtxos`].
+    pub outputs: Vec<usize>,
+    /// The submitted transaction, or why it could not be built or submitted.
+    pub result: Result<Psbt, Error>,
+}
+
+/// The VTXOs we can spend, as listed by [`Client::spendable_vtxos`], along with the keypair of
+/// the client that owns them.
+type SpendableVtxos = Vec<(Vec<ark_core::server::VtxoOutPoint>, Vtxo, Keypair)>;
+
+/// An unsigned redeem transaction, along with what it spends.
+struct PreparedRedeem {
+    selected: Vec<VtxoOutPoint>,
+    vtxo_inputs: Vec<redeem::VtxoInput>,
+    /// The keypair that signs each of `vtxo_inputs`.
+    signers: Vec<Keypair>,
+    psbt: Psbt,
+}
 
 impl<B, W> Client<B, W>
 where
@@ -22,22 +71,240 @@ where
     W: BoardingWallet + OnchainWallet,
 {
     pub async fn send_vtxo(&self, address: ArkAddress, amount: Amount) -> Result<Psbt, Error> {
-        let spendable_vtxos = self
-            .spendable_vtxos()
-            .await
-            .context("failed to get spendable VTXOs")?;
+        self.send_vtxo_from(&[], address, amount).await
+    }
+
+    /// Like [`Client::send_vtxo`], but also spend the VTXOs of `others`: clients of the same Ark
+    /// server, for keys we hold as well. Every input is signed by the client that owns it, and
+    /// the change comes back to our own address.
+    pub async fn send_vtxo_from(
+        &self,
+        others: &[&Self],
+        address: ArkAddress,
+        amount: Amount,
+    ) -> Result<Psbt, Error> {
+        let spendable_vtxos = self.spendable_vtxos_with(others).await?;
+
+        let prepared =
+            self.prepare_redeem(&spendable_vtxos, &HashSet::new(), &[(&address, amount)])?;
+
+        self.sign_and_submit_redeem(prepared).await
+    }
+
+    /// Pay every `(address, amount)` of `outputs` off-chain, in as few redeem transactions as
+    /// the limits on their inputs and outputs allow.
+    ///
+    /// Outputs are paid in order, up to [`MAX_REDEEM_OUTPUTS`] minus one for the change per
+    /// transaction. A group of outputs that needs more than [`MAX_REDEEM_INPUTS`] VTXOs is split
+    /// in half until it does not. Every transaction spends VTXOs of its own, so one failing does
+    /// not stop the others; the outcome of each is returned.
+    ///
+    /// Fails without paying anything if `outputs` is empty, if any amount is below the dust
+    /// limit, or if the spendable VTXOs do not add up to the total.
+    pub async fn send_vtxos(
+        &self,
+        outputs: &[(ArkAddress, Amount)],
+    ) -> Result<Vec<BatchTransaction>, Error> {
+        self.send_vtxos_from(&[], outputs).await
+    }
+
+    /// Like [`Client::send_vtxos`], but also spend the VTXOs of `others`, as
+    /// [`Client::send_vtxo_from`] does.
+    pub async fn send_vtxos_from(
+        &self,
+        others: &[&Self],
+        outputs: &[(ArkAddress, Amount)],
+    ) -> Result<Vec<BatchTransaction>, Error> {
+        if outputs.is_empty() {
+            return Err(Error::ad_hoc("no outputs to pay"));
+        }
+
+        let dust = self.server_info.dust;
+        if let Some((i, (_, amount))) = outputs
+            .iter()
+            .enumerate()
+            .find(|(_, (_, amount))| *amount < dust)
+        {
+            return Err(Error::coin_select(format!(
+                "output {i} of {amount} is below the dust limit of {dust}"
+            )));
+        }
+
+        let spendable_vtxos = self.spendable_vtxos_with(others).await?;
+
+        let total: Amount = outputs.iter().map(|(_, amount)| *amount).sum();
+        let available: Amount = spendable_vtxos
+            .iter()
+            .flat_map(|(outpoints, _, _)| outpoints.iter().map(|outpoint| outpoint.amount))
+            .sum();
+        if available < total {
+            return Err(Error::coin_select(format!(
+                "insufficient funds: available = {available}, needed = {total}"
+            )));
+        }
+
+        let positions = (0..outputs.len()).collect::<Vec<_>>();
+        let mut pending = positions
+            .chunks(MAX_REDEEM_OUTPUTS - 1)
+            .map(<[usize]>::to_vec)
+            .collect::<VecDeque<_>>();
+
+        let mut spent = HashSet::new();
+        let mut transactions = Vec::new();
+        while let Some(batch) = pending.pop_front() {
+            let batch_outputs = batch
+                .iter()
+                .map(|i| (&outputs[*i].0, outputs[*i].1))
+                .collect::<Vec<_>>();
+
+            let prepared = match self.prepare_redeem(&spendable_vtxos, &spent, &batch_outputs) {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    transactions.push(BatchTransaction {
+                        outputs: batch,
+                        result: Err(e),
+                    });
+                    continue;
+                }
+            };
+
+            if prepared.selected.len() > MAX_REDEEM_INPUTS {
+                if batch.len() > 1 {
+                    let (first, second) = batch.split_at(batch.len() / 2);
+                    pending.push_front(second.to_vec());
+                    pending.push_front(first.to_vec());
+                } else {
+                    transactions.push(BatchTransaction {
+                        outputs: batch,
+                        result: Err(Error::coin_select(format!(
+                            "payment needs {} VTXOs, more than the {MAX_REDEEM_INPUTS} a \
+                             transaction can spend",
+                            prepared.selected.len()
+                        ))),
+                    });
+                }
+                continue;
+            }
 
+            // The recipients' outputs may have paid the fee, and the change may be too small.
+            if let Some(output) = prepared
+                .psbt
+                .unsigned_tx
+                .output
+                .iter()
+                .find(|output| output.value < dust)
+            {
+                transactions.push(BatchTransaction {
+                    outputs: batch,
+                    result: Err(Error::coin_select(format!(
+                        "transaction would create an output of {}, below the dust limit of {dust}",
+                        output.value
+                    ))),
+                });
+                continue;
+            }
+
+            // A failed submission may still have reached the Ark server, so its VTXOs are not
+            // offered to later transactions either.
+            spent.extend(prepared.selected.iter().map(|vtxo| vtxo.outpoint));
+
+            let result = self.sign_and_submit_redeem(prepared).await;
+            transactions.push(BatchTransaction {
+                outputs: batch,
+                result,
+            });
+        }
+
+        transactions.sort_by_key(|transaction| transaction.outputs[0]);
+
+        Ok(transactions)
+    }
+
+    /// Select the VTXOs and build the redeem transaction that [`Client::send_vtxo`] would,
+    /// without signing or submitting it.
+    pub async fn preview_send_vtxo(
+        &self,
+        address: ArkAddress,
+        amount: Amount,
+    ) -> Result<SendPreview, Error> {
+        self.preview_send_vtxo_from(&[], address, amount).await
+    }
+
+    /// What [`Client::send_vtxo_from`] would do, without signing or submitting anything.
+    pub async fn preview_send_vtxo_from(
+        &self,
+        others: &[&Self],
+        address: ArkAddress,
+        amount: Amount,
+    ) -> Result<SendPreview, Error> {
+        let spendable_vtxos = self.spendable_vtxos_with(others).await?;
+
+        let PreparedRedeem {
+            selected,
+            vtxo_inputs,
+            psbt,
+            ..
+        } = self.prepare_redeem(&spendable_vtxos, &HashSet::new(), &[(&address, amount)])?;
+
+        let outputs = &psbt.unsigned_tx.output;
+        let fee = redeem_transaction_fee(&vtxo_inputs, outputs.len()).map_err(Error::from)?;
+
+        // The recipient's output comes first, followed by the change output, if any.
+        let sent = outputs[0].value;
+        let change = outputs.get(1).map(|output| output.value).unwrap_or_default();
+
+        Ok(SendPreview {
+            selected,
+            psbt,
+            fee,
+            sent,
+            change,
+            dust: self.server_info.dust,
+        })
+    }
+
+    /// Our spendable VTXOs and those of `others`.
+    async fn spendable_vtxos_with(&self, others: &[&Self]) -> Result<SpendableVtxos, Error> {
+        let mut spendable_vtxos = Vec::new();
+        for client in std::iter::once(self).chain(others.iter().copied()) {
+            let kp = *client.kp();
+            let vtxos = client
+                .spendable_vtxos()
+                .await
+                .context("failed to get spendable VTXOs")?;
+
+            spendable_vtxos.extend(
+                vtxos
+                    .into_iter()
+                    .map(|(outpoints, vtxo)| (outpoints, vtxo, kp)),
+            );
+        }
+
+        Ok(spendable_vtxos)
+    }
+
+    /// Select VTXOs other than `spent` to pay `outputs` and build the unsigned redeem
+    /// transaction that spends them, sending the change back to our own address.
+    fn prepare_redeem(
+        &self,
+        spendable_vtxos: &SpendableVtxos,
+        spent: &HashSet<OutPoint>,
+        outputs: &[(&ArkAddress, Amount)],
+    ) -> Result<PreparedRedeem, Error> {
         // Run coin selection algorithm on candidate spendable VTXOs.
         let spendable_vtxo_outpoints = spendable_vtxos
             .iter()
-            .flat_map(|(vtxos, _)| vtxos.clone())
-            .map(|vtxo| ark_core::coin_select::VtxoOutPoint {
+            .flat_map(|(vtxos, _, _)| vtxos.clone())
+            .filter(|vtxo| !spent.contains(&vtxo.outpoint))
+            .map(|vtxo| VtxoOutPoint {
                 outpoint: vtxo.outpoint,
                 expire_at: vtxo.expire_at,
                 amount: vtxo.amount,
             })
             .collect::<Vec<_>>();
 
+        let amount = outputs.iter().map(|(_, amount)| *amount).sum();
+
         let selected_coins = select_vtxos(
             spendable_vtxo_outpoints,
             amount,
@@ -47,39 +314,59 @@ where
         .map_err(Error::from)
         .context("failed to select coins")?;
 
-        let vtxo_inputs = selected_coins
-            .into_iter()
+        let (vtxo_inputs, signers): (Vec<_>, Vec<_>) = selected_coins
+            .iter()
             .map(|vtxo_outpoint| {
-                let vtxo = spendable_vtxos
-                    .clone()
-                    .into_iter()
-                    .find_map(|(vtxo_outpoints, vtxo)| {
+                let (vtxo, kp) = spendable_vtxos
+                    .iter()
+                    .find_map(|(vtxo_outpoints, vtxo, kp)| {
                         vtxo_outpoints
                             .iter()
                             .any(|v| v.outpoint == vtxo_outpoint.outpoint)
-                            .then_some(vtxo)
+                            .then_some((vtxo.clone(), *kp))
                     })
                     .expect("to find matching default VTXO");
 
-                redeem::VtxoInput::new(vtxo, vtxo_outpoint.amount, vtxo_outpoint.outpoint)
+                (
+                    redeem::VtxoInput::new(vtxo, vtxo_outpoint.amount, vtxo_outpoint.outpoint),
+                    kp,
+                )
             })
-            .collect::<Vec<_>>();
+            .unzip();
 
         let (change_address, _) = self.get_offchain_address()?;
 
-        let mut redeem_psbt =
-            build_redeem_transaction(&[(&address, amount)], Some(&change_address), &vtxo_inputs)
-                .map_err(Error::from)?;
+        let psbt = build_redeem_transaction(outputs, Some(&change_address), &vtxo_inputs)
+            .map_err(Error::from)?;
+
+        Ok(PreparedRedeem {
+            selected: selected_coins,
+            vtxo_inputs,
+            signers,
+            psbt,
+        })
+    }
+
+    async fn sign_and_submit_redeem(&self, prepared: PreparedRedeem) -> Result<Psbt, Error> {
+        let PreparedRedeem {
+            vtxo_inputs,
+            signers,
+            psbt: mut redeem_psbt,
+            ..
+        } = prepared;
 
-        let sign_fn =
-        |msg: secp256k1::Message| -> Result<(schnorr::Signature, XOnlyPublicKey), ark_core::Error> {
-            let sig = Secp256k1::new().sign_schnorr_no_aux_rand(&msg, self.kp());
-            let pk = self.kp().x_only_public_key().0;
+        let secp = Secp256k1::new();
+        for (i, kp) in signers.iter().enumerate() {
+            let sign_fn = |msg: secp256k1::Message| -> Result<
+                (schnorr::Signature, XOnlyPublicKey),
+                ark_core::Error,
+            > {
+                let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
+                let pk = kp.x_only_public_key().0;
 
-            Ok((sig, pk))
-        };
+                Ok((sig, pk))
+            };
 
-        for (i, _) in vtxo_inputs.iter().enumerate() {
             sign_redeem_transaction(sign_fn, &mut redeem_psbt, &vtxo_inputs, i)?;
         }
 
@@ -7,17 +7,36 @@ use crate::wallet::OnchainWallet;
 use crate::Blockchain;
 use crate::Client;
 use ark_core::unilateral_exit;
+use ark_core::unilateral_exit::create_unilateral_exit_sweep;
 use ark_core::unilateral_exit::create_unilateral_exit_transaction;
 use ark_core::unilateral_exit::prepare_vtxo_tree_transactions;
+use ark_core::unilateral_exit::VtxoInput;
+use ark_core::Vtxo;
 use backon::ExponentialBuilder;
 use backon::Retryable;
 use bitcoin::Address;
 use bitcoin::Amount;
+use bitcoin::FeeRate;
 use bitcoin::Transaction;
 use bitcoin::TxOut;
 use bitcoin::Txid;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::time::Duration;
+
+/// Everything needed to leave the Ark unilaterally, prepared while the Ark server is still
+/// reachable and the owner's key is at hand.
+#[derive(Debug, Clone)]
+pub struct UnilateralExit {
+    /// The transactions of the VTXO tree branches that lead to our VTXOs, in an order in which
+    /// they can be published.
+    pub branch_txs: Vec<Transaction>,
+    /// A signed transaction that spends the VTXOs to the destination address. It only becomes
+    /// valid once every branch transaction is confirmed and `exit_delay` has passed.
+    pub sweep_tx: Transaction,
+    /// The relative timelock on the exit path of the VTXOs.
+    pub exit_delay: Duration,
+}
 
 // TODO: We should not _need_ to connect to the Ark server to perform unilateral exit. Currently we
 // do talk to the Ark server for simplicity.
@@ -30,9 +49,169 @@ where
     pub async fn commit_vtxos_on_chain(&self) -> Result<(), Error> {
         let spendable_vtxos = self.spendable_vtxos().await?;
 
-        let network_client = &self.network_client();
-        let vtxos = spendable_vtxos
+        let off_board_txs = self.vtxo_tree_transactions(&spendable_vtxos).await?;
+
+        let blockchain = &self.blockchain();
+
+        let off_board_txs_len = off_board_txs.len();
+        for (i, tx) in off_board_txs.iter().enumerate() {
+            let txid = tx.compute_txid();
+
+            let is_not_published = blockchain.find_tx(&txid).await?.is_none();
+            if is_not_published {
+                tracing::info!(%txid, "Broadcasting VTXO transaction");
+                let broadcast = || async { blockchain.broadcast(tx).await };
+
+                broadcast
+                    .retry(ExponentialBuilder::default().with_max_times(5))
+                    .sleep(sleep)
+                    // TODO: Use `when` to only retry certain errors.
+                    .notify(|err: &Error, dur: std::time::Duration| {
+                        tracing::warn!(
+                            "Retrying broadcasting VTXO transaction {txid} after {dur:?}. Error: {err}",
+                        );
+                    })
+                    .await
+                    .with_context(|| format!("Failed to broadcast VTXO transaction {txid}"))?;
+
+                tracing::info!(%txid, i, total_txs = off_board_txs_len, "Broadcasted VTXO transaction");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prepare a unilateral exit of our VTXOs to `to_address`, without publishing anything.
+    ///
+    /// The sweep transaction is signed up front, so that the exit can be completed without the
+    /// owner's key or the Ark server. It pays `fee_rate`.
+    ///
+    /// Unconfirmed (out-of-round) VTXOs cannot be exited yet and are left out; they are listed by
+    /// [`Client::unsettled_vtxos`]. Returns `None` if there is nothing to exit.
+    pub async fn prepare_unilateral_exit(
+        &self,
+        to_address: Address,
+        fee_rate: FeeRate,
+    ) -> Result<Option<UnilateralExit>, Error> {
+        let mut unsettled = Vec::new();
+        let spendable_vtxos = self
+            .spendable_vtxos()
+            .await?
             .into_iter()
+            .map(|(vtxo_outpoints, vtxo)| {
+                let (confirmed, unconfirmed): (Vec<_>, Vec<_>) = vtxo_outpoints
+                    .into_iter()
+                    .partition(|vtxo_outpoint| vtxo_outpoint.redeem_tx.is_none());
+                unsettled.extend(unconfirmed.into_iter().map(|v| v.outpoint));
+                (confirmed, vtxo)
+            })
+            .filter(|(vtxo_outpoints, _)| !vtxo_outpoints.is_empty())
+            .collect::<Vec<_>>();
+
+        if !unsettled.is_empty() {
+            tracing::warn!(
+                ?unsettled,
+                "Leaving VTXOs that are not settled in a round yet out of the unilateral exit"
+            );
+        }
+
+        let vtxo_inputs = spendable_vtxos
+            .iter()
+            .flat_map(|(vtxo_outpoints, vtxo)| {
+                vtxo_outpoints.iter().map(|vtxo_outpoint| {
+                    VtxoInput::new(vtxo.clone(), vtxo_outpoint.amount, vtxo_outpoint.outpoint)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let exit_delay = match spendable_vtxos.first() {
+            Some((_, vtxo)) => vtxo.exit_delay_duration(),
+            None => return Ok(None),
+        };
+
+        let sweep_tx = create_unilateral_exit_sweep(
+            self.kp(),
+            to_address,
+            fee_rate,
+            self.server_info.dust,
+            &vtxo_inputs,
+        )
+        .map_err(Error::from)?;
+
+        let branch_txs = self.vtxo_tree_transactions(&spendable_vtxos).await?;
+
+        Ok(Some(UnilateralExit {
+            branch_txs,
+            sweep_tx,
+            exit_delay,
+        }))
+    }
+
+    /// Our spendable VTXOs that are not settled in a round yet, and therefore cannot be exited
+    /// unilaterally.
+    pub async fn unsettled_vtxos(&self) -> Result<Vec<ark_core::server::VtxoOutPoint>, Error> {
+        let unsettled = self
+            .spendable_vtxos()
+            .await?
+            .into_iter()
+            .flat_map(|(vtxo_outpoints, _)| vtxo_outpoints)
+            .filter(|vtxo_outpoint| vtxo_outpoint.redeem_tx.is_some())
+            .collect();
+
+        Ok(unsettled)
+    }
+
+    /// The VTXO tree branch of each of `vtxos`, i.e. the transactions that must be published to
+    /// get it on chain, in publishing order.
+    ///
+    /// Unsettled VTXOs have no branch yet and get an empty one.
+    pub async fn vtxo_branches(
+        &self,
+        vtxos: &[ark_core::server::VtxoOutPoint],
+    ) -> Result<Vec<Vec<Transaction>>, Error> {
+        let provenances = vtxos
+            .iter()
+            .filter(|vtxo_outpoint| vtxo_outpoint.redeem_tx.is_none())
+            .map(|vtxo_outpoint| {
+                unilateral_exit::VtxoProvenance::new(
+                    vtxo_outpoint.outpoint,
+                    vtxo_outpoint.round_txid,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let rounds = self.rounds_of(&provenances).await?;
+
+        vtxos
+            .iter()
+            .map(|vtxo_outpoint| {
+                if vtxo_outpoint.redeem_tx.is_some() {
+                    return Ok(Vec::new());
+                }
+
+                let round_txid = vtxo_outpoint.round_txid;
+                let round = rounds[&round_txid].clone();
+
+                prepare_vtxo_tree_transactions(
+                    &[unilateral_exit::VtxoProvenance::new(
+                        vtxo_outpoint.outpoint,
+                        round_txid,
+                    )],
+                    HashMap::from([(round_txid, round)]),
+                )
+                .map_err(Error::from)
+            })
+            .collect()
+    }
+
+    /// The VTXO tree transactions that must be published to get `spendable_vtxos` on chain.
+    async fn vtxo_tree_transactions(
+        &self,
+        spendable_vtxos: &[(Vec<ark_core::server::VtxoOutPoint>, Vtxo)],
+    ) -> Result<Vec<Transaction>, Error> {
+        let vtxos = spendable_vtxos
+            .iter()
+            .cloned()
             .flat_map(|(vtxo_outpoints, _)| {
                 vtxo_outpoints
                     .into_iter()
@@ -53,6 +232,18 @@ where
             })
             .collect::<Vec<_>>();
 
+        let rounds = self.rounds_of(&vtxos).await?;
+
+        prepare_vtxo_tree_transactions(vtxos.as_slice(), rounds).map_err(Error::from)
+    }
+
+    /// The rounds that `vtxos` come from, fetched from the Ark server.
+    async fn rounds_of(
+        &self,
+        vtxos: &[unilateral_exit::VtxoProvenance],
+    ) -> Result<HashMap<Txid, ark_core::server::Round>, Error> {
+        let network_client = &self.network_client();
+
         let mut rounds = HashMap::new();
         for vtxo in vtxos.iter() {
             let round_txid = vtxo.round_txid();
@@ -67,37 +258,7 @@ where
             }
         }
 
-        let off_board_txs =
-            prepare_vtxo_tree_transactions(vtxos.as_slice(), rounds).map_err(Error::from)?;
-
-        let blockchain = &self.blockchain();
-
-        let off_board_txs_len = off_board_txs.len();
-        for (i, tx) in off_board_txs.iter().enumerate() {
-            let txid = tx.compute_txid();
-
-            let is_not_published = blockchain.find_tx(&txid).await?.is_none();
-            if is_not_published {
-                tracing::info!(%txid, "Broadcasting VTXO transaction");
-                let broadcast = || async { blockchain.broadcast(tx).await };
-
-                broadcast
-                    .retry(ExponentialBuilder::default().with_max_times(5))
-                    .sleep(sleep)
-                    // TODO: Use `when` to only retry certain errors.
-                    .notify(|err: &Error, dur: std::time::Duration| {
-                        tracing::warn!(
-                            "Retrying broadcasting VTXO transaction {txid} after {dur:?}. Error: {err}",
-                        );
-                    })
-                    .await
-                    .with_context(|| format!("Failed to broadcast VTXO transaction {txid}"))?;
-
-                tracing::info!(%txid, i, total_txs = off_board_txs_len, "Broadcasted VTXO transaction");
-            }
-        }
-
-        Ok(())
+        Ok(rounds)
     }
 
     /// Spend boarding outputs and VTXOs to an _on-chain_ address.
@@ -1,4 +1,5 @@
 use crate::error::Error;
+use ark_core::server;
 use ark_core::BoardingOutput;
 use bitcoin::secp256k1::schnorr::Signature;
 use bitcoin::secp256k1::Message;
@@ -6,17 +7,13 @@ use bitcoin::secp256k1::SecretKey;
 use bitcoin::Address;
 use bitcoin::Amount;
 use bitcoin::FeeRate;
-use bitcoin::Network;
 use bitcoin::Psbt;
 use bitcoin::XOnlyPublicKey;
 
 pub trait BoardingWallet {
-    fn new_boarding_output(
-        &self,
-        server_pubkey: XOnlyPublicKey,
-        exit_delay: bitcoin::Sequence,
-        network: Network,
-    ) -> Result<BoardingOutput, Error>;
+    /// The boarding output of the wallet's key for the Ark server described by `server_info`,
+    /// as built by [`ark_core::descriptor::boarding_output`].
+    fn new_boarding_output(&self, server_info: &server::Info) -> Result<BoardingOutput, Error>;
 
     fn get_boarding_outputs(&self) -> Result<Vec<BoardingOutput>, Error>;
 
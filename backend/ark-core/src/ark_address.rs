@@ -1,13 +1,33 @@
 use crate::Error;
+use bech32::primitives::decode::CheckedHrpstring;
 use bech32::Bech32m;
 use bech32::Hrp;
 use bitcoin::key::TweakedPublicKey;
 use bitcoin::Network;
+use bitcoin::NetworkKind;
 use bitcoin::ScriptBuf;
 use bitcoin::XOnlyPublicKey;
 
-#[derive(Debug, Clone, Copy)]
+/// The human-readable part of mainnet addresses.
+const MAINNET_HRP: &str = "ark";
+
+/// The human-readable part of the addresses of every test network.
+const TESTNET_HRP: &str = "tark";
+
+/// The layout of the payload of an [`ArkAddress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressVersion {
+    /// The 64-byte payload `server || vtxo_tap_key`, from before addresses carried a version.
+    ///
+    /// Ark servers of this protocol version expect it, so [`ArkAddress::new`] still uses it.
+    Unversioned,
+    /// The 65-byte payload `0x00 || server || vtxo_tap_key`.
+    V0,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ArkAddress {
+    version: AddressVersion,
     hrp: Hrp,
     server: XOnlyPublicKey,
     vtxo_tap_key: TweakedPublicKey,
@@ -22,45 +42,109 @@ impl ArkAddress {
 impl ArkAddress {
     pub fn new(network: Network, server: XOnlyPublicKey, vtxo_tap_key: TweakedPublicKey) -> Self {
         let hrp = match network {
-            Network::Bitcoin => "ark",
-            _ => "tark",
+            Network::Bitcoin => MAINNET_HRP,
+            _ => TESTNET_HRP,
         };
 
         let hrp = Hrp::parse_unchecked(hrp);
 
         Self {
+            version: AddressVersion::Unversioned,
             hrp,
             server,
             vtxo_tap_key,
         }
     }
 
+    /// The same address, encoded with the payload layout of `version`.
+    pub fn with_version(self, version: AddressVersion) -> Self {
+        Self { version, ..self }
+    }
+
+    pub fn version(&self) -> AddressVersion {
+        self.version
+    }
+
+    /// Whether the address is for mainnet or for one of the test networks, which share their
+    /// addresses.
+    pub fn network(&self) -> NetworkKind {
+        if self.hrp == Hrp::parse_unchecked(MAINNET_HRP) {
+            NetworkKind::Main
+        } else {
+            NetworkKind::Test
+        }
+    }
+
     pub fn encode(&self) -> String {
-        let mut bytes = [0u8; 64];
+        let mut bytes = Vec::with_capacity(65);
 
-        bytes[..32].copy_from_slice(&self.server.serialize());
-        bytes[32..].copy_from_slice(&self.vtxo_tap_key.serialize());
+        match self.version {
+            AddressVersion::Unversioned => {}
+            AddressVersion::V0 => bytes.push(0),
+        }
+        bytes.extend_from_slice(&self.server.serialize());
+        bytes.extend_from_slice(&self.vtxo_tap_key.serialize());
 
         bech32::encode::<Bech32m>(self.hrp, bytes.as_slice()).expect("data can be encoded")
     }
 
+    /// Decode an address of any network. Both payload layouts of [`AddressVersion`] are
+    /// accepted.
     pub fn decode(value: &str) -> Result<Self, Error> {
-        let (hrp, bytes) = bech32::decode(value).map_err(Error::address_format)?;
+        let checked = CheckedHrpstring::new::<Bech32m>(value).map_err(Error::address_format)?;
+
+        let hrp = checked.hrp();
+        if hrp != Hrp::parse_unchecked(MAINNET_HRP) && hrp != Hrp::parse_unchecked(TESTNET_HRP) {
+            return Err(Error::address_format(format!(
+                "unknown human-readable part {hrp}"
+            )));
+        }
+
+        let bytes = checked.byte_iter().collect::<Vec<_>>();
+        let (version, keys) = match bytes.as_slice() {
+            keys if keys.len() == 64 => (AddressVersion::Unversioned, keys),
+            [0, keys @ ..] if keys.len() == 64 => (AddressVersion::V0, keys),
+            [version, keys @ ..] if keys.len() == 64 => {
+                return Err(Error::address_format(format!(
+                    "unsupported address version {version}"
+                )));
+            }
+            _ => {
+                return Err(Error::address_format(format!(
+                    "invalid payload length {}",
+                    bytes.len()
+                )));
+            }
+        };
 
-        let server = XOnlyPublicKey::from_slice(&bytes[..32]).map_err(Error::address_format)?;
+        let server = XOnlyPublicKey::from_slice(&keys[..32]).map_err(Error::address_format)?;
         let vtxo_tap_key =
-            XOnlyPublicKey::from_slice(&bytes[32..]).map_err(Error::address_format)?;
+            XOnlyPublicKey::from_slice(&keys[32..]).map_err(Error::address_format)?;
 
         // It is safe to call `dangerous_assume_tweaked` because we are treating the VTXO tap key as
         // finished product i.e. we are only going to use it as an address to send coins to.
         let vtxo_tap_key = TweakedPublicKey::dangerous_assume_tweaked(vtxo_tap_key);
 
         Ok(Self {
+            version,
             hrp,
             server,
             vtxo_tap_key,
         })
     }
+
+    /// Decode an address, rejecting it unless it is for `network`.
+    pub fn decode_for_network(value: &str, network: Network) -> Result<Self, Error> {
+        let address = Self::decode(value)?;
+
+        if address.network() != NetworkKind::from(network) {
+            return Err(Error::address_format(format!(
+                "address {value} is not for {network}"
+            )));
+        }
+
+        Ok(address)
+    }
 }
 
 impl std::fmt::Display for ArkAddress {
@@ -73,6 +157,32 @@ impl std::fmt::Display for ArkAddress {
 mod tests {
     use super::*;
     use bitcoin::hex::DisplayHex;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct TestVectors {
+        valid: Vec<ValidVector>,
+        invalid: Vec<InvalidVector>,
+    }
+
+    #[derive(Deserialize)]
+    struct ValidVector {
+        address: String,
+        version: Option<u8>,
+        testnet: bool,
+        server: String,
+        vtxo_tap_key: String,
+    }
+
+    #[derive(Deserialize)]
+    struct InvalidVector {
+        address: String,
+        reason: String,
+    }
+
+    fn test_vectors() -> TestVectors {
+        serde_json::from_str(include_str!("../test-vectors/ark_address.json")).unwrap()
+    }
 
     // Taken from https://github.com/ark-network/ark/blob/b536a9e65252573aaa48110ef5d0c90894eb550c/common/fixtures/encoding.json.
     #[test]
@@ -100,4 +210,75 @@ mod tests {
 
         assert_eq!(encoded, address);
     }
+
+    #[test]
+    fn valid_test_vectors() {
+        for vector in test_vectors().valid {
+            let decoded = ArkAddress::decode(&vector.address).unwrap();
+
+            let version = match vector.version {
+                None => AddressVersion::Unversioned,
+                Some(0) => AddressVersion::V0,
+                Some(version) => panic!("unexpected version {version}"),
+            };
+            assert_eq!(decoded.version(), version, "{}", vector.address);
+
+            let network = match vector.testnet {
+                true => NetworkKind::Test,
+                false => NetworkKind::Main,
+            };
+            assert_eq!(decoded.network(), network, "{}", vector.address);
+
+            assert_eq!(
+                decoded.server.serialize().as_hex().to_string(),
+                vector.server
+            );
+            assert_eq!(
+                decoded.vtxo_tap_key.serialize().as_hex().to_string(),
+                vector.vtxo_tap_key
+            );
+            assert_eq!(decoded.encode(), vector.address);
+        }
+    }
+
+    #[test]
+    fn invalid_test_vectors() {
+        for vector in test_vectors().invalid {
+            assert!(
+                ArkAddress::decode(&vector.address).is_err(),
+                "{} was accepted, but {}",
+                vector.address,
+                vector.reason
+            );
+        }
+    }
+
+    #[test]
+    fn addresses_are_checked_against_the_network() {
+        let vectors = test_vectors().valid;
+        let testnet = &vectors[0].address;
+        let mainnet = &vectors[2].address;
+
+        assert!(ArkAddress::decode_for_network(testnet, Network::Regtest).is_ok());
+        assert!(ArkAddress::decode_for_network(testnet, Network::Signet).is_ok());
+        assert!(ArkAddress::decode_for_network(testnet, Network::Bitcoin).is_err());
+
+        assert!(ArkAddress::decode_for_network(mainnet, Network::Bitcoin).is_ok());
+        assert!(ArkAddress::decode_for_network(mainnet, Network::Testnet).is_err());
+    }
+
+    #[test]
+    fn new_addresses_can_be_versioned() {
+        let decoded = ArkAddress::decode(&test_vectors().valid[0].address).unwrap();
+
+        let address = ArkAddress::new(Network::Regtest, decoded.server, decoded.vtxo_tap_key);
+        assert_eq!(address.version(), AddressVersion::Unversioned);
+
+        let versioned = address.with_version(AddressVersion::V0);
+        assert_eq!(versioned.encode(), test_vectors().valid[1].address);
+        assert_eq!(
+            versioned.to_p2tr_script_pubkey(),
+            address.to_p2tr_script_pubkey()
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::descriptor::DescriptorTemplate;
 use crate::script::csv_sig_script;
 use crate::script::multisig_script;
 use crate::script::tr_script_pubkey;
@@ -83,6 +84,52 @@ impl BoardingOutput {
         })
     }
 
+    /// Build the boarding output of `owner` described by `template`, a boarding descriptor
+    /// template of the Ark server.
+    ///
+    /// The template must consist of a forfeit leaf and an exit leaf locked in seconds, whose
+    /// timelock becomes the exit delay.
+    pub fn from_template<C>(
+        secp: &Secp256k1<C>,
+        template: &DescriptorTemplate,
+        server: XOnlyPublicKey,
+        owner: XOnlyPublicKey,
+        network: Network,
+    ) -> Result<Self, Error>
+    where
+        C: Verification,
+    {
+        let leaves = template.leaves(server, owner)?;
+
+        if !leaves.others.is_empty() {
+            return Err(Error::ad_hoc(format!(
+                "boarding descriptor template {template} has more than a forfeit and an exit leaf"
+            )));
+        }
+
+        if !matches!(
+            leaves.exit_delay.to_relative_lock_time(),
+            Some(relative::LockTime::Time(_))
+        ) {
+            return Err(Error::ad_hoc(format!(
+                "exit leaf of boarding descriptor template {template} is not locked in seconds"
+            )));
+        }
+
+        let spend_info = template.spend_info(secp, owner);
+
+        let script_pubkey = tr_script_pubkey(&spend_info);
+        let address = Address::from_script(&script_pubkey, network).expect("valid script");
+
+        Ok(Self {
+            server,
+            owner,
+            spend_info,
+            address,
+            exit_delay: leaves.exit_delay,
+        })
+    }
+
     pub fn address(&self) -> &Address {
         &self.address
     }
@@ -0,0 +1,848 @@
+//! The tapscript descriptor templates with which the Ark server advertises its script policy,
+//! such as
+//!
+//! ```text
+//! tr(0250929b..., { and(pk(33ffb3de...), pk(USER)), and(older(512), pk(USER)) })
+//! ```
+//!
+//! A template is a Taproot output descriptor in which the key of the user is left as `USER`. The
+//! fragments which are understood are:
+//!
+//! - `pk(K)`, which compiles to `<K> CHECKSIG`.
+//! - `older(n)`, which compiles to `<n> CSV DROP`. As in miniscript, `n` is the raw `nSequence`.
+//! - `after(n)`, which compiles to `<n> CLTV DROP`.
+//! - `and(X, Y)`, which compiles to `X` as a verification, followed by `Y`.
+//!
+//! Every leaf must end with a `pk` fragment. Keys are either x-only or compressed public keys,
+//! in hex.
+//!
+//! Our boarding outputs and default VTXOs are built from the templates with [`boarding_output`]
+//! and [`default_vtxo`], so that they follow the Ark server when it changes its policy.
+
+use crate::script::classify_tapscript;
+use crate::script::csv_sig_script;
+use crate::script::multisig_script;
+use crate::script::tr_script_pubkey;
+use crate::script::TapscriptKind;
+use crate::server;
+use crate::BoardingOutput;
+use crate::Error;
+use crate::Vtxo;
+use crate::UNSPENDABLE_KEY;
+use bitcoin::absolute;
+use bitcoin::key::PublicKey;
+use bitcoin::key::Secp256k1;
+use bitcoin::key::Verification;
+use bitcoin::opcodes::all::*;
+use bitcoin::script;
+use bitcoin::taproot::TaprootBuilder;
+use bitcoin::taproot::TaprootSpendInfo;
+use bitcoin::taproot::TAPROOT_CONTROL_MAX_NODE_COUNT;
+use bitcoin::ScriptBuf;
+use bitcoin::XOnlyPublicKey;
+use std::fmt;
+use std::str::FromStr;
+
+/// The placeholder for the key of the user in a [`DescriptorTemplate`].
+pub const USER_KEY_PLACEHOLDER: &str = "USER";
+
+/// A parsed tapscript descriptor template. See the [module documentation](self) for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorTemplate {
+    /// The template, as given.
+    template: String,
+    internal_key: Key,
+    tree: Option<Tree>,
+}
+
+/// The leaves of a [`DescriptorTemplate`] with the key of the user filled in, sorted by how they
+/// are spent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateLeaves {
+    /// The leaf signed by the Ark server and the user, as built by [`multisig_script`].
+    pub forfeit: ScriptBuf,
+    /// The leaf in which the user alone spends after `exit_delay`, as built by
+    /// [`csv_sig_script`].
+    pub exit: ScriptBuf,
+    pub exit_delay: bitcoin::Sequence,
+    /// Every other leaf, in depth-first order.
+    pub others: Vec<ScriptBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    User,
+    Fixed(XOnlyPublicKey),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tree {
+    Leaf(Policy),
+    Branch(Box<Tree>, Box<Tree>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Policy {
+    Pk(Key),
+    Older(bitcoin::Sequence),
+    After(absolute::LockTime),
+    And(Box<Policy>, Box<Policy>),
+}
+
+impl DescriptorTemplate {
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let stripped = template
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+
+        let mut parser = Parser {
+            input: &stripped,
+            pos: 0,
+        };
+
+        let (internal_key, tree) = parser
+            .descriptor()
+            .map_err(|e| Error::ad_hoc(format!("invalid descriptor template {template}: {e}")))?;
+
+        Ok(Self {
+            template: template.to_string(),
+            internal_key,
+            tree,
+        })
+    }
+
+    /// The leaf scripts of the template with `user` filled in, along with their depths in the
+    /// tree, in depth-first order.
+    pub fn tapscripts(&self, user: XOnlyPublicKey) -> Vec<(u8, ScriptBuf)> {
+        let mut leaves = Vec::new();
+        if let Some(tree) = &self.tree {
+            tree.leaves(0, user, &mut leaves);
+        }
+
+        leaves
+    }
+
+    /// The Taproot spend info of the output described by the template, with `user` filled in.
+    pub fn spend_info<C>(&self, secp: &Secp256k1<C>, user: XOnlyPublicKey) -> TaprootSpendInfo
+    where
+        C: Verification,
+    {
+        let mut builder = TaprootBuilder::new();
+        for (depth, script) in self.tapscripts(user) {
+            builder = builder
+                .add_leaf(depth, script)
+                .expect("depth checked when parsing");
+        }
+
+        builder
+            .finalize(secp, self.internal_key.fill(user))
+            .expect("leaves of a full tree, in depth-first order")
+    }
+
+    pub fn script_pubkey<C>(&self, secp: &Secp256k1<C>, user: XOnlyPublicKey) -> ScriptBuf
+    where
+        C: Verification,
+    {
+        tr_script_pubkey(&self.spend_info(secp, user))
+    }
+
+    /// Sort the leaves of the template with `user` filled in by how they are spent, for an Ark
+    /// server whose key is `server`.
+    ///
+    /// The first forfeit leaf and the first exit leaf are taken as such. Fails if either is
+    /// missing, or if the key path can be spent by someone other than `user`.
+    pub fn leaves(
+        &self,
+        server: XOnlyPublicKey,
+        user: XOnlyPublicKey,
+    ) -> Result<TemplateLeaves, Error> {
+        let unspendable_key: PublicKey = UNSPENDABLE_KEY.parse().expect("valid key");
+        let (unspendable_key, _) = unspendable_key.inner.x_only_public_key();
+
+        if self.internal_key != Key::User && self.internal_key != Key::Fixed(unspendable_key) {
+            return Err(Error::ad_hoc(format!(
+                "descriptor template {self} has a key path that is not ours"
+            )));
+        }
+
+        let forfeit_script = multisig_script(server, user);
+
+        let mut forfeit = None;
+        let mut exit = None;
+        let mut others = Vec::new();
+        for (_, script) in self.tapscripts(user) {
+            if forfeit.is_none() && script == forfeit_script {
+                forfeit = Some(script);
+                continue;
+            }
+
+            if exit.is_none() {
+                if let TapscriptKind::CsvExit { sequence } = classify_tapscript(&script, server) {
+                    if script == csv_sig_script(sequence, user) {
+                        exit = Some((script, sequence));
+                        continue;
+                    }
+                }
+            }
+
+            others.push(script);
+        }
+
+        let forfeit = forfeit.ok_or_else(|| {
+            Error::ad_hoc(format!(
+                "descriptor template {self} has no leaf for the Ark server and the user"
+            ))
+        })?;
+        let (exit, exit_delay) = exit.ok_or_else(|| {
+            Error::ad_hoc(format!(
+                "descriptor template {self} has no exit leaf for the user"
+            ))
+        })?;
+
+        Ok(TemplateLeaves {
+            forfeit,
+            exit,
+            exit_delay,
+            others,
+        })
+    }
+}
+
+impl FromStr for DescriptorTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for DescriptorTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+/// The boarding output of `user`, built from the boarding descriptor template of the Ark server.
+///
+/// If the Ark server does not advertise a template that we can build from, we fall back to the
+/// boarding output that we have always built, which must still match the template.
+pub fn boarding_output<C>(
+    secp: &Secp256k1<C>,
+    server_info: &server::Info,
+    user: XOnlyPublicKey,
+) -> Result<BoardingOutput, Error>
+where
+    C: Verification,
+{
+    let (server, _) = server_info.pk.x_only_public_key();
+
+    if !server_info.boarding_descriptor_template.is_empty() {
+        let built = DescriptorTemplate::parse(&server_info.boarding_descriptor_template).and_then(
+            |template| {
+                BoardingOutput::from_template(secp, &template, server, user, server_info.network)
+            },
+        );
+
+        match built {
+            Ok(boarding_output) => return Ok(boarding_output),
+            Err(e) => tracing::warn!("Cannot build boarding output from template: {e}"),
+        }
+    }
+
+    check_boarding_template(secp, server_info, user)?;
+
+    BoardingOutput::new(
+        secp,
+        server,
+        user,
+        server_info.unilateral_exit_delay,
+        server_info.network,
+    )
+}
+
+/// The default VTXO of `user`, built from the first of the VTXO descriptor templates of the Ark
+/// server that we can build from.
+///
+/// If there is none, we fall back to the VTXO that we have always built, which must still match
+/// one of the templates.
+pub fn default_vtxo<C>(
+    secp: &Secp256k1<C>,
+    server_info: &server::Info,
+    user: XOnlyPublicKey,
+) -> Result<Vtxo, Error>
+where
+    C: Verification,
+{
+    let (server, _) = server_info.pk.x_only_public_key();
+
+    for template in server_info.vtxo_descriptor_templates.iter() {
+        let built = DescriptorTemplate::parse(template).and_then(|template| {
+            Vtxo::from_template(secp, &template, server, user, server_info.network)
+        });
+
+        match built {
+            Ok(vtxo) => return Ok(vtxo),
+            Err(e) => tracing::warn!("Cannot build VTXO from template: {e}"),
+        }
+    }
+
+    check_vtxo_templates(secp, server_info, user)?;
+
+    Vtxo::new_default(
+        secp,
+        server,
+        user,
+        server_info.unilateral_exit_delay,
+        server_info.network,
+    )
+}
+
+/// Check that the boarding outputs and VTXOs that we build for `user` without templates are the
+/// ones described by the descriptor templates of the Ark server.
+///
+/// The boarding output must match the boarding template, and the default VTXO must match one of
+/// the VTXO templates. Templates which the Ark server leaves empty are not checked.
+pub fn check_descriptor_templates<C>(
+    secp: &Secp256k1<C>,
+    server_info: &server::Info,
+    user: XOnlyPublicKey,
+) -> Result<(), Error>
+where
+    C: Verification,
+{
+    check_boarding_template(secp, server_info, user)?;
+    check_vtxo_templates(secp, server_info, user)
+}
+
+fn check_boarding_template<C>(
+    secp: &Secp256k1<C>,
+    server_info: &server::Info,
+    user: XOnlyPublicKey,
+) -> Result<(), Error>
+where
+    C: Verification,
+{
+    let (server, _) = server_info.pk.x_only_public_key();
+
+    if !server_info.boarding_descriptor_template.is_empty() {
+        let template = DescriptorTemplate::parse(&server_info.boarding_descriptor_template)?;
+
+        let boarding_output = BoardingOutput::new(
+            secp,
+            server,
+            user,
+            server_info.unilateral_exit_delay,
+            server_info.network,
+        )?;
+
+        if template.script_pubkey(secp, user) != boarding_output.script_pubkey() {
+            return Err(Error::ad_hoc(format!(
+                "boarding output does not match the Ark server's boarding descriptor \
+                 template {template}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_vtxo_templates<C>(
+    secp: &Secp256k1<C>,
+    server_info: &server::Info,
+    user: XOnlyPublicKey,
+) -> Result<(), Error>
+where
+    C: Verification,
+{
+    let (server, _) = server_info.pk.x_only_public_key();
+
+    if !server_info.vtxo_descriptor_templates.is_empty() {
+        let templates = server_info
+            .vtxo_descriptor_templates
+            .iter()
+            .map(|template| DescriptorTemplate::parse(template))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let vtxo = Vtxo::new_default(
+            secp,
+            server,
+            user,
+            server_info.unilateral_exit_delay,
+            server_info.network,
+        )?;
+
+        if !templates
+            .iter()
+            .any(|template| template.script_pubkey(secp, user) == vtxo.script_pubkey())
+        {
+            return Err(Error::ad_hoc(format!(
+                "VTXO does not match any of the Ark server's VTXO descriptor templates: {}",
+                server_info.vtxo_descriptor_templates.join(", ")
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl Key {
+    fn fill(self, user: XOnlyPublicKey) -> XOnlyPublicKey {
+        match self {
+            Key::User => user,
+            Key::Fixed(pk) => pk,
+        }
+    }
+}
+
+impl Tree {
+    fn leaves(&self, depth: u8, user: XOnlyPublicKey, leaves: &mut Vec<(u8, ScriptBuf)>) {
+        match self {
+            Tree::Leaf(policy) => {
+                let script = policy.compile(script::Builder::new(), user, false);
+
+                leaves.push((depth, script.into_script()));
+            }
+            Tree::Branch(left, right) => {
+                left.leaves(depth + 1, user, leaves);
+                right.leaves(depth + 1, user, leaves);
+            }
+        }
+    }
+}
+
+impl Policy {
+    /// Append the script of the policy to `builder`. If `verify` is set, the script must leave
+    /// nothing on the stack when it succeeds.
+    fn compile(
+        &self,
+        builder: script::Builder,
+        user: XOnlyPublicKey,
+        verify: bool,
+    ) -> script::Builder {
+        match self {
+            Policy::Pk(key) => {
+                let builder = builder.push_x_only_key(&key.fill(user));
+                match verify {
+                    true => builder.push_opcode(OP_CHECKSIGVERIFY),
+                    false => builder.push_opcode(OP_CHECKSIG),
+                }
+            }
+            Policy::Older(sequence) => builder
+                .push_int(sequence.to_consensus_u32() as i64)
+                .push_opcode(OP_CSV)
+                .push_opcode(OP_DROP),
+            Policy::After(locktime) => builder
+                .push_int(locktime.to_consensus_u32() as i64)
+                .push_opcode(OP_CLTV)
+                .push_opcode(OP_DROP),
+            Policy::And(x, y) => {
+                let builder = x.compile(builder, user, true);
+                y.compile(builder, user, verify)
+            }
+        }
+    }
+
+    /// Whether the script of the policy leaves the result of a signature check on the stack, as a
+    /// leaf script must.
+    fn ends_with_signature(&self) -> bool {
+        match self {
+            Policy::Pk(_) => true,
+            Policy::Older(_) | Policy::After(_) => false,
+            Policy::And(_, y) => y.ends_with_signature(),
+        }
+    }
+}
+
+/// A recursive descent parser over a template stripped of whitespace.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn descriptor(&mut self) -> Result<(Key, Option<Tree>), String> {
+        self.expect("tr(")?;
+        let internal_key = self.key()?;
+
+        let tree = match self.eat(",") {
+            true => Some(self.tree(0)?),
+            false => None,
+        };
+        self.expect(")")?;
+
+        if self.pos != self.input.len() {
+            return Err(format!(
+                "unexpected {} at position {}",
+                &self.input[self.pos..],
+                self.pos
+            ));
+        }
+
+        Ok((internal_key, tree))
+    }
+
+    fn tree(&mut self, depth: usize) -> Result<Tree, String> {
+        if depth > TAPROOT_CONTROL_MAX_NODE_COUNT {
+            return Err(format!(
+                "tree is deeper than {TAPROOT_CONTROL_MAX_NODE_COUNT}"
+            ));
+        }
+
+        if self.eat("{") {
+            let left = self.tree(depth + 1)?;
+            self.expect(",")?;
+            let right = self.tree(depth + 1)?;
+            self.expect("}")?;
+
+            return Ok(Tree::Branch(Box::new(left), Box::new(right)));
+        }
+
+        let start = self.pos;
+        let policy = self.policy()?;
+        if !policy.ends_with_signature() {
+            return Err(format!(
+                "leaf {} does not end with a signature check",
+                &self.input[start..self.pos]
+            ));
+        }
+
+        Ok(Tree::Leaf(policy))
+    }
+
+    fn policy(&mut self) -> Result<Policy, String> {
+        let policy = if self.eat("pk(") {
+            Policy::Pk(self.key()?)
+        } else if self.eat("older(") {
+            let n = self.number()?;
+            let sequence = bitcoin::Sequence::from_consensus(n);
+            if !sequence.is_relative_lock_time() || n == 0 {
+                return Err(format!("invalid relative locktime {n}"));
+            }
+
+            Policy::Older(sequence)
+        } else if self.eat("after(") {
+            let n = self.number()?;
+            if n == 0 {
+                return Err(format!("invalid absolute locktime {n}"));
+            }
+
+            Policy::After(absolute::LockTime::from_consensus(n))
+        } else if self.eat("and(") {
+            let x = self.policy()?;
+            self.expect(",")?;
+            let y = self.policy()?;
+
+            Policy::And(Box::new(x), Box::new(y))
+        } else {
+            return Err(format!("unknown fragment at position {}", self.pos));
+        };
+        self.expect(")")?;
+
+        Ok(policy)
+    }
+
+    fn key(&mut self) -> Result<Key, String> {
+        if self.eat(USER_KEY_PLACEHOLDER) {
+            return Ok(Key::User);
+        }
+
+        let start = self.pos;
+        let hex = self.take_while(|c| c.is_ascii_hexdigit());
+        let pk = match hex.len() {
+            64 => XOnlyPublicKey::from_str(hex).map_err(|e| format!("invalid key {hex}: {e}"))?,
+            66 => {
+                let pk = PublicKey::from_str(hex).map_err(|e| format!("invalid key {hex}: {e}"))?;
+                pk.inner.x_only_public_key().0
+            }
+            _ => return Err(format!("invalid key at position {start}")),
+        };
+
+        Ok(Key::Fixed(pk))
+    }
+
+    fn number(&mut self) -> Result<u32, String> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+
+        digits
+            .parse()
+            .map_err(|e| format!("invalid number {digits:?}: {e}"))
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        let len = self.input[start..]
+            .find(|c| !f(c))
+            .unwrap_or(self.input.len() - start);
+        self.pos += len;
+
+        &self.input[start..self.pos]
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.input[self.pos..].starts_with(token) {
+            true => {
+                self.pos += token.len();
+                true
+            }
+            false => false,
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(format!("expected {token} at position {}", self.pos)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::csv_sig_script;
+    use crate::script::multisig_script;
+    use crate::UNSPENDABLE_KEY;
+    use bitcoin::key::Keypair;
+    use bitcoin::Amount;
+    use bitcoin::Network;
+
+    fn key(i: u8) -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        Keypair::from_seckey_slice(&secp, &[i; 32])
+            .unwrap()
+            .x_only_public_key()
+            .0
+    }
+
+    fn server_info(exit_delay: bitcoin::Sequence) -> server::Info {
+        let secp = Secp256k1::new();
+        let server_pk = Keypair::from_seckey_slice(&secp, &[1; 32])
+            .unwrap()
+            .public_key();
+
+        server::Info {
+            pk: server_pk,
+            vtxo_tree_expiry: bitcoin::Sequence::from_512_second_intervals(2),
+            unilateral_exit_delay: exit_delay,
+            round_interval: 10,
+            network: Network::Regtest,
+            dust: Amount::from_sat(330),
+            boarding_descriptor_template: String::new(),
+            vtxo_descriptor_templates: Vec::new(),
+            forfeit_address: "bcrt1qqqqsyqcyq5rqwzqfpg9scrgwpugpzysnard0ew"
+                .parse::<bitcoin::Address<bitcoin::address::NetworkUnchecked>>()
+                .unwrap()
+                .assume_checked(),
+        }
+    }
+
+    /// The template of the default VTXO, as an Ark server would advertise it.
+    fn vtxo_template(exit_delay: bitcoin::Sequence) -> String {
+        format!(
+            "tr({UNSPENDABLE_KEY}, {{ and(pk({}), pk({USER_KEY_PLACEHOLDER})), and(older({}), pk({USER_KEY_PLACEHOLDER})) }})",
+            key(1),
+            exit_delay.to_consensus_u32()
+        )
+    }
+
+    #[test]
+    fn templates_compile_to_our_scripts() {
+        let exit_delay = bitcoin::Sequence::from_512_second_intervals(2);
+        let template = DescriptorTemplate::parse(&vtxo_template(exit_delay)).unwrap();
+
+        let user = key(2);
+        assert_eq!(
+            template.tapscripts(user),
+            vec![
+                (1, multisig_script(key(1), user)),
+                (1, csv_sig_script(exit_delay, user)),
+            ]
+        );
+
+        let secp = Secp256k1::new();
+        let vtxo = Vtxo::new_default(&secp, key(1), user, exit_delay, Network::Regtest).unwrap();
+        assert_eq!(template.script_pubkey(&secp, user), vtxo.script_pubkey());
+        assert_eq!(template.to_string(), vtxo_template(exit_delay));
+    }
+
+    #[test]
+    fn templates_may_nest_leaves_and_conditions() {
+        let template = DescriptorTemplate::parse(&format!(
+            "tr({},{{pk(USER),{{and(after(800000),and(pk(USER),pk({}))),and(and(older(144),pk({})),pk(USER))}}}})",
+            key(3),
+            key(1),
+            key(4)
+        ))
+        .unwrap();
+
+        let user = key(2);
+        let leaves = template.tapscripts(user);
+        let depths = leaves.iter().map(|(depth, _)| *depth).collect::<Vec<_>>();
+        assert_eq!(depths, vec![1, 2, 2]);
+
+        let refund = ScriptBuf::builder()
+            .push_int(800_000)
+            .push_opcode(OP_CLTV)
+            .push_opcode(OP_DROP)
+            .push_x_only_key(&user)
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_x_only_key(&key(1))
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        assert_eq!(leaves[1].1, refund);
+
+        let spend_info = template.spend_info(&Secp256k1::new(), user);
+        assert_eq!(spend_info.internal_key(), key(3));
+        for (_, script) in leaves {
+            assert!(spend_info
+                .control_block(&(script, bitcoin::taproot::LeafVersion::TapScript))
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        let exit_delay = bitcoin::Sequence::from_512_second_intervals(2);
+        let valid = vtxo_template(exit_delay);
+
+        for template in [
+            // Not a Taproot descriptor.
+            valid.replacen("tr(", "wsh(", 1),
+            // Trailing characters.
+            format!("{valid})"),
+            // Unbalanced tree.
+            valid.replacen(" }", "", 1),
+            // Unknown fragment.
+            valid.replacen("older", "sha256", 1),
+            // Invalid key.
+            valid.replacen(USER_KEY_PLACEHOLDER, "ALICE", 1),
+            // A leaf without a signature check.
+            format!("tr({UNSPENDABLE_KEY},older(144))"),
+            // A disabled relative locktime.
+            format!("tr({UNSPENDABLE_KEY},and(older(2147483648),pk(USER)))"),
+        ] {
+            assert!(
+                DescriptorTemplate::parse(&template).is_err(),
+                "{template} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn our_outputs_are_checked_against_the_server_templates() {
+        let secp = Secp256k1::new();
+        let exit_delay = bitcoin::Sequence::from_512_second_intervals(2);
+        let user = key(2);
+
+        // Servers which do not advertise templates are trusted.
+        let mut info = server_info(exit_delay);
+        check_descriptor_templates(&secp, &info, user).unwrap();
+
+        // Boarding outputs use twice the exit delay of VTXOs.
+        let boarding_delay = bitcoin::Sequence::from_512_second_intervals(4);
+        info.boarding_descriptor_template = vtxo_template(boarding_delay);
+        info.vtxo_descriptor_templates = vec![
+            vtxo_template(bitcoin::Sequence::from_512_second_intervals(8)),
+            vtxo_template(exit_delay),
+        ];
+        check_descriptor_templates(&secp, &info, user).unwrap();
+
+        let mut changed = info.clone();
+        changed.boarding_descriptor_template = vtxo_template(exit_delay);
+        assert!(check_descriptor_templates(&secp, &changed, user).is_err());
+
+        let mut changed = info.clone();
+        changed.vtxo_descriptor_templates.pop();
+        assert!(check_descriptor_templates(&secp, &changed, user).is_err());
+
+        let mut changed = info;
+        changed.vtxo_descriptor_templates[1] = "not a descriptor".to_string();
+        assert!(check_descriptor_templates(&secp, &changed, user).is_err());
+    }
+
+    #[test]
+    fn our_outputs_follow_the_server_templates() {
+        let secp = Secp256k1::new();
+        let exit_delay = bitcoin::Sequence::from_512_second_intervals(2);
+        let user = key(2);
+
+        // Without templates, we build what we always did.
+        let mut info = server_info(exit_delay);
+        let vtxo = default_vtxo(&secp, &info, user).unwrap();
+        assert_eq!(vtxo.exit_delay(), exit_delay);
+        let boarding = boarding_output(&secp, &info, user).unwrap();
+        assert_eq!(
+            boarding.exit_delay(),
+            bitcoin::Sequence::from_512_second_intervals(4)
+        );
+
+        // A server that changes its delays does not lock us out.
+        let vtxo_delay = bitcoin::Sequence::from_512_second_intervals(8);
+        let boarding_delay = bitcoin::Sequence::from_512_second_intervals(6);
+        info.boarding_descriptor_template = vtxo_template(boarding_delay);
+        info.vtxo_descriptor_templates = vec![vtxo_template(vtxo_delay)];
+
+        let vtxo = default_vtxo(&secp, &info, user).unwrap();
+        assert_eq!(vtxo.exit_delay(), vtxo_delay);
+        assert_eq!(
+            vtxo.script_pubkey(),
+            DescriptorTemplate::parse(&info.vtxo_descriptor_templates[0])
+                .unwrap()
+                .script_pubkey(&secp, user)
+        );
+        assert_eq!(vtxo.exit_spend_info().0, csv_sig_script(vtxo_delay, user));
+
+        let boarding = boarding_output(&secp, &info, user).unwrap();
+        assert_eq!(boarding.exit_delay(), boarding_delay);
+        assert_eq!(
+            boarding.script_pubkey(),
+            DescriptorTemplate::parse(&info.boarding_descriptor_template)
+                .unwrap()
+                .script_pubkey(&secp, user)
+        );
+
+        // Extra leaves which need the server are kept.
+        let extra = format!(
+            "tr({UNSPENDABLE_KEY}, {{ {{ and(pk({}), pk(USER)), and(older({}), pk(USER)) }}, and(pk({}), pk({})) }})",
+            key(1),
+            vtxo_delay.to_consensus_u32(),
+            key(3),
+            key(1)
+        );
+        info.vtxo_descriptor_templates = vec![extra];
+        let vtxo = default_vtxo(&secp, &info, user).unwrap();
+        assert_eq!(vtxo.tapscripts().len(), 3);
+
+        // Templates we cannot build from only work if they describe our own outputs.
+        for template in [
+            // The key path belongs to the server.
+            vtxo_template(vtxo_delay).replacen(UNSPENDABLE_KEY, &key(1).to_string(), 1),
+            // No exit leaf.
+            format!("tr({UNSPENDABLE_KEY}, and(pk({}), pk(USER)))", key(1)),
+            // A leaf the server can spend alone.
+            format!(
+                "tr({UNSPENDABLE_KEY}, {{ {{ and(pk({}), pk(USER)), and(older({}), pk(USER)) }}, pk({}) }})",
+                key(1),
+                vtxo_delay.to_consensus_u32(),
+                key(1)
+            ),
+        ] {
+            info.vtxo_descriptor_templates = vec![template.clone()];
+            assert!(
+                default_vtxo(&secp, &info, user).is_err(),
+                "{template} was accepted"
+            );
+        }
+
+        // The first template we can build from is taken.
+        info.vtxo_descriptor_templates = vec![
+            vtxo_template(vtxo_delay).replacen(UNSPENDABLE_KEY, &key(1).to_string(), 1),
+            vtxo_template(exit_delay),
+        ];
+        assert_eq!(
+            default_vtxo(&secp, &info, user).unwrap().exit_delay(),
+            exit_delay
+        );
+    }
+}
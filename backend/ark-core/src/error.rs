@@ -28,6 +28,16 @@ enum Kind {
     ArkAddress(ArkAddressError),
 }
 
+/// The broad category of an [`Error`], for callers that need to react to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    AdHoc,
+    Crypto,
+    Transaction,
+    CoinSelect,
+    ArkAddress,
+}
+
 #[derive(Debug)]
 struct AdHocError {
     source: Source,
@@ -89,6 +99,25 @@ impl Error {
             source: source.into(),
         }))
     }
+
+    /// The kind of the first error in the causal chain that is not ad-hoc context, or
+    /// [`ErrorKind::AdHoc`] if there is none.
+    pub fn kind(&self) -> ErrorKind {
+        let mut err = self;
+        loop {
+            let kind = match err.inner.kind {
+                Kind::AdHoc(_) => ErrorKind::AdHoc,
+                Kind::Crypto(_) => return ErrorKind::Crypto,
+                Kind::Transaction(_) => return ErrorKind::Transaction,
+                Kind::CoinSelect(_) => return ErrorKind::CoinSelect,
+                Kind::ArkAddress(_) => return ErrorKind::ArkAddress,
+            };
+            err = match err.inner.cause.as_ref() {
+                None => return kind,
+                Some(err) => err,
+            };
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -4,8 +4,11 @@ use bitcoin::OutPoint;
 pub mod boarding_output;
 pub mod coin_select;
 pub mod conversions;
+pub mod descriptor;
+pub mod multi_owner;
 pub mod redeem;
 pub mod round;
+pub mod script;
 pub mod server;
 pub mod tx_weight_estimator;
 pub mod unilateral_exit;
@@ -16,15 +19,18 @@ mod error;
 mod forfeit_fee;
 mod history;
 mod internal_node;
-mod script;
+mod payment_uri;
 
+pub use ark_address::AddressVersion;
 pub use ark_address::ArkAddress;
 pub use boarding_output::BoardingOutput;
 pub use error::Error;
 pub use error::ErrorContext;
+pub use error::ErrorKind;
 pub use history::generate_incoming_vtxo_transaction_history;
 pub use history::generate_outgoing_vtxo_transaction_history;
 pub use history::ArkTransaction;
+pub use payment_uri::PaymentUri;
 pub use script::extract_sequence_from_csv_sig_script;
 pub use vtxo::Vtxo;
 
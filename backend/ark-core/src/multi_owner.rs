@@ -0,0 +1,626 @@
+//! VTXOs owned by several parties at once, such as shared treasuries and escrows.
+//!
+//! The owner key of such a VTXO is the MuSig2 aggregate of the keys of its [`CoOwners`], so the
+//! VTXO looks like any other: the Ark server sees a single owner, and every spend path that needs
+//! the owner's signature needs the signatures of every co-owner.
+//!
+//! Signing takes two rounds of messages between the co-owners. In the first one they share
+//! public nonces, and in the second one partial signatures, which anyone can then aggregate into
+//! the owner's signature. [`generate_nonces`], [`NonceRound::partial_sign`] and
+//! [`aggregate_signatures`] are the steps of each co-owner, for callers that carry the messages
+//! themselves. [`CoOwnerSigner`] runs the whole protocol over a [`NonceExchange`], and plugs into
+//! [`crate::redeem::sign_redeem_transaction`] and
+//! [`crate::round::create_and_sign_forfeit_txs_with`].
+
+use crate::conversions::from_zkp_xonly;
+use crate::conversions::to_zkp_pk;
+use crate::Error;
+use crate::Vtxo;
+use bitcoin::key::Keypair;
+use bitcoin::key::Secp256k1;
+use bitcoin::key::Verification;
+use bitcoin::secp256k1;
+use bitcoin::secp256k1::schnorr;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Network;
+use bitcoin::XOnlyPublicKey;
+use rand::CryptoRng;
+use rand::Rng;
+use zkp::new_musig_nonce_pair;
+use zkp::MusigAggNonce;
+use zkp::MusigKeyAggCache;
+use zkp::MusigPartialSignature;
+use zkp::MusigPubNonce;
+use zkp::MusigSecNonce;
+use zkp::MusigSession;
+use zkp::MusigSessionId;
+
+/// The co-owners of a VTXO, whose keys are aggregated into its owner key.
+#[derive(Debug, Clone)]
+pub struct CoOwners {
+    /// Sorted, so that every co-owner aggregates the keys in the same order.
+    pks: Vec<PublicKey>,
+    key_agg_cache: MusigKeyAggCache,
+}
+
+impl CoOwners {
+    pub fn new(mut pks: Vec<PublicKey>) -> Result<Self, Error> {
+        if pks.is_empty() {
+            return Err(Error::ad_hoc("a VTXO needs at least one co-owner"));
+        }
+
+        pks.sort_by_key(|pk| pk.serialize());
+        if let Some(pair) = pks.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(Error::ad_hoc(format!("duplicate co-owner {}", pair[0])));
+        }
+
+        let secp_zkp = zkp::Secp256k1::new();
+        let key_agg_cache = {
+            let pks = pks.iter().map(|pk| to_zkp_pk(*pk)).collect::<Vec<_>>();
+            MusigKeyAggCache::new(&secp_zkp, &pks)
+        };
+
+        Ok(Self { pks, key_agg_cache })
+    }
+
+    /// The public keys of the co-owners, in the order in which every message of the signing
+    /// protocol is indexed.
+    pub fn pks(&self) -> &[PublicKey] {
+        &self.pks
+    }
+
+    /// The aggregate key which owns the VTXO.
+    pub fn owner_pk(&self) -> XOnlyPublicKey {
+        from_zkp_xonly(self.key_agg_cache.agg_pk())
+    }
+
+    /// Build the default VTXO owned by the co-owners.
+    pub fn vtxo<C>(
+        &self,
+        secp: &Secp256k1<C>,
+        server: XOnlyPublicKey,
+        exit_delay: bitcoin::Sequence,
+        network: Network,
+    ) -> Result<Vtxo, Error>
+    where
+        C: Verification,
+    {
+        Vtxo::new_default(secp, server, self.owner_pk(), exit_delay, network)
+    }
+
+    fn index_of(&self, pk: &PublicKey) -> Result<usize, Error> {
+        self.pks
+            .iter()
+            .position(|co_owner| co_owner == pk)
+            .ok_or_else(|| Error::ad_hoc(format!("{pk} is not a co-owner")))
+    }
+
+    /// Check that a message of the signing protocol has one entry per co-owner, each with one item
+    /// per message being signed.
+    fn check_shape<T>(
+        &self,
+        items: &[Vec<T>],
+        num_messages: usize,
+        what: &str,
+    ) -> Result<(), Error> {
+        if items.len() != self.pks.len() {
+            return Err(Error::crypto(format!(
+                "got {what} from {} co-owners, expected {}",
+                items.len(),
+                self.pks.len()
+            )));
+        }
+
+        if let Some((pk, items)) = self
+            .pks
+            .iter()
+            .zip(items)
+            .find(|(_, items)| items.len() != num_messages)
+        {
+            return Err(Error::crypto(format!(
+                "got {} {what} from co-owner {pk}, expected {num_messages}",
+                items.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// The state of a co-owner between sharing its public nonces and signing.
+///
+/// The secret nonces are consumed by [`NonceRound::partial_sign`], so that they cannot be used
+/// twice.
+pub struct NonceRound {
+    pk: PublicKey,
+    messages: Vec<secp256k1::Message>,
+    sec_nonces: Vec<MusigSecNonce>,
+}
+
+/// Start signing `messages` as the co-owner with the keypair `kp`, giving our public nonces to
+/// share with the other co-owners.
+pub fn generate_nonces<R>(
+    rng: &mut R,
+    co_owners: &CoOwners,
+    kp: &Keypair,
+    messages: &[secp256k1::Message],
+) -> Result<(NonceRound, Vec<MusigPubNonce>), Error>
+where
+    R: Rng + CryptoRng,
+{
+    let secp_zkp = zkp::Secp256k1::new();
+
+    let pk = kp.public_key();
+    co_owners.index_of(&pk)?;
+
+    let sk = zkp::SecretKey::from_slice(&kp.secret_bytes()).expect("valid conversion");
+
+    let mut sec_nonces = Vec::with_capacity(messages.len());
+    let mut pub_nonces = Vec::with_capacity(messages.len());
+    for msg in messages {
+        let (sec_nonce, pub_nonce) = new_musig_nonce_pair(
+            &secp_zkp,
+            MusigSessionId::new(rng),
+            Some(&co_owners.key_agg_cache),
+            Some(sk),
+            to_zkp_pk(pk),
+            Some(to_zkp_message(msg)),
+            Some(rng.gen()),
+        )
+        .map_err(Error::crypto)?;
+
+        sec_nonces.push(sec_nonce);
+        pub_nonces.push(pub_nonce);
+    }
+
+    let round = NonceRound {
+        pk,
+        messages: messages.to_vec(),
+        sec_nonces,
+    };
+
+    Ok((round, pub_nonces))
+}
+
+impl NonceRound {
+    /// Sign every message, given the public nonces of every co-owner in the order of
+    /// [`CoOwners::pks`].
+    pub fn partial_sign(
+        self,
+        co_owners: &CoOwners,
+        kp: &Keypair,
+        pub_nonces: &[Vec<MusigPubNonce>],
+    ) -> Result<Vec<MusigPartialSignature>, Error> {
+        if kp.public_key() != self.pk {
+            return Err(Error::crypto("signing with another key than the nonces"));
+        }
+        co_owners.check_shape(pub_nonces, self.messages.len(), "public nonces")?;
+
+        let secp_zkp = zkp::Secp256k1::new();
+        let kp =
+            zkp::Keypair::from_seckey_slice(&secp_zkp, &kp.secret_bytes()).expect("valid keypair");
+
+        self.messages
+            .iter()
+            .zip(self.sec_nonces)
+            .enumerate()
+            .map(|(i, (msg, sec_nonce))| {
+                session(co_owners, pub_nonces, i, msg)
+                    .partial_sign(&secp_zkp, sec_nonce, &kp, &co_owners.key_agg_cache)
+                    .map_err(Error::crypto)
+            })
+            .collect()
+    }
+}
+
+/// Aggregate the partial signatures of every co-owner into a signature of each of `messages` by
+/// [`CoOwners::owner_pk`].
+///
+/// Both `pub_nonces` and `partial_sigs` are in the order of [`CoOwners::pks`]. Every partial
+/// signature is checked, so that a co-owner who sent an invalid one can be told apart.
+pub fn aggregate_signatures(
+    co_owners: &CoOwners,
+    messages: &[secp256k1::Message],
+    pub_nonces: &[Vec<MusigPubNonce>],
+    partial_sigs: &[Vec<MusigPartialSignature>],
+) -> Result<Vec<schnorr::Signature>, Error> {
+    co_owners.check_shape(pub_nonces, messages.len(), "public nonces")?;
+    co_owners.check_shape(partial_sigs, messages.len(), "partial signatures")?;
+
+    let secp = Secp256k1::verification_only();
+    let secp_zkp = zkp::Secp256k1::new();
+
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, msg)| {
+            let session = session(co_owners, pub_nonces, i, msg);
+
+            let mut sigs = Vec::with_capacity(co_owners.pks.len());
+            for (j, pk) in co_owners.pks.iter().enumerate() {
+                let sig = partial_sigs[j][i];
+
+                if !session.partial_verify(
+                    &secp_zkp,
+                    &co_owners.key_agg_cache,
+                    sig,
+                    pub_nonces[j][i],
+                    to_zkp_pk(*pk),
+                ) {
+                    return Err(Error::crypto(format!(
+                        "invalid partial signature of message {i} from co-owner {pk}"
+                    )));
+                }
+
+                sigs.push(sig);
+            }
+
+            let sig = session.partial_sig_agg(&sigs);
+            let sig = schnorr::Signature::from_slice(sig.as_ref()).expect("valid conversion");
+
+            secp.verify_schnorr(&sig, msg, &co_owners.owner_pk())
+                .map_err(Error::crypto)?;
+
+            Ok(sig)
+        })
+        .collect()
+}
+
+/// The MuSig2 session for message `i`.
+fn session(
+    co_owners: &CoOwners,
+    pub_nonces: &[Vec<MusigPubNonce>],
+    i: usize,
+    msg: &secp256k1::Message,
+) -> MusigSession {
+    let secp_zkp = zkp::Secp256k1::new();
+
+    let pub_nonces = pub_nonces
+        .iter()
+        .map(|nonces| nonces[i])
+        .collect::<Vec<_>>();
+    let agg_nonce = MusigAggNonce::new(&secp_zkp, &pub_nonces);
+
+    MusigSession::new(
+        &secp_zkp,
+        &co_owners.key_agg_cache,
+        agg_nonce,
+        to_zkp_message(msg),
+    )
+}
+
+fn to_zkp_message(msg: &secp256k1::Message) -> zkp::Message {
+    zkp::Message::from_digest(*msg.as_ref())
+}
+
+/// Carries the messages of the signing protocol between the co-owners, who may be in separate
+/// processes.
+///
+/// Each method sends our own items to every other co-owner, and returns the items of every
+/// co-owner, ours included, in the order of [`CoOwners::pks`].
+pub trait NonceExchange {
+    fn exchange_nonces(
+        &mut self,
+        own: Vec<MusigPubNonce>,
+    ) -> Result<Vec<Vec<MusigPubNonce>>, Error>;
+
+    fn exchange_partial_sigs(
+        &mut self,
+        own: Vec<MusigPartialSignature>,
+    ) -> Result<Vec<Vec<MusigPartialSignature>>, Error>;
+}
+
+/// Signs as one of the [`CoOwners`], running the signing protocol with the others over a
+/// [`NonceExchange`].
+///
+/// Every co-owner signs the same messages in the same order, and every one of them ends up with
+/// the aggregate signatures. To sign a redeem transaction, for instance, every co-owner builds it
+/// and calls
+///
+/// ```ignore
+/// sign_redeem_transaction(|msg| signer.sign(msg), &mut redeem_psbt, &vtxo_inputs, i)?;
+/// ```
+pub struct CoOwnerSigner<R, E> {
+    rng: R,
+    co_owners: CoOwners,
+    kp: Keypair,
+    exchange: E,
+}
+
+impl<R, E> CoOwnerSigner<R, E>
+where
+    R: Rng + CryptoRng,
+    E: NonceExchange,
+{
+    pub fn new(rng: R, co_owners: CoOwners, kp: Keypair, exchange: E) -> Result<Self, Error> {
+        co_owners.index_of(&kp.public_key())?;
+
+        Ok(Self {
+            rng,
+            co_owners,
+            kp,
+            exchange,
+        })
+    }
+
+    /// Sign `msg` with every other co-owner, returning the signature and the owner key.
+    pub fn sign(
+        &mut self,
+        msg: secp256k1::Message,
+    ) -> Result<(schnorr::Signature, XOnlyPublicKey), Error> {
+        let sig = self.sign_all(&[msg])?.remove(0);
+
+        Ok((sig, self.co_owners.owner_pk()))
+    }
+
+    /// Sign every message of `messages` with every other co-owner, in a single run of the
+    /// protocol.
+    pub fn sign_all(
+        &mut self,
+        messages: &[secp256k1::Message],
+    ) -> Result<Vec<schnorr::Signature>, Error> {
+        let own_index = self.co_owners.index_of(&self.kp.public_key())?;
+
+        let (round, own_nonces) =
+            generate_nonces(&mut self.rng, &self.co_owners, &self.kp, messages)?;
+        let pub_nonces = self.exchange.exchange_nonces(own_nonces.clone())?;
+        if pub_nonces.get(own_index) != Some(&own_nonces) {
+            return Err(Error::crypto("exchanged public nonces do not include ours"));
+        }
+
+        let own_sigs = round.partial_sign(&self.co_owners, &self.kp, &pub_nonces)?;
+        let partial_sigs = self.exchange.exchange_partial_sigs(own_sigs)?;
+
+        aggregate_signatures(&self.co_owners, messages, &pub_nonces, &partial_sigs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redeem::build_redeem_transaction;
+    use crate::redeem::sign_redeem_transaction;
+    use crate::redeem::VtxoInput;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Amount;
+    use bitcoin::OutPoint;
+    use bitcoin::Txid;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Carries the messages between co-owners running in separate threads, serialized as they
+    /// would be between processes.
+    struct ChannelExchange {
+        index: usize,
+        nonces: Round,
+        partial_sigs: Round,
+    }
+
+    /// One round of messages: a sender to every co-owner, and our inbox.
+    struct Round {
+        peers: Vec<mpsc::Sender<(usize, Vec<Vec<u8>>)>>,
+        inbox: mpsc::Receiver<(usize, Vec<Vec<u8>>)>,
+    }
+
+    impl Round {
+        fn exchange(&self, index: usize, own: Vec<Vec<u8>>) -> Vec<Vec<Vec<u8>>> {
+            for (i, peer) in self.peers.iter().enumerate() {
+                if i != index {
+                    peer.send((index, own.clone())).unwrap();
+                }
+            }
+
+            let mut all = vec![Vec::new(); self.peers.len()];
+            all[index] = own;
+            for _ in 1..self.peers.len() {
+                let (i, items) = self.inbox.recv().unwrap();
+                all[i] = items;
+            }
+
+            all
+        }
+    }
+
+    fn rounds(n: usize) -> Vec<Round> {
+        let (peers, inboxes): (Vec<_>, Vec<_>) = (0..n).map(|_| mpsc::channel()).unzip();
+
+        inboxes
+            .into_iter()
+            .map(|inbox| Round {
+                peers: peers.clone(),
+                inbox,
+            })
+            .collect()
+    }
+
+    impl NonceExchange for ChannelExchange {
+        fn exchange_nonces(
+            &mut self,
+            own: Vec<MusigPubNonce>,
+        ) -> Result<Vec<Vec<MusigPubNonce>>, Error> {
+            let own = own.iter().map(|nonce| nonce.serialize().to_vec()).collect();
+
+            self.nonces
+                .exchange(self.index, own)
+                .iter()
+                .map(|nonces| {
+                    nonces
+                        .iter()
+                        .map(|nonce| MusigPubNonce::from_slice(nonce).map_err(Error::crypto))
+                        .collect()
+                })
+                .collect()
+        }
+
+        fn exchange_partial_sigs(
+            &mut self,
+            own: Vec<MusigPartialSignature>,
+        ) -> Result<Vec<Vec<MusigPartialSignature>>, Error> {
+            let own = own.iter().map(|sig| sig.serialize().to_vec()).collect();
+
+            self.partial_sigs
+                .exchange(self.index, own)
+                .iter()
+                .map(|sigs| {
+                    sigs.iter()
+                        .map(|sig| MusigPartialSignature::from_slice(sig).map_err(Error::crypto))
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    fn keypairs(n: u8) -> Vec<Keypair> {
+        let secp = Secp256k1::new();
+
+        (1..=n)
+            .map(|i| Keypair::from_seckey_slice(&secp, &[i; 32]).unwrap())
+            .collect()
+    }
+
+    fn server() -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        Keypair::from_seckey_slice(&secp, &[42; 32])
+            .unwrap()
+            .x_only_public_key()
+            .0
+    }
+
+    #[test]
+    fn co_owners_share_one_owner_key_whatever_their_order() {
+        let pks = keypairs(3)
+            .iter()
+            .map(|kp| kp.public_key())
+            .collect::<Vec<_>>();
+
+        let co_owners = CoOwners::new(pks.clone()).unwrap();
+        let reversed = CoOwners::new(pks.iter().rev().copied().collect()).unwrap();
+        assert_eq!(co_owners.owner_pk(), reversed.owner_pk());
+        assert!(pks
+            .iter()
+            .all(|pk| pk.x_only_public_key().0 != co_owners.owner_pk()));
+
+        assert!(CoOwners::new(vec![pks[0], pks[1], pks[0]]).is_err());
+        assert!(CoOwners::new(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn co_owners_in_separate_threads_sign_a_redeem_transaction() {
+        let kps = keypairs(3);
+        let co_owners =
+            CoOwners::new(kps.iter().map(|kp| kp.public_key()).collect::<Vec<_>>()).unwrap();
+
+        let secp = Secp256k1::new();
+        let exit_delay = bitcoin::Sequence::from_512_second_intervals(10);
+        let vtxo = co_owners
+            .vtxo(&secp, server(), exit_delay, Network::Regtest)
+            .unwrap();
+        let destination = Vtxo::new_default(
+            &secp,
+            server(),
+            kps[0].x_only_public_key().0,
+            exit_delay,
+            Network::Regtest,
+        )
+        .unwrap()
+        .to_ark_address();
+        let vtxo_inputs = vec![VtxoInput::new(
+            vtxo,
+            Amount::from_sat(100_000),
+            OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0,
+            },
+        )];
+
+        let handles = rounds(kps.len())
+            .into_iter()
+            .zip(rounds(kps.len()))
+            .enumerate()
+            .map(|(index, (nonces, partial_sigs))| {
+                let exchange = ChannelExchange {
+                    index,
+                    nonces,
+                    partial_sigs,
+                };
+                let pk = co_owners.pks()[index];
+                let kp = *kps.iter().find(|kp| kp.public_key() == pk).unwrap();
+                let co_owners = co_owners.clone();
+                let vtxo_inputs = vtxo_inputs.clone();
+
+                thread::spawn(move || {
+                    let mut signer =
+                        CoOwnerSigner::new(rand::thread_rng(), co_owners, kp, exchange).unwrap();
+                    let mut psbt = build_redeem_transaction(
+                        &[(&destination, Amount::from_sat(100_000))],
+                        None,
+                        &vtxo_inputs,
+                    )
+                    .unwrap();
+
+                    let mut signed = None;
+                    let sign_fn = |msg| {
+                        let (sig, pk) = signer.sign(msg)?;
+                        signed = Some((msg, sig, pk));
+                        Ok((sig, pk))
+                    };
+                    sign_redeem_transaction(sign_fn, &mut psbt, &vtxo_inputs, 0).unwrap();
+
+                    signed.unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let signed = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+
+        // Every co-owner ends up with the same signature by the owner key.
+        let (msg, sig, pk) = signed[0];
+        assert_eq!(pk, co_owners.owner_pk());
+        assert!(signed.iter().all(|signed| *signed == (msg, sig, pk)));
+        secp.verify_schnorr(&sig, &msg, &pk).unwrap();
+    }
+
+    #[test]
+    fn invalid_partial_signatures_are_traced_to_their_co_owner() {
+        let kps = keypairs(2);
+        let co_owners =
+            CoOwners::new(kps.iter().map(|kp| kp.public_key()).collect::<Vec<_>>()).unwrap();
+        let messages = [secp256k1::Message::from_digest([1; 32])];
+
+        let mut rng = rand::thread_rng();
+        let (rounds, pub_nonces): (Vec<_>, Vec<_>) = co_owners
+            .pks()
+            .iter()
+            .map(|pk| {
+                let kp = kps.iter().find(|kp| kp.public_key() == *pk).unwrap();
+                generate_nonces(&mut rng, &co_owners, kp, &messages).unwrap()
+            })
+            .unzip();
+
+        let mut partial_sigs = rounds
+            .into_iter()
+            .zip(co_owners.pks())
+            .map(|(round, pk)| {
+                let kp = kps.iter().find(|kp| kp.public_key() == *pk).unwrap();
+                round.partial_sign(&co_owners, kp, &pub_nonces).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let sigs = aggregate_signatures(&co_owners, &messages, &pub_nonces, &partial_sigs).unwrap();
+        Secp256k1::verification_only()
+            .verify_schnorr(&sigs[0], &messages[0], &co_owners.owner_pk())
+            .unwrap();
+
+        // The second co-owner sends the first one's partial signature.
+        partial_sigs[1] = partial_sigs[0].clone();
+        let err =
+            aggregate_signatures(&co_owners, &messages, &pub_nonces, &partial_sigs).unwrap_err();
+        assert!(
+            err.to_string().contains(&co_owners.pks()[1].to_string()),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! BIP21-style payment URIs that offer both an on-chain address and an Ark address, such as
+//!
+//! ```text
+//! bitcoin:bcrt1p...?ark=tark1...&amount=0.0005&label=Coffee%20shop
+//! ```
+//!
+//! The on-chain address, usually a boarding address, is the path of the URI, so that wallets
+//! without Ark support can still pay it. The Ark address goes in the `ark` parameter.
+
+use crate::ArkAddress;
+use crate::Error;
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::Denomination;
+use bitcoin::Network;
+use std::fmt;
+
+const SCHEME: &str = "bitcoin";
+
+/// A payment request to an on-chain address, an Ark address, or both.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentUri {
+    pub onchain_address: Option<Address>,
+    pub ark_address: Option<ArkAddress>,
+    pub amount: Option<Amount>,
+    /// Who is being paid, for the payer to see.
+    pub label: Option<String>,
+}
+
+impl PaymentUri {
+    /// A request to pay `onchain_address` or `ark_address`, whichever the payer supports.
+    pub fn new(onchain_address: Address, ark_address: ArkAddress) -> Self {
+        Self {
+            onchain_address: Some(onchain_address),
+            ark_address: Some(ark_address),
+            amount: None,
+            label: None,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut uri = format!("{SCHEME}:");
+        if let Some(address) = &self.onchain_address {
+            uri.push_str(&address.to_string());
+        }
+
+        let mut params = Vec::new();
+        if let Some(address) = &self.ark_address {
+            params.push(format!("ark={}", address.encode()));
+        }
+        if let Some(amount) = self.amount {
+            params.push(format!(
+                "amount={}",
+                amount.display_in(Denomination::Bitcoin)
+            ));
+        }
+        if let Some(label) = &self.label {
+            params.push(format!("label={}", percent_encode(label)));
+        }
+
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+
+        uri
+    }
+
+    /// Decode a payment URI whose addresses are for `network`.
+    ///
+    /// Unknown parameters are ignored, unless their name starts with `req-`, which marks them as
+    /// required. At least one of the addresses must be present.
+    pub fn decode(value: &str, network: Network) -> Result<Self, Error> {
+        let (scheme, rest) = value
+            .split_once(':')
+            .ok_or_else(|| Error::ad_hoc("payment URI has no scheme"))?;
+        if !scheme.eq_ignore_ascii_case(SCHEME) {
+            return Err(Error::ad_hoc(format!(
+                "unsupported payment URI scheme {scheme}"
+            )));
+        }
+
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let onchain_address = match path {
+            "" => None,
+            path => {
+                let address = path
+                    .parse::<Address<NetworkUnchecked>>()
+                    .map_err(Error::address_format)?
+                    .require_network(network)
+                    .map_err(Error::address_format)?;
+
+                Some(address)
+            }
+        };
+
+        let mut uri = Self {
+            onchain_address,
+            ark_address: None,
+            amount: None,
+            label: None,
+        };
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').ok_or_else(|| {
+                Error::ad_hoc(format!("payment URI parameter {param} has no value"))
+            })?;
+            let value = percent_decode(value)?;
+
+            match name.to_ascii_lowercase().as_str() {
+                "ark" if uri.ark_address.is_none() => {
+                    uri.ark_address = Some(ArkAddress::decode_for_network(&value, network)?);
+                }
+                "amount" if uri.amount.is_none() => {
+                    let amount = Amount::from_str_in(&value, Denomination::Bitcoin)
+                        .map_err(Error::ad_hoc)?;
+                    uri.amount = Some(amount);
+                }
+                "label" if uri.label.is_none() => uri.label = Some(value),
+                "ark" | "amount" | "label" => {
+                    return Err(Error::ad_hoc(format!(
+                        "payment URI parameter {name} is repeated"
+                    )));
+                }
+                name if name.starts_with("req-") => {
+                    return Err(Error::ad_hoc(format!(
+                        "payment URI requires unsupported parameter {name}"
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        if uri.onchain_address.is_none() && uri.ark_address.is_none() {
+            return Err(Error::ad_hoc("payment URI has no address"));
+        }
+
+        Ok(uri)
+    }
+}
+
+impl fmt::Display for PaymentUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+fn percent_decode(value: &str) -> Result<String, Error> {
+    let bytes = value.as_bytes();
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| Error::ad_hoc(format!("invalid percent-encoding in {value}")))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(Error::ad_hoc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARK_ADDRESS: &str = "tark1qqellv77udfmr20tun8dvju5vgudpf9vxe8jwhthrkn26fz96pawqfdy8nk05rsmrf8h94j26905e7n6sng8y059z8ykn2j5xcuw4xt846qj6x";
+    const ONCHAIN_ADDRESS: &str = "bcrt1qqqqsyqcyq5rqwzqfpg9scrgwpugpzysnard0ew";
+
+    fn uri() -> PaymentUri {
+        let onchain_address = ONCHAIN_ADDRESS
+            .parse::<Address<NetworkUnchecked>>()
+            .unwrap()
+            .assume_checked();
+        let ark_address = ArkAddress::decode(ARK_ADDRESS).unwrap();
+
+        PaymentUri {
+            amount: Some(Amount::from_sat(50_000)),
+            label: Some("Coffee & cake".to_string()),
+            ..PaymentUri::new(onchain_address, ark_address)
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let encoded = uri().encode();
+
+        assert_eq!(
+            encoded,
+            format!(
+                "bitcoin:{ONCHAIN_ADDRESS}?ark={ARK_ADDRESS}&amount=0.0005&label=Coffee%20%26%20cake"
+            )
+        );
+        assert_eq!(
+            PaymentUri::decode(&encoded, Network::Regtest).unwrap(),
+            uri()
+        );
+    }
+
+    #[test]
+    fn either_address_may_be_left_out() {
+        let uri =
+            PaymentUri::decode(&format!("BITCOIN:?ark={ARK_ADDRESS}"), Network::Regtest).unwrap();
+        assert_eq!(uri.onchain_address, None);
+        assert!(uri.ark_address.is_some());
+
+        let uri =
+            PaymentUri::decode(&format!("bitcoin:{ONCHAIN_ADDRESS}"), Network::Regtest).unwrap();
+        assert!(uri.onchain_address.is_some());
+        assert_eq!(uri.ark_address, None);
+
+        assert!(PaymentUri::decode("bitcoin:?amount=1", Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn addresses_must_be_for_the_network() {
+        let encoded = uri().encode();
+
+        assert!(PaymentUri::decode(&encoded, Network::Bitcoin).is_err());
+
+        let mainnet_ark = format!("bitcoin:{ONCHAIN_ADDRESS}?ark=ark1qqellv77udfmr20tun8dvju5vgudpf9vxe8jwhthrkn26fz96pawqfdy8nk05rsmrf8h94j26905e7n6sng8y059z8ykn2j5xcuw4xt8ngt9rw");
+        assert!(PaymentUri::decode(&mainnet_ark, Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn unknown_parameters_are_ignored_unless_required() {
+        let encoded = format!("{}&message=thanks", uri().encode());
+        assert_eq!(
+            PaymentUri::decode(&encoded, Network::Regtest).unwrap(),
+            uri()
+        );
+
+        let encoded = format!("{}&req-expiry=1700000000", uri().encode());
+        assert!(PaymentUri::decode(&encoded, Network::Regtest).is_err());
+
+        let encoded = format!("{}&amount=1", uri().encode());
+        assert!(PaymentUri::decode(&encoded, Network::Regtest).is_err());
+    }
+}
@@ -5,6 +5,7 @@ use crate::ArkAddress;
 use crate::Error;
 use crate::ErrorContext;
 use bitcoin::absolute::LockTime;
+use bitcoin::hashes::sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::psbt;
 use bitcoin::secp256k1;
@@ -18,11 +19,13 @@ use bitcoin::Amount;
 use bitcoin::FeeRate;
 use bitcoin::OutPoint;
 use bitcoin::Psbt;
+use bitcoin::ScriptBuf;
 use bitcoin::TapLeafHash;
 use bitcoin::TapSighashType;
 use bitcoin::Transaction;
 use bitcoin::TxIn;
 use bitcoin::TxOut;
+use bitcoin::Witness;
 use bitcoin::XOnlyPublicKey;
 use std::collections::BTreeMap;
 use std::io;
@@ -32,6 +35,10 @@ use std::io::Write;
 const VTXO_TAPROOT_KEY: [u8; 7] = [116, 97, 112, 116, 114, 101, 101];
 // const VTXO_TAPROOT_KEY: [u8; 7] = [97, 112, 116, 114, 101, 101, 0];
 
+/// The byte value corresponds to the string "condition". The value is the witness satisfying the
+/// condition of the spent leaf, which the Ark server places below the signatures.
+const CONDITION_WITNESS_KEY: [u8; 9] = [99, 111, 110, 100, 105, 116, 105, 111, 110];
+
 /// A VTXO to be spent into an unconfirmed VTXO.
 #[derive(Debug, Clone)]
 pub struct VtxoInput {
@@ -41,6 +48,8 @@ pub struct VtxoInput {
     amount: Amount,
     /// Where the VTXO would end up on the blockchain if it were to become a UTXO.
     outpoint: OutPoint,
+    /// The preimage revealed to spend an HTLC VTXO through its claim path.
+    preimage: Option<[u8; 32]>,
 }
 
 impl VtxoInput {
@@ -49,10 +58,98 @@ impl VtxoInput {
             vtxo,
             amount,
             outpoint,
+            preimage: None,
+        }
+    }
+
+    /// An HTLC VTXO to be claimed by revealing `preimage`.
+    pub fn htlc_claim(
+        vtxo: Vtxo,
+        amount: Amount,
+        outpoint: OutPoint,
+        preimage: [u8; 32],
+    ) -> Result<Self, Error> {
+        let htlc = vtxo
+            .htlc()
+            .ok_or_else(|| Error::ad_hoc(format!("VTXO {outpoint} is not locked in an HTLC")))?;
+
+        if sha256::Hash::hash(&preimage) != htlc.payment_hash {
+            return Err(Error::ad_hoc(format!(
+                "preimage does not match payment hash {}",
+                htlc.payment_hash
+            )));
+        }
+
+        Ok(Self {
+            vtxo,
+            amount,
+            outpoint,
+            preimage: Some(preimage),
+        })
+    }
+
+    /// The leaf spent by this input, its control block and the size of its witness.
+    fn spend_path(&self) -> (ScriptBuf, taproot::ControlBlock, usize) {
+        match self.preimage {
+            Some(_) => {
+                let (script, control_block) = self
+                    .vtxo
+                    .htlc_claim_spend_info()
+                    .expect("checked in VtxoInput::htlc_claim");
+
+                (script, control_block, Vtxo::HTLC_CLAIM_WITNESS_SIZE)
+            }
+            None => {
+                let (script, control_block) = self.vtxo.forfeit_spend_info();
+
+                (script, control_block, Vtxo::FORFEIT_WITNESS_SIZE)
+            }
+        }
+    }
+
+    /// The locktime the redeem transaction needs to spend this input, if its leaf has one.
+    fn lock_time(&self) -> Option<LockTime> {
+        match (self.preimage, self.vtxo.htlc()) {
+            (None, Some(htlc)) => Some(htlc.refund_locktime),
+            _ => None,
         }
     }
 }
 
+/// The most VTXOs a redeem transaction spends. Larger payments are split over several
+/// transactions.
+pub const MAX_REDEEM_INPUTS: usize = 32;
+
+/// The most outputs a redeem transaction has, including the change output.
+pub const MAX_REDEEM_OUTPUTS: usize = 32;
+
+/// The fee rate paid by redeem transactions.
+pub const REDEEM_FEE_RATE: FeeRate = FeeRate::from_sat_per_kwu(253);
+
+/// The fee of a redeem transaction spending `vtxo_inputs` into `num_outputs` outputs, as
+/// deducted by [`build_redeem_transaction`].
+pub fn redeem_transaction_fee(
+    vtxo_inputs: &[VtxoInput],
+    num_outputs: usize,
+) -> Result<Amount, Error> {
+    let vtxos = vtxo_inputs
+        .iter()
+        .map(|vtxo_input| {
+            let (script, control_block, witness_size) = vtxo_input.spend_path();
+
+            tx_weight_estimator::VtxoInput {
+                outpoint: vtxo_input.outpoint,
+                amount: vtxo_input.amount,
+                revealed_script: Some(script),
+                control_block,
+                witness_size,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    compute_redeem_tx_fee(REDEEM_FEE_RATE, vtxos.as_slice(), num_outputs)
+}
+
 /// Build a transaction to send VTXOs to another [`ArkAddress`].
 pub fn build_redeem_transaction(
     outputs: &[(&ArkAddress, Amount)],
@@ -98,37 +195,7 @@ pub fn build_redeem_transaction(
         (None, Amount::ZERO)
     };
 
-    let fee = {
-        let vtxos = vtxo_inputs
-            .iter()
-            .map(
-                |VtxoInput {
-                     vtxo,
-                     amount,
-                     outpoint,
-                 }| {
-                    let (script, control_block) = vtxo.forfeit_spend_info();
-
-                    tx_weight_estimator::VtxoInput {
-                        outpoint: *outpoint,
-                        amount: *amount,
-                        revealed_script: Some(script),
-                        control_block,
-                        witness_size: Vtxo::FORFEIT_WITNESS_SIZE,
-                    }
-                },
-            )
-            .collect::<Vec<_>>();
-
-        let computed_fee = compute_redeem_tx_fee(
-            FeeRate::from_sat_per_kwu(253),
-            vtxos.as_slice(),
-            outputs.len(),
-        )
-        .map_err(Error::from)?;
-
-        computed_fee + extra_fee
-    };
+    let fee = redeem_transaction_fee(vtxo_inputs, outputs.len())? + extra_fee;
 
     // Subtract the fee from somewhere.
     //
@@ -157,19 +224,36 @@ pub fn build_redeem_transaction(
         }
     };
 
-    // TODO: Use a different locktime if we have CLTV multisig script.
-    let lock_time = LockTime::ZERO;
+    // Inputs spent through a CLTV leaf need the transaction to be locked at least as long.
+    let mut lock_time = None;
+    for input_lock_time in vtxo_inputs.iter().filter_map(VtxoInput::lock_time) {
+        lock_time = match lock_time {
+            None => Some(input_lock_time),
+            Some(lock_time) if input_lock_time.is_implied_by(lock_time) => Some(lock_time),
+            Some(lock_time) if lock_time.is_implied_by(input_lock_time) => Some(input_lock_time),
+            Some(lock_time) => {
+                return Err(Error::transaction(format!(
+                    "cannot combine inputs locked until {lock_time} and {input_lock_time}"
+                )));
+            }
+        };
+    }
+
+    // The locktime is only enforced if some input has a non-final sequence number.
+    let sequence = match lock_time {
+        Some(_) => bitcoin::Sequence::ENABLE_LOCKTIME_NO_RBF,
+        None => bitcoin::Sequence::MAX,
+    };
 
     let unsigned_tx = Transaction {
         version: transaction::Version::TWO,
-        lock_time,
+        lock_time: lock_time.unwrap_or(LockTime::ZERO),
         input: vtxo_inputs
             .iter()
             .map(|VtxoInput { outpoint, .. }| TxIn {
                 previous_output: *outpoint,
                 script_sig: Default::default(),
-                // TODO: Use a different sequence number if we have a CLTV multisig script.
-                sequence: bitcoin::Sequence::MAX,
+                sequence,
                 witness: Default::default(),
             })
             .collect(),
@@ -186,8 +270,8 @@ pub fn build_redeem_transaction(
             .map_err(Error::transaction)?;
 
         for script in vtxo_input.vtxo.tapscripts().iter() {
-            // Write the depth (always 1). TODO: Support more depth.
-            bytes.push(1);
+            let control_block = vtxo_input.vtxo.get_spend_info(script.clone())?;
+            bytes.push(control_block.merkle_branch.len() as u8);
 
             // TODO: Support future leaf versions.
             bytes.push(LeafVersion::TapScript.to_consensus());
@@ -238,13 +322,15 @@ pub fn sign_redeem_transaction<S>(
 where
     S: FnOnce(secp256k1::Message) -> Result<(schnorr::Signature, XOnlyPublicKey), Error>,
 {
+    let vtxo_input = vtxo_inputs
+        .get(input_index)
+        .ok_or_else(|| Error::ad_hoc(format!("no input to sign at index {input_index}")))?;
     let VtxoInput {
         vtxo,
         amount,
         outpoint,
-    } = vtxo_inputs
-        .get(input_index)
-        .ok_or_else(|| Error::ad_hoc(format!("no input to sign at index {input_index}")))?;
+        preimage,
+    } = vtxo_input;
 
     tracing::debug!(
         ?outpoint,
@@ -281,13 +367,22 @@ where
     psbt_input.witness_utxo = Some(prevout.clone());
 
     // In the case of input VTXOs, we are actually using a script spend path.
-    let (forfeit_script, forfeit_control_block) = vtxo.forfeit_spend_info();
+    let (script, control_block, _) = vtxo_input.spend_path();
 
-    let leaf_version = forfeit_control_block.leaf_version;
-    psbt_input.tap_scripts = BTreeMap::from_iter([(
-        forfeit_control_block,
-        (forfeit_script.clone(), leaf_version),
-    )]);
+    let leaf_version = control_block.leaf_version;
+    psbt_input.tap_scripts = BTreeMap::from_iter([(control_block, (script.clone(), leaf_version))]);
+
+    if let Some(preimage) = preimage {
+        let witness = Witness::from_slice(&[preimage]);
+
+        psbt_input.unknown.insert(
+            psbt::raw::Key {
+                type_value: u8::MAX,
+                key: CONDITION_WITNESS_KEY.to_vec(),
+            },
+            bitcoin::consensus::serialize(&witness),
+        );
+    }
 
     let prevouts = vtxo_inputs
         .iter()
@@ -298,7 +393,7 @@ where
         .collect::<Vec<_>>();
     let prevouts = Prevouts::All(&prevouts);
 
-    let leaf_hash = TapLeafHash::from_script(&forfeit_script, leaf_version);
+    let leaf_hash = TapLeafHash::from_script(&script, leaf_version);
 
     let tap_sighash = SighashCache::new(&redeem_psbt.unsigned_tx)
         .taproot_script_spend_signature_hash(
@@ -323,3 +418,398 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vtxo::Htlc;
+    use bitcoin::hashes::hash160;
+    use bitcoin::key::Keypair;
+    use bitcoin::key::Secp256k1;
+    use bitcoin::opcodes::all::*;
+    use bitcoin::script::Instruction;
+    use bitcoin::taproot::ControlBlock;
+    use bitcoin::Network;
+    use bitcoin::Script;
+    use bitcoin::Txid;
+
+    const PREIMAGE: [u8; 32] = [7; 32];
+
+    struct Parties {
+        secp: Secp256k1<secp256k1::All>,
+        server: Keypair,
+        sender: Keypair,
+        receiver: Keypair,
+    }
+
+    impl Parties {
+        fn new() -> Self {
+            let secp = Secp256k1::new();
+            let keypair = |byte| Keypair::from_seckey_slice(&secp, &[byte; 32]).unwrap();
+
+            Self {
+                server: keypair(1),
+                sender: keypair(2),
+                receiver: keypair(3),
+                secp,
+            }
+        }
+
+        fn htlc_vtxo(&self) -> Vtxo {
+            let htlc = Htlc {
+                sender: self.sender.x_only_public_key().0,
+                receiver: self.receiver.x_only_public_key().0,
+                payment_hash: sha256::Hash::hash(&PREIMAGE),
+                refund_locktime: LockTime::from_height(800_000).unwrap(),
+            };
+
+            Vtxo::new_htlc(
+                &self.secp,
+                self.server.x_only_public_key().0,
+                htlc,
+                bitcoin::Sequence::from_512_second_intervals(10),
+                Network::Regtest,
+            )
+            .unwrap()
+        }
+
+        fn redeem_psbt(&self, vtxo_input: &VtxoInput) -> Psbt {
+            let destination = Vtxo::new_default(
+                &self.secp,
+                self.server.x_only_public_key().0,
+                self.receiver.x_only_public_key().0,
+                bitcoin::Sequence::from_512_second_intervals(10),
+                Network::Regtest,
+            )
+            .unwrap()
+            .to_ark_address();
+
+            build_redeem_transaction(
+                &[(&destination, vtxo_input.amount)],
+                None,
+                std::slice::from_ref(vtxo_input),
+            )
+            .unwrap()
+        }
+
+        fn sign(&self, psbt: &mut Psbt, vtxo_input: &VtxoInput, signer: &Keypair) {
+            let sign_fn = |msg| {
+                let sig = self.secp.sign_schnorr_no_aux_rand(&msg, signer);
+                Ok((sig, signer.x_only_public_key().0))
+            };
+
+            sign_redeem_transaction(sign_fn, psbt, std::slice::from_ref(vtxo_input), 0).unwrap();
+        }
+
+        /// Add the Ark server's signature and build the witness, as the Ark server would.
+        fn finalize(&self, psbt: &Psbt) -> Transaction {
+            let input = &psbt.inputs[0];
+            let (control_block, (script, leaf_version)) =
+                input.tap_scripts.first_key_value().unwrap();
+            let leaf_hash = TapLeafHash::from_script(script, *leaf_version);
+
+            let prevouts = [input.witness_utxo.clone().unwrap()];
+            let sighash = SighashCache::new(&psbt.unsigned_tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &Prevouts::All(&prevouts),
+                    leaf_hash,
+                    TapSighashType::Default,
+                )
+                .unwrap();
+            let msg = secp256k1::Message::from_digest(sighash.to_byte_array());
+            let server_sig = self.secp.sign_schnorr_no_aux_rand(&msg, &self.server);
+
+            let (_, user_sig) = input.tap_script_sigs.first_key_value().unwrap();
+
+            let mut witness = Witness::new();
+            witness.push(server_sig.as_ref());
+            witness.push(user_sig.to_vec());
+
+            let condition_key = psbt::raw::Key {
+                type_value: u8::MAX,
+                key: CONDITION_WITNESS_KEY.to_vec(),
+            };
+            if let Some(condition) = input.unknown.get(&condition_key) {
+                let condition: Witness = bitcoin::consensus::deserialize(condition).unwrap();
+                for item in condition.iter() {
+                    witness.push(item);
+                }
+            }
+
+            witness.push(script.as_bytes());
+            witness.push(control_block.serialize());
+
+            let mut tx = psbt.unsigned_tx.clone();
+            tx.input[0].witness = witness;
+
+            tx
+        }
+    }
+
+    fn vtxo_input(vtxo: Vtxo) -> VtxoInput {
+        VtxoInput::new(vtxo, Amount::from_sat(100_000), outpoint())
+    }
+
+    fn outpoint() -> OutPoint {
+        OutPoint {
+            txid: Txid::all_zeros(),
+            vout: 0,
+        }
+    }
+
+    /// Run the script path spend of the first input of `tx`, which spends `prevout`.
+    ///
+    /// This only knows the opcodes of Ark's leaves.
+    fn verify_script_spend(tx: &Transaction, prevout: &TxOut) -> Result<(), String> {
+        let secp = Secp256k1::verification_only();
+
+        let items = tx.input[0].witness.iter().collect::<Vec<_>>();
+        let (control_block, items) = items.split_last().ok_or("empty witness")?;
+        let (script, items) = items.split_last().ok_or("no script")?;
+
+        let control_block = ControlBlock::decode(control_block).map_err(|e| e.to_string())?;
+        let script = Script::from_bytes(script);
+        let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..])
+            .map_err(|e| e.to_string())?;
+        if !control_block.verify_taproot_commitment(&secp, output_key, script) {
+            return Err("script is not committed to by the output".to_string());
+        }
+
+        let leaf_hash = TapLeafHash::from_script(script, LeafVersion::TapScript);
+        let prevouts = [prevout.clone()];
+        let sighash = SighashCache::new(tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&prevouts),
+                leaf_hash,
+                TapSighashType::Default,
+            )
+            .map_err(|e| e.to_string())?;
+        let msg = secp256k1::Message::from_digest(sighash.to_byte_array());
+
+        let mut stack = items.iter().map(|item| item.to_vec()).collect::<Vec<_>>();
+
+        for instruction in script.instructions() {
+            let op = match instruction.map_err(|e| e.to_string())? {
+                Instruction::PushBytes(bytes) => {
+                    stack.push(bytes.as_bytes().to_vec());
+                    continue;
+                }
+                Instruction::Op(op) => op,
+            };
+
+            match op {
+                OP_CSV => {
+                    let n = bitcoin::script::read_scriptint(stack.last().ok_or("stack underflow")?)
+                        .map_err(|e| e.to_string())?;
+                    let locktime = bitcoin::Sequence::from_consensus(n as u32)
+                        .to_relative_lock_time()
+                        .ok_or("CSV is disabled")?;
+                    let satisfied = tx.input[0]
+                        .sequence
+                        .to_relative_lock_time()
+                        .is_some_and(|sequence| locktime.is_implied_by(sequence));
+                    if !satisfied {
+                        return Err("relative timelock not satisfied".to_string());
+                    }
+                }
+                OP_CLTV => {
+                    let n = bitcoin::script::read_scriptint(stack.last().ok_or("stack underflow")?)
+                        .map_err(|e| e.to_string())?;
+                    let locktime = LockTime::from_consensus(n as u32);
+                    if tx.input[0].sequence == bitcoin::Sequence::MAX
+                        || !locktime.is_implied_by(tx.lock_time)
+                    {
+                        return Err("absolute timelock not satisfied".to_string());
+                    }
+                }
+                OP_DROP => {
+                    pop(&mut stack)?;
+                }
+                OP_HASH160 => {
+                    let item = pop(&mut stack)?;
+                    stack.push(hash160::Hash::hash(&item).to_byte_array().to_vec());
+                }
+                OP_SHA256 => {
+                    let item = pop(&mut stack)?;
+                    stack.push(sha256::Hash::hash(&item).to_byte_array().to_vec());
+                }
+                OP_EQUALVERIFY => {
+                    if pop(&mut stack)? != pop(&mut stack)? {
+                        return Err("OP_EQUALVERIFY failed".to_string());
+                    }
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    let pk =
+                        XOnlyPublicKey::from_slice(&pop(&mut stack)?).map_err(|e| e.to_string())?;
+                    let valid = schnorr::Signature::from_slice(&pop(&mut stack)?)
+                        .is_ok_and(|sig| secp.verify_schnorr(&sig, &msg, &pk).is_ok());
+
+                    match (op, valid) {
+                        (OP_CHECKSIGVERIFY, false) => {
+                            return Err(format!("missing signature of {pk}"));
+                        }
+                        (OP_CHECKSIGVERIFY, true) => {}
+                        (_, valid) => stack.push(if valid { vec![1] } else { vec![] }),
+                    }
+                }
+                op => return Err(format!("unsupported opcode {op}")),
+            }
+        }
+
+        match stack.as_slice() {
+            [top] if top == &[1] => Ok(()),
+            _ => Err(format!("script left {} items on the stack", stack.len())),
+        }
+    }
+
+    fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+        stack.pop().ok_or("stack underflow".to_string())
+    }
+
+    #[test]
+    fn htlc_can_be_claimed_with_the_preimage() {
+        let parties = Parties::new();
+        let vtxo = parties.htlc_vtxo();
+
+        assert!(VtxoInput::htlc_claim(
+            vtxo.clone(),
+            Amount::from_sat(100_000),
+            outpoint(),
+            [8; 32]
+        )
+        .is_err());
+
+        let vtxo_input = VtxoInput::htlc_claim(
+            vtxo.clone(),
+            Amount::from_sat(100_000),
+            outpoint(),
+            PREIMAGE,
+        )
+        .unwrap();
+        let mut psbt = parties.redeem_psbt(&vtxo_input);
+        parties.sign(&mut psbt, &vtxo_input, &parties.receiver);
+        let tx = parties.finalize(&psbt);
+
+        let prevout = psbt.inputs[0].witness_utxo.clone().unwrap();
+        verify_script_spend(&tx, &prevout).unwrap();
+
+        // The preimage sits right below the script.
+        let mut items = tx.input[0].witness.to_vec();
+        let preimage = items.len() - 3;
+        assert_eq!(items[preimage], PREIMAGE);
+        items[preimage] = vec![8; 32];
+        let mut tampered = tx.clone();
+        tampered.input[0].witness = Witness::from_slice(&items);
+        assert!(verify_script_spend(&tampered, &prevout).is_err());
+
+        // The sender cannot claim.
+        let mut psbt = parties.redeem_psbt(&vtxo_input);
+        parties.sign(&mut psbt, &vtxo_input, &parties.sender);
+        assert!(verify_script_spend(&parties.finalize(&psbt), &prevout).is_err());
+    }
+
+    #[test]
+    fn htlc_can_be_refunded_after_the_locktime() {
+        let parties = Parties::new();
+        let vtxo_input = vtxo_input(parties.htlc_vtxo());
+
+        let mut psbt = parties.redeem_psbt(&vtxo_input);
+        assert_eq!(
+            psbt.unsigned_tx.lock_time,
+            LockTime::from_height(800_000).unwrap()
+        );
+        let prevout = TxOut {
+            value: vtxo_input.amount,
+            script_pubkey: vtxo_input.vtxo.script_pubkey(),
+        };
+
+        let mut early = psbt.clone();
+        early.unsigned_tx.lock_time = LockTime::from_height(799_999).unwrap();
+        parties.sign(&mut early, &vtxo_input, &parties.sender);
+        assert!(verify_script_spend(&parties.finalize(&early), &prevout).is_err());
+
+        parties.sign(&mut psbt, &vtxo_input, &parties.sender);
+        verify_script_spend(&parties.finalize(&psbt), &prevout).unwrap();
+    }
+
+    #[test]
+    fn htlc_can_be_refunded_unilaterally_after_the_exit_delay() {
+        let parties = Parties::new();
+        let vtxo = parties.htlc_vtxo();
+        let (exit_script, control_block) = vtxo.exit_spend_info();
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: vtxo.script_pubkey(),
+        };
+
+        let spend = |sequence| {
+            let mut tx = Transaction {
+                version: transaction::Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: outpoint(),
+                    script_sig: Default::default(),
+                    sequence,
+                    witness: Default::default(),
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(99_000),
+                    script_pubkey: ScriptBuf::new_op_return([]),
+                }],
+            };
+
+            let leaf_hash = TapLeafHash::from_script(&exit_script, LeafVersion::TapScript);
+            let sighash = SighashCache::new(&tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &Prevouts::All(std::slice::from_ref(&prevout)),
+                    leaf_hash,
+                    TapSighashType::Default,
+                )
+                .unwrap();
+            let msg = secp256k1::Message::from_digest(sighash.to_byte_array());
+            let sig = parties.secp.sign_schnorr_no_aux_rand(&msg, &parties.sender);
+
+            tx.input[0].witness = Witness::from_slice(&[
+                sig.as_ref().to_vec(),
+                exit_script.to_bytes(),
+                control_block.serialize(),
+            ]);
+
+            tx
+        };
+
+        let tx = spend(bitcoin::Sequence::from_512_second_intervals(10));
+        verify_script_spend(&tx, &prevout).unwrap();
+
+        let tx = spend(bitcoin::Sequence::from_512_second_intervals(9));
+        assert!(verify_script_spend(&tx, &prevout).is_err());
+    }
+
+    #[test]
+    fn taptree_records_the_depth_of_every_leaf() {
+        let parties = Parties::new();
+        let vtxo_input = vtxo_input(parties.htlc_vtxo());
+        let psbt = parties.redeem_psbt(&vtxo_input);
+
+        let taptree = psbt.inputs[0]
+            .unknown
+            .get(&psbt::raw::Key {
+                type_value: u8::MAX,
+                key: VTXO_TAPROOT_KEY.to_vec(),
+            })
+            .unwrap();
+
+        // Three leaves: the exit and refund leaves at depth 2, then the claim leaf at depth 1.
+        assert_eq!(taptree[0], 3);
+        let mut depths = Vec::new();
+        let mut rest = &taptree[1..];
+        while let [depth, _leaf_version, len, tail @ ..] = rest {
+            depths.push(*depth);
+            rest = &tail[*len as usize..];
+        }
+        assert_eq!(depths, [2, 2, 1]);
+    }
+}
@@ -361,8 +361,6 @@ pub fn sign_vtxo_tree(
 /// Build and sign a forfeit transaction per [`VtxoInput`] to be used in an upcoming round
 /// transaction.
 pub fn create_and_sign_forfeit_txs(
-    // For now we only support a single keypair. Eventually we may need to provide something like a
-    // `Sign` trait, so that the caller can find the secret key for the given `VtxoInput`.
     kp: &Keypair,
     vtxo_inputs: &[VtxoInput],
     connector_tree: TxTree,
@@ -372,6 +370,42 @@ pub fn create_and_sign_forfeit_txs(
     // As defined by the server.
     dust: Amount,
 ) -> Result<Vec<Psbt>, Error> {
+    let secp = Secp256k1::new();
+
+    let sign_fn = |msg| {
+        let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
+        Ok((sig, kp.x_only_public_key().0))
+    };
+
+    create_and_sign_forfeit_txs_with(
+        sign_fn,
+        vtxo_inputs,
+        connector_tree,
+        connector_index,
+        min_relay_fee_rate_sats_per_kvb,
+        server_forfeit_address,
+        dust,
+    )
+}
+
+/// Like [`create_and_sign_forfeit_txs`], but signing with `sign_fn`, which is called once per
+/// [`VtxoInput`] and returns the signature and the public key of the VTXO's owner.
+///
+/// This allows VTXOs whose owner key is not held by a single party, such as those of
+/// [`crate::multi_owner::CoOwners`].
+pub fn create_and_sign_forfeit_txs_with<S>(
+    mut sign_fn: S,
+    vtxo_inputs: &[VtxoInput],
+    connector_tree: TxTree,
+    connector_index: &HashMap<OutPoint, OutPoint>,
+    min_relay_fee_rate_sats_per_kvb: i64,
+    server_forfeit_address: &Address,
+    // As defined by the server.
+    dust: Amount,
+) -> Result<Vec<Psbt>, Error>
+where
+    S: FnMut(secp256k1::Message) -> Result<(schnorr::Signature, XOnlyPublicKey), Error>,
+{
     const FORFEIT_TX_CONNECTOR_INDEX: usize = 0;
     const FORFEIT_TX_VTXO_INDEX: usize = 1;
 
@@ -436,16 +470,28 @@ pub fn create_and_sign_forfeit_txs(
             script_pubkey: server_forfeit_address.script_pubkey(),
         };
 
+        // The forfeit path of an HTLC VTXO is its refund leaf, so the forfeit transaction must
+        // be locked until the refund locktime, like a redeem transaction spending that leaf.
+        let (lock_time, sequence) = match vtxo.htlc() {
+            Some(htlc) => (
+                htlc.refund_locktime,
+                bitcoin::Sequence::ENABLE_LOCKTIME_NO_RBF,
+            ),
+            None => (LockTime::ZERO, bitcoin::Sequence::MAX),
+        };
+
         let mut forfeit_psbt = Psbt::from_unsigned_tx(Transaction {
             version: transaction::Version::TWO,
-            lock_time: LockTime::ZERO,
+            lock_time,
             input: vec![
                 TxIn {
                     previous_output: *connector_outpoint,
+                    sequence,
                     ..Default::default()
                 },
                 TxIn {
                     previous_output: *vtxo_outpoint,
+                    sequence,
                     ..Default::default()
                 },
             ],
@@ -492,8 +538,7 @@ pub fn create_and_sign_forfeit_txs(
 
         let msg = secp256k1::Message::from_digest(tap_sighash.to_raw_hash().to_byte_array());
 
-        let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
-        let pk = kp.x_only_public_key().0;
+        let (sig, pk) = sign_fn(msg)?;
 
         secp.verify_schnorr(&sig, &msg, &pk)
             .map_err(Error::crypto)
@@ -605,3 +650,120 @@ fn extract_cosigner_pks_from_vtxo_psbt(psbt: &Psbt) -> Result<Vec<PublicKey>, Er
     }
     Ok(cosigner_pks)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::TxTreeLevel;
+    use crate::vtxo::Htlc;
+    use bitcoin::hashes::sha256;
+    use bitcoin::Network;
+    use bitcoin::ScriptBuf;
+    use bitcoin::Txid;
+
+    const DUST: Amount = Amount::from_sat(330);
+
+    fn keypair(byte: u8) -> Keypair {
+        Keypair::from_seckey_slice(&Secp256k1::new(), &[byte; 32]).unwrap()
+    }
+
+    fn forfeit_address() -> Address {
+        "bcrt1qqqqsyqcyq5rqwzqfpg9scrgwpugpzysnard0ew"
+            .parse::<Address<bitcoin::address::NetworkUnchecked>>()
+            .unwrap()
+            .assume_checked()
+    }
+
+    /// Sign the forfeit transaction of `vtxo` against a connector tree with a single connector.
+    fn forfeit(kp: &Keypair, vtxo: Vtxo) -> Psbt {
+        let connector_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: DUST,
+                script_pubkey: ScriptBuf::new_p2tr(
+                    &Secp256k1::new(),
+                    keypair(9).x_only_public_key().0,
+                    None,
+                ),
+            }],
+        };
+        let connector_txid = connector_tx.compute_txid();
+        let connector_tree = TxTree {
+            levels: vec![TxTreeLevel {
+                nodes: vec![TxTreeNode {
+                    txid: connector_txid,
+                    tx: Psbt::from_unsigned_tx(connector_tx).unwrap(),
+                    parent_txid: Txid::all_zeros(),
+                }],
+            }],
+        };
+
+        let vtxo_outpoint = OutPoint::new(Txid::all_zeros(), 1);
+        let connector_index = HashMap::from([(vtxo_outpoint, OutPoint::new(connector_txid, 0))]);
+
+        let vtxo_input = VtxoInput::new(vtxo, Amount::from_sat(100_000), vtxo_outpoint);
+
+        let mut forfeits = create_and_sign_forfeit_txs(
+            kp,
+            &[vtxo_input],
+            connector_tree,
+            &connector_index,
+            1_000,
+            &forfeit_address(),
+            DUST,
+        )
+        .unwrap();
+
+        assert_eq!(forfeits.len(), 1);
+        forfeits.remove(0)
+    }
+
+    #[test]
+    fn forfeits_of_htlc_vtxos_are_locked_until_the_refund() {
+        let secp = Secp256k1::new();
+        let server = keypair(1).x_only_public_key().0;
+        let sender = keypair(2);
+        let exit_delay = bitcoin::Sequence::from_512_second_intervals(10);
+
+        let refund_locktime = LockTime::from_height(800_000).unwrap();
+        let htlc = Htlc {
+            sender: sender.x_only_public_key().0,
+            receiver: keypair(3).x_only_public_key().0,
+            payment_hash: sha256::Hash::hash(&[7; 32]),
+            refund_locktime,
+        };
+        let vtxo = Vtxo::new_htlc(&secp, server, htlc, exit_delay, Network::Regtest).unwrap();
+
+        let psbt = forfeit(&sender, vtxo.clone());
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.lock_time, refund_locktime);
+        assert!(tx
+            .input
+            .iter()
+            .all(|input| input.sequence.enables_absolute_lock_time()));
+
+        // The forfeit is signed through the refund leaf.
+        let (refund_script, _) = vtxo.forfeit_spend_info();
+        let (_, (script, _)) = psbt.inputs[1].tap_scripts.first_key_value().unwrap();
+        assert_eq!(script, &refund_script);
+
+        // Forfeits of other VTXOs are not locked.
+        let vtxo = Vtxo::new_default(
+            &secp,
+            server,
+            sender.x_only_public_key().0,
+            exit_delay,
+            Network::Regtest,
+        )
+        .unwrap();
+
+        let tx = forfeit(&sender, vtxo).unsigned_tx;
+        assert_eq!(tx.lock_time, LockTime::ZERO);
+        assert!(tx
+            .input
+            .iter()
+            .all(|input| input.sequence == bitcoin::Sequence::MAX));
+    }
+}
@@ -1,5 +1,11 @@
+use bitcoin::absolute;
+use bitcoin::hashes::ripemd160;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
 use bitcoin::opcodes::all::*;
+use bitcoin::script::Instruction;
 use bitcoin::taproot::TaprootSpendInfo;
+use bitcoin::Script;
 use bitcoin::ScriptBuf;
 use bitcoin::XOnlyPublicKey;
 use std::fmt;
@@ -26,6 +32,47 @@ pub fn csv_sig_script(locktime: bitcoin::Sequence, pk: XOnlyPublicKey) -> Script
         .into_script()
 }
 
+/// A [`ScriptBuf`] allowing `receiver` to claim an HTLC with the Ark server, by revealing the
+/// preimage of `payment_hash`.
+///
+/// The script checks the HASH160 of the preimage, which is the RIPEMD160 of `payment_hash`, so that
+/// the same preimage settles a Lightning payment to `payment_hash`.
+pub fn htlc_claim_script(
+    payment_hash: sha256::Hash,
+    receiver: XOnlyPublicKey,
+    server: XOnlyPublicKey,
+) -> ScriptBuf {
+    let hash = ripemd160::Hash::hash(payment_hash.as_byte_array());
+
+    ScriptBuf::builder()
+        .push_opcode(OP_HASH160)
+        .push_slice(hash.as_byte_array())
+        .push_opcode(OP_EQUALVERIFY)
+        .push_x_only_key(&receiver)
+        .push_opcode(OP_CHECKSIGVERIFY)
+        .push_x_only_key(&server)
+        .push_opcode(OP_CHECKSIG)
+        .into_script()
+}
+
+/// A [`ScriptBuf`] allowing `sender` to take back an HTLC with the Ark server once
+/// `refund_locktime` has passed.
+pub fn htlc_refund_script(
+    refund_locktime: absolute::LockTime,
+    sender: XOnlyPublicKey,
+    server: XOnlyPublicKey,
+) -> ScriptBuf {
+    ScriptBuf::builder()
+        .push_lock_time(refund_locktime)
+        .push_opcode(OP_CLTV)
+        .push_opcode(OP_DROP)
+        .push_x_only_key(&sender)
+        .push_opcode(OP_CHECKSIGVERIFY)
+        .push_x_only_key(&server)
+        .push_opcode(OP_CHECKSIG)
+        .into_script()
+}
+
 /// The script pubkey for the Taproot output corresponding to the given [`TaprootSpendInfo`].
 pub fn tr_script_pubkey(spend_info: &TaprootSpendInfo) -> ScriptBuf {
     let output_key = spend_info.output_key();
@@ -71,6 +118,168 @@ pub fn extract_sequence_from_csv_sig_script(
     Ok(sequence)
 }
 
+/// How a tapscript leaf of a VTXO can be spent, as far as the Ark protocol is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TapscriptKind {
+    /// The leaf needs the signature of the Ark server and of someone else.
+    Collaborative,
+    /// The leaf can be spent without the Ark server once `sequence` has passed since the output
+    /// was confirmed.
+    CsvExit { sequence: bitcoin::Sequence },
+    /// The leaf can be spent without the Ark server after the absolute `locktime`.
+    Cltv { locktime: absolute::LockTime },
+    /// The leaf can be spent without the Ark server at any time, by the Ark server alone, or in a
+    /// way that is not understood.
+    Invalid { reason: String },
+}
+
+/// Classify a tapscript leaf of a VTXO whose Ark server is `server`.
+///
+/// Only scripts made of a sequence of conditions that must all hold are understood:
+///
+/// - `<n> OP_CSV OP_DROP` and `<n> OP_CLTV OP_DROP`, at most once each.
+/// - `OP_HASH160 <hash> OP_EQUALVERIFY` and `OP_SHA256 <hash> OP_EQUALVERIFY`.
+/// - `<pk> OP_CHECKSIGVERIFY`, and `<pk> OP_CHECKSIG` as the last condition, which is required.
+///
+/// Anything else, such as branches, is [`TapscriptKind::Invalid`].
+pub fn classify_tapscript(script: &Script, server: XOnlyPublicKey) -> TapscriptKind {
+    match ScriptConditions::parse(script) {
+        Ok(conditions) => conditions.classify(server),
+        Err(reason) => TapscriptKind::Invalid { reason },
+    }
+}
+
+/// The conditions that must all hold to spend a tapscript leaf.
+#[derive(Default)]
+struct ScriptConditions {
+    signers: Vec<XOnlyPublicKey>,
+    csv: Option<i64>,
+    cltv: Option<i64>,
+}
+
+impl ScriptConditions {
+    fn parse(script: &Script) -> Result<Self, String> {
+        let instructions = script
+            .instructions()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("malformed script: {e}"))?;
+
+        let mut conditions = Self::default();
+        let mut rest = instructions.as_slice();
+        loop {
+            rest = match rest {
+                [Instruction::PushBytes(pk), Instruction::Op(OP_CHECKSIG)] => {
+                    conditions.signers.push(parse_key(pk.as_bytes())?);
+                    return Ok(conditions);
+                }
+                [Instruction::PushBytes(pk), Instruction::Op(OP_CHECKSIGVERIFY), rest @ ..] => {
+                    conditions.signers.push(parse_key(pk.as_bytes())?);
+                    rest
+                }
+                [n, Instruction::Op(OP_CSV), Instruction::Op(OP_DROP), rest @ ..] => {
+                    let n = locktime_operand(n).ok_or("CSV operand is not a number")?;
+                    if conditions.csv.replace(n).is_some() {
+                        return Err("more than one relative timelock".to_string());
+                    }
+                    rest
+                }
+                [n, Instruction::Op(OP_CLTV), Instruction::Op(OP_DROP), rest @ ..] => {
+                    let n = locktime_operand(n).ok_or("CLTV operand is not a number")?;
+                    if conditions.cltv.replace(n).is_some() {
+                        return Err("more than one absolute timelock".to_string());
+                    }
+                    rest
+                }
+                [Instruction::Op(OP_HASH160), Instruction::PushBytes(hash), Instruction::Op(OP_EQUALVERIFY), rest @ ..]
+                    if hash.len() == 20 =>
+                {
+                    rest
+                }
+                [Instruction::Op(OP_SHA256), Instruction::PushBytes(hash), Instruction::Op(OP_EQUALVERIFY), rest @ ..]
+                    if hash.len() == 32 =>
+                {
+                    rest
+                }
+                [] => return Err("script does not end with OP_CHECKSIG".to_string()),
+                _ => {
+                    let position = instructions.len() - rest.len();
+                    return Err(format!("unsupported instructions from position {position}"));
+                }
+            };
+        }
+    }
+
+    fn classify(self, server: XOnlyPublicKey) -> TapscriptKind {
+        let invalid = |reason: &str| TapscriptKind::Invalid {
+            reason: reason.to_string(),
+        };
+
+        if self.signers.contains(&server) {
+            if self.signers.iter().all(|signer| *signer == server) {
+                return invalid("only the Ark server's signature is required");
+            }
+
+            return TapscriptKind::Collaborative;
+        }
+
+        if let Some(n) = self.csv {
+            let sequence = match u32::try_from(n) {
+                Ok(n) => bitcoin::Sequence::from_consensus(n),
+                Err(_) => return invalid("CSV operand is out of range"),
+            };
+
+            // A sequence with the disable flag set does not lock anything.
+            return match sequence.to_relative_lock_time() {
+                Some(locktime) if locktime.to_consensus_u32() & 0xffff != 0 => {
+                    TapscriptKind::CsvExit { sequence }
+                }
+                _ => invalid("relative timelock has no effect"),
+            };
+        }
+
+        if let Some(n) = self.cltv {
+            return match u32::try_from(n) {
+                Ok(n) if n > 0 => TapscriptKind::Cltv {
+                    locktime: absolute::LockTime::from_consensus(n),
+                },
+                _ => invalid("absolute timelock has no effect"),
+            };
+        }
+
+        invalid("can be spent without the Ark server and without a timelock")
+    }
+}
+
+/// The number pushed by `instruction` for `OP_CSV` or `OP_CLTV`, which take numbers of up to 5
+/// bytes rather than the usual 4.
+fn locktime_operand(instruction: &Instruction) -> Option<i64> {
+    let bytes = match instruction {
+        Instruction::Op(_) => return instruction.script_num(),
+        Instruction::PushBytes(bytes) if bytes.len() <= 5 => bytes.as_bytes(),
+        Instruction::PushBytes(_) => return None,
+    };
+
+    let Some(last) = bytes.last() else {
+        return Some(0);
+    };
+
+    // Little-endian magnitude, with the sign in the top bit of the last byte.
+    let magnitude = bytes
+        .iter()
+        .enumerate()
+        .fold(0i64, |n, (i, byte)| n | (i64::from(*byte) << (8 * i)))
+        & !(0x80 << (8 * (bytes.len() - 1)));
+
+    match last & 0x80 {
+        0 => Some(magnitude),
+        _ => Some(-magnitude),
+    }
+}
+
+fn parse_key(bytes: &[u8]) -> Result<XOnlyPublicKey, String> {
+    XOnlyPublicKey::from_slice(bytes).map_err(|e| format!("invalid public key: {e}"))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InvalidCsvSigScriptError;
 
@@ -87,6 +296,7 @@ mod tests {
     use super::*;
     use bitcoin::locktime;
     use bitcoin::XOnlyPublicKey;
+    use proptest::prelude::*;
     use std::str::FromStr;
 
     #[test]
@@ -110,4 +320,238 @@ mod tests {
             locktime::relative::LockTime::from_512_second_intervals(2).into()
         );
     }
+
+    fn server() -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str("18845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166")
+            .unwrap()
+    }
+
+    fn owner() -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str("33ffb3dee353b1a9ebe4ced64b946238d0a4ac364f275d771da6ad2445d07ae0")
+            .unwrap()
+    }
+
+    #[test]
+    fn ark_leaves_are_classified() {
+        let sequence = bitcoin::Sequence::from_512_second_intervals(2);
+
+        assert_eq!(
+            classify_tapscript(&multisig_script(server(), owner()), server()),
+            TapscriptKind::Collaborative
+        );
+        assert_eq!(
+            classify_tapscript(&csv_sig_script(sequence, owner()), server()),
+            TapscriptKind::CsvExit { sequence }
+        );
+
+        let cltv = ScriptBuf::builder()
+            .push_int(840_000)
+            .push_opcode(OP_CLTV)
+            .push_opcode(OP_DROP)
+            .push_x_only_key(&owner())
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        assert_eq!(
+            classify_tapscript(&cltv, server()),
+            TapscriptKind::Cltv {
+                locktime: absolute::LockTime::from_consensus(840_000)
+            }
+        );
+    }
+
+    #[test]
+    fn htlc_leaves_are_collaborative() {
+        let payment_hash = sha256::Hash::hash(&[7; 32]);
+        let refund_locktime = absolute::LockTime::from_height(800_000).unwrap();
+
+        assert_eq!(
+            classify_tapscript(
+                &htlc_claim_script(payment_hash, owner(), server()),
+                server()
+            ),
+            TapscriptKind::Collaborative
+        );
+        assert_eq!(
+            classify_tapscript(
+                &htlc_refund_script(refund_locktime, owner(), server()),
+                server()
+            ),
+            TapscriptKind::Collaborative
+        );
+    }
+
+    #[test]
+    fn optional_signatures_are_not_understood() {
+        // The server's signature can be left out: the result of `OP_CHECKSIG` is dropped.
+        let script = ScriptBuf::builder()
+            .push_x_only_key(&server())
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_DROP)
+            .push_x_only_key(&owner())
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+
+        assert!(matches!(
+            classify_tapscript(&script, server()),
+            TapscriptKind::Invalid { .. }
+        ));
+    }
+
+    #[derive(Debug, Clone)]
+    enum Condition {
+        Signature(XOnlyPublicKey),
+        Csv(u32),
+        Cltv(u32),
+        Hash160([u8; 20]),
+        Sha256([u8; 32]),
+    }
+
+    fn key() -> impl Strategy<Value = XOnlyPublicKey> {
+        prop_oneof![
+            Just(server()),
+            Just(owner()),
+            any::<[u8; 32]>().prop_filter_map("not a valid key", |bytes| {
+                XOnlyPublicKey::from_slice(&bytes).ok()
+            }),
+        ]
+    }
+
+    fn condition() -> impl Strategy<Value = Condition> {
+        prop_oneof![
+            key().prop_map(Condition::Signature),
+            prop_oneof![
+                any::<u16>().prop_map(|n| bitcoin::Sequence::from_height(n).to_consensus_u32()),
+                any::<u16>().prop_map(|n| {
+                    bitcoin::Sequence::from_512_second_intervals(n).to_consensus_u32()
+                }),
+                any::<u32>(),
+            ]
+            .prop_map(Condition::Csv),
+            any::<u32>().prop_map(Condition::Cltv),
+            any::<[u8; 20]>().prop_map(Condition::Hash160),
+            any::<[u8; 32]>().prop_map(Condition::Sha256),
+        ]
+    }
+
+    /// A leaf of `conditions`, ending with a signature by `signer`.
+    fn leaf_script(conditions: &[Condition], signer: XOnlyPublicKey) -> ScriptBuf {
+        let mut builder = ScriptBuf::builder();
+        for condition in conditions {
+            builder = match condition {
+                Condition::Signature(pk) => {
+                    builder.push_x_only_key(pk).push_opcode(OP_CHECKSIGVERIFY)
+                }
+                Condition::Csv(n) => builder
+                    .push_int(*n as i64)
+                    .push_opcode(OP_CSV)
+                    .push_opcode(OP_DROP),
+                Condition::Cltv(n) => builder
+                    .push_int(*n as i64)
+                    .push_opcode(OP_CLTV)
+                    .push_opcode(OP_DROP),
+                Condition::Hash160(hash) => builder
+                    .push_opcode(OP_HASH160)
+                    .push_slice(hash)
+                    .push_opcode(OP_EQUALVERIFY),
+                Condition::Sha256(hash) => builder
+                    .push_opcode(OP_SHA256)
+                    .push_slice(hash)
+                    .push_opcode(OP_EQUALVERIFY),
+            };
+        }
+
+        builder
+            .push_x_only_key(&signer)
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    }
+
+    fn is_invalid(kind: &TapscriptKind) -> bool {
+        matches!(kind, TapscriptKind::Invalid { .. })
+    }
+
+    proptest! {
+        #[test]
+        fn generated_leaves_are_classified(
+            conditions in prop::collection::vec(condition(), 0..5),
+            signer in key(),
+        ) {
+            let kind = classify_tapscript(&leaf_script(&conditions, signer), server());
+
+            let signers = conditions
+                .iter()
+                .filter_map(|condition| match condition {
+                    Condition::Signature(pk) => Some(*pk),
+                    _ => None,
+                })
+                .chain([signer])
+                .collect::<Vec<_>>();
+            let csvs = conditions
+                .iter()
+                .filter_map(|condition| match condition {
+                    Condition::Csv(n) => Some(*n),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let cltvs = conditions
+                .iter()
+                .filter(|condition| matches!(condition, Condition::Cltv(_)))
+                .count();
+
+            if csvs.len() > 1 || cltvs > 1 {
+                prop_assert!(is_invalid(&kind));
+            } else if signers.contains(&server()) {
+                if signers.iter().all(|signer| *signer == server()) {
+                    prop_assert!(is_invalid(&kind));
+                } else {
+                    prop_assert_eq!(kind, TapscriptKind::Collaborative);
+                }
+            } else {
+                match kind {
+                    TapscriptKind::CsvExit { sequence } => {
+                        prop_assert_eq!(Some(sequence.to_consensus_u32()), csvs.first().copied());
+                        prop_assert!(sequence.is_relative_lock_time());
+                    }
+                    TapscriptKind::Cltv { locktime } => {
+                        prop_assert!(csvs.is_empty());
+                        prop_assert!(locktime.to_consensus_u32() > 0);
+                    }
+                    TapscriptKind::Invalid { .. } => {}
+                    TapscriptKind::Collaborative => prop_assert!(false, "no server signature"),
+                }
+
+                // Only timelocks make a path without the server valid.
+                if csvs.is_empty() && cltvs == 0 {
+                    prop_assert!(is_invalid(&kind));
+                }
+            }
+        }
+
+        #[test]
+        fn nothing_may_follow_the_last_signature(
+            conditions in prop::collection::vec(condition(), 0..5),
+            signer in key(),
+            trailer in prop::collection::vec(any::<u8>(), 1..8),
+        ) {
+            let mut script = leaf_script(&conditions, signer).into_bytes();
+            script.extend(trailer);
+
+            prop_assert!(is_invalid(&classify_tapscript(
+                &ScriptBuf::from_bytes(script),
+                server()
+            )));
+        }
+
+        #[test]
+        fn arbitrary_scripts_are_classified_without_panicking(
+            script in prop::collection::vec(any::<u8>(), 0..200),
+        ) {
+            let script = ScriptBuf::from_bytes(script);
+
+            if classify_tapscript(&script, server()) == TapscriptKind::Collaborative {
+                let server = server().serialize();
+                prop_assert!(script.as_bytes().windows(32).any(|window| window == server));
+            }
+        }
+    }
 }
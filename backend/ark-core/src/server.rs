@@ -154,7 +154,7 @@ pub struct VtxoOutPoint {
     pub created_at: i64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Info {
     pub pk: PublicKey,
     pub vtxo_tree_expiry: bitcoin::Sequence,
@@ -236,3 +236,9 @@ pub struct RoundTransaction {
     pub spendable_vtxos: Vec<VtxoOutPoint>,
     pub claimed_boarding_utxos: Vec<OutPoint>,
 }
+
+/// VTXOs of a subscribed address that were created or spent.
+pub struct AddressEvent {
+    pub new_vtxos: Vec<VtxoOutPoint>,
+    pub spent_vtxos: Vec<VtxoOutPoint>,
+}
@@ -15,6 +15,7 @@ use bitcoin::sighash::SighashCache;
 use bitcoin::transaction;
 use bitcoin::Address;
 use bitcoin::Amount;
+use bitcoin::FeeRate;
 use bitcoin::OutPoint;
 use bitcoin::Psbt;
 use bitcoin::TapLeafHash;
@@ -106,8 +107,6 @@ pub fn create_unilateral_exit_transaction(
         ));
     }
 
-    let secp = Secp256k1::new();
-
     let mut output = vec![TxOut {
         value: to_amount,
         script_pubkey: to_address.script_pubkey(),
@@ -132,6 +131,19 @@ pub fn create_unilateral_exit_transaction(
         });
     }
 
+    sign_unilateral_exit_transaction(kp, output, onchain_inputs, vtxo_inputs)
+}
+
+/// Build a transaction paying `output` out of `onchain_inputs` and `vtxo_inputs`, and sign every
+/// input through its exit path.
+fn sign_unilateral_exit_transaction(
+    kp: &Keypair,
+    output: Vec<TxOut>,
+    onchain_inputs: &[OnChainInput],
+    vtxo_inputs: &[VtxoInput],
+) -> Result<Transaction, Error> {
+    let secp = Secp256k1::new();
+
     let input = {
         let onchain_inputs = onchain_inputs.iter().map(|o| TxIn {
             previous_output: o.outpoint,
@@ -244,6 +256,49 @@ pub fn create_unilateral_exit_transaction(
     Ok(tx)
 }
 
+/// Build a transaction that sends all of `vtxo_inputs` to `to_address` through their exit paths,
+/// paying a fee of `fee_rate` out of the total.
+///
+/// The transaction is only valid once the VTXOs are on chain and their exit delay has passed.
+/// Fails if the amount left after the fee would be below `dust`.
+pub fn create_unilateral_exit_sweep(
+    kp: &Keypair,
+    to_address: Address,
+    fee_rate: FeeRate,
+    dust: Amount,
+    vtxo_inputs: &[VtxoInput],
+) -> Result<Transaction, Error> {
+    if vtxo_inputs.is_empty() {
+        return Err(Error::transaction("cannot create sweep without inputs"));
+    }
+
+    let total_amount: Amount = vtxo_inputs.iter().map(|v| v.amount).sum();
+    let output = |value| {
+        vec![TxOut {
+            value,
+            script_pubkey: to_address.script_pubkey(),
+        }]
+    };
+
+    // Signatures are always 64 bytes, so a transaction without fee is as large as the final one.
+    let unfunded = sign_unilateral_exit_transaction(kp, output(total_amount), &[], vtxo_inputs)?;
+
+    let fee = fee_rate
+        .fee_vb(unfunded.vsize() as u64)
+        .ok_or_else(|| Error::transaction(format!("fee rate {fee_rate} is too high")))?;
+
+    let to_amount = total_amount
+        .checked_sub(fee)
+        .filter(|to_amount| *to_amount >= dust)
+        .ok_or_else(|| {
+            Error::transaction(format!(
+                "cannot sweep {total_amount}: less than fee ({fee}) plus dust ({dust})"
+            ))
+        })?;
+
+    sign_unilateral_exit_transaction(kp, output(to_amount), &[], vtxo_inputs)
+}
+
 pub struct VtxoProvenance {
     /// Where the VTXO would end up on the blockchain if it were to become a UTXO.
     outpoint: OutPoint,
@@ -436,3 +491,80 @@ pub fn prepare_vtxo_tree_transactions(
 struct RedeemBranch {
     branch: Vec<Psbt>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+    use bitcoin::Sequence;
+
+    fn vtxo_inputs(kp: &Keypair, amounts: &[u64]) -> Vec<VtxoInput> {
+        let secp = Secp256k1::new();
+        let server = Keypair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+
+        let vtxo = Vtxo::new(
+            &secp,
+            server.x_only_public_key().0,
+            kp.x_only_public_key().0,
+            vec![],
+            Sequence::from_512_second_intervals(2),
+            Network::Regtest,
+        )
+        .unwrap();
+
+        amounts
+            .iter()
+            .enumerate()
+            .map(|(i, amount)| {
+                let outpoint = OutPoint {
+                    txid: Txid::from_byte_array([i as u8; 32]),
+                    vout: 0,
+                };
+                VtxoInput::new(vtxo.clone(), Amount::from_sat(*amount), outpoint)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sweep_pays_the_fee_rate_on_its_own_size() {
+        let secp = Secp256k1::new();
+        let kp = Keypair::from_seckey_slice(&secp, &[2; 32]).unwrap();
+        let inputs = vtxo_inputs(&kp, &[10_000, 20_000]);
+        let to_address = inputs[0].vtxo.address().clone();
+        let fee_rate = FeeRate::from_sat_per_vb(3).unwrap();
+
+        let tx = create_unilateral_exit_sweep(
+            &kp,
+            to_address.clone(),
+            fee_rate,
+            Amount::from_sat(330),
+            &inputs,
+        )
+        .unwrap();
+
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(tx.output[0].script_pubkey, to_address.script_pubkey());
+
+        let fee = Amount::from_sat(30_000) - tx.output[0].value;
+        assert_eq!(Some(fee), fee_rate.fee_vb(tx.vsize() as u64));
+    }
+
+    #[test]
+    fn sweep_must_leave_more_than_dust() {
+        let secp = Secp256k1::new();
+        let kp = Keypair::from_seckey_slice(&secp, &[2; 32]).unwrap();
+        let inputs = vtxo_inputs(&kp, &[600]);
+        let to_address = inputs[0].vtxo.address().clone();
+
+        let result = create_unilateral_exit_sweep(
+            &kp,
+            to_address,
+            FeeRate::from_sat_per_vb(5).unwrap(),
+            Amount::from_sat(330),
+            &inputs,
+        );
+
+        assert!(result.is_err());
+    }
+}
@@ -1,11 +1,18 @@
 use crate::ark_address::ArkAddress;
+use crate::descriptor::DescriptorTemplate;
+use crate::script::classify_tapscript;
 use crate::script::csv_sig_script;
+use crate::script::htlc_claim_script;
+use crate::script::htlc_refund_script;
 use crate::script::multisig_script;
 use crate::script::tr_script_pubkey;
+use crate::script::TapscriptKind;
 use crate::server::VtxoOutPoint;
 use crate::Error;
 use crate::ExplorerUtxo;
 use crate::UNSPENDABLE_KEY;
+use bitcoin::absolute;
+use bitcoin::hashes::sha256;
 use bitcoin::key::PublicKey;
 use bitcoin::key::Secp256k1;
 use bitcoin::key::Verification;
@@ -22,11 +29,22 @@ use bitcoin::XOnlyPublicKey;
 use std::collections::HashMap;
 use std::time::Duration;
 
+/// A hash-time-locked contract paying `receiver` in exchange for the preimage of `payment_hash`,
+/// or paying `sender` back after `refund_locktime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Htlc {
+    pub sender: XOnlyPublicKey,
+    pub receiver: XOnlyPublicKey,
+    pub payment_hash: sha256::Hash,
+    pub refund_locktime: absolute::LockTime,
+}
+
 /// All the information needed to _spend_ a VTXO.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Vtxo {
     server: XOnlyPublicKey,
     owner: XOnlyPublicKey,
+    htlc: Option<Htlc>,
     spend_info: TaprootSpendInfo,
     extra_scripts: Vec<ScriptBuf>,
     address: Address,
@@ -39,15 +57,79 @@ impl Vtxo {
     /// 64 bytes per pubkey.
     pub const FORFEIT_WITNESS_SIZE: usize = 64 * 2;
 
+    /// 64 bytes per pubkey, plus the 32-byte preimage.
+    pub const HTLC_CLAIM_WITNESS_SIZE: usize = 64 * 2 + 32;
+
     /// Build a VTXO.
     ///
     /// The `extra_scripts` argument allows for additional spend paths. All unilateral spend paths
-    /// must be timelocked. Any other spend path must involve the Ark server.
+    /// must be locked for at least `exit_delay` with a relative timelock. Any other spend path
+    /// must involve the Ark server. See [`classify_tapscript`] for the scripts that are understood;
+    /// any other script is rejected.
     pub fn new<C>(
         secp: &Secp256k1<C>,
         server: XOnlyPublicKey,
         owner: XOnlyPublicKey,
-        // TODO: Verify the validity of these scripts before constructing the `Vtxo`.
+        extra_scripts: Vec<ScriptBuf>,
+        exit_delay: bitcoin::Sequence,
+        network: Network,
+    ) -> Result<Self, Error>
+    where
+        C: Verification,
+    {
+        for (i, script) in extra_scripts.iter().enumerate() {
+            check_extra_script(script, server, exit_delay)
+                .map_err(|e| Error::ad_hoc(format!("invalid extra script {i} ({script}): {e}")))?;
+        }
+
+        Self::build(
+            secp,
+            server,
+            owner,
+            None,
+            extra_scripts,
+            exit_delay,
+            network,
+        )
+    }
+
+    /// Build a VTXO locked in an [`Htlc`], with three spend paths:
+    ///
+    /// - The claim path, taken by the receiver and the Ark server with the preimage.
+    /// - The refund path, taken by the sender and the Ark server after the refund locktime.
+    /// - The unilateral refund path, taken by the sender alone after `exit_delay`.
+    ///
+    /// The sender is the owner of the VTXO: the refund path is its forfeit path, and the
+    /// unilateral refund path is its exit path. The claim path is spent with
+    /// [`crate::redeem::VtxoInput::htlc_claim`].
+    pub fn new_htlc<C>(
+        secp: &Secp256k1<C>,
+        server: XOnlyPublicKey,
+        htlc: Htlc,
+        exit_delay: bitcoin::Sequence,
+        network: Network,
+    ) -> Result<Self, Error>
+    where
+        C: Verification,
+    {
+        let claim_script = htlc_claim_script(htlc.payment_hash, htlc.receiver, server);
+
+        Self::build(
+            secp,
+            server,
+            htlc.sender,
+            Some(htlc),
+            vec![claim_script],
+            exit_delay,
+            network,
+        )
+    }
+
+    fn build<C>(
+        secp: &Secp256k1<C>,
+        server: XOnlyPublicKey,
+        owner: XOnlyPublicKey,
+        htlc: Option<Htlc>,
         extra_scripts: Vec<ScriptBuf>,
         exit_delay: bitcoin::Sequence,
         network: Network,
@@ -58,7 +140,7 @@ impl Vtxo {
         let unspendable_key: PublicKey = UNSPENDABLE_KEY.parse().expect("valid key");
         let (unspendable_key, _) = unspendable_key.inner.x_only_public_key();
 
-        let forfeit_script = multisig_script(server, owner);
+        let forfeit_script = forfeit_script(server, owner, htlc.as_ref());
         let redeem_script = csv_sig_script(exit_delay, owner);
 
         let spend_info = if extra_scripts.is_empty() {
@@ -74,7 +156,7 @@ impl Vtxo {
 
             let leaf_distribution = calculate_leaf_depths(scripts.len());
 
-            if leaf_distribution.len() == scripts.len() {
+            if leaf_distribution.len() != scripts.len() {
                 return Err(Error::ad_hoc("wrong leaf distribution calculated"));
             }
 
@@ -101,6 +183,7 @@ impl Vtxo {
         Ok(Self {
             server,
             owner,
+            htlc,
             spend_info,
             extra_scripts,
             address,
@@ -110,6 +193,59 @@ impl Vtxo {
         })
     }
 
+    /// Build the VTXO of `owner` described by `template`, a VTXO descriptor template of the Ark
+    /// server.
+    ///
+    /// The template must have a forfeit leaf and an exit leaf locked in seconds, whose timelock
+    /// becomes the exit delay. Any other leaf must be safe, as with the `extra_scripts` of
+    /// [`Vtxo::new`].
+    pub fn from_template<C>(
+        secp: &Secp256k1<C>,
+        template: &DescriptorTemplate,
+        server: XOnlyPublicKey,
+        owner: XOnlyPublicKey,
+        network: Network,
+    ) -> Result<Self, Error>
+    where
+        C: Verification,
+    {
+        let leaves = template.leaves(server, owner)?;
+
+        let exit_delay_seconds = match leaves.exit_delay.to_relative_lock_time() {
+            Some(relative::LockTime::Time(time)) => time.value() as u64 * 512,
+            _ => {
+                return Err(Error::ad_hoc(format!(
+                    "exit leaf of VTXO descriptor template {template} is not locked in seconds"
+                )))
+            }
+        };
+
+        for script in leaves.others.iter() {
+            check_extra_script(script, server, leaves.exit_delay).map_err(|e| {
+                Error::ad_hoc(format!(
+                    "invalid leaf {script} in VTXO descriptor template {template}: {e}"
+                ))
+            })?;
+        }
+
+        let spend_info = template.spend_info(secp, owner);
+
+        let script_pubkey = tr_script_pubkey(&spend_info);
+        let address = Address::from_script(&script_pubkey, network).expect("valid script");
+
+        Ok(Self {
+            server,
+            owner,
+            htlc: None,
+            spend_info,
+            extra_scripts: leaves.others,
+            address,
+            exit_delay: leaves.exit_delay,
+            exit_delay_seconds,
+            network,
+        })
+    }
+
     /// Build a default VTXO.
     pub fn new_default<C>(
         secp: &Secp256k1<C>,
@@ -136,6 +272,10 @@ impl Vtxo {
         &self.address
     }
 
+    pub fn owner_pk(&self) -> XOnlyPublicKey {
+        self.owner
+    }
+
     pub fn exit_delay(&self) -> bitcoin::Sequence {
         self.exit_delay
     }
@@ -171,6 +311,24 @@ impl Vtxo {
         (forfeit_script, control_block)
     }
 
+    /// The HTLC locking this VTXO, if it was built with [`Vtxo::new_htlc`].
+    pub fn htlc(&self) -> Option<&Htlc> {
+        self.htlc.as_ref()
+    }
+
+    /// The spend info for the claim branch of an HTLC VTXO.
+    pub fn htlc_claim_spend_info(&self) -> Option<(ScriptBuf, taproot::ControlBlock)> {
+        let htlc = self.htlc.as_ref()?;
+        let claim_script = htlc_claim_script(htlc.payment_hash, htlc.receiver, self.server);
+
+        let control_block = self
+            .spend_info
+            .control_block(&(claim_script.clone(), LeafVersion::TapScript))
+            .expect("claim script");
+
+        Some((claim_script, control_block))
+    }
+
     /// The spend info for the unilateral exit branch of a VTXO.
     pub fn exit_spend_info(&self) -> (ScriptBuf, taproot::ControlBlock) {
         let exit_script = self.exit_script();
@@ -206,7 +364,7 @@ impl Vtxo {
     }
 
     fn forfeit_script(&self) -> ScriptBuf {
-        multisig_script(self.server, self.owner)
+        forfeit_script(self.server, self.owner, self.htlc.as_ref())
     }
 
     fn exit_script(&self) -> ScriptBuf {
@@ -214,6 +372,50 @@ impl Vtxo {
     }
 }
 
+/// The collaborative spend path of the owner: the refund path of an HTLC, or else a plain 2-of-2
+/// with the Ark server.
+fn forfeit_script(server: XOnlyPublicKey, owner: XOnlyPublicKey, htlc: Option<&Htlc>) -> ScriptBuf {
+    match htlc {
+        Some(htlc) => htlc_refund_script(htlc.refund_locktime, owner, server),
+        None => multisig_script(server, owner),
+    }
+}
+
+/// Whether `script` is a safe spend path for a VTXO of `server` with `exit_delay`.
+fn check_extra_script(
+    script: &ScriptBuf,
+    server: XOnlyPublicKey,
+    exit_delay: bitcoin::Sequence,
+) -> Result<(), String> {
+    match classify_tapscript(script, server) {
+        TapscriptKind::Collaborative => Ok(()),
+        TapscriptKind::CsvExit { sequence } => {
+            let exit_delay = exit_delay
+                .to_relative_lock_time()
+                .ok_or("the exit delay is not a relative timelock")?;
+
+            let locktime = sequence
+                .to_relative_lock_time()
+                .ok_or("unilateral path is not locked")?;
+
+            // The Ark server needs the whole exit delay to react to a published VTXO.
+            if !exit_delay.is_implied_by(locktime) {
+                return Err(format!(
+                    "unilateral path is locked for {locktime:#}, less than the exit delay of \
+                     {exit_delay:#}"
+                ));
+            }
+
+            Ok(())
+        }
+        // An absolute timelock may already have passed by the time the VTXO is published.
+        TapscriptKind::Cltv { .. } => {
+            Err("unilateral path must use a relative timelock, not an absolute one".to_string())
+        }
+        TapscriptKind::Invalid { reason } => Err(reason),
+    }
+}
+
 fn calculate_leaf_depths(n: usize) -> Vec<usize> {
     // Handle edge cases
     if n == 0 {
@@ -226,9 +428,10 @@ fn calculate_leaf_depths(n: usize) -> Vec<usize> {
     // Calculate the minimum depth required for n leaves
     let min_depth = (n as f64).log2().ceil() as usize;
 
-    // Calculate the number of nodes at the deepest level
-    let nodes_at_max_depth = n - (1 << (min_depth - 1)) + 1;
-    let nodes_at_min_depth = (1 << min_depth) - nodes_at_max_depth;
+    // Calculate the number of nodes at the deepest level. Each leaf moved one level down makes
+    // room for one more leaf.
+    let nodes_at_min_depth = (1 << min_depth) - n;
+    let nodes_at_max_depth = n - nodes_at_min_depth;
 
     // Create the result vector with the appropriate depths
     let mut result = Vec::with_capacity(n);
@@ -312,3 +515,152 @@ where
 
     Ok(VirtualTxOutpoints { spendable, expired })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::opcodes::all::*;
+    use proptest::prelude::*;
+    use std::str::FromStr;
+
+    fn server() -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str("18845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166")
+            .unwrap()
+    }
+
+    fn owner() -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str("33ffb3dee353b1a9ebe4ced64b946238d0a4ac364f275d771da6ad2445d07ae0")
+            .unwrap()
+    }
+
+    fn cltv_sig_script(locktime: i64, pk: XOnlyPublicKey) -> ScriptBuf {
+        ScriptBuf::builder()
+            .push_int(locktime)
+            .push_opcode(OP_CLTV)
+            .push_opcode(OP_DROP)
+            .push_x_only_key(&pk)
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    }
+
+    fn new_vtxo(
+        extra_scripts: Vec<ScriptBuf>,
+        exit_delay: bitcoin::Sequence,
+    ) -> Result<Vtxo, Error> {
+        Vtxo::new(
+            &Secp256k1::verification_only(),
+            server(),
+            owner(),
+            extra_scripts,
+            exit_delay,
+            Network::Regtest,
+        )
+    }
+
+    #[test]
+    fn extra_scripts_must_follow_the_spend_policy() {
+        let exit_delay = bitcoin::Sequence::from_512_second_intervals(10);
+
+        // A later exit and an extra collaborative path.
+        let vtxo = new_vtxo(
+            vec![
+                csv_sig_script(bitcoin::Sequence::from_512_second_intervals(20), owner()),
+                ScriptBuf::builder()
+                    .push_int(840_000)
+                    .push_opcode(OP_CLTV)
+                    .push_opcode(OP_DROP)
+                    .push_x_only_key(&owner())
+                    .push_opcode(OP_CHECKSIGVERIFY)
+                    .push_x_only_key(&server())
+                    .push_opcode(OP_CHECKSIG)
+                    .into_script(),
+            ],
+            exit_delay,
+        )
+        .unwrap();
+        assert_eq!(vtxo.tapscripts().len(), 4);
+
+        let rejected = [
+            // Exits too early.
+            csv_sig_script(bitcoin::Sequence::from_512_second_intervals(9), owner()),
+            // Exits in a different unit.
+            csv_sig_script(bitcoin::Sequence::from_height(1_000), owner()),
+            // Exits at a fixed time.
+            cltv_sig_script(840_000, owner()),
+            // Never locked.
+            multisig_script(owner(), owner()),
+            // Spendable by the server alone.
+            multisig_script(server(), server()),
+        ];
+        for script in rejected {
+            let err = new_vtxo(vec![script.clone()], exit_delay).unwrap_err();
+            assert!(err.to_string().contains("invalid extra script 0"), "{err}");
+        }
+    }
+
+    #[test]
+    fn leaf_depths_fill_a_tree() {
+        for n in 1..100 {
+            let depths = calculate_leaf_depths(n);
+            assert_eq!(depths.len(), n);
+
+            // A full binary tree, by Kraft's equality.
+            let max_depth = *depths.iter().max().unwrap();
+            let sum = depths
+                .iter()
+                .map(|depth| 1 << (max_depth - depth))
+                .sum::<usize>();
+            assert_eq!(sum, 1 << max_depth, "{n} leaves");
+        }
+    }
+
+    /// An extra script, and whether a VTXO with an exit delay of `EXIT_DELAY` may use it.
+    fn extra_script() -> impl Strategy<Value = (ScriptBuf, bool)> {
+        const EXIT_DELAY: u16 = 1_000;
+
+        let key = any::<[u8; 32]>().prop_filter_map("not a valid key", |bytes| {
+            XOnlyPublicKey::from_slice(&bytes).ok()
+        });
+
+        prop_oneof![
+            key.clone()
+                .prop_map(|pk| (multisig_script(pk, server()), pk != server())),
+            (0..EXIT_DELAY * 2).prop_map(|n| {
+                let sequence = bitcoin::Sequence::from_512_second_intervals(n);
+                (csv_sig_script(sequence, owner()), n >= EXIT_DELAY)
+            }),
+            any::<u16>().prop_map(|n| {
+                let sequence = bitcoin::Sequence::from_height(n);
+                (csv_sig_script(sequence, owner()), false)
+            }),
+            any::<u32>().prop_map(|n| (cltv_sig_script(n as i64, owner()), false)),
+            key.prop_map(|pk| (multisig_script(pk, owner()), false)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn vtxos_are_built_only_from_valid_extra_scripts(
+            scripts in prop::collection::vec(extra_script(), 0..8),
+        ) {
+            let valid = scripts.iter().all(|(_, valid)| *valid);
+            let scripts = scripts.into_iter().map(|(script, _)| script).collect::<Vec<_>>();
+
+            let exit_delay = bitcoin::Sequence::from_512_second_intervals(1_000);
+            match new_vtxo(scripts.clone(), exit_delay) {
+                Ok(vtxo) => {
+                    prop_assert!(valid);
+
+                    // Every extra script is a leaf of the tree.
+                    for script in scripts {
+                        prop_assert!(vtxo
+                            .spend_info()
+                            .control_block(&(script, LeafVersion::TapScript))
+                            .is_some());
+                    }
+                }
+                Err(e) => prop_assert!(!valid, "{}", e),
+            }
+        }
+    }
+}
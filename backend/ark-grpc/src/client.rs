@@ -18,9 +18,11 @@ use crate::generated::ark::v1::SubmitRedeemTxRequest;
 use crate::generated::ark::v1::SubmitSignedForfeitTxsRequest;
 use crate::generated::ark::v1::SubmitTreeNoncesRequest;
 use crate::generated::ark::v1::SubmitTreeSignaturesRequest;
+use crate::generated::ark::v1::SubscribeForAddressRequest;
 use crate::generated::ark::v1::Tapscripts;
 use crate::tree;
 use crate::Error;
+use ark_core::server::AddressEvent;
 use ark_core::server::Info;
 use ark_core::server::ListVtxo;
 use ark_core::server::RedeemTransaction;
@@ -394,6 +396,41 @@ impl Client {
         Ok(stream.boxed())
     }
 
+    /// Stream the VTXOs of `address` that are created or spent from now on.
+    pub async fn subscribe_for_address(
+        &self,
+        address: &ArkAddress,
+    ) -> Result<impl Stream<Item = Result<AddressEvent, Error>> + Unpin, Error> {
+        let mut client = self.inner_explorer_client()?;
+
+        let response = client
+            .subscribe_for_address(SubscribeForAddressRequest {
+                address: address.encode(),
+            })
+            .await
+            .map_err(Error::request)?;
+
+        let mut stream = response.into_inner();
+
+        let stream = stream! {
+            loop {
+                match stream.try_next().await {
+                    Ok(Some(event)) => {
+                        yield AddressEvent::try_from(event);
+                    }
+                    Ok(None) => {
+                        yield Err(Error::event_stream_disconnect());
+                    }
+                    Err(e) => {
+                        yield Err(Error::event_stream(e));
+                    }
+                }
+            }
+        };
+
+        Ok(stream.boxed())
+    }
+
     pub async fn get_round(&self, round_txid: String) -> Result<Option<Round>, Error> {
         let mut client = self.inner_explorer_client()?;
 
@@ -673,6 +710,31 @@ impl TryFrom<generated::ark::v1::get_transactions_stream_response::Tx> for Trans
     }
 }
 
+impl TryFrom<generated::ark::v1::SubscribeForAddressResponse> for AddressEvent {
+    type Error = Error;
+
+    fn try_from(
+        value: generated::ark::v1::SubscribeForAddressResponse,
+    ) -> Result<Self, Self::Error> {
+        let new_vtxos = value
+            .new_vtxos
+            .iter()
+            .map(VtxoOutPoint::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let spent_vtxos = value
+            .spent_vtxos
+            .iter()
+            .map(VtxoOutPoint::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(AddressEvent {
+            new_vtxos,
+            spent_vtxos,
+        })
+    }
+}
+
 impl TryFrom<generated::ark::v1::RoundTransaction> for RoundTransaction {
     type Error = Error;
 
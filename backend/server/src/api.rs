@@ -1,834 +1,1883 @@
 pub mod accounts {
-    use actix_web::{get, post, web, HttpResponse, Responder};
-    use bitcoin::key::Keypair;
-    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
-    use rand::thread_rng;
+    use actix_web::{delete, get, post, web, HttpResponse};
+    use bip39::Mnemonic;
+    use bitcoin::bip32::Xpub;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
     use std::str::FromStr;
+    use std::time::Duration;
     use uuid::Uuid;
 
+    use crate::auth;
     use crate::core::model::*;
-    use ark_core::{BoardingOutput, Vtxo};
-
+    use crate::error::{ApiError, ErrorCode};
+    use crate::history;
+    use crate::keystore::{self, EncryptedKey, UnlockedKeys, MIN_PASSPHRASE_LEN};
+    use crate::webhooks;
+    use ark_core::{BoardingOutput, PaymentUri, Vtxo};
+
+    /// Restoring an account stops looking for used address keys after this many unused ones in a
+    /// row.
+    const GAP_LIMIT: u32 = 20;
+
+    #[utoipa::path(
+        tag = "accounts",
+        request_body = AccountCreationRequest,
+        responses(
+            (status = 201, description = "Account created", body = AccountCreationResponse),
+            (status = 400, description = "Passphrase too short", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+    )]
     #[post("/api/accounts")]
-    pub async fn create_account(state: web::Data<ApplicationState>) -> impl Responder {
-        // Generate cryptographic keys
-        let mut rng = thread_rng();
-        let secp = Secp256k1::new();
-        let keypair = Keypair::new(&secp, &mut rng);
-        let secret_key = keypair.secret_key();
+    pub async fn create_account(
+        state: web::Data<ApplicationState>,
+        req: web::Json<AccountCreationRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        if req.passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+            return Err(ApiError::invalid_request(format!(
+                "Passphrase must be at least {} characters",
+                MIN_PASSPHRASE_LEN
+            )));
+        }
+
+        // Get network info
+        let network_info = match state.server_connection.lock().unwrap().clone() {
+            Some(info) => info,
+            None => return Err(ApiError::network_unavailable()),
+        };
 
-        // Create unique identifier
-        let account_id = Uuid::new_v4().to_string();
+        // Generate the backup phrase
+        let mnemonic = keystore::generate_mnemonic();
 
         // Create account record
-        let account = UserAccount {
-            id: account_id.clone(),
-            private_key: secret_key.display_secret().to_string(),
+        let account = match new_hd_account(mnemonic.clone(), req.passphrase.clone(), network_info.network).await {
+            Ok(account) => account,
+            Err(e) => {
+                return Err(ApiError::internal("Failed to create account keys", e));
+            }
         };
 
         // Store account
-        let mut accounts = state.accounts.lock().unwrap();
-        accounts.insert(account_id.clone(), account);
+        if let Err(e) = state.accounts.insert_account(&account) {
+            return Err(ApiError::internal("Failed to store account", e));
+        }
+
+        // Issue the account's first API token
+        let api_token = match auth::issue_token(state.accounts.as_ref(), &account.id, TokenScope::Full) {
+            Ok(token) => token,
+            Err(e) => {
+                return Err(ApiError::internal("Failed to issue API token", e));
+            }
+        };
 
-        // Return response
-        HttpResponse::Created().json(AccountCreationResponse { account_id })
+        // Return response. This is the only time the mnemonic leaves the server
+        Ok(HttpResponse::Created().json(AccountCreationResponse {
+            account_id: account.id,
+            mnemonic: mnemonic.to_string(),
+            api_token,
+        }))
     }
 
-    #[get("/api/accounts/{account_id}/addresses")]
-    pub async fn get_account_addresses(
-        account_id: web::Path<String>,
+    #[utoipa::path(
+        tag = "accounts",
+        request_body = AccountRestoreRequest,
+        responses(
+            (status = 201, description = "Account restored", body = AccountRestoreResponse),
+            (status = 400, description = "Invalid mnemonic or passphrase too short", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+    )]
+    #[post("/api/accounts/restore")]
+    pub async fn restore_account(
         state: web::Data<ApplicationState>,
-    ) -> impl Responder {
-        // Retrieve account
-        let accounts = state.accounts.lock().unwrap();
-        let account = match accounts.get(&account_id.into_inner()) {
-            Some(account) => account.clone(),
-            None => return HttpResponse::NotFound().body("Account not found"),
+        req: web::Json<AccountRestoreRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        if req.passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+            return Err(ApiError::invalid_request(format!(
+                "Passphrase must be at least {} characters",
+                MIN_PASSPHRASE_LEN
+            )));
+        }
+
+        let mnemonic = match Mnemonic::parse_normalized(req.mnemonic.trim()) {
+            Ok(mnemonic) => mnemonic,
+            Err(_) => return Err(ApiError::new(ErrorCode::InvalidMnemonic, "Invalid mnemonic")),
         };
 
         // Get network info
-        let network_info = match state.server_connection.as_ref() {
-            Some(info) => info.lock().unwrap().clone(),
-            None => return HttpResponse::ServiceUnavailable().body("Network unavailable"),
-        };
-
-        // Parse private key
-        let private_key = match SecretKey::from_str(&account.private_key) {
-            Ok(key) => key,
-            Err(_) => return HttpResponse::InternalServerError().body("Invalid private key"),
-        };
-
-        // Initialize cryptography
-        let secp = Secp256k1::new();
-        let public_key = PublicKey::from_secret_key(&secp, &private_key);
-
-        // Generate on-chain address
-        let boarding_output = match BoardingOutput::new(
-            &secp,
-            network_info.pk.x_only_public_key().0,
-            public_key.x_only_public_key().0,
-            network_info.unilateral_exit_delay,
-            network_info.network,
-        ) {
-            Ok(bo) => bo,
-            Err(_) => {
-                return HttpResponse::InternalServerError().body("Address generation failed");
+        let network_info = match state.server_connection.lock().unwrap().clone() {
+            Some(info) => info,
+            None => return Err(ApiError::network_unavailable()),
+        };
+
+        // Get blockchain client
+        let blockchain_client = match state.blockchain_client.lock().unwrap().clone() {
+            Some(client) => client,
+            None => return Err(ApiError::blockchain_unavailable()),
+        };
+
+        // Connect to network
+        let mut grpc_client = ark_grpc::Client::new(state.config.ark_server_url.clone());
+        if grpc_client.connect().await.is_err() {
+            return Err(ApiError::new(ErrorCode::NetworkUnavailable, "Network connection failed"));
+        }
+
+        let mut account = match new_hd_account(mnemonic, req.passphrase.clone(), network_info.network).await {
+            Ok(account) => account,
+            Err(e) => {
+                return Err(ApiError::internal("Failed to create account keys", e));
             }
         };
 
-        // Generate virtual address
-        let vtxo = match Vtxo::new(
-            &secp,
-            network_info.pk.x_only_public_key().0,
-            public_key.x_only_public_key().0,
-            vec![],
-            network_info.unilateral_exit_delay,
-            network_info.network,
-        ) {
-            Ok(vtxo) => vtxo,
-            Err(_) => return HttpResponse::InternalServerError().body("Virtual address generation failed"),
+        // Find the last address key that was ever used
+        let mut index = 0;
+        let mut unused = 0;
+        while unused < GAP_LIMIT {
+            let (boarding_output, vtxo) = match account.key_outputs(&network_info, index) {
+                Ok(outputs) => outputs,
+                Err(_) => {
+                    return Err(ApiError::new(ErrorCode::Internal, "Address generation failed"));
+                }
+            };
+
+            match key_has_history(&grpc_client, &blockchain_client, &boarding_output, &vtxo).await {
+                Ok(true) => {
+                    account.key_count = index + 1;
+                    unused = 0;
+                }
+                Ok(false) => unused += 1,
+                Err(e) => {
+                    return Err(ApiError::internal("Failed to scan account history", e));
+                }
+            }
+
+            index += 1;
+        }
+
+        // Store account
+        if let Err(e) = state.accounts.insert_account(&account) {
+            return Err(ApiError::internal("Failed to store account", e));
+        }
+
+        // Issue the account's first API token
+        let api_token = match auth::issue_token(state.accounts.as_ref(), &account.id, TokenScope::Full) {
+            Ok(token) => token,
+            Err(e) => {
+                return Err(ApiError::internal("Failed to issue API token", e));
+            }
         };
 
-        // Return both addresses
-        HttpResponse::Ok().json(AddressDetails {
+        Ok(HttpResponse::Created().json(AccountRestoreResponse {
             account_id: account.id,
-            chain_address: boarding_output.address().to_string(),
-            virtual_address: vtxo.to_ark_address().to_string(),
+            key_count: account.key_count,
+            api_token,
+        }))
+    }
+
+    /// A new account backed by `mnemonic`, with its seed encrypted under `passphrase`.
+    ///
+    /// The key derivation is deliberately slow, so it runs on the blocking thread pool.
+    async fn new_hd_account(
+        mnemonic: Mnemonic,
+        passphrase: String,
+        network: bitcoin::Network,
+    ) -> Result<UserAccount, anyhow::Error> {
+        web::block(move || {
+            let seed = mnemonic.to_seed("");
+            let account_xpriv = keystore::account_xpriv(&seed, network.into())?;
+            let xpub = Xpub::from_priv(&Secp256k1::new(), &account_xpriv);
+
+            Ok(UserAccount {
+                id: Uuid::new_v4().to_string(),
+                xpub: Some(xpub.to_string()),
+                key_count: 1,
+                public_key: None,
+                encrypted_key: Some(EncryptedKey::encrypt(&seed, &passphrase)?),
+                private_key: None,
+            })
         })
+        .await?
     }
-}
 
-pub mod finance {
-    use actix_web::{get, post, web, HttpResponse, Responder};
-    use bitcoin::{Amount, Txid, XOnlyPublicKey};
-    use bitcoin::key::{Keypair, Secp256k1};
-    use bitcoin::secp256k1::{Message, PublicKey, SecretKey, schnorr};
-    use std::collections::HashMap;
-    use std::process::Command;
-    use std::str::FromStr;
-    use futures::StreamExt;
-    use rand::thread_rng;
+    /// Whether the boarding or the off-chain address of an address key has ever been used.
+    async fn key_has_history(
+        grpc_client: &ark_grpc::Client,
+        blockchain_client: &BlockchainClient,
+        boarding_output: &BoardingOutput,
+        vtxo: &Vtxo,
+    ) -> Result<bool, anyhow::Error> {
+        let vtxos = grpc_client.list_vtxos(&vtxo.to_ark_address()).await?;
+        if !vtxos.spendable.is_empty() || !vtxos.spent.is_empty() {
+            return Ok(true);
+        }
 
-    use crate::core::model::*;
-    use crate::core::utils;
-    use ark_core::{ArkAddress, BoardingOutput, Vtxo};
-    use ark_core::vtxo::list_virtual_tx_outpoints;
-    use ark_core::boarding_output::list_boarding_outpoints;
-    use ark_core::coin_select::select_vtxos;
-    use ark_core::redeem::{self, build_redeem_transaction, sign_redeem_transaction};
-    use ark_core::round::{self, create_and_sign_forfeit_txs, generate_nonce_tree, sign_round_psbt, sign_vtxo_tree};
-    use ark_core::server::{RoundInput, RoundOutput, RoundStreamEvent};
-    use ark_core::ExplorerUtxo;
+        blockchain_client.has_history(boarding_output.address()).await
+    }
 
-    #[get("/api/accounts/{account_id}/balance")]
-    pub async fn get_account_balance(
+    #[utoipa::path(
+        tag = "accounts",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        request_body = UnlockRequest,
+        responses(
+            (status = 200, description = "Account unlocked", body = UnlockResponse),
+            (status = 401, description = "Missing or unknown API token, or wrong passphrase", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 409, description = "Unencrypted legacy key, to be migrated by the operator first", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/accounts/{account_id}/unlock")]
+    pub async fn unlock_account(
         account_id: web::Path<String>,
+        token: ApiToken,
         state: web::Data<ApplicationState>,
-    ) -> impl Responder {
-        // Retrieve account
-        let accounts = state.accounts.lock().unwrap();
-        let account = match accounts.get(&account_id.into_inner()) {
-            Some(account) => account.clone(),
-            None => return HttpResponse::NotFound().body("Account not found"),
-        };
+        req: web::Json<UnlockRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
 
-        // Get network info
-        let network_info = match state.server_connection.as_ref() {
-            Some(info) => info.lock().unwrap().clone(),
-            None => return HttpResponse::ServiceUnavailable().body("Network unavailable"),
+        // Retrieve account
+        let account = match state.accounts.get_account(&account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load account", e));
+            }
         };
 
-        // Get blockchain client
-        let blockchain_client = match state.blockchain_client.as_ref() {
-            Some(client) => client.lock().unwrap().clone(),
-            None => return HttpResponse::ServiceUnavailable().body("Blockchain client unavailable"),
-        };
-
-        // Parse private key
-        let private_key = match SecretKey::from_str(&account.private_key) {
-            Ok(key) => key,
-            Err(_) => return HttpResponse::InternalServerError().body("Invalid private key"),
-        };
-
-        // Initialize cryptography
-        let secp = Secp256k1::new();
-        let public_key = PublicKey::from_secret_key(&secp, &private_key);
-
-        // Generate addresses
-        let boarding_output = match BoardingOutput::new(
-            &secp,
-            network_info.pk.x_only_public_key().0,
-            public_key.x_only_public_key().0,
-            network_info.unilateral_exit_delay,
-            network_info.network,
-        ) {
-            Ok(bo) => bo,
-            Err(_) => {
-                return HttpResponse::InternalServerError().body("Address generation failed");
+        let unlocked = match (&account.encrypted_key, &account.private_key) {
+            (Some(encrypted_key), _) => {
+                // The key derivation is deliberately slow, so it runs on the blocking thread pool.
+                let encrypted_key = encrypted_key.clone();
+                let passphrase = req.passphrase.clone();
+                let secret = match web::block(move || encrypted_key.decrypt(&passphrase)).await {
+                    Ok(Ok(secret)) => secret,
+                    Ok(Err(_)) => {
+                        return Err(ApiError::new(ErrorCode::InvalidPassphrase, "Invalid passphrase"));
+                    }
+                    Err(e) => {
+                        return Err(ApiError::internal("Failed to decrypt account key", e));
+                    }
+                };
+
+                match unlocked_keys(&account, &secret) {
+                    Ok(unlocked) => unlocked,
+                    Err(e) => {
+                        return Err(ApiError::internal("Invalid account key", e));
+                    }
+                }
+            }
+            // Accounts created before keys were encrypted need the operator to encrypt their key
+            // first, so that nobody can set its passphrase by unlocking the account.
+            (None, Some(_)) => {
+                return Err(ApiError::new(
+                    ErrorCode::KeyMigrationRequired,
+                    "The account key is not encrypted yet; the operator has to run `migrate-keys` first",
+                ));
+            }
+            (None, None) => {
+                return Err(ApiError::new(ErrorCode::Internal, "Account has no private key"));
             }
         };
 
-        let vtxo = match Vtxo::new(
-            &secp,
-            network_info.pk.x_only_public_key().0,
-            public_key.x_only_public_key().0,
-            vec![],
-            network_info.unilateral_exit_delay,
-            network_info.network,
-        ) {
-            Ok(vtxo) => vtxo,
-            Err(_) => return HttpResponse::InternalServerError().body("Virtual address generation failed"),
+        // Keep the decrypted keys in memory for at most the configured TTL
+        let max_ttl = state.config.keys.unlock_ttl_seconds;
+        let ttl = req.ttl_seconds.unwrap_or(max_ttl).min(max_ttl);
+
+        state
+            .unlocked_keys
+            .unlock(&account.id, unlocked, Duration::from_secs(ttl));
+
+        Ok(HttpResponse::Ok().json(UnlockResponse {
+            account_id: account.id,
+            expires_in_seconds: ttl,
+        }))
+    }
+
+    /// Turn the decrypted secret of `account` into its signing keys: the account-level
+    /// extended key for a BIP39 seed, or the secret key itself for a single-key account.
+    fn unlocked_keys(account: &UserAccount, secret: &[u8]) -> Result<UnlockedKeys, anyhow::Error> {
+        let Some(xpub) = &account.xpub else {
+            return Ok(UnlockedKeys::Single(SecretKey::from_slice(secret)?));
         };
 
-        // Connect to network
-        let mut grpc_client = ark_grpc::Client::new(state.config.ark_server_url.clone());
-        if let Err(_) = grpc_client.connect().await {
-            return HttpResponse::ServiceUnavailable().body("Network connection failed");
+        let xpub = Xpub::from_str(xpub)?;
+        let account_xpriv = keystore::account_xpriv(secret, xpub.network)?;
+
+        if Xpub::from_priv(&Secp256k1::new(), &account_xpriv) != xpub {
+            anyhow::bail!("decrypted seed does not match the account xpub");
         }
 
-        // Query virtual transactions
-        let vtxos = match grpc_client.list_vtxos(&vtxo.to_ark_address()).await {
-            Ok(vtxos) => vtxos,
+        Ok(UnlockedKeys::Hd(account_xpriv))
+    }
+
+    #[utoipa::path(
+        tag = "accounts",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        responses(
+            (status = 204, description = "Account locked"),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/accounts/{account_id}/lock")]
+    pub async fn lock_account(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
+
+        let account = match state.accounts.get_account(&account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
             Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to query virtual transactions: {}", e));
+                return Err(ApiError::internal("Failed to load account", e));
             }
         };
 
-        // Create cache for blockchain queries
-        let mut spendable_vtxos = HashMap::new();
-        spendable_vtxos.insert(vtxo.clone(), vtxos.spendable);
+        state.unlocked_keys.lock(&account.id);
+        state.clients.evict(&account.id);
 
-        // Query on-chain outputs
-        let boarding_address = boarding_output.address();
-        let boarding_outputs = match blockchain_client.query_utxos(&boarding_address).await {
-            Ok(outputs) => outputs,
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    #[utoipa::path(
+        tag = "accounts",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        request_body = TokenRequest,
+        responses(
+            (status = 201, description = "Token issued", body = TokenResponse),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/accounts/{account_id}/tokens")]
+    pub async fn issue_token(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+        req: web::Json<TokenRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
+
+        let api_token = match auth::issue_token(state.accounts.as_ref(), &account_id, req.scope) {
+            Ok(token) => token,
+            Err(e) => {
+                return Err(ApiError::internal("Failed to issue API token", e));
+            }
+        };
+
+        Ok(HttpResponse::Created().json(TokenResponse {
+            account_id,
+            scope: req.scope,
+            api_token,
+        }))
+    }
+
+    #[utoipa::path(
+        tag = "accounts",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        responses(
+            (status = 200, description = "Addresses of the latest address key", body = AddressDetails),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/accounts/{account_id}/addresses")]
+    pub async fn get_account_addresses(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Read)?;
+
+        // Retrieve account
+        let account = match state.accounts.get_account(&account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
             Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to query blockchain: {}", e));
+                return Err(ApiError::internal("Failed to load account", e));
             }
         };
 
-        // Store results in cache
-        let mut output_cache = HashMap::new();
-        output_cache.insert(boarding_address.to_string(), boarding_outputs);
+        // Get network info
+        let network_info = match state.server_connection.lock().unwrap().clone() {
+            Some(info) => info,
+            None => return Err(ApiError::network_unavailable()),
+        };
 
-        // Create lookup function
-        let find_outputs =
-            move |address: &bitcoin::Address| -> Result<Vec<ExplorerUtxo>, ark_core::Error> {
-                let address_str = address.to_string();
-                match output_cache.get(&address_str) {
-                    Some(outputs) => Ok(outputs.clone()),
-                    None => Ok(Vec::new()),
+        // Addresses only need public keys, so they are available while the account is locked
+        let key_index = account.key_count.max(1) - 1;
+        address_details(&account, &network_info, key_index).map(|details| HttpResponse::Ok().json(details))
+    }
+
+    #[utoipa::path(
+        tag = "accounts",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        responses(
+            (status = 201, description = "Addresses of a new address key", body = AddressDetails),
+            (status = 400, description = "Account has a single address", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 409, description = "Too many unused addresses in a row", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/accounts/{account_id}/addresses")]
+    pub async fn add_account_address(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
+
+        // Get network info
+        let network_info = match state.server_connection.lock().unwrap().clone() {
+            Some(info) => info,
+            None => return Err(ApiError::network_unavailable()),
+        };
+
+        // Get blockchain client
+        let blockchain_client = match state.blockchain_client.lock().unwrap().clone() {
+            Some(client) => client,
+            None => return Err(ApiError::blockchain_unavailable()),
+        };
+
+        // Connect to network
+        let mut grpc_client = ark_grpc::Client::new(state.config.ark_server_url.clone());
+        if grpc_client.connect().await.is_err() {
+            return Err(ApiError::new(ErrorCode::NetworkUnavailable, "Network connection failed"));
+        }
+
+        loop {
+            // Retrieve account
+            let account = match state.accounts.get_account(&account_id) {
+                Ok(Some(account)) => account,
+                Ok(None) => return Err(ApiError::account_not_found()),
+                Err(e) => {
+                    return Err(ApiError::internal("Failed to load account", e));
                 }
             };
 
-        // Calculate virtual balances
-        let virtual_tx_outputs =
-            match list_virtual_tx_outpoints(find_outputs.clone(), spendable_vtxos) {
-                Ok(outputs) => outputs,
+            if !account.is_hd() {
+                return Err(ApiError::invalid_request(
+                    "Account was created without a mnemonic and has a single address",
+                ));
+            }
+
+            // Restoring stops after GAP_LIMIT unused address keys in a row, so one more would
+            // hide the funds it receives from a restored account
+            let key_index = account.key_count;
+            if key_index >= GAP_LIMIT {
+                let mut used = false;
+                for index in (key_index - GAP_LIMIT..key_index).rev() {
+                    let (boarding_output, vtxo) = match account.key_outputs(&network_info, index) {
+                        Ok(outputs) => outputs,
+                        Err(_) => {
+                            return Err(ApiError::new(ErrorCode::Internal, "Address generation failed"));
+                        }
+                    };
+
+                    used = match key_has_history(&grpc_client, &blockchain_client, &boarding_output, &vtxo).await {
+                        Ok(used) => used,
+                        Err(e) => {
+                            return Err(ApiError::internal("Failed to scan account history", e));
+                        }
+                    };
+                    if used {
+                        break;
+                    }
+                }
+
+                if !used {
+                    return Err(ApiError::new(
+                        ErrorCode::AddressGapLimit,
+                        format!("The last {GAP_LIMIT} addresses are unused; use one of them first"),
+                    ));
+                }
+            }
+
+            // Hand out the next address key, unless another request just did
+            match state.accounts.add_address_key(&account.id, account.key_count) {
+                Ok(true) => {}
+                Ok(false) => continue,
                 Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .body(format!("Failed to process virtual transactions: {}", e));
+                    return Err(ApiError::internal("Failed to store account", e));
                 }
-            };
+            }
 
-        // Calculate on-chain balances
-        let boarding_outputs = match list_boarding_outpoints(find_outputs, &[boarding_output]) {
+            return address_details(&account, &network_info, key_index)
+                .map(|details| HttpResponse::Created().json(details));
+        }
+    }
+
+    /// The addresses of address key `key_index` of `account`.
+    fn address_details(
+        account: &UserAccount,
+        network_info: &ark_core::server::Info,
+        key_index: u32,
+    ) -> Result<AddressDetails, ApiError> {
+        let (boarding_output, vtxo) = match account.key_outputs(network_info, key_index) {
             Ok(outputs) => outputs,
-            Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to process on-chain outputs: {}", e));
+            Err(_) => {
+                return Err(ApiError::new(ErrorCode::Internal, "Address generation failed"));
             }
         };
 
-        // Return balance details
-        HttpResponse::Ok().json(BalanceDetails {
-            account_id: account.id,
-            virtual_balance: VirtualBalance {
-                available: virtual_tx_outputs.spendable_balance().to_sat(),
-                expired: virtual_tx_outputs.expired_balance().to_sat(),
-            },
-            onchain_balance: OnchainBalance {
-                available: boarding_outputs.spendable_balance().to_sat(),
-                expired: boarding_outputs.expired_balance().to_sat(),
-                pending: boarding_outputs.pending_balance().to_sat(),
-            },
+        Ok(AddressDetails {
+            account_id: account.id.clone(),
+            key_index,
+            chain_address: boarding_output.address().to_string(),
+            virtual_address: vtxo.to_ark_address().to_string(),
+            payment_uri: PaymentUri::new(boarding_output.address().clone(), vtxo.to_ark_address())
+                .to_string(),
         })
     }
 
-    #[post("/api/transfer")]
-    pub async fn transfer_funds(
+    #[utoipa::path(
+        tag = "webhooks",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        request_body = WebhookRequest,
+        responses(
+            (status = 201, description = "Webhook registered", body = WebhookCreationResponse),
+            (status = 400, description = "Invalid request", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 409, description = "Too many webhooks", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/accounts/{account_id}/webhooks")]
+    pub async fn register_webhook(
+        account_id: web::Path<String>,
+        token: ApiToken,
         state: web::Data<ApplicationState>,
-        req: web::Json<TransferRequest>,
-    ) -> impl Responder {
-        // Retrieve account
-        let accounts = state.accounts.lock().unwrap();
-        let account = match accounts.get(&req.account_id) {
-            Some(info) => info.clone(),
-            None => return HttpResponse::NotFound().body("Account not found"),
+        req: web::Json<WebhookRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
+
+        let req = req.into_inner();
+        if let Err(e) = webhooks::validate(&req) {
+            return Err(ApiError::invalid_request(e.to_string()));
+        }
+        if let Err(e) = webhooks::resolve_destination(&req.url).await {
+            return Err(ApiError::invalid_request(e.to_string()));
+        }
+
+        match state.accounts.list_webhooks(&account_id) {
+            Ok(existing) if existing.len() >= webhooks::MAX_WEBHOOKS => {
+                return Err(ApiError::new(
+                    ErrorCode::TooManyWebhooks,
+                    format!("Accounts can have at most {} webhooks", webhooks::MAX_WEBHOOKS),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load webhooks", e));
+            }
+        }
+
+        match webhooks::register(&state, &account_id, req) {
+            Ok(webhook) => Ok(HttpResponse::Created().json(WebhookCreationResponse {
+                webhook: WebhookDetails::from(&webhook),
+                secret: webhook.secret,
+            })),
+            Err(e) => Err(ApiError::internal("Failed to register webhook", e)),
+        }
+    }
+
+    #[utoipa::path(
+        tag = "webhooks",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        responses(
+            (status = 200, description = "Webhooks of the account", body = WebhookList),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/accounts/{account_id}/webhooks")]
+    pub async fn list_webhooks(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Read)?;
+
+        match state.accounts.list_webhooks(&account_id) {
+            Ok(list) => Ok(HttpResponse::Ok().json(WebhookList {
+                webhooks: list.iter().map(WebhookDetails::from).collect(),
+                account_id,
+            })),
+            Err(e) => Err(ApiError::internal("Failed to load webhooks", e)),
+        }
+    }
+
+    #[utoipa::path(
+        tag = "webhooks",
+        params(("account_id" = String, Path, description = "ID of the account"), ("webhook_id" = String, Path, description = "ID of the webhook")),
+        responses(
+            (status = 204, description = "Webhook deleted"),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Webhook not found", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[delete("/api/accounts/{account_id}/webhooks/{webhook_id}")]
+    pub async fn delete_webhook(
+        path: web::Path<(String, String)>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let (account_id, webhook_id) = path.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
+
+        match webhooks::unregister(&state, &account_id, &webhook_id) {
+            Ok(true) => Ok(HttpResponse::NoContent().finish()),
+            Ok(false) => Err(ApiError::new(ErrorCode::WebhookNotFound, "Webhook not found")),
+            Err(e) => Err(ApiError::internal("Failed to delete webhook", e)),
+        }
+    }
+
+    #[utoipa::path(
+        tag = "webhooks",
+        params(("account_id" = String, Path, description = "ID of the account"), ("webhook_id" = String, Path, description = "ID of the webhook"), DeliveryQuery),
+        responses(
+            (status = 200, description = "Latest deliveries, newest first", body = DeliveryLog),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/accounts/{account_id}/webhooks/{webhook_id}/deliveries")]
+    pub async fn get_webhook_deliveries(
+        path: web::Path<(String, String)>,
+        query: web::Query<DeliveryQuery>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let (account_id, webhook_id) = path.into_inner();
+        token.authorize(&account_id, TokenScope::Read)?;
+
+        let limit = query
+            .limit
+            .unwrap_or(history::DEFAULT_PAGE_SIZE)
+            .clamp(1, history::MAX_PAGE_SIZE);
+
+        match state
+            .accounts
+            .list_deliveries(&account_id, &webhook_id, limit)
+        {
+            Ok(deliveries) => Ok(HttpResponse::Ok().json(DeliveryLog {
+                webhook_id,
+                deliveries,
+            })),
+            Err(e) => Err(ApiError::internal("Failed to load webhook deliveries", e)),
+        }
+    }
+}
+
+pub mod finance {
+    use actix_web::{get, post, web, HttpRequest, HttpResponse, ResponseError};
+    use bitcoin::Amount;
+    use futures::stream;
+    use std::collections::{HashSet, VecDeque};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use rand::thread_rng;
+    use tokio::sync::broadcast;
+
+    use crate::clients::AccountClient;
+    use crate::core::model::*;
+    use crate::error::{ApiError, ErrorCode};
+    use crate::events;
+    use crate::exits;
+    use crate::history;
+    use crate::idempotency::{self, IdempotencyKey};
+    use crate::payouts;
+    use crate::preview;
+    use ark_core::ArkAddress;
+
+    /// Parse the Ark address `value`, which must be for the Ark server's network. `what` names
+    /// it in the error.
+    fn ark_address(state: &ApplicationState, value: &str, what: &str) -> Result<ArkAddress, ApiError> {
+        let network = match state.server_connection.lock().unwrap().as_ref() {
+            Some(info) => info.network,
+            None => return Err(ApiError::network_unavailable()),
         };
 
+        ArkAddress::decode_for_network(value, network)
+            .map_err(|e| ApiError::new(ErrorCode::InvalidAddress, format!("Invalid {what}: {e}")))
+    }
+
+    /// The connected clients of an unlocked `account`.
+    async fn account_client(
+        state: &ApplicationState,
+        account: &UserAccount,
+    ) -> Result<Arc<AccountClient>, ApiError> {
         // Get network info
-        let network_info = match state.server_connection.as_ref() {
-            Some(info) => info.lock().unwrap().clone(),
-            None => return HttpResponse::ServiceUnavailable().body("Network unavailable"),
+        let network_info = match state.server_connection.lock().unwrap().clone() {
+            Some(info) => info,
+            None => return Err(ApiError::network_unavailable()),
         };
 
         // Get blockchain client
-        let blockchain_client = match state.blockchain_client.as_ref() {
-            Some(client) => client.lock().unwrap().clone(),
-            None => return HttpResponse::ServiceUnavailable().body("Blockchain client unavailable"),
+        let blockchain_client = match state.blockchain_client.lock().unwrap().clone() {
+            Some(client) => client,
+            None => return Err(ApiError::blockchain_unavailable()),
         };
 
-        // Parse private key
-        let private_key = match SecretKey::from_str(&account.private_key) {
-            Ok(key) => key,
-            Err(_) => return HttpResponse::InternalServerError().body("Invalid private key"),
+        // Get the decrypted private keys
+        let keys = match state.unlocked_keys.get(&account.id) {
+            Some(keys) => keys,
+            None => {
+                state.clients.evict(&account.id);
+                return Err(ApiError::new(ErrorCode::AccountLocked, "Account is locked"));
+            }
         };
 
-        // Parse destination address
-        let destination = match ArkAddress::decode(&req.recipient) {
-            Ok(address) => address,
-            Err(_) => return HttpResponse::BadRequest().body("Invalid recipient address"),
-        };
+        // Reuse the account's connected clients, or connect them
+        state
+            .clients
+            .get_or_connect(
+                &state.config,
+                &blockchain_client,
+                network_info.network,
+                account,
+                &keys,
+            )
+            .await
+            .map_err(|e| {
+                ApiError::caused_by("Network connection failed", &e, ErrorCode::NetworkUnavailable)
+            })
+    }
 
-        // Calculate amount
-        let transfer_amount = Amount::from_sat(req.amount);
+    #[utoipa::path(
+        tag = "finance",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        responses(
+            (status = 200, description = "Balances in sats", body = BalanceDetails),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/accounts/{account_id}/balance")]
+    pub async fn get_account_balance(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Read)?;
 
-        // Initialize cryptography
-        let secp = Secp256k1::new();
-        let public_key = PublicKey::from_secret_key(&secp, &private_key);
+        // Retrieve account
+        let account = match state.accounts.get_account(&account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load account", e));
+            }
+        };
 
-        // Generate virtual address
-        let vtxo = match Vtxo::new(
-            &secp,
-            network_info.pk.x_only_public_key().0,
-            public_key.x_only_public_key().0,
-            vec![],
-            network_info.unilateral_exit_delay,
-            network_info.network,
-        ) {
-            Ok(vtxo) => vtxo,
-            Err(_) => return HttpResponse::InternalServerError().body("Address generation failed"),
+        // Balances are only shown to unlocked accounts
+        let client = account_client(&state, &account).await?;
+
+        // Calculate virtual and on-chain balances
+        let balance = match client.balance().await {
+            Ok(balance) => balance,
+            Err(e) => {
+                return Err(ApiError::caused_by("Failed to load funds", &e, ErrorCode::Internal));
+            }
         };
 
-        // Connect to network
-        let mut grpc_client = ark_grpc::Client::new(state.config.ark_server_url.clone());
-        if let Err(_) = grpc_client.connect().await {
-            return HttpResponse::ServiceUnavailable().body("Network connection failed");
-        }
+        // Return balance details
+        Ok(HttpResponse::Ok().json(BalanceDetails {
+            account_id: account.id,
+            virtual_balance: VirtualBalance {
+                available: balance.virtual_available.to_sat(),
+                expired: balance.virtual_expired.to_sat(),
+            },
+            onchain_balance: OnchainBalance {
+                available: balance.boarding_confirmed.to_sat(),
+                expired: balance.boarding_expired.to_sat(),
+                pending: balance.boarding_pending.to_sat(),
+            },
+        }))
+    }
 
-        // Query available outputs
-        let vtxos = match grpc_client.list_vtxos(&vtxo.to_ark_address()).await {
-            Ok(vtxos) => vtxos,
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to query available outputs"),
+    #[utoipa::path(
+        tag = "finance",
+        params(("account_id" = String, Path, description = "ID of the account"), TransactionQuery),
+        responses(
+            (status = 200, description = "A page of the transaction history", body = TransactionPage),
+            (status = 400, description = "Invalid cursor or range", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/accounts/{account_id}/transactions")]
+    pub async fn get_account_transactions(
+        account_id: web::Path<String>,
+        query: web::Query<TransactionQuery>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Read)?;
+
+        // Retrieve account
+        let account = match state.accounts.get_account(&account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load account", e));
+            }
         };
 
-        // Query blockchain for output details
-        let vtxo_address = vtxo.address();
-        let vtxo_outputs = match blockchain_client.query_utxos(&vtxo_address).await {
-            Ok(outputs) => outputs,
+        let client = account_client(&state, &account).await?;
+
+        // Collect the history of every address key
+        let histories = match client.transaction_histories().await {
+            Ok(histories) => histories,
             Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to query blockchain: {}", e));
+                return Err(ApiError::caused_by(
+                    "Failed to load transaction history",
+                    &e,
+                    ErrorCode::Internal,
+                ));
             }
         };
 
-        // Create cache for blockchain data
-        let mut output_cache = HashMap::new();
-        output_cache.insert(vtxo_address.to_string(), vtxo_outputs);
+        // Filter and paginate
+        let (transactions, next_cursor) = match history::page(history::merge(histories), &query) {
+            Ok(page) => page,
+            Err(e) => return Err(ApiError::invalid_request(e.to_string())),
+        };
+
+        Ok(HttpResponse::Ok().json(TransactionPage {
+            account_id: account.id,
+            transactions,
+            next_cursor,
+        }))
+    }
+
+    /// How often an idle event stream sends a comment, so that proxies keep it open.
+    const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+    /// How many stored events are replayed at once.
+    const REPLAY_BATCH: usize = 100;
+
+    struct EventStream {
+        state: web::Data<ApplicationState>,
+        account_id: String,
+        receiver: broadcast::Receiver<StoredEvent>,
+        /// The sequence number of the last event sent.
+        last_seq: u64,
+        /// Whether stored events after `last_seq` may not have been sent yet.
+        replaying: bool,
+        pending: VecDeque<StoredEvent>,
+        keep_alive: tokio::time::Interval,
+    }
 
-        // Create lookup function
-        let find_outputs =
-            move |address: &bitcoin::Address| -> Result<Vec<ExplorerUtxo>, ark_core::Error> {
-                let address_str = address.to_string();
-                match output_cache.get(&address_str) {
-                    Some(outputs) => Ok(outputs.clone()),
-                    None => Ok(Vec::new()),
+    impl EventStream {
+        /// The next chunk of the stream, or `None` once it is over.
+        async fn next_chunk(&mut self) -> Option<Result<web::Bytes, actix_web::Error>> {
+            loop {
+                if let Some(event) = self.pending.pop_front() {
+                    if event.seq <= self.last_seq {
+                        continue;
+                    }
+                    self.last_seq = event.seq;
+
+                    let data = match serde_json::to_string(&event) {
+                        Ok(data) => data,
+                        Err(e) => return Some(Err(actix_web::error::ErrorInternalServerError(e))),
+                    };
+                    let message = format!(
+                        "id: {}\nevent: {}\ndata: {}\n\n",
+                        event.seq,
+                        event.event.name(),
+                        data
+                    );
+                    return Some(Ok(web::Bytes::from(message)));
                 }
-            };
 
-        // Map available outputs
-        let mut spendable_vtxos = HashMap::new();
-        spendable_vtxos.insert(vtxo.clone(), vtxos.spendable);
+                // Catch up from the event log
+                if self.replaying {
+                    let events = self.state.accounts.events_after(
+                        &self.account_id,
+                        self.last_seq,
+                        REPLAY_BATCH,
+                    );
+                    match events {
+                        Ok(events) if events.is_empty() => self.replaying = false,
+                        Ok(events) => self.pending.extend(events),
+                        Err(e) => return Some(Err(actix_web::error::ErrorInternalServerError(e))),
+                    }
+                    continue;
+                }
 
-        // Process virtual transactions
-        let virtual_tx_outputs = match list_virtual_tx_outpoints(find_outputs, spendable_vtxos) {
-            Ok(outputs) => outputs,
-            Err(_) => {
-                return HttpResponse::InternalServerError().body("Failed to process virtual transactions");
+                tokio::select! {
+                    event = self.receiver.recv() => match event {
+                        Ok(event) => self.pending.push_back(event),
+                        // Events were dropped; they are in the log
+                        Err(broadcast::error::RecvError::Lagged(_)) => self.replaying = true,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = self.keep_alive.tick() => {
+                        return Some(Ok(web::Bytes::from_static(b": keep-alive\n\n")));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Server-sent events for incoming and spent VTXOs, boarding confirmations and completed
+    /// rounds. Each event's `id` is its sequence number; reconnecting with it as `Last-Event-ID`
+    /// (or `?cursor=`) replays everything that happened since.
+    #[utoipa::path(
+        tag = "finance",
+        params(("account_id" = String, Path, description = "ID of the account"), EventQuery, ("Last-Event-ID" = Option<u64>, Header, description = "Sequence number of the last event seen")),
+        responses(
+            (status = 200, description = "Stream of server-sent events", body = StoredEvent, content_type = "text/event-stream"),
+            (status = 400, description = "Invalid Last-Event-ID", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/accounts/{account_id}/events")]
+    pub async fn stream_account_events(
+        account_id: web::Path<String>,
+        query: web::Query<EventQuery>,
+        req: HttpRequest,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Read)?;
+
+        // Retrieve account
+        match state.accounts.get_account(&account_id) {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load account", e));
             }
+        }
+
+        // Resume after the last event the client saw
+        let cursor = match req.headers().get("Last-Event-ID") {
+            Some(header) => match header.to_str().ok().and_then(|id| id.trim().parse().ok()) {
+                Some(seq) => Some(seq),
+                None => return Err(ApiError::invalid_request("Invalid Last-Event-ID")),
+            },
+            None => query.cursor,
         };
 
-        // Get list of spendable outputs
-        let vtxo_outpoints = virtual_tx_outputs
-            .spendable
-            .iter()
-            .map(|(outpoint, _)| ark_core::coin_select::VtxoOutPoint {
-                outpoint: outpoint.outpoint,
-                expire_at: outpoint.expire_at,
-                amount: outpoint.amount,
-            })
-            .collect::<Vec<_>>();
+        // Subscribe before replaying, so that nothing falls in between
+        let receiver = state.events.subscribe(&state, &account_id);
 
-        // Select outputs for transfer amount
-        let selected_outpoints = match select_vtxos(vtxo_outpoints, transfer_amount, network_info.dust, true) {
-            Ok(outpoints) => outpoints,
-            Err(_) => return HttpResponse::BadRequest().body("Insufficient funds or invalid amount"),
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+        keep_alive.reset();
+
+        let events = EventStream {
+            state: state.clone(),
+            account_id,
+            receiver,
+            last_seq: cursor.unwrap_or(0),
+            replaying: cursor.is_some(),
+            pending: VecDeque::new(),
+            keep_alive,
         };
 
-        // Prepare inputs
-        let vtxo_inputs = virtual_tx_outputs
-            .spendable
-            .into_iter()
-            .filter(|(outpoint, _)| {
-                selected_outpoints
-                    .iter()
-                    .any(|o| o.outpoint == outpoint.outpoint)
-            })
-            .map(|(outpoint, vtxo)| redeem::VtxoInput::new(vtxo, outpoint.amount, outpoint.outpoint))
-            .collect::<Vec<_>>();
+        let body = stream::unfold(events, |mut events| async move {
+            events.next_chunk().await.map(|chunk| (chunk, events))
+        });
+
+        Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(body))
+    }
+
+    #[utoipa::path(
+        tag = "finance",
+        params(("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key get the first response back")),
+        request_body = TransferRequest,
+        responses(
+            (status = 200, description = "Transaction submitted", body = TransferResponse),
+            (status = 400, description = "Invalid address or insufficient funds", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 409, description = "Request with the same Idempotency-Key in progress", body = ApiError),
+            (status = 422, description = "Idempotency-Key used for a different request", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/transfer")]
+    pub async fn transfer_funds(
+        token: ApiToken,
+        key: IdempotencyKey,
+        state: web::Data<ApplicationState>,
+        req: web::Json<TransferRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        token.authorize(&req.account_id, TokenScope::Full)?;
 
-        // Change address is the sender's address
-        let change_address = vtxo.to_ark_address();
+        let req = req.into_inner();
+        let account_id = req.account_id.clone();
+        let fingerprint = idempotency::fingerprint("transfer", &req);
 
-        // Create keypair for signing
-        let secp = Secp256k1::new();
-        let keypair = Keypair::from_secret_key(&secp, &private_key);
+        idempotency::run_once(&state, &account_id, key, fingerprint, transfer(state.clone(), req))
+            .await
+    }
 
-        // Build transaction
-        let mut redeem_psbt = match build_redeem_transaction(
-            &[(&destination, transfer_amount)],
-            Some(&change_address),
-            &vtxo_inputs,
-        ) {
-            Ok(psbt) => psbt,
-            Err(_) => {
-                return HttpResponse::InternalServerError().body("Failed to build transaction");
+    /// The body of `transfer_funds`. Fails if nothing was submitted.
+    async fn transfer(
+        state: web::Data<ApplicationState>,
+        req: TransferRequest,
+    ) -> Result<HttpResponse, ApiError> {
+        // Retrieve account
+        let account = match state.accounts.get_account(&req.account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load account", e));
             }
         };
 
-        // Sign transaction
-        let sign_fn = |msg: Message| -> Result<(schnorr::Signature, XOnlyPublicKey), ark_core::Error> {
-            let sig = Secp256k1::new().sign_schnorr_no_aux_rand(&msg, &keypair);
-            let pk = keypair.x_only_public_key().0;
-            Ok((sig, pk))
-        };
+        let client = account_client(&state, &account).await?;
+        let _payment = state.clients.lock_payments(&account.id).await;
 
-        // Sign all inputs
-        for (i, _) in vtxo_inputs.iter().enumerate() {
-            if let Err(_) = sign_redeem_transaction(sign_fn, &mut redeem_psbt, &vtxo_inputs, i) {
-                return HttpResponse::InternalServerError().body("Failed to sign transaction");
-            }
-        }
+        // Parse destination address
+        let destination = ark_address(&state, &req.recipient, "recipient address")?;
 
-        // Submit transaction to network
-        let psbt = match grpc_client.submit_redeem_transaction(redeem_psbt).await {
-            Ok(psbt) => psbt,
-            Err(_) => {
-                return HttpResponse::InternalServerError().body("Failed to submit transaction");
+        // Build, sign and submit the transaction
+        let tx_id = match client.send_vtxo(destination, Amount::from_sat(req.amount)).await {
+            Ok(Some(txid)) => {
+                let completed = AccountEvent::TransferCompleted {
+                    txid: txid.to_string(),
+                    recipient: req.recipient.clone(),
+                    amount: req.amount,
+                };
+                events::report(&state, &account.id, completed);
+
+                txid.to_string()
+            }
+            Ok(None) => {
+                return Err(ApiError::new(ErrorCode::InsufficientFunds, "Insufficient funds"));
+            }
+            // The transaction may have reached the Ark server, so the failure is stored
+            Err(e) => {
+                let error =
+                    ApiError::caused_by("Failed to submit transaction", &e, ErrorCode::Internal);
+                return Ok(error.error_response());
             }
-        };
-
-        // Extract transaction ID
-        let tx_id = match psbt.extract_tx() {
-            Ok(tx) => tx.compute_txid().to_string(),
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to extract transaction ID"),
         };
 
         // Return success response
-        HttpResponse::Ok().json(TransferResponse {
+        Ok(HttpResponse::Ok().json(TransferResponse {
             account_id: account.id,
-            recipient: req.recipient.clone(),
+            recipient: req.recipient,
             amount: req.amount,
             transaction_id: tx_id,
-        })
+        }))
     }
 
-    #[post("/api/fund")]
-    pub async fn fund_account(req: web::Json<FundingRequest>) -> impl Responder {
-        // Validate input
-        if req.chain_address.is_empty() {
-            return HttpResponse::BadRequest().json(FundingResponse {
-                success: false,
-                recipient: req.chain_address.clone(),
-                amount: req.amount,
-                transaction_id: None,
-                error_message: Some("Empty address provided".to_string()),
-                command_output: String::new(),
-            });
+    #[utoipa::path(
+        tag = "finance",
+        params(("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key get the first response back")),
+        request_body = BatchTransferRequest,
+        responses(
+            (status = 200, description = "The outcome of every payout", body = BatchTransferResponse),
+            (status = 400, description = "No payout can be made, or insufficient funds", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 409, description = "Request with the same Idempotency-Key in progress", body = ApiError),
+            (status = 422, description = "Idempotency-Key used for a different request", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/transfer/batch")]
+    pub async fn transfer_batch(
+        token: ApiToken,
+        key: IdempotencyKey,
+        state: web::Data<ApplicationState>,
+        req: web::Json<BatchTransferRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        token.authorize(&req.account_id, TokenScope::Full)?;
+
+        if req.payouts.is_empty() || req.payouts.len() > payouts::MAX_PAYOUTS {
+            return Err(ApiError::invalid_request(format!(
+                "A batch must have between 1 and {} payouts",
+                payouts::MAX_PAYOUTS
+            )));
         }
 
-        if req.amount <= 0.0 {
-            return HttpResponse::BadRequest().json(FundingResponse {
-                success: false,
-                recipient: req.chain_address.clone(),
-                amount: req.amount,
-                transaction_id: None,
-                error_message: Some("Amount must be positive".to_string()),
-                command_output: String::new(),
-            });
-        }
-
-        // Execute funding command
-        let output = Command::new("nigiri")
-            .arg("faucet")
-            .arg(&req.chain_address)
-            .arg(req.amount.to_string())
-            .output();
-
-        // Process command result
-        match output {
-            Ok(output) => {
-                let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-
-                if output.status.success() {
-                    let tx_id = utils::extract_transaction_id(&stdout);
-
-                    HttpResponse::Ok().json(FundingResponse {
-                        success: true,
-                        recipient: req.chain_address.clone(),
-                        amount: req.amount,
-                        transaction_id: tx_id,
-                        error_message: None,
-                        command_output: stdout,
-                    })
-                } else {
-                    HttpResponse::InternalServerError().json(FundingResponse {
-                        success: false,
-                        recipient: req.chain_address.clone(),
-                        amount: req.amount,
-                        transaction_id: None,
-                        error_message: Some(format!("Command failed: {}", stderr)),
-                        command_output: stdout,
-                    })
-                }
-            }
-            Err(e) => HttpResponse::InternalServerError().json(FundingResponse {
-                success: false,
-                recipient: req.chain_address.clone(),
-                amount: req.amount,
-                transaction_id: None,
-                error_message: Some(format!("Command execution error: {}", e)),
-                command_output: String::new(),
-            }),
-        }
+        let req = req.into_inner();
+        let account_id = req.account_id.clone();
+        let fingerprint = idempotency::fingerprint("transfer_batch", &req);
+
+        idempotency::run_once(&state, &account_id, key, fingerprint, pay_out(state.clone(), req))
+            .await
     }
 
-    #[post("/api/withdraw")]
-    pub async fn withdraw_funds(
+    /// The body of `transfer_batch`. Fails if nothing was submitted.
+    async fn pay_out(
         state: web::Data<ApplicationState>,
-        req: web::Json<WithdrawalRequest>,
-    ) -> impl Responder {
+        req: BatchTransferRequest,
+    ) -> Result<HttpResponse, ApiError> {
         // Retrieve account
-        let accounts = state.accounts.lock().unwrap();
-        let account = match accounts.get(&req.account_id) {
-            Some(info) => info.clone(),
-            None => return HttpResponse::NotFound().body("Account not found"),
+        let account = match state.accounts.get_account(&req.account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => return Err(ApiError::internal("Failed to load account", e)),
         };
 
-        // Get network info
-        let network_info = match state.server_connection.as_ref() {
-            Some(info) => info.lock().unwrap().clone(),
-            None => return HttpResponse::ServiceUnavailable().body("Network unavailable"),
-        };
+        let client = account_client(&state, &account).await?;
+        let _payment = state.clients.lock_payments(&account.id).await;
 
-        // Get blockchain client
-        let blockchain_client = match state.blockchain_client.as_ref() {
-            Some(client) => client.lock().unwrap().clone(),
-            None => return HttpResponse::ServiceUnavailable().body("Blockchain client unavailable"),
-        };
-
-        // Parse private key
-        let private_key = match SecretKey::from_str(&account.private_key) {
-            Ok(key) => key,
-            Err(_) => return HttpResponse::InternalServerError().body("Invalid private key"),
-        };
-
-        // Initialize cryptography
-        let secp = Secp256k1::new();
-        let public_key = PublicKey::from_secret_key(&secp, &private_key);
-
-        // Generate addresses
-        let boarding_output = match BoardingOutput::new(
-            &secp,
-            network_info.pk.x_only_public_key().0,
-            public_key.x_only_public_key().0,
-            network_info.unilateral_exit_delay,
-            network_info.network,
-        ) {
-            Ok(bo) => bo,
-            Err(_) => {
-                return HttpResponse::InternalServerError().body("Address generation failed");
-            }
+        // Check every payout on its own, against the Ark server's dust limit
+        let (network, dust) = match state.server_connection.lock().unwrap().as_ref() {
+            Some(info) => (info.network, info.dust),
+            None => return Err(ApiError::network_unavailable()),
         };
+        let validated = payouts::validate(&req.payouts, network, dust);
 
-        let vtxo = match Vtxo::new(
-            &secp,
-            network_info.pk.x_only_public_key().0,
-            public_key.x_only_public_key().0,
-            vec![],
-            network_info.unilateral_exit_delay,
-            network_info.network,
-        ) {
-            Ok(vtxo) => vtxo,
-            Err(_) => return HttpResponse::InternalServerError().body("Virtual address generation failed"),
+        let outputs = req
+            .payouts
+            .iter()
+            .zip(&validated)
+            .filter_map(|(payout, address)| {
+                let address = address.as_ref().ok()?;
+                Some((*address, Amount::from_sat(payout.amount)))
+            })
+            .collect::<Vec<_>>();
+
+        if outputs.is_empty() {
+            let (i, error) = validated
+                .iter()
+                .enumerate()
+                .find_map(|(i, validated)| validated.as_ref().err().map(|e| (i, e)))
+                .expect("payouts were rejected");
+            return Err(ApiError::new(error.code, format!("Payout {}: {}", i, error.message)));
+        }
+
+        // Pack the payouts into as few transactions as possible and submit them
+        let outcomes = match client.send_vtxos(&outputs).await {
+            Ok(Some(outcomes)) => outcomes,
+            Ok(None) => {
+                return Err(ApiError::new(ErrorCode::InsufficientFunds, "Insufficient funds"));
+            }
+            Err(e) => return Err(ApiError::caused_by("Failed to pay out", &e, ErrorCode::Internal)),
         };
 
-        // Connect to network
-        let mut grpc_client = ark_grpc::Client::new(state.config.ark_server_url.clone());
-        if let Err(_) = grpc_client.connect().await {
-            return HttpResponse::ServiceUnavailable().body("Network connection failed");
+        // The transactions may have reached the Ark server even if none went through, so the
+        // failure is stored
+        if !outcomes.iter().any(|(_, outcome)| outcome.is_ok()) {
+            let e = outcomes
+                .iter()
+                .find_map(|(_, outcome)| outcome.as_ref().err())
+                .expect("at least one transaction");
+            let error = ApiError::caused_by(
+                "Failed to pay out: no transaction went through",
+                e,
+                ErrorCode::Internal,
+            );
+            return Ok(error.error_response());
         }
 
-        // Query blockchain
-        let boarding_address = boarding_output.address();
-        let boarding_outputs = match blockchain_client.query_utxos(&boarding_address).await {
-            Ok(outputs) => outputs,
-            Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to query blockchain: {}", e));
+        let results = payouts::report(&req.payouts, validated, &outcomes);
+
+        for result in &results {
+            if let Some(txid) = &result.transaction_id {
+                let completed = AccountEvent::TransferCompleted {
+                    txid: txid.clone(),
+                    recipient: result.recipient.clone(),
+                    amount: result.amount,
+                };
+                events::report(&state, &account.id, completed);
             }
+        }
+
+        Ok(HttpResponse::Ok().json(BatchTransferResponse {
+            account_id: account.id,
+            results,
+            transaction_ids: outcomes
+                .iter()
+                .filter_map(|(_, outcome)| outcome.as_ref().ok())
+                .map(|txid| txid.to_string())
+                .collect(),
+        }))
+    }
+
+    #[utoipa::path(
+        tag = "finance",
+        request_body = TransferRequest,
+        responses(
+            (status = 200, description = "What the transfer would do", body = TransferPreview),
+            (status = 400, description = "Invalid address or insufficient funds", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/transfer/preview")]
+    pub async fn preview_transfer(
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+        req: web::Json<TransferRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        token.authorize(&req.account_id, TokenScope::Read)?;
+
+        // Retrieve account
+        let account = match state.accounts.get_account(&req.account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => return Err(ApiError::internal("Failed to load account", e)),
         };
 
-        // Create cache for blockchain data
-        let mut output_cache = HashMap::new();
-        output_cache.insert(boarding_address.to_string(), boarding_outputs);
+        let client = account_client(&state, &account).await?;
 
-        // Create output lookup function
-        let find_outputs =
-            move |address: &bitcoin::Address| -> Result<Vec<ExplorerUtxo>, ark_core::Error> {
-                let address_str = address.to_string();
-                match output_cache.get(&address_str) {
-                    Some(outputs) => Ok(outputs.clone()),
-                    None => Ok(Vec::new()),
-                }
-            };
+        let destination = ark_address(&state, &req.recipient, "recipient address")?;
 
-        // Query virtual transactions
-        let vtxos = match grpc_client.list_vtxos(&vtxo.to_ark_address()).await {
-            Ok(vtxos) => vtxos,
+        // Select coins and build the transaction the transfer would submit
+        let preview = client
+            .preview_send_vtxo(destination, Amount::from_sat(req.amount))
+            .await;
+        let preview = match preview {
+            Ok(Some(preview)) => preview,
+            Ok(None) => {
+                return Err(ApiError::new(ErrorCode::InsufficientFunds, "Insufficient funds"));
+            }
             Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to query virtual transactions: {}", e));
+                return Err(ApiError::caused_by("Failed to preview transfer", &e, ErrorCode::Internal));
             }
         };
 
-        // Map available virtual transactions
-        let mut spendable_vtxos = HashMap::new();
-        spendable_vtxos.insert(vtxo.clone(), vtxos.spendable);
+        let now = jiff::Timestamp::now().as_second();
+        Ok(HttpResponse::Ok().json(preview::describe(&req, &preview, now)))
+    }
 
-        // Process virtual transactions
-        let virtual_tx_outputs =
-            match list_virtual_tx_outpoints(find_outputs.clone(), spendable_vtxos) {
-                Ok(outputs) => outputs,
-                Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .body(format!("Failed to process virtual transactions: {}", e));
+    #[utoipa::path(
+        tag = "finance",
+        request_body = FundingRequest,
+        responses(
+            (status = 200, description = "Coins sent", body = FundingResponse),
+            (status = 400, description = "Invalid address, or amount above the faucet's cap", body = ApiError),
+            (status = 401, description = "Missing or invalid API token", body = ApiError),
+            (status = 403, description = "Faucet unavailable, or token scope too narrow", body = ApiError),
+            (status = 429, description = "Address funded too recently", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+    )]
+    #[post("/api/fund")]
+    pub async fn fund_account(
+        state: web::Data<ApplicationState>,
+        token: ApiToken,
+        req: web::Json<FundingRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        // Any account may use the faucet, but only with a token that may move funds
+        if !token.scope.allows(TokenScope::Full) {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Token scope does not allow this operation",
+            ));
+        }
+
+        if !state.faucet.is_enabled() {
+            return Err(ApiError::new(ErrorCode::FaucetUnavailable, "Faucet is disabled"));
+        }
+
+        // Never hand out real coins
+        let network = match state.server_connection.lock().unwrap().as_ref() {
+            Some(info) => info.network,
+            None => return Err(ApiError::network_unavailable()),
+        };
+        if network == bitcoin::Network::Bitcoin {
+            return Err(ApiError::new(
+                ErrorCode::FaucetUnavailable,
+                "Funding is not available on mainnet",
+            ));
+        }
+
+        // Validate input
+        let address = match req
+            .chain_address
+            .parse::<bitcoin::Address<_>>()
+            .ok()
+            .and_then(|address| address.require_network(network).ok())
+        {
+            Some(address) => address,
+            None => return Err(ApiError::new(ErrorCode::InvalidAddress, "Invalid address")),
+        };
+
+        if req.amount == 0 {
+            return Err(ApiError::invalid_request("Amount must be positive"));
+        }
+
+        // Send the coins
+        match state.faucet.fund(&address, Amount::from_sat(req.amount)).await {
+            Ok(txid) => Ok(HttpResponse::Ok().json(FundingResponse {
+                recipient: req.chain_address.clone(),
+                amount: req.amount,
+                transaction_id: txid.to_string(),
+            })),
+            Err(e) => match e.downcast_ref::<ApiError>() {
+                Some(error) => Err(error.clone()),
+                None => {
+                    // The cause may carry the signed transaction, so it is only logged
+                    tracing::error!("Funding {} failed: {:#}", req.chain_address, e);
+                    Err(ApiError::new(ErrorCode::Internal, "Funding failed"))
                 }
-            };
+            },
+        }
+    }
 
-        // Process on-chain outputs
-        let boarding_outputs = match list_boarding_outpoints(find_outputs, &[boarding_output]) {
-            Ok(outputs) => outputs,
+    #[utoipa::path(
+        tag = "finance",
+        params(("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key get the first response back")),
+        request_body = WithdrawalRequest,
+        responses(
+            (status = 200, description = "Funds settled in one round per address key", body = WithdrawalResponse),
+            (status = 400, description = "Invalid address or no funds to withdraw", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 409, description = "Request with the same Idempotency-Key in progress", body = ApiError),
+            (status = 422, description = "Idempotency-Key used for a different request", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 502, description = "Round failed", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/withdraw")]
+    pub async fn withdraw_funds(
+        token: ApiToken,
+        key: IdempotencyKey,
+        state: web::Data<ApplicationState>,
+        req: web::Json<WithdrawalRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        token.authorize(&req.account_id, TokenScope::Full)?;
+
+        let req = req.into_inner();
+        let account_id = req.account_id.clone();
+        let fingerprint = idempotency::fingerprint("withdraw", &req);
+
+        idempotency::run_once(&state, &account_id, key, fingerprint, withdraw(state.clone(), req))
+            .await
+    }
+
+    /// The body of `withdraw_funds`. Fails if no round was joined.
+    async fn withdraw(
+        state: web::Data<ApplicationState>,
+        req: WithdrawalRequest,
+    ) -> Result<HttpResponse, ApiError> {
+        // Retrieve account
+        let account = match state.accounts.get_account(&req.account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
             Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to process on-chain outputs: {}", e));
+                return Err(ApiError::internal("Failed to load account", e));
             }
         };
 
+        let client = account_client(&state, &account).await?;
+        let _payment = state.clients.lock_payments(&account.id).await;
+
         // Determine destination address
         let destination_address = match &req.destination_address {
-            Some(addr) => match ArkAddress::decode(addr) {
+            Some(addr) => ark_address(&state, addr, "destination address")?,
+            None => match client.primary_address() {
                 Ok(address) => address,
-                Err(_) => return HttpResponse::BadRequest().body("Invalid destination address"),
+                Err(_) => {
+                    return Err(ApiError::new(ErrorCode::Internal, "Address generation failed"));
+                }
             },
-            None => vtxo.to_ark_address(),
         };
 
-        // Process withdrawal
-        let withdrawal_result = execute_withdrawal(
-            &grpc_client,
-            &network_info,
-            private_key,
-            virtual_tx_outputs,
-            boarding_outputs,
-            destination_address,
-        )
-        .await;
+        // Take part in rounds until every key's funds are settled
+        let withdrawal_result = client
+            .settle_to(&mut thread_rng(), destination_address)
+            .await;
 
         // Handle result
         match withdrawal_result {
-            Ok(Some(txid)) => {
-                HttpResponse::Ok().json(WithdrawalResponse {
-                    account_id: account.id,
-                    success: true,
-                    transaction_id: Some(txid.to_string()),
-                    error_message: None,
-                })
-            }
-            Ok(None) => {
-                HttpResponse::Ok().json(WithdrawalResponse {
+            Ok(txids) if !txids.is_empty() => {
+                Ok(HttpResponse::Ok().json(WithdrawalResponse {
                     account_id: account.id,
-                    success: false,
-                    transaction_id: None,
-                    error_message: Some(
-                        "No available funds to withdraw at this time".to_string(),
-                    ),
-                })
+                    transaction_id: txids[0].to_string(),
+                    transaction_ids: txids.iter().map(|txid| txid.to_string()).collect(),
+                }))
             }
+            Ok(_) => Err(ApiError::new(
+                ErrorCode::InsufficientFunds,
+                "No available funds to withdraw at this time",
+            )),
+            // Rounds may have been joined by then, so the failure is stored
             Err(e) => {
-                HttpResponse::InternalServerError().json(WithdrawalResponse {
-                    account_id: account.id,
-                    success: false,
-                    transaction_id: None,
-                    error_message: Some(format!("Withdrawal failed: {}", e)),
-                })
+                events::report_round_failure(&state, &account.id, &e);
+
+                let error = ApiError::caused_by("Withdrawal failed", &e, ErrorCode::Internal);
+                Ok(error.error_response())
             }
         }
     }
 
-    // Internal helper function for withdrawal processing
-    async fn execute_withdrawal(
-        grpc_client: &ark_grpc::Client,
-        network_info: &ark_core::server::Info,
-        private_key: SecretKey,
-        vtxos: VirtualTxOutpoints,
-        boarding_outputs: BoardingOutpoints,
-        destination_address: ArkAddress,
-    ) -> Result<Option<Txid>, anyhow::Error> {
-        let secp = Secp256k1::new();
-        let mut rng = thread_rng();
-
-        // Check for available outputs
-        if vtxos.spendable.is_empty() && boarding_outputs.spendable.is_empty() {
-            return Ok(None);
-        }
-
-        // Generate a co-signer key
-        let cosigner_keypair = Keypair::new(&secp, &mut rng);
-
-        // Prepare round inputs
-        let round_inputs = {
-            let boarding_inputs = boarding_outputs
-                .spendable
-                .clone()
-                .into_iter()
-                .map(|o| RoundInput::new(o.0, o.2.tapscripts()));
-
-            let vtxo_inputs = vtxos
-                .spendable
-                .clone()
-                .into_iter()
-                .map(|v| RoundInput::new(v.0.outpoint, v.1.tapscripts()));
-
-            boarding_inputs.chain(vtxo_inputs).collect::<Vec<_>>()
-        };
-
-        // Register inputs for the next round
-        let payment_id = grpc_client
-            .register_inputs_for_next_round(&round_inputs)
-            .await?;
-
-        // Calculate total available amount
-        let available_amount = boarding_outputs.spendable_balance() + vtxos.spendable_balance();
-
-        // Prepare outputs
-        let round_outputs = vec![RoundOutput::new_virtual(destination_address, available_amount)];
-        
-        // Register outputs for the next round
-        grpc_client
-            .register_outputs_for_next_round(
-                payment_id.clone(),
-                &round_outputs,
-                &[cosigner_keypair.public_key()],
-                false,
-            )
-            .await?;
+    #[utoipa::path(
+        tag = "finance",
+        params(
+            ("account_id" = String, Path, description = "ID of the account"),
+            ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key get the first response back"),
+        ),
+        request_body = OffboardRequest,
+        responses(
+            (status = 200, description = "Rounds finalized, to be confirmed on-chain", body = OffboardResponse),
+            (status = 400, description = "Invalid address or amount, or insufficient funds", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 409, description = "Request with the same Idempotency-Key in progress", body = ApiError),
+            (status = 422, description = "Idempotency-Key used for a different request", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 502, description = "Round failed", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/accounts/{account_id}/offboard")]
+    pub async fn offboard_funds(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        key: IdempotencyKey,
+        state: web::Data<ApplicationState>,
+        req: web::Json<OffboardRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
+
+        let req = req.into_inner();
+        let fingerprint = idempotency::fingerprint("offboard", &req);
+
+        idempotency::run_once(
+            &state,
+            &account_id,
+            key,
+            fingerprint,
+            offboard(state.clone(), account_id.clone(), req),
+        )
+        .await
+    }
 
-        // Ping to initiate processing
-        grpc_client.ping(payment_id).await?;
+    /// The body of `offboard_funds`. Fails if no round was joined.
+    async fn offboard(
+        state: web::Data<ApplicationState>,
+        account_id: String,
+        req: OffboardRequest,
+    ) -> Result<HttpResponse, ApiError> {
+        // Retrieve account
+        let account = match state.accounts.get_account(&account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load account", e));
+            }
+        };
 
-        // Get event stream
-        let mut event_stream = grpc_client.get_event_stream().await?;
+        let client = account_client(&state, &account).await?;
+        let _payment = state.clients.lock_payments(&account.id).await;
 
-        // Wait for round signing event
-        let round_signing_event = match event_stream.next().await {
-            Some(Ok(RoundStreamEvent::RoundSigning(e))) => e,
-            other => {
-                return Err(anyhow::anyhow!(
-                    "Expected round signing event, got: {:?}",
-                    other
+        // Validate the on-chain destination
+        let network = match state.server_connection.lock().unwrap().as_ref() {
+            Some(info) => info.network,
+            None => return Err(ApiError::network_unavailable()),
+        };
+        let destination = match req
+            .destination_address
+            .parse::<bitcoin::Address<_>>()
+            .ok()
+            .and_then(|address| address.require_network(network).ok())
+        {
+            Some(address) => address,
+            None => {
+                return Err(ApiError::new(
+                    ErrorCode::InvalidAddress,
+                    format!("Invalid destination address for {}", network),
                 ));
             }
         };
 
-        let round_id = round_signing_event.id;
+        if req.amount == Some(0) {
+            return Err(ApiError::invalid_request("Amount must be positive"));
+        }
+
+        // Join a round that pays the destination on-chain and the change back to the account
+        let (txids, failure) = match req.amount {
+            Some(amount) => {
+                match client
+                    .off_board(&mut thread_rng(), &destination, Amount::from_sat(amount))
+                    .await
+                {
+                    Ok(Some(txid)) => (vec![txid], None),
+                    Ok(None) => {
+                        return Err(ApiError::new(
+                            ErrorCode::InsufficientFunds,
+                            "Insufficient funds in any single address; withdraw to the \
+                             account's first address to consolidate them",
+                        ));
+                    }
+                    Err(e) => (Vec::new(), Some(e)),
+                }
+            }
+            None => client.off_board_all(&mut thread_rng(), &destination).await,
+        };
 
-        // Get unsigned VTXO tree
-        let unsigned_vtxo_tree = round_signing_event
-            .unsigned_vtxo_tree
-            .expect("unsigned VTXO tree should be present");
+        let failure = failure.map(|e| {
+            events::report_round_failure(&state, &account.id, &e);
+            ApiError::caused_by("Off-boarding failed", &e, ErrorCode::Internal)
+        });
+
+        if txids.is_empty() {
+            return match failure {
+                // Rounds may have been joined by then, so the failure is stored
+                Some(error) => Ok(error.error_response()),
+                None => Err(ApiError::new(
+                    ErrorCode::InsufficientFunds,
+                    "No available funds to off-board at this time",
+                )),
+            };
+        }
 
-        // Generate nonce tree
-        let nonce_tree = generate_nonce_tree(&mut rng, &unsigned_vtxo_tree, cosigner_keypair.public_key())?;
+        events::report_confirmations(state.clone(), account.id.clone(), txids.clone());
 
-        // Submit nonce tree
-        grpc_client
-            .submit_tree_nonces(
-                &round_id,
-                cosigner_keypair.public_key(),
-                nonce_tree.to_pub_nonce_tree().into_inner(),
-            )
-            .await?;
-
-        // Wait for nonces generated event
-        let round_signing_nonces_generated_event = match event_stream.next().await {
-            Some(Ok(RoundStreamEvent::RoundSigningNoncesGenerated(e))) => e,
-            other => {
-                return Err(anyhow::anyhow!(
-                    "Expected nonces generated event, got: {:?}",
-                    other
-                ));
+        let status = match failure {
+            Some(_) => OffboardStatus::Partial,
+            None => OffboardStatus::Pending,
+        };
+
+        Ok(HttpResponse::Ok().json(OffboardResponse {
+            account_id: account.id,
+            destination_address: destination.to_string(),
+            transaction_id: txids[0].to_string(),
+            transaction_ids: txids.iter().map(|txid| txid.to_string()).collect(),
+            status,
+            error: failure,
+        }))
+    }
+
+    #[utoipa::path(
+        tag = "finance",
+        params(("account_id" = String, Path, description = "ID of the account")),
+        request_body = ExitRequest,
+        responses(
+            (status = 202, description = "Exit started", body = ExitJob),
+            (status = 400, description = "Invalid address, no VTXOs to exit, or not enough to pay the sweep fee", body = ApiError),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Account not found", body = ApiError),
+            (status = 409, description = "Some of the VTXOs are already being exited", body = ApiError),
+            (status = 423, description = "Account is locked", body = ApiError),
+            (status = 503, description = "Ark server or blockchain explorer unavailable", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[post("/api/accounts/{account_id}/exit")]
+    pub async fn start_exit(
+        account_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+        req: web::Json<ExitRequest>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Full)?;
+
+        // Retrieve account
+        let account = match state.accounts.get_account(&account_id) {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::account_not_found()),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load account", e));
             }
         };
 
-        let agg_pub_nonce_tree = round_signing_nonces_generated_event.tree_nonces;
+        // Signing the sweeps needs the account's keys
+        let keys = match state.unlocked_keys.get(&account.id) {
+            Some(keys) => keys,
+            None => return Err(ApiError::new(ErrorCode::AccountLocked, "Account is locked")),
+        };
 
-        // Sign VTXO tree
-        let partial_sig_tree = sign_vtxo_tree(
-            network_info.vtxo_tree_expiry,
-            network_info.pk.x_only_public_key().0,
-            &cosigner_keypair,
-            &unsigned_vtxo_tree,
-            &round_signing_event.unsigned_round_tx,
-            nonce_tree,
-            &agg_pub_nonce_tree.into(),
-        )?;
+        // Bring the exit paths up to date if the Ark server is reachable; otherwise exit the
+        // VTXOs whose paths were recorded before it went away
+        let (paths, unsettled) = match account_client(&state, &account).await {
+            Ok(client) => exits::record_exit_paths(&state, &account.id, &client)
+                .await
+                .map_err(|e| {
+                    ApiError::caused_by("Failed to fetch exit paths", &e, ErrorCode::Internal)
+                })?,
+            Err(e) if e.code == ErrorCode::NetworkUnavailable => {
+                tracing::warn!(
+                    account_id = account.id,
+                    "Exiting with the recorded exit paths: {}",
+                    e.message
+                );
+
+                let paths = state
+                    .accounts
+                    .list_exit_paths(&account.id)
+                    .map_err(|e| ApiError::internal("Failed to load exit paths", e))?;
+                (paths, Vec::new())
+            }
+            Err(e) => return Err(e),
+        };
 
-        // Submit signatures
-        grpc_client
-            .submit_tree_signatures(
-                &round_id,
-                cosigner_keypair.public_key(),
-                partial_sig_tree.into_inner(),
-            )
-            .await?;
-
-        // Wait for finalization event
-        let round_finalized_event = match event_stream.next().await {
-            Some(Ok(RoundStreamEvent::RoundFinalized(e))) => e,
-            other => {
-                return Err(anyhow::anyhow!(
-                    "Expected round finalized event, got: {:?}",
-                    other
+        // Validate the on-chain destination
+        let network = state
+            .server_connection
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|info| info.network)
+            .or(state.config.network)
+            .or_else(|| paths.first().and_then(|path| path.network.parse().ok()));
+        let Some(network) = network else {
+            return Err(ApiError::network_unavailable());
+        };
+        let destination = match req
+            .destination_address
+            .parse::<bitcoin::Address<_>>()
+            .ok()
+            .and_then(|address| address.require_network(network).ok())
+        {
+            Some(address) => address,
+            None => {
+                return Err(ApiError::new(ErrorCode::InvalidAddress, "Invalid destination address"));
+            }
+        };
+
+        if paths.is_empty() {
+            return Err(ApiError::new(ErrorCode::InsufficientFunds, "No VTXOs to exit"));
+        }
+
+        // A VTXO can only be exited once
+        let jobs = state
+            .accounts
+            .list_exit_jobs()
+            .map_err(|e| ApiError::internal("Failed to load exit jobs", e))?;
+        let exiting = jobs
+            .iter()
+            .filter(|job| job.account_id == account.id && job.status != ExitStatus::Failed)
+            .flat_map(|job| &job.vtxos)
+            .collect::<HashSet<_>>();
+        if let Some(path) = paths.iter().find(|path| exiting.contains(&path.outpoint)) {
+            return Err(ApiError::new(
+                ErrorCode::ExitInProgress,
+                format!("VTXO {} is already being exited", path.outpoint),
+            ));
+        }
+
+        // Sign a sweep per address key, paying the current fee rate
+        let blockchain_client = match state.blockchain_client.lock().unwrap().clone() {
+            Some(client) => client,
+            None => return Err(ApiError::blockchain_unavailable()),
+        };
+        let fee_rate = match blockchain_client.client.get_fee_estimates().await {
+            Ok(estimates) => exits::sweep_fee_rate(&estimates),
+            Err(e) => {
+                return Err(ApiError::new(
+                    ErrorCode::BlockchainUnavailable,
+                    format!("Failed to estimate the sweep fee: {e}"),
                 ));
             }
         };
 
-        // Return transaction ID
-        Ok(Some(round_finalized_event.round_txid))
+        let prepared = exits::prepare_exits(&keys, &paths, &destination, fee_rate).map_err(|e| {
+            ApiError::caused_by("Failed to prepare exit", &e, ErrorCode::InsufficientFunds)
+        })?;
+
+        // Persist the job; the exit runner takes it from here
+        let vtxos = paths.into_iter().map(|path| path.outpoint).collect();
+        let job = exits::new_job(&account.id, &destination, &prepared, vtxos, unsettled);
+        if let Err(e) = state.accounts.insert_exit_job(&job) {
+            return Err(ApiError::internal("Failed to store exit job", e));
+        }
+
+        Ok(HttpResponse::Accepted().json(job))
     }
-}
\ No newline at end of file
+
+    #[utoipa::path(
+        tag = "finance",
+        params(("job_id" = String, Path, description = "ID of the exit job")),
+        responses(
+            (status = 200, description = "Progress of the exit", body = ExitJob),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+            (status = 404, description = "Exit job not found, or of another account", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/exits/{job_id}")]
+    pub async fn get_exit(
+        job_id: web::Path<String>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        // The jobs of other accounts are not found either, so that their IDs cannot be probed
+        let job = match state.accounts.get_exit_job(&job_id) {
+            Ok(Some(job)) if job.account_id == token.account_id => job,
+            Ok(_) => return Err(ApiError::new(ErrorCode::ExitJobNotFound, "Exit job not found")),
+            Err(e) => {
+                return Err(ApiError::internal("Failed to load exit job", e));
+            }
+        };
+
+        token.authorize(&job.account_id, TokenScope::Read)?;
+
+        Ok(HttpResponse::Ok().json(job))
+    }
+
+    #[utoipa::path(
+        tag = "finance",
+        params(("account_id" = String, Path, description = "ID of the account"), RenewalQuery),
+        responses(
+            (status = 200, description = "Latest renewals, newest first", body = RenewalLog),
+            (status = 401, description = "Missing or unknown API token", body = ApiError),
+            (status = 403, description = "Token not valid for this account or operation", body = ApiError),
+        ),
+        security(("api_token" = [])),
+    )]
+    #[get("/api/accounts/{account_id}/renewals")]
+    pub async fn get_account_renewals(
+        account_id: web::Path<String>,
+        query: web::Query<RenewalQuery>,
+        token: ApiToken,
+        state: web::Data<ApplicationState>,
+    ) -> Result<HttpResponse, ApiError> {
+        let account_id = account_id.into_inner();
+        token.authorize(&account_id, TokenScope::Read)?;
+
+        let limit = query
+            .limit
+            .unwrap_or(history::DEFAULT_PAGE_SIZE)
+            .clamp(1, history::MAX_PAGE_SIZE);
+
+        match state.accounts.list_renewals(&account_id, None, limit) {
+            Ok(renewals) => Ok(HttpResponse::Ok().json(RenewalLog {
+                account_id,
+                renewals,
+            })),
+            Err(e) => Err(ApiError::internal("Failed to load renewal log", e)),
+        }
+    }
+}
+
+pub mod monitoring {
+    use actix_web::{get, web, HttpResponse, Responder};
+
+    use crate::core::model::{ApplicationState, HealthReport};
+    use crate::metrics::METRICS;
+    use crate::supervisor;
+
+    #[utoipa::path(
+        tag = "monitoring",
+        responses(
+            (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+        ),
+    )]
+    #[get("/metrics")]
+    pub async fn get_metrics(state: web::Data<ApplicationState>) -> impl Responder {
+        match state.accounts.count_accounts() {
+            Ok(accounts) => METRICS.accounts.set(accounts as i64),
+            Err(e) => tracing::warn!("Failed to count accounts for metrics: {:#}", e),
+        }
+        METRICS
+            .unlocked_accounts
+            .set(state.unlocked_keys.unlocked_count() as i64);
+
+        HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(METRICS.encode())
+    }
+
+    #[utoipa::path(
+        tag = "monitoring",
+        responses(
+            (status = 200, description = "The server is running; the health of its services", body = HealthReport),
+        ),
+    )]
+    #[get("/healthz")]
+    pub async fn get_health(state: web::Data<ApplicationState>) -> impl Responder {
+        HttpResponse::Ok().json(supervisor::report(&state))
+    }
+
+    #[utoipa::path(
+        tag = "monitoring",
+        responses(
+            (status = 200, description = "Every service is up", body = HealthReport),
+            (status = 503, description = "A service is down", body = HealthReport),
+        ),
+    )]
+    #[get("/readyz")]
+    pub async fn get_readiness(state: web::Data<ApplicationState>) -> impl Responder {
+        let report = supervisor::report(&state);
+
+        if report.ready {
+            HttpResponse::Ok().json(report)
+        } else {
+            HttpResponse::ServiceUnavailable().json(report)
+        }
+    }
+}
+
+pub mod docs {
+    use actix_web::{get, HttpResponse, Responder};
+    use utoipa::OpenApi;
+
+    use crate::openapi::ApiDoc;
+
+    #[utoipa::path(
+        tag = "monitoring",
+        responses(
+            (status = 200, description = "This document", content_type = "application/json"),
+        ),
+    )]
+    #[get("/api/openapi.json")]
+    pub async fn get_openapi() -> impl Responder {
+        HttpResponse::Ok().json(ApiDoc::openapi())
+    }
+}
@@ -0,0 +1,509 @@
+//! Bearer-token authentication for account-scoped routes.
+//!
+//! Every account gets a full-access API token when it is created or restored, and may issue
+//! more tokens with a narrower [`TokenScope`]. Accounts created before API tokens existed get
+//! their first one from the operator, through the `issue-tokens` command and
+//! [`bootstrap_tokens`]. The [`authenticate`] middleware resolves the
+//! `Authorization: Bearer <token>` header to the stored [`ApiToken`]; handlers then take the
+//! token as an extractor and check it against the account they act on with
+//! [`ApiToken::authorize`].
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use anyhow::Result;
+use bitcoin::hashes::{sha256, Hash};
+use rand::RngCore;
+use std::future::{ready, Ready};
+
+use crate::core::model::{ApiToken, ApplicationState, TokenScope};
+use crate::error::{ApiError, ErrorCode};
+use crate::storage::AccountStore;
+
+const TOKEN_PREFIX: &str = "ark_";
+const TOKEN_LEN: usize = 32;
+
+/// The SHA-256 hash under which the token `secret` is stored.
+pub fn hash_token(secret: &str) -> String {
+    sha256::Hash::hash(secret.as_bytes()).to_string()
+}
+
+/// Create and store a new token for `account_id`, returning its secret. The secret is not kept
+/// anywhere, so this is the only chance to hand it to the caller.
+pub fn issue_token(
+    accounts: &dyn AccountStore,
+    account_id: &str,
+    scope: TokenScope,
+) -> Result<String> {
+    let mut bytes = [0u8; TOKEN_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = format!("{TOKEN_PREFIX}{}", hex::encode(bytes));
+
+    accounts.insert_token(&ApiToken {
+        token_hash: hash_token(&secret),
+        account_id: account_id.to_string(),
+        scope,
+    })?;
+
+    Ok(secret)
+}
+
+/// Issue a full-access token to every account without any token, or only to `account_id`,
+/// returning the account IDs with the secrets. Accounts that have a token already are left
+/// alone, so that this cannot be used to get into an account its owner still controls.
+pub fn bootstrap_tokens(
+    accounts: &dyn AccountStore,
+    account_id: Option<&str>,
+) -> Result<Vec<(String, String)>> {
+    let ids = match account_id {
+        Some(account_id) => {
+            accounts
+                .get_account(account_id)?
+                .ok_or_else(|| anyhow::anyhow!("account {account_id} not found"))?;
+            vec![account_id.to_string()]
+        }
+        None => accounts
+            .list_accounts()?
+            .into_iter()
+            .map(|account| account.id)
+            .collect(),
+    };
+
+    let mut issued = Vec::new();
+    for id in ids {
+        if accounts.count_tokens(&id)? > 0 {
+            continue;
+        }
+
+        let secret = issue_token(accounts, &id, TokenScope::Full)?;
+        issued.push((id, secret));
+    }
+
+    Ok(issued)
+}
+
+/// Attach the [`ApiToken`] named by the request's bearer token, if any, to the request.
+///
+/// Requests without an `Authorization` header pass through, since some routes (such as account
+/// creation) are public; routes that need a token reject them when extracting [`ApiToken`].
+/// A header with an unknown or malformed token is rejected right away.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if let Some(header) = req.headers().get(actix_web::http::header::AUTHORIZATION) {
+        let secret = header
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "Malformed Authorization header"))?;
+
+        let state = req
+            .app_data::<web::Data<ApplicationState>>()
+            .ok_or_else(|| ApiError::new(ErrorCode::Internal, "Application state missing"))?;
+
+        let token = match state.accounts.get_token(&hash_token(secret.trim())) {
+            Ok(Some(token)) => token,
+            Ok(None) => return Err(ApiError::new(ErrorCode::Unauthorized, "Invalid API token").into()),
+            Err(e) => return Err(ApiError::internal("Failed to load API token", e).into()),
+        };
+
+        req.extensions_mut().insert(token);
+    }
+
+    next.call(req).await
+}
+
+impl FromRequest for ApiToken {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<ApiToken>()
+                .cloned()
+                .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "Missing API token")),
+        )
+    }
+}
+
+impl ApiToken {
+    /// Check that this token may act on `account_id` with at least `scope`.
+    pub fn authorize(&self, account_id: &str, scope: TokenScope) -> Result<(), ApiError> {
+        if self.account_id != account_id {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Token is not valid for this account",
+            ));
+        }
+
+        if !self.scope.allows(scope) {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Token scope does not allow this operation",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{middleware, test, App};
+    use serde_json::json;
+
+    use crate::api;
+    use crate::clients::ClientCache;
+    use crate::core::config::{
+        AppConfig, ConnectionConfig, FaucetConfig, KeyConfig, LoggingConfig, RenewalConfig,
+        ServerConfig, StorageConfig,
+    };
+    use crate::core::model::UserAccount;
+    use crate::events::EventHub;
+    use crate::faucet::DisabledFaucet;
+    use crate::keystore::{self, KeyCache};
+    use crate::storage;
+    use crate::supervisor::Health;
+    use std::sync::Mutex;
+
+    pub(crate) fn config(dir: &tempfile::TempDir) -> AppConfig {
+        AppConfig {
+            ark_server_url: "http://127.0.0.1:1".to_string(),
+            esplora_url: "http://127.0.0.1:1".to_string(),
+            network: None,
+            server: ServerConfig::default(),
+            logging: LoggingConfig::default(),
+            storage: StorageConfig::Sqlite {
+                path: dir.path().join("ark.db"),
+            },
+            keys: KeyConfig::default(),
+            faucet: FaucetConfig::default(),
+            renewal: RenewalConfig::default(),
+            connection: ConnectionConfig::default(),
+        }
+    }
+
+    pub(crate) fn state(dir: &tempfile::TempDir) -> web::Data<ApplicationState> {
+        state_from(config(dir))
+    }
+
+    pub(crate) fn state_from(config: AppConfig) -> web::Data<ApplicationState> {
+        web::Data::new(ApplicationState {
+            accounts: storage::open(&config.storage).unwrap(),
+            unlocked_keys: KeyCache::default(),
+            clients: ClientCache::default(),
+            events: EventHub::default(),
+            config,
+            server_connection: Mutex::new(None),
+            blockchain_client: Mutex::new(None),
+            faucet: Box::new(DisabledFaucet),
+            health: Health::default(),
+        })
+    }
+
+    pub(crate) fn add_account(state: &ApplicationState) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+
+        state
+            .accounts
+            .insert_account(&UserAccount {
+                id: id.clone(),
+                xpub: None,
+                key_count: 1,
+                public_key: None,
+                encrypted_key: None,
+                private_key: None,
+            })
+            .unwrap();
+
+        id
+    }
+
+    /// Store an exit job of `account_id`, returning its ID.
+    fn exit_job(state: &ApplicationState, account_id: &str) -> String {
+        use crate::core::model::{ExitJob, ExitStatus};
+
+        let job = ExitJob {
+            id: uuid::Uuid::new_v4().to_string(),
+            account_id: account_id.to_string(),
+            destination_address: "bcrt1q6rz28mcfaxtmd6v789l9rrlrusdprr9pz3cppk".to_string(),
+            status: ExitStatus::Committing,
+            exit_delay_seconds: 600,
+            spendable_at: None,
+            transactions: Vec::new(),
+            vtxos: Vec::new(),
+            unsettled_vtxos: Vec::new(),
+            error: None,
+            created_at: 0,
+            updated_at: 0,
+        };
+        state.accounts.insert_exit_job(&job).unwrap();
+
+        job.id
+    }
+
+    macro_rules! app {
+        ($state:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($state.clone())
+                    .wrap(middleware::from_fn(authenticate))
+                    .service(api::accounts::lock_account)
+                    .service(api::accounts::unlock_account)
+                    .service(api::accounts::issue_token)
+                    .service(api::accounts::add_account_address)
+                    .service(api::finance::get_account_balance)
+                    .service(api::finance::transfer_funds)
+                    .service(api::finance::fund_account)
+                    .service(api::finance::get_exit),
+            )
+            .await
+        };
+    }
+
+    fn lock(account_id: &str, token: Option<&str>) -> test::TestRequest {
+        let req = test::TestRequest::post().uri(&format!("/api/accounts/{account_id}/lock"));
+
+        match token {
+            Some(token) => req.insert_header(("Authorization", format!("Bearer {token}"))),
+            None => req,
+        }
+    }
+
+    #[actix_web::test]
+    async fn missing_or_unknown_token_is_unauthorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let app = app!(state);
+        let account = add_account(&state);
+
+        let res = test::call_service(&app, lock(&account, None).to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "unauthorized");
+
+        let Err(err) =
+            test::try_call_service(&app, lock(&account, Some("ark_nope")).to_request()).await
+        else {
+            panic!("unknown token was accepted");
+        };
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // The faucet is not open to the public either.
+        let req = test::TestRequest::post()
+            .uri("/api/fund")
+            .set_json(json!({ "chain_address": "", "amount": 1000 }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn accounts_without_tokens_get_one_from_the_operator() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let app = app!(state);
+
+        let legacy = add_account(&state);
+        let owned = add_account(&state);
+        issue_token(state.accounts.as_ref(), &owned, TokenScope::Read).unwrap();
+
+        let issued = bootstrap_tokens(state.accounts.as_ref(), None).unwrap();
+        assert_eq!(issued.len(), 1);
+        let (account_id, token) = &issued[0];
+        assert_eq!(account_id, &legacy);
+
+        // The token gives full access to its account.
+        let res = test::call_service(&app, lock(&legacy, Some(token)).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // Accounts are only bootstrapped once.
+        assert!(bootstrap_tokens(state.accounts.as_ref(), None)
+            .unwrap()
+            .is_empty());
+        assert!(bootstrap_tokens(state.accounts.as_ref(), Some(&owned))
+            .unwrap()
+            .is_empty());
+        assert!(bootstrap_tokens(state.accounts.as_ref(), Some("unknown")).is_err());
+    }
+
+    #[actix_web::test]
+    async fn cross_account_access_is_forbidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let app = app!(state);
+
+        let alice = add_account(&state);
+        let bob = add_account(&state);
+        let alice_token = issue_token(state.accounts.as_ref(), &alice, TokenScope::Full).unwrap();
+
+        let res = test::call_service(&app, lock(&bob, Some(&alice_token)).to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/api/transfer")
+            .insert_header(("Authorization", format!("Bearer {alice_token}")))
+            .set_json(json!({ "account_id": bob, "recipient": "", "amount": 1000 }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/accounts/{bob}/balance"))
+            .insert_header(("Authorization", format!("Bearer {alice_token}")))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/accounts/{bob}/tokens"))
+            .insert_header(("Authorization", format!("Bearer {alice_token}")))
+            .set_json(json!({ "scope": "full" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // Exit jobs of other accounts look like they do not exist.
+        let job = exit_job(&state, &bob);
+        for job_id in [job.as_str(), "unknown"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/exits/{job_id}"))
+                .insert_header(("Authorization", format!("Bearer {alice_token}")))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        let bob_token = issue_token(state.accounts.as_ref(), &bob, TokenScope::Read).unwrap();
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/exits/{job}"))
+            .insert_header(("Authorization", format!("Bearer {bob_token}")))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The token still works for its own account.
+        let res = test::call_service(&app, lock(&alice, Some(&alice_token)).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn read_only_token_cannot_move_funds() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let app = app!(state);
+
+        let account = add_account(&state);
+        let full_token = issue_token(state.accounts.as_ref(), &account, TokenScope::Full).unwrap();
+
+        // Issue a read-only token through the API.
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/accounts/{account}/tokens"))
+            .insert_header(("Authorization", format!("Bearer {full_token}")))
+            .set_json(json!({ "scope": "read" }))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let read_token = res["api_token"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::post()
+            .uri("/api/transfer")
+            .insert_header(("Authorization", format!("Bearer {read_token}")))
+            .set_json(json!({ "account_id": account, "recipient": "", "amount": 1000 }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = test::call_service(&app, lock(&account, Some(&read_token)).to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // Handing out an address changes the account.
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/accounts/{account}/addresses"))
+            .insert_header(("Authorization", format!("Bearer {read_token}")))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/api/fund")
+            .insert_header(("Authorization", format!("Bearer {read_token}")))
+            .set_json(json!({ "chain_address": "", "amount": 1000 }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // Balances pass authorization and only fail for lack of a network connection.
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/accounts/{account}/balance"))
+            .insert_header(("Authorization", format!("Bearer {read_token}")))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn legacy_keys_must_be_migrated_before_unlocking() {
+        use bitcoin::secp256k1::SecretKey;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let app = app!(state);
+
+        let secret_key = SecretKey::new(&mut rand::thread_rng());
+        let account = uuid::Uuid::new_v4().to_string();
+        state
+            .accounts
+            .insert_account(&UserAccount {
+                id: account.clone(),
+                xpub: None,
+                key_count: 1,
+                public_key: None,
+                encrypted_key: None,
+                private_key: Some(secret_key.display_secret().to_string()),
+            })
+            .unwrap();
+        let token = issue_token(state.accounts.as_ref(), &account, TokenScope::Full).unwrap();
+
+        let unlock = |passphrase: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/accounts/{account}/unlock"))
+                .insert_header(("Authorization", format!("Bearer {token}")))
+                .set_json(json!({ "passphrase": passphrase }))
+                .to_request()
+        };
+
+        // Unlocking does not get to choose the passphrase.
+        let res = test::call_service(&app, unlock("attacker passphrase")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "key_migration_required");
+        let stored = state.accounts.get_account(&account).unwrap().unwrap();
+        assert!(stored.encrypted_key.is_none());
+
+        let migrated =
+            keystore::migrate_legacy_keys(state.accounts.as_ref(), None, "operator passphrase")
+                .unwrap();
+        assert_eq!(migrated, vec![account.clone()]);
+        assert!(keystore::migrate_legacy_keys(state.accounts.as_ref(), None, "operator passphrase")
+            .unwrap()
+            .is_empty());
+
+        let res = test::call_service(&app, unlock("attacker passphrase")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = test::call_service(&app, unlock("operator passphrase")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let stored = state.accounts.get_account(&account).unwrap().unwrap();
+        assert!(stored.private_key.is_none());
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        assert_eq!(stored.public_key_at(0).unwrap(), secret_key.public_key(&secp));
+    }
+}
@@ -0,0 +1,660 @@
+//! Connected [`ark_client::Client`]s for unlocked accounts.
+//!
+//! An `ark_client::Client` signs with a single keypair, so an account is served by one client per
+//! address key it has handed out. The clients of an account are connected the first time a
+//! handler needs them and kept in the [`ClientCache`] until the account is locked, its keys
+//! expire, or it hands out a new address key.
+
+use anyhow::{anyhow, Result};
+use ark_bdk_wallet::Wallet;
+use ark_client::wallet::Persistence;
+use ark_client::{Client, ErrorKind, OfflineClient, SendPreview};
+use ark_core::descriptor::DescriptorTemplate;
+use ark_core::{ArkAddress, ArkTransaction, BoardingOutput, Vtxo};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::key::{Keypair, Secp256k1};
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::{Address, Amount, Network, Txid, XOnlyPublicKey};
+use jiff::Timestamp;
+use rand::{CryptoRng, Rng};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::core::config::AppConfig;
+use crate::core::model::{BlockchainClient, UserAccount, VtxoExitPath};
+use crate::error::ApiError;
+use crate::keystore::UnlockedKeys;
+use crate::metrics::METRICS;
+
+/// `ark_client` errors do not implement [`std::error::Error`], so they are carried over as an
+/// [`ApiError`] with the code of their kind. Failed rounds become a [`RoundFailed`], which
+/// callers can downcast to. Errors from the Ark server are counted in the metrics on the way.
+fn client_error(e: ark_client::Error) -> anyhow::Error {
+    if e.kind() == ErrorKind::ArkServer {
+        METRICS.grpc_errors.inc();
+    }
+
+    match e.round_failure() {
+        Some(event) => anyhow::Error::new(RoundFailed {
+            round_id: event.id.clone(),
+            reason: event.reason.clone(),
+        })
+        .context(e.to_string()),
+        None => anyhow::Error::new(ApiError::from(&e)),
+    }
+}
+
+/// The Ark server reported a `RoundFailed` event for a round a client had registered for.
+#[derive(Debug, Clone)]
+pub struct RoundFailed {
+    pub round_id: String,
+    pub reason: String,
+}
+
+impl fmt::Display for RoundFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "round {} failed: {}", self.round_id, self.reason)
+    }
+}
+
+impl std::error::Error for RoundFailed {}
+
+pub type ArkClient = Client<BlockchainClient, Wallet<BoardingOutputs>>;
+
+/// How many times a round that failed, e.g. because another participant dropped out, is joined
+/// again before settling gives up.
+const ROUND_RETRIES: usize = 3;
+
+/// The boarding outputs of one address key, along with the key that spends them.
+///
+/// They are only kept in memory: the client registers its boarding output again every time it
+/// connects.
+#[derive(Default)]
+pub struct BoardingOutputs {
+    outputs: Mutex<Vec<(SecretKey, BoardingOutput)>>,
+}
+
+impl Persistence for BoardingOutputs {
+    fn save_boarding_output(
+        &self,
+        sk: SecretKey,
+        boarding_output: BoardingOutput,
+    ) -> Result<(), ark_client::Error> {
+        let mut outputs = self.outputs.lock().unwrap();
+
+        if !outputs
+            .iter()
+            .any(|(_, known)| known.address() == boarding_output.address())
+        {
+            outputs.push((sk, boarding_output));
+        }
+
+        Ok(())
+    }
+
+    fn load_boarding_outputs(&self) -> Result<Vec<BoardingOutput>, ark_client::Error> {
+        Ok(self
+            .outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, boarding_output)| boarding_output.clone())
+            .collect())
+    }
+
+    fn sk_for_pk(&self, pk: &XOnlyPublicKey) -> Result<SecretKey, ark_client::Error> {
+        self.outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, boarding_output)| boarding_output.owner_pk() == *pk)
+            .map(|(sk, _)| *sk)
+            .ok_or_else(|| ark_client::Error::wallet(format!("no key for boarding output {pk}")))
+    }
+}
+
+/// The funds of an account, summed over all of its address keys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccountBalance {
+    /// VTXOs that have not reached their expiry yet.
+    pub virtual_available: Amount,
+    /// VTXOs past their expiry, which the Ark server may sweep.
+    pub virtual_expired: Amount,
+    pub boarding_confirmed: Amount,
+    pub boarding_expired: Amount,
+    pub boarding_pending: Amount,
+}
+
+/// The connected clients of one account, one per address key in derivation order.
+pub struct AccountClient {
+    clients: Vec<ArkClient>,
+}
+
+impl AccountClient {
+    async fn connect(
+        config: &AppConfig,
+        blockchain: &BlockchainClient,
+        network: Network,
+        account: &UserAccount,
+        keys: &UnlockedKeys,
+    ) -> Result<Self> {
+        let secp = Secp256k1::new();
+        let blockchain = Arc::new(blockchain.clone());
+
+        let mut clients = Vec::new();
+        for (index, public_key) in account.public_keys()?.into_iter().enumerate() {
+            let kp = Keypair::from_secret_key(&secp, &keys.secret_key(index as u32)?);
+            if kp.public_key() != public_key {
+                return Err(anyhow!("unlocked keys do not match address key {}", index));
+            }
+
+            let wallet = Wallet::new(
+                kp,
+                secp.clone(),
+                network,
+                &config.esplora_url,
+                BoardingOutputs::default(),
+            )?;
+
+            let client = OfflineClient::new(
+                format!("{}/{}", account.id, index),
+                kp,
+                blockchain.clone(),
+                Arc::new(wallet),
+                config.ark_server_url.clone(),
+            )
+            .connect()
+            .await
+            .map_err(client_error)?;
+
+            // Register the key's boarding output with the wallet, so that balances and rounds
+            // include it.
+            client.get_boarding_address().map_err(client_error)?;
+
+            clients.push(client);
+        }
+
+        Ok(Self { clients })
+    }
+
+    pub fn key_count(&self) -> u32 {
+        self.clients.len() as u32
+    }
+
+    /// The off-chain address of the account's first key, which receives consolidated funds.
+    pub fn primary_address(&self) -> Result<ArkAddress> {
+        let (address, _) = self.clients[0]
+            .get_offchain_address()
+            .map_err(client_error)?;
+        Ok(address)
+    }
+
+    pub async fn balance(&self) -> Result<AccountBalance> {
+        let now = Timestamp::now().as_second();
+
+        let mut balance = AccountBalance::default();
+        for client in &self.clients {
+            for (outpoints, _) in client.spendable_vtxos().await.map_err(client_error)? {
+                for outpoint in outpoints {
+                    if outpoint.expire_at > now {
+                        balance.virtual_available += outpoint.amount;
+                    } else {
+                        balance.virtual_expired += outpoint.amount;
+                    }
+                }
+            }
+
+            let boarding = client.boarding_balance().await.map_err(client_error)?;
+            balance.boarding_confirmed += boarding.confirmed();
+            balance.boarding_expired += boarding.expired();
+            balance.boarding_pending += boarding.pending();
+        }
+
+        Ok(balance)
+    }
+
+    /// The transaction history of every address key, in derivation order.
+    pub async fn transaction_histories(&self) -> Result<Vec<Vec<ArkTransaction>>> {
+        let mut histories = Vec::new();
+        for client in &self.clients {
+            histories.push(client.transaction_history().await.map_err(client_error)?);
+        }
+
+        Ok(histories)
+    }
+
+    /// The client of the address key holding the most off-chain funds, if it can afford
+    /// `amount`.
+    async fn richest_client(&self, amount: Amount) -> Result<Option<&ArkClient>> {
+        let mut richest: Option<(usize, Amount)> = None;
+        for (index, client) in self.clients.iter().enumerate() {
+            let available = client
+                .offchain_balance()
+                .await
+                .map_err(client_error)?
+                .total();
+            if richest.is_none_or(|(_, most)| available > most) {
+                richest = Some((index, available));
+            }
+        }
+
+        Ok(match richest {
+            Some((index, available)) if available >= amount => Some(&self.clients[index]),
+            _ => None,
+        })
+    }
+
+    /// Whether the off-chain funds of all address keys together can afford `amount`.
+    async fn can_afford(&self, amount: Amount) -> Result<bool> {
+        let mut available = Amount::ZERO;
+        for client in &self.clients {
+            available += client
+                .offchain_balance()
+                .await
+                .map_err(client_error)?
+                .total();
+        }
+
+        Ok(available >= amount)
+    }
+
+    /// The client of the first address key, which pays and gets the change, and those of the
+    /// other keys, whose VTXOs it may spend as well.
+    fn payers(&self) -> (&ArkClient, Vec<&ArkClient>) {
+        let (primary, others) = self
+            .clients
+            .split_first()
+            .expect("accounts have at least one address key");
+
+        (primary, others.iter().collect())
+    }
+
+    /// Send `amount` off-chain to `address`, spending the VTXOs of any address key. The change
+    /// goes back to the first key.
+    ///
+    /// Returns `None` if the account cannot afford it.
+    pub async fn send_vtxo(&self, address: ArkAddress, amount: Amount) -> Result<Option<Txid>> {
+        if !self.can_afford(amount).await? {
+            return Ok(None);
+        }
+
+        let (primary, others) = self.payers();
+        let psbt = primary
+            .send_vtxo_from(&others, address, amount)
+            .await
+            .map_err(client_error)?;
+        let tx = psbt
+            .extract_tx()
+            .map_err(|e| anyhow!("failed to extract transaction: {}", e))?;
+
+        Ok(Some(tx.compute_txid()))
+    }
+
+    /// Pay every `(address, amount)` of `outputs` off-chain, in as many redeem transactions as
+    /// it takes, spending the VTXOs of any address key as [`AccountClient::send_vtxo`] does.
+    ///
+    /// Returns the outcome of each transaction along with the positions of the outputs it pays,
+    /// or `None` if the account cannot afford the total. Fails only if no transaction could be
+    /// attempted, in which case nothing was submitted.
+    pub async fn send_vtxos(
+        &self,
+        outputs: &[(ArkAddress, Amount)],
+    ) -> Result<Option<Vec<(Vec<usize>, Result<Txid>)>>> {
+        let total = outputs.iter().map(|(_, amount)| *amount).sum();
+        if !self.can_afford(total).await? {
+            return Ok(None);
+        }
+
+        let (primary, others) = self.payers();
+        let transactions = primary
+            .send_vtxos_from(&others, outputs)
+            .await
+            .map_err(client_error)?;
+
+        let outcomes = transactions
+            .into_iter()
+            .map(|transaction| {
+                let txid = transaction.result.map_err(client_error).and_then(|psbt| {
+                    let tx = psbt
+                        .extract_tx()
+                        .map_err(|e| anyhow!("failed to extract transaction: {}", e))?;
+                    Ok(tx.compute_txid())
+                });
+
+                (transaction.outputs, txid)
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Some(outcomes))
+    }
+
+    /// What [`AccountClient::send_vtxo`] would do. Nothing is signed or submitted.
+    pub async fn preview_send_vtxo(
+        &self,
+        address: ArkAddress,
+        amount: Amount,
+    ) -> Result<Option<SendPreview>> {
+        if !self.can_afford(amount).await? {
+            return Ok(None);
+        }
+
+        let (primary, others) = self.payers();
+        let preview = primary
+            .preview_send_vtxo_from(&others, address, amount)
+            .await
+            .map_err(client_error)?;
+
+        Ok(Some(preview))
+    }
+
+    /// Settle the VTXOs and boarding outputs of every address key into a new VTXO at
+    /// `address`, joining one round per key that holds any funds.
+    ///
+    /// Returns the TXIDs of the rounds that were joined.
+    pub async fn settle_to<R>(&self, rng: &mut R, address: ArkAddress) -> Result<Vec<Txid>>
+    where
+        R: Rng + CryptoRng + Clone,
+    {
+        let mut txids = Vec::new();
+        for client in &self.clients {
+            if let Some(txid) = client
+                .settle_to(rng, address, ROUND_RETRIES)
+                .await
+                .map_err(client_error)?
+            {
+                txids.push(txid);
+            }
+        }
+
+        Ok(txids)
+    }
+
+    /// Send `amount` on-chain to `address` from the address key holding the most off-chain
+    /// funds, joining a round that sends the rest back to that key.
+    ///
+    /// Returns `None` if no single key can afford it.
+    pub async fn off_board<R>(
+        &self,
+        rng: &mut R,
+        address: &Address,
+        amount: Amount,
+    ) -> Result<Option<Txid>>
+    where
+        R: Rng + CryptoRng + Clone,
+    {
+        let Some(client) = self.richest_client(amount).await? else {
+            return Ok(None);
+        };
+
+        let txid = client
+            .off_board(rng, address.clone(), amount)
+            .await
+            .map_err(client_error)?;
+
+        Ok(Some(txid))
+    }
+
+    /// Send the funds of every address key on-chain to `address`, joining one round per key
+    /// that holds any funds.
+    ///
+    /// Returns the TXIDs of the rounds that were joined, and the error that stopped it if a key
+    /// failed. The funds of the keys before it went on-chain all the same.
+    pub async fn off_board_all<R>(
+        &self,
+        rng: &mut R,
+        address: &Address,
+    ) -> (Vec<Txid>, Option<anyhow::Error>)
+    where
+        R: Rng + CryptoRng + Clone,
+    {
+        let mut txids = Vec::new();
+        for client in &self.clients {
+            match client.off_board_all(rng, address.clone()).await {
+                Ok(txid) => txids.extend(txid),
+                Err(e) => return (txids, Some(client_error(e))),
+            }
+        }
+
+        (txids, None)
+    }
+
+    /// The earliest expiry of the spendable VTXOs of every address key, in derivation order,
+    /// along with their total amount. Keys without VTXOs have `None`.
+    pub async fn vtxo_expiries(&self) -> Result<Vec<Option<(i64, Amount)>>> {
+        let mut expiries = Vec::new();
+        for client in &self.clients {
+            let outpoints = client
+                .spendable_vtxos()
+                .await
+                .map_err(client_error)?
+                .into_iter()
+                .flat_map(|(outpoints, _)| outpoints)
+                .collect::<Vec<_>>();
+
+            let earliest = outpoints.iter().map(|outpoint| outpoint.expire_at).min();
+            let total = outpoints.iter().map(|outpoint| outpoint.amount).sum();
+
+            expiries.push(earliest.map(|expire_at| (expire_at, total)));
+        }
+
+        Ok(expiries)
+    }
+
+    /// Settle the funds of address key `index` back to its own off-chain address, renewing its
+    /// VTXOs.
+    ///
+    /// Returns the TXID of the round that was joined, or `None` if there was nothing to settle.
+    pub async fn renew<R>(&self, rng: &mut R, index: usize) -> Result<Option<Txid>>
+    where
+        R: Rng + CryptoRng + Clone,
+    {
+        let client = self
+            .clients
+            .get(index)
+            .ok_or_else(|| anyhow!("no address key {}", index))?;
+        let (address, _) = client.get_offchain_address().map_err(client_error)?;
+
+        client
+            .settle_to(rng, address, ROUND_RETRIES)
+            .await
+            .map_err(client_error)
+    }
+
+    /// The exit path of every settled VTXO of every address key, and the outpoints of the VTXOs
+    /// that are not settled in a round yet and so cannot be exited. Branches are only fetched
+    /// from the Ark server for VTXOs without a path in `known`.
+    pub async fn exit_paths(
+        &self,
+        known: &[VtxoExitPath],
+    ) -> Result<(Vec<VtxoExitPath>, Vec<String>)> {
+        let now = Timestamp::now().as_second();
+
+        let mut paths = Vec::new();
+        let mut unsettled = Vec::new();
+        for (index, client) in self.clients.iter().enumerate() {
+            let (settled, pending): (Vec<_>, Vec<_>) = client
+                .spendable_vtxos()
+                .await
+                .map_err(client_error)?
+                .into_iter()
+                .flat_map(|(outpoints, vtxo)| {
+                    outpoints.into_iter().map(move |outpoint| (outpoint, vtxo.clone()))
+                })
+                .filter(|(outpoint, _)| !outpoint.swept)
+                .partition(|(outpoint, _)| outpoint.redeem_tx.is_none());
+            unsettled.extend(pending.iter().map(|(outpoint, _)| outpoint.outpoint.to_string()));
+
+            let mut new = Vec::new();
+            for (outpoint, vtxo) in settled {
+                match known.iter().find(|path| path.outpoint == outpoint.outpoint.to_string()) {
+                    Some(path) => paths.push(path.clone()),
+                    None => new.push((outpoint, vtxo)),
+                }
+            }
+
+            let outpoints = new.iter().map(|(outpoint, _)| outpoint.clone()).collect::<Vec<_>>();
+            let branches = client.vtxo_branches(&outpoints).await.map_err(client_error)?;
+            let info = &client.server_info;
+            paths.extend(new.iter().zip(branches).map(|((outpoint, vtxo), branch_txs)| {
+                VtxoExitPath {
+                    outpoint: outpoint.outpoint.to_string(),
+                    key_index: index as u32,
+                    amount: outpoint.amount.to_sat(),
+                    branch_txs: branch_txs.iter().map(serialize_hex).collect(),
+                    server_pk: info.pk.x_only_public_key().0.to_string(),
+                    exit_delay: vtxo.exit_delay().to_consensus_u32(),
+                    network: info.network.to_string(),
+                    vtxo_template: vtxo_template(info, vtxo),
+                    recorded_at: now,
+                }
+            }));
+        }
+
+        Ok((paths, unsettled))
+    }
+}
+
+/// The [`AccountClient`]s of unlocked accounts.
+#[derive(Default)]
+pub struct ClientCache {
+    clients: Mutex<HashMap<String, Arc<AccountClient>>>,
+    /// Held while the clients of an account connect, so that concurrent requests wait for a
+    /// single connection instead of each making their own.
+    connecting: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// Held while an account spends its VTXOs, see [`ClientCache::lock_payments`].
+    payments: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl ClientCache {
+    /// The clients of `account`. They are connected if they are not cached yet, or if the
+    /// account has handed out address keys since they were.
+    pub async fn get_or_connect(
+        &self,
+        config: &AppConfig,
+        blockchain: &BlockchainClient,
+        network: Network,
+        account: &UserAccount,
+        keys: &UnlockedKeys,
+    ) -> Result<Arc<AccountClient>> {
+        if let Some(client) = self.cached(account) {
+            return Ok(client);
+        }
+
+        let connecting = self
+            .connecting
+            .lock()
+            .unwrap()
+            .entry(account.id.clone())
+            .or_default()
+            .clone();
+        let _connecting = connecting.lock().await;
+
+        // Another request may have connected while we waited.
+        if let Some(client) = self.cached(account) {
+            return Ok(client);
+        }
+
+        let client =
+            Arc::new(AccountClient::connect(config, blockchain, network, account, keys).await?);
+
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(account.id.clone(), client.clone());
+
+        Ok(client)
+    }
+
+    /// Wait until no other payment of `account_id` is in flight, and keep others out until the
+    /// guard is dropped.
+    ///
+    /// Transfers, withdrawals and renewals select VTXOs from the same snapshot, so two of them
+    /// running at once would spend the same VTXOs twice and one would fail.
+    pub async fn lock_payments(&self, account_id: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let payments = self
+            .payments
+            .lock()
+            .unwrap()
+            .entry(account_id.to_string())
+            .or_default()
+            .clone();
+
+        payments.lock_owned().await
+    }
+
+    /// The cached clients of `account`, if they cover all of its address keys.
+    fn cached(&self, account: &UserAccount) -> Option<Arc<AccountClient>> {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(&account.id)
+            .filter(|client| client.key_count() == account.key_count.max(1))
+            .cloned()
+    }
+
+    /// Drop the clients of `account_id`, e.g. because the account was locked.
+    pub fn evict(&self, account_id: &str) {
+        self.clients.lock().unwrap().remove(account_id);
+        self.connecting.lock().unwrap().remove(account_id);
+    }
+
+    /// Drop the clients of every account, e.g. because the Ark server's info changed.
+    pub fn clear(&self) {
+        self.clients.lock().unwrap().clear();
+    }
+}
+
+/// The VTXO descriptor template of the Ark server that `vtxo` was built from, if any.
+fn vtxo_template(info: &ark_core::server::Info, vtxo: &Vtxo) -> Option<String> {
+    let secp = Secp256k1::new();
+
+    info.vtxo_descriptor_templates
+        .iter()
+        .find(|template| {
+            DescriptorTemplate::parse(template).is_ok_and(|template| {
+                template.script_pubkey(&secp, vtxo.owner_pk()) == vtxo.script_pubkey()
+            })
+        })
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boarding_output(sk: &SecretKey) -> BoardingOutput {
+        let secp = Secp256k1::new();
+        let server = SecretKey::new(&mut rand::thread_rng()).x_only_public_key(&secp).0;
+
+        BoardingOutput::new(
+            &secp,
+            server,
+            sk.x_only_public_key(&secp).0,
+            bitcoin::Sequence::from_seconds_ceil(86_400).unwrap(),
+            Network::Regtest,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn boarding_outputs_remember_their_keys() {
+        let db = BoardingOutputs::default();
+        let secp = Secp256k1::new();
+        let sk = SecretKey::new(&mut rand::thread_rng());
+        let output = boarding_output(&sk);
+
+        // Registering the same output again, as every reconnect does, keeps a single copy.
+        db.save_boarding_output(sk, output.clone()).unwrap();
+        db.save_boarding_output(sk, output.clone()).unwrap();
+
+        let loaded = db.load_boarding_outputs().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].address(), output.address());
+
+        assert_eq!(db.sk_for_pk(&output.owner_pk()).unwrap(), sk);
+
+        let other = SecretKey::new(&mut rand::thread_rng());
+        assert!(db.sk_for_pk(&other.x_only_public_key(&secp).0).is_err());
+    }
+}
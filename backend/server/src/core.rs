@@ -1,26 +1,604 @@
 pub mod config {
+    use anyhow::{anyhow, Context, Result};
+    use bitcoin::Network;
     use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// The configuration file used without `--config`, in the working directory.
+    pub const DEFAULT_PATH: &str = "ark.config.toml";
 
     #[derive(Deserialize, Clone)]
     pub struct AppConfig {
         pub ark_server_url: String,
         pub esplora_url: String,
+        /// The network the Ark server and esplora must be on. Without it, esplora is checked
+        /// against the Ark server's network.
+        #[serde(default, deserialize_with = "deserialize_network")]
+        pub network: Option<Network>,
+        #[serde(default)]
+        pub server: ServerConfig,
+        #[serde(default)]
+        pub logging: LoggingConfig,
+        #[serde(default)]
+        pub storage: StorageConfig,
+        #[serde(default)]
+        pub keys: KeyConfig,
+        #[serde(default)]
+        pub faucet: FaucetConfig,
+        #[serde(default)]
+        pub renewal: RenewalConfig,
+        #[serde(default)]
+        pub connection: ConnectionConfig,
+    }
+
+    fn deserialize_network<'de, D>(deserializer: D) -> Result<Option<Network>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let Some(name) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+
+        name.parse().map(Some).map_err(serde::de::Error::custom)
+    }
+
+    /// Where and how the HTTP API is served, e.g.
+    ///
+    /// ```toml
+    /// [server]
+    /// bind = "0.0.0.0:8443"
+    /// cors_origins = ["https://wallet.example.com"]
+    ///
+    /// [server.tls]
+    /// cert_path = "certs/server.pem"
+    /// key_path = "certs/server.key"
+    /// ```
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct ServerConfig {
+        #[serde(default = "default_bind")]
+        pub bind: String,
+        /// Serve HTTPS instead of HTTP.
+        #[serde(default)]
+        pub tls: Option<TlsConfig>,
+        /// Origins that browsers may call the API from, or `"*"` for any.
+        #[serde(default)]
+        pub cors_origins: Vec<String>,
+    }
+
+    impl Default for ServerConfig {
+        fn default() -> Self {
+            Self {
+                bind: default_bind(),
+                tls: None,
+                cors_origins: Vec::new(),
+            }
+        }
+    }
+
+    fn default_bind() -> String {
+        "127.0.0.1:8080".to_string()
+    }
+
+    /// A PEM certificate chain and the PEM private key that goes with it.
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct TlsConfig {
+        pub cert_path: PathBuf,
+        pub key_path: PathBuf,
+    }
+
+    #[derive(Deserialize, Clone, Debug, Default)]
+    pub struct LoggingConfig {
+        /// Log one JSON object per line instead of human-readable text.
+        #[serde(default)]
+        pub json: bool,
+        /// A `tracing` filter directive, such as `info,ark_client=debug`, replacing the default.
+        #[serde(default)]
+        pub filter: Option<String>,
+    }
+
+    #[derive(Clone, Copy)]
+    enum EnvValue {
+        String,
+        Bool,
+        /// Comma-separated.
+        List,
+    }
+
+    /// Environment variables that override a key of the configuration file.
+    const ENV_OVERRIDES: &[(&str, &str, EnvValue)] = &[
+        ("ARK_SERVER_URL", "ark_server_url", EnvValue::String),
+        ("ARK_ESPLORA_URL", "esplora_url", EnvValue::String),
+        ("ARK_NETWORK", "network", EnvValue::String),
+        ("ARK_BIND", "server.bind", EnvValue::String),
+        ("ARK_TLS_CERT", "server.tls.cert_path", EnvValue::String),
+        ("ARK_TLS_KEY", "server.tls.key_path", EnvValue::String),
+        ("ARK_CORS_ORIGINS", "server.cors_origins", EnvValue::List),
+        ("ARK_LOG_JSON", "logging.json", EnvValue::Bool),
+        ("ARK_LOG_FILTER", "logging.filter", EnvValue::String),
+        ("ARK_STORAGE_BACKEND", "storage.backend", EnvValue::String),
+        ("ARK_STORAGE_PATH", "storage.path", EnvValue::String),
+    ];
+
+    /// Read the configuration file at `path` and apply the `ARK_*` environment overrides. Unless
+    /// `required`, a missing file counts as empty, so that everything can come from the
+    /// environment.
+    pub fn load(path: &Path, required: bool) -> Result<AppConfig> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", path.display()));
+            }
+        };
+
+        parse(&contents, std::env::vars())
+            .with_context(|| format!("invalid configuration in {}", path.display()))
+    }
+
+    /// Parse the contents of a configuration file, applying the overrides found in `env`.
+    pub fn parse(
+        contents: &str,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<AppConfig> {
+        let mut table: toml::Table = toml::from_str(contents)?;
+        let env = env.into_iter().collect::<HashMap<_, _>>();
+
+        for (var, key, kind) in ENV_OVERRIDES {
+            let Some(raw) = env.get(*var) else {
+                continue;
+            };
+
+            let value = match kind {
+                EnvValue::String => toml::Value::String(raw.clone()),
+                EnvValue::Bool => match raw.trim().to_ascii_lowercase().as_str() {
+                    "1" | "true" | "yes" => toml::Value::Boolean(true),
+                    "0" | "false" | "no" => toml::Value::Boolean(false),
+                    _ => return Err(anyhow!("{var} must be true or false, not {raw:?}")),
+                },
+                EnvValue::List => toml::Value::Array(
+                    raw.split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .map(|item| toml::Value::String(item.to_string()))
+                        .collect(),
+                ),
+            };
+
+            set(&mut table, key, value).with_context(|| format!("cannot apply {var}"))?;
+        }
+
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    /// Set the dotted `key` of `table` to `value`, creating the tables on the way.
+    fn set(table: &mut toml::Table, key: &str, value: toml::Value) -> Result<()> {
+        let (parents, name) = match key.rsplit_once('.') {
+            Some((parents, name)) => (parents.split('.').collect::<Vec<_>>(), name),
+            None => (Vec::new(), key),
+        };
+
+        let mut table = table;
+        for parent in parents {
+            table = table
+                .entry(parent)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("{parent} is not a table"))?;
+        }
+
+        table.insert(name.to_string(), value);
+
+        Ok(())
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct KeyConfig {
+        /// How long an unlocked account keeps its decrypted key in memory. Unlock requests may
+        /// ask for a shorter TTL, never a longer one.
+        #[serde(default = "default_unlock_ttl_seconds")]
+        pub unlock_ttl_seconds: u64,
+    }
+
+    impl Default for KeyConfig {
+        fn default() -> Self {
+            Self {
+                unlock_ttl_seconds: default_unlock_ttl_seconds(),
+            }
+        }
+    }
+
+    fn default_unlock_ttl_seconds() -> u64 {
+        15 * 60
+    }
+
+    /// Where accounts are persisted, e.g.
+    ///
+    /// ```toml
+    /// [storage]
+    /// backend = "sqlite"
+    /// path = "wallets/ark.db"
+    /// ```
+    #[derive(Deserialize, Clone, Debug)]
+    #[serde(tag = "backend", rename_all = "lowercase")]
+    pub enum StorageConfig {
+        /// A single embedded SQLite database file.
+        Sqlite { path: PathBuf },
+        /// One JSON file per account inside a directory.
+        File { path: PathBuf },
+    }
+
+    impl Default for StorageConfig {
+        fn default() -> Self {
+            StorageConfig::Sqlite {
+                path: PathBuf::from("wallets/ark.db"),
+            }
+        }
+    }
+
+    /// Where `POST /api/fund` gets its coins from, e.g.
+    ///
+    /// ```toml
+    /// [faucet]
+    /// backend = "bitcoind"
+    /// url = "http://127.0.0.1:18443"
+    /// user = "admin1"
+    /// password = "123"
+    /// ```
+    ///
+    /// Funding is always refused on mainnet. Every request is capped at `max_amount_sats`, and
+    /// an address that was funded is not funded again for `address_cooldown_seconds`.
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct FaucetConfig {
+        #[serde(flatten)]
+        pub backend: FaucetBackend,
+        #[serde(default = "default_faucet_max_amount_sats")]
+        pub max_amount_sats: u64,
+        #[serde(default = "default_faucet_address_cooldown_seconds")]
+        pub address_cooldown_seconds: u64,
+    }
+
+    impl Default for FaucetConfig {
+        fn default() -> Self {
+            Self {
+                backend: FaucetBackend::default(),
+                max_amount_sats: default_faucet_max_amount_sats(),
+                address_cooldown_seconds: default_faucet_address_cooldown_seconds(),
+            }
+        }
+    }
+
+    fn default_faucet_max_amount_sats() -> u64 {
+        1_000_000
+    }
+
+    fn default_faucet_address_cooldown_seconds() -> u64 {
+        10 * 60
+    }
+
+    /// Where the faucet's coins come from.
+    #[derive(Deserialize, Clone, Debug, Default)]
+    #[serde(tag = "backend", rename_all = "lowercase")]
+    pub enum FaucetBackend {
+        /// Funding requests are rejected.
+        #[default]
+        Disabled,
+        /// `sendtoaddress` on the wallet of a regtest bitcoind, over JSON-RPC.
+        Bitcoind {
+            url: String,
+            user: String,
+            password: String,
+        },
+        /// A single-key P2WPKH hot wallet whose coins are found and spent through the configured
+        /// esplora server.
+        Esplora {
+            /// Hex-encoded secret key of the hot wallet.
+            secret_key: String,
+            #[serde(default = "default_fee_rate")]
+            fee_rate: u64,
+        },
+    }
+
+    /// Fee rate of hot wallet payments, in sats per virtual byte.
+    fn default_fee_rate() -> u64 {
+        2
+    }
+
+    /// Automatic renewal of VTXOs before they expire, e.g.
+    ///
+    /// ```toml
+    /// [renewal]
+    /// threshold = 0.2
+    /// interval_seconds = 300
+    ///
+    /// [renewal.retry]
+    /// max_attempts = 5
+    /// ```
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct RenewalConfig {
+        #[serde(default = "default_renewal_enabled")]
+        pub enabled: bool,
+        /// Renew the VTXOs of an address key once the earliest of them has less than this
+        /// fraction of the VTXO tree lifetime left.
+        #[serde(default = "default_renewal_threshold")]
+        pub threshold: f64,
+        /// How often accounts are checked for expiring VTXOs.
+        #[serde(default = "default_renewal_interval_seconds")]
+        pub interval_seconds: u64,
+        #[serde(default)]
+        pub retry: RetryPolicy,
+    }
+
+    impl Default for RenewalConfig {
+        fn default() -> Self {
+            Self {
+                enabled: default_renewal_enabled(),
+                threshold: default_renewal_threshold(),
+                interval_seconds: default_renewal_interval_seconds(),
+                retry: RetryPolicy::default(),
+            }
+        }
+    }
+
+    fn default_renewal_enabled() -> bool {
+        true
+    }
+
+    fn default_renewal_threshold() -> f64 {
+        0.2
+    }
+
+    fn default_renewal_interval_seconds() -> u64 {
+        5 * 60
+    }
+
+    /// How failed renewals of the same VTXOs, or failed webhook deliveries, are retried. The delay
+    /// doubles after every failure, starting at `backoff_seconds`, up to `max_backoff_seconds`.
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct RetryPolicy {
+        /// Give up after this many failed attempts in a row.
+        #[serde(default = "default_max_attempts")]
+        pub max_attempts: u32,
+        #[serde(default = "default_backoff_seconds")]
+        pub backoff_seconds: u64,
+        #[serde(default = "default_max_backoff_seconds")]
+        pub max_backoff_seconds: u64,
+    }
+
+    impl RetryPolicy {
+        /// The delay before the next attempt after `failures` failed attempts in a row.
+        pub fn delay(&self, failures: u32) -> u64 {
+            2u64
+                .checked_pow(failures.saturating_sub(1))
+                .and_then(|factor| self.backoff_seconds.checked_mul(factor))
+                .unwrap_or(u64::MAX)
+                .min(self.max_backoff_seconds)
+        }
+    }
+
+    impl Default for RetryPolicy {
+        fn default() -> Self {
+            Self {
+                max_attempts: default_max_attempts(),
+                backoff_seconds: default_backoff_seconds(),
+                max_backoff_seconds: default_max_backoff_seconds(),
+            }
+        }
+    }
+
+    fn default_max_attempts() -> u32 {
+        5
+    }
+
+    fn default_backoff_seconds() -> u64 {
+        60
+    }
+
+    fn default_max_backoff_seconds() -> u64 {
+        60 * 60
+    }
+
+    /// How the connection to the Ark server is kept up, e.g.
+    ///
+    /// ```toml
+    /// [connection]
+    /// refresh_interval_seconds = 60
+    /// max_backoff_seconds = 120
+    /// ```
+    ///
+    /// While the Ark server is unreachable, reconnecting is retried with a delay that doubles
+    /// after every failure, starting at `backoff_seconds`, up to `max_backoff_seconds`. Account
+    /// event watchers reconnect their streams the same way.
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct ConnectionConfig {
+        /// How often the Ark server's info is fetched again while it is reachable.
+        #[serde(default = "default_refresh_interval_seconds")]
+        pub refresh_interval_seconds: u64,
+        #[serde(default = "default_reconnect_backoff_seconds")]
+        pub backoff_seconds: u64,
+        #[serde(default = "default_reconnect_max_backoff_seconds")]
+        pub max_backoff_seconds: u64,
+    }
+
+    impl ConnectionConfig {
+        /// How reconnecting is retried. It never gives up.
+        pub fn retry_policy(&self) -> RetryPolicy {
+            RetryPolicy {
+                max_attempts: u32::MAX,
+                backoff_seconds: self.backoff_seconds,
+                max_backoff_seconds: self.max_backoff_seconds,
+            }
+        }
+    }
+
+    impl Default for ConnectionConfig {
+        fn default() -> Self {
+            Self {
+                refresh_interval_seconds: default_refresh_interval_seconds(),
+                backoff_seconds: default_reconnect_backoff_seconds(),
+                max_backoff_seconds: default_reconnect_max_backoff_seconds(),
+            }
+        }
+    }
+
+    fn default_refresh_interval_seconds() -> u64 {
+        60
+    }
+
+    fn default_reconnect_backoff_seconds() -> u64 {
+        1
+    }
+
+    fn default_reconnect_max_backoff_seconds() -> u64 {
+        60
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const MINIMAL: &str = r#"
+            ark_server_url = "http://localhost:7070"
+            esplora_url = "http://localhost:30000"
+        "#;
+
+        fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        }
+
+        #[test]
+        fn missing_sections_get_defaults() {
+            let config = parse(MINIMAL, env(&[])).unwrap();
+
+            assert_eq!(config.network, None);
+            assert_eq!(config.server.bind, "127.0.0.1:8080");
+            assert!(config.server.tls.is_none());
+            assert!(config.server.cors_origins.is_empty());
+            assert!(!config.logging.json);
+        }
+
+        #[test]
+        fn faucet_limits_sit_next_to_the_backend() {
+            let config = parse(MINIMAL, env(&[])).unwrap().faucet;
+            assert!(matches!(config.backend, FaucetBackend::Disabled));
+            assert_eq!(config.max_amount_sats, 1_000_000);
+
+            let contents = format!(
+                "{MINIMAL}
+                [faucet]
+                backend = \"esplora\"
+                secret_key = \"01\"
+                max_amount_sats = 5000
+                address_cooldown_seconds = 30"
+            );
+            let config = parse(&contents, env(&[])).unwrap().faucet;
+
+            assert!(matches!(
+                config.backend,
+                FaucetBackend::Esplora { fee_rate: 2, .. }
+            ));
+            assert_eq!(config.max_amount_sats, 5000);
+            assert_eq!(config.address_cooldown_seconds, 30);
+        }
+
+        #[test]
+        fn reconnecting_backs_off_up_to_the_maximum() {
+            let config = parse(MINIMAL, env(&[])).unwrap().connection;
+
+            assert_eq!(config.refresh_interval_seconds, 60);
+
+            let retry = config.retry_policy();
+            assert_eq!(retry.delay(1), 1);
+            assert_eq!(retry.delay(4), 8);
+            assert_eq!(retry.delay(7), 60);
+            assert_eq!(retry.delay(200), 60);
+        }
+
+        #[test]
+        fn environment_overrides_the_file() {
+            let contents = format!(
+                "{MINIMAL}
+                network = \"signet\"
+
+                [server]
+                bind = \"0.0.0.0:80\"
+                cors_origins = [\"https://old.example\"]"
+            );
+
+            let config = parse(
+                &contents,
+                env(&[
+                    ("ARK_NETWORK", "regtest"),
+                    ("ARK_BIND", "0.0.0.0:8443"),
+                    ("ARK_TLS_CERT", "cert.pem"),
+                    ("ARK_TLS_KEY", "key.pem"),
+                    ("ARK_CORS_ORIGINS", "https://a.example, https://b.example"),
+                    ("ARK_LOG_JSON", "true"),
+                    ("ARK_UNRELATED", "ignored"),
+                ]),
+            )
+            .unwrap();
+
+            assert_eq!(config.network, Some(Network::Regtest));
+            assert_eq!(config.server.bind, "0.0.0.0:8443");
+            let tls = config.server.tls.unwrap();
+            assert_eq!(tls.cert_path, PathBuf::from("cert.pem"));
+            assert_eq!(tls.key_path, PathBuf::from("key.pem"));
+            assert_eq!(
+                config.server.cors_origins,
+                vec!["https://a.example", "https://b.example"]
+            );
+            assert!(config.logging.json);
+
+            // Without a file, everything comes from the environment.
+            let config = parse(
+                "",
+                env(&[
+                    ("ARK_SERVER_URL", "http://ark:7070"),
+                    ("ARK_ESPLORA_URL", "http://esplora:3000"),
+                ]),
+            )
+            .unwrap();
+            assert_eq!(config.ark_server_url, "http://ark:7070");
+        }
+
+        #[test]
+        fn invalid_settings_are_rejected() {
+            assert!(parse("", env(&[])).is_err());
+            assert!(parse(&format!("{MINIMAL}network = \"moon\""), env(&[])).is_err());
+            assert!(parse(MINIMAL, env(&[("ARK_LOG_JSON", "maybe")])).is_err());
+            assert!(parse(
+                &format!("{MINIMAL}server = \"0.0.0.0:80\""),
+                env(&[("ARK_BIND", "0.0.0.0:8080")])
+            )
+            .is_err());
+        }
     }
 }
 
 pub mod model {
     use serde::{Deserialize, Serialize};
-    use std::collections::HashMap;
+    use utoipa::{IntoParams, ToSchema};
     use std::sync::Mutex;
     use bitcoin::Txid;
     use ark_core::ArkAddress;
-    use ark_core::ExplorerUtxo;
+    use ark_client::{ExplorerUtxo, SpendStatus};
+    use ark_core::descriptor;
+    use ark_core::{BoardingOutput, Vtxo};
     use bitcoin::Amount;
 
+    use crate::clients::ClientCache;
     use crate::core::config;
-
-    pub use ark_core::vtxo::VirtualTxOutpoints;
-    pub use ark_core::boarding_output::BoardingOutpoints;
+    use crate::error::ApiError;
+    use crate::events::EventHub;
+    use crate::faucet::Faucet;
+    use crate::keystore::{self, EncryptedKey, KeyCache};
+    use crate::metrics;
+    use crate::storage::AccountStore;
+    use crate::supervisor::Health;
 
     #[derive(Clone)]
     pub struct CryptoAddress(pub ArkAddress);
@@ -37,56 +615,375 @@ pub mod model {
     #[derive(Serialize, Deserialize, Clone)]
     pub struct UserAccount {
         pub id: String,
-        pub private_key: String,
+        /// Account-level extended public key of an account backed by a mnemonic. Address key `i`
+        /// is derived from it at `0/i`, so addresses are available while the account is locked.
+        #[serde(default)]
+        pub xpub: Option<String>,
+        /// Number of address keys handed out so far. Balances and withdrawals scan all of them.
+        #[serde(default = "default_key_count")]
+        pub key_count: u32,
+        /// Hex-encoded public key of a single-key account created before mnemonics were
+        /// introduced.
+        #[serde(default)]
+        pub public_key: Option<String>,
+        /// The encrypted BIP39 seed, or the encrypted secret key of a single-key account.
+        #[serde(default)]
+        pub encrypted_key: Option<EncryptedKey>,
+        /// Plaintext key of an account created before keys were encrypted at rest. The account
+        /// cannot be unlocked until the operator encrypts it with the `migrate-keys` command.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub private_key: Option<String>,
+    }
+
+    fn default_key_count() -> u32 {
+        1
+    }
+
+    impl UserAccount {
+        pub fn is_hd(&self) -> bool {
+            self.xpub.is_some()
+        }
+
+        /// The public key of address key `index`.
+        pub fn public_key_at(
+            &self,
+            index: u32,
+        ) -> Result<bitcoin::secp256k1::PublicKey, anyhow::Error> {
+            if let Some(xpub) = &self.xpub {
+                let xpub: bitcoin::bip32::Xpub = xpub.parse()?;
+                return keystore::derive_public_key(&xpub, index);
+            }
+
+            if index != 0 {
+                return Err(anyhow::anyhow!(
+                    "single-key account {} has no key {}",
+                    self.id,
+                    index
+                ));
+            }
+
+            if let Some(public_key) = &self.public_key {
+                return Ok(public_key.parse()?);
+            }
+
+            match &self.private_key {
+                Some(private_key) => {
+                    let secret_key: bitcoin::secp256k1::SecretKey = private_key.parse()?;
+                    let secp = bitcoin::secp256k1::Secp256k1::new();
+                    Ok(secret_key.public_key(&secp))
+                }
+                None => Err(anyhow::anyhow!("account {} has no public key", self.id)),
+            }
+        }
+
+        /// The public keys of all address keys handed out so far, in derivation order.
+        pub fn public_keys(
+            &self,
+        ) -> Result<Vec<bitcoin::secp256k1::PublicKey>, anyhow::Error> {
+            (0..self.key_count.max(1))
+                .map(|index| self.public_key_at(index))
+                .collect()
+        }
+
+        /// The boarding output and VTXO of address key `index`.
+        pub fn key_outputs(
+            &self,
+            network_info: &ark_core::server::Info,
+            index: u32,
+        ) -> Result<(BoardingOutput, Vtxo), anyhow::Error> {
+            let public_key = self.public_key_at(index)?;
+            key_outputs(network_info, &public_key)
+        }
+    }
+
+    fn key_outputs(
+        network_info: &ark_core::server::Info,
+        public_key: &bitcoin::secp256k1::PublicKey,
+    ) -> Result<(BoardingOutput, Vtxo), anyhow::Error> {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let (owner, _) = public_key.x_only_public_key();
+
+        let boarding_output = descriptor::boarding_output(&secp, network_info, owner)?;
+        let vtxo = descriptor::default_vtxo(&secp, network_info, owner)?;
+
+        Ok((boarding_output, vtxo))
     }
 
     pub struct ApplicationState {
-        pub accounts: Mutex<HashMap<String, UserAccount>>,
+        pub accounts: Box<dyn AccountStore>,
+        pub unlocked_keys: KeyCache,
+        pub clients: ClientCache,
+        pub events: EventHub,
         pub config: config::AppConfig,
-        pub server_connection: Option<Mutex<ark_core::server::Info>>,
-        pub blockchain_client: Option<Mutex<BlockchainClient>>,
+        /// The Ark server's info, kept up to date by [`crate::supervisor`]. `None` until the
+        /// Ark server has been reached.
+        pub server_connection: Mutex<Option<ark_core::server::Info>>,
+        /// The esplora client. `None` until it could be built, which [`crate::supervisor`] keeps
+        /// trying.
+        pub blockchain_client: Mutex<Option<BlockchainClient>>,
+        pub faucet: Box<dyn Faucet>,
+        pub health: Health,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
     pub struct AddressDetails {
         pub account_id: String,
+        pub key_index: u32,
         pub chain_address: String,
         pub virtual_address: String,
+        /// A `bitcoin:` URI offering both addresses, for sharing as one QR code.
+        pub payment_uri: String,
+    }
+
+    #[derive(Deserialize, ToSchema)]
+    pub struct AccountCreationRequest {
+        pub passphrase: String,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
     pub struct AccountCreationResponse {
         pub account_id: String,
+        /// The backup phrase. It is only ever returned here.
+        pub mnemonic: String,
+        /// Bearer token with full access to the account.
+        pub api_token: String,
+    }
+
+    #[derive(Deserialize, ToSchema)]
+    pub struct AccountRestoreRequest {
+        pub mnemonic: String,
+        pub passphrase: String,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct AccountRestoreResponse {
+        pub account_id: String,
+        pub key_count: u32,
+        /// Bearer token with full access to the account.
+        pub api_token: String,
+    }
+
+    #[derive(Deserialize, ToSchema)]
+    pub struct UnlockRequest {
+        pub passphrase: String,
+        pub ttl_seconds: Option<u64>,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
+    pub struct UnlockResponse {
+        pub account_id: String,
+        pub expires_in_seconds: u64,
+    }
+
+    /// What an API token may do with its account.
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TokenScope {
+        /// Addresses and balances only.
+        Read,
+        /// Everything, including moving funds and issuing more tokens.
+        Full,
+    }
+
+    impl TokenScope {
+        pub fn allows(self, required: TokenScope) -> bool {
+            match required {
+                TokenScope::Read => true,
+                TokenScope::Full => self == TokenScope::Full,
+            }
+        }
+    }
+
+    /// A stored API token. Only the SHA-256 hash of the secret is kept.
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct ApiToken {
+        pub token_hash: String,
+        pub account_id: String,
+        pub scope: TokenScope,
+    }
+
+    #[derive(Deserialize, ToSchema)]
+    pub struct TokenRequest {
+        pub scope: TokenScope,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct TokenResponse {
+        pub account_id: String,
+        pub scope: TokenScope,
+        pub api_token: String,
+    }
+
+    #[derive(Serialize, ToSchema)]
     pub struct BalanceDetails {
         pub account_id: String,
         pub virtual_balance: VirtualBalance,
         pub onchain_balance: OnchainBalance,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
     pub struct VirtualBalance {
         pub available: u64,
         pub expired: u64,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
     pub struct OnchainBalance {
         pub available: u64,
         pub expired: u64,
         pub pending: u64,
     }
 
-    #[derive(Deserialize)]
+    /// Filters and paging of `GET /api/accounts/{account_id}/transactions`.
+    #[derive(Deserialize, Default, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct TransactionQuery {
+        /// The `next_cursor` of the previous page.
+        pub cursor: Option<String>,
+        pub limit: Option<usize>,
+        /// Only list entries created at or after this Unix time.
+        pub from: Option<i64>,
+        /// Only list entries created at or before this Unix time.
+        pub to: Option<i64>,
+    }
+
+    #[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, ToSchema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TransactionKind {
+        /// An on-chain payment to a boarding address.
+        Boarding,
+        /// A round transaction that created or spent VTXOs of ours.
+        Round,
+        /// An off-chain transaction that sent or received VTXOs.
+        Redeem,
+    }
+
+    #[derive(Serialize, Clone, Debug, PartialEq, ToSchema)]
+    pub struct TransactionEntry {
+        pub txid: String,
+        pub kind: TransactionKind,
+        /// Net effect on the account in sats, negative if funds left it.
+        pub amount: i64,
+        /// Whether the entry can no longer change: the boarding transaction is confirmed, or our
+        /// outputs of the redeem transaction were settled in a round. Rounds are always settled.
+        pub settled: bool,
+        /// Unix time, or `None` for a boarding transaction that is still unconfirmed.
+        pub created_at: Option<i64>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct TransactionPage {
+        pub account_id: String,
+        /// Newest first. Unconfirmed boarding transactions come before everything else.
+        pub transactions: Vec<TransactionEntry>,
+        /// Pass as `cursor` to get the next page; `None` on the last page.
+        pub next_cursor: Option<String>,
+    }
+
+    /// Something that happened to the funds of an account, as pushed by
+    /// `GET /api/accounts/{account_id}/events`.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum AccountEvent {
+        /// A VTXO paid to one of the account's address keys, in a round or off-chain.
+        VtxoReceived {
+            outpoint: String,
+            amount: u64,
+            key_index: u32,
+        },
+        /// A VTXO of the account was spent.
+        VtxoSpent {
+            outpoint: String,
+            amount: u64,
+            spent_by: Option<String>,
+            key_index: u32,
+        },
+        /// A payment to a boarding address was confirmed on-chain.
+        BoardingConfirmed {
+            outpoint: String,
+            amount: u64,
+            confirmed_at: u64,
+            key_index: u32,
+        },
+        /// A round that created VTXOs of the account was finalized.
+        RoundCompleted { txid: String },
+        /// An off-chain payment made with `POST /api/transfer` was accepted by the Ark server.
+        TransferCompleted {
+            txid: String,
+            recipient: String,
+            amount: u64,
+        },
+        /// The Ark server gave up on a round the account had registered for, while withdrawing
+        /// or renewing VTXOs.
+        RoundFailed { round_id: String, reason: String },
+        /// A round joined by `POST /api/accounts/{account_id}/offboard` was confirmed on-chain.
+        OffboardConfirmed { txid: String, confirmed_at: u64 },
+        /// The VTXOs of an address key are due for renewal, but the account is locked. Reported
+        /// once for every expiry.
+        VtxosExpiring {
+            key_index: u32,
+            amount: u64,
+            expire_at: i64,
+        },
+    }
+
+    impl AccountEvent {
+        /// The name of the event, as used by the `type` field and the SSE `event` field.
+        pub fn name(&self) -> &'static str {
+            match self {
+                AccountEvent::VtxoReceived { .. } => "vtxo_received",
+                AccountEvent::VtxoSpent { .. } => "vtxo_spent",
+                AccountEvent::BoardingConfirmed { .. } => "boarding_confirmed",
+                AccountEvent::RoundCompleted { .. } => "round_completed",
+                AccountEvent::TransferCompleted { .. } => "transfer_completed",
+                AccountEvent::RoundFailed { .. } => "round_failed",
+                AccountEvent::OffboardConfirmed { .. } => "offboard_confirmed",
+                AccountEvent::VtxosExpiring { .. } => "vtxos_expiring",
+            }
+        }
+
+        /// The names of all events.
+        pub const NAMES: &[&str] = &[
+            "vtxo_received",
+            "vtxo_spent",
+            "boarding_confirmed",
+            "round_completed",
+            "transfer_completed",
+            "round_failed",
+            "offboard_confirmed",
+            "vtxos_expiring",
+        ];
+    }
+
+    /// An [`AccountEvent`] in the event log of an account.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+    pub struct StoredEvent {
+        /// Position in the account's event log, starting at 1. Clients resume a stream after the
+        /// last sequence number they saw.
+        pub seq: u64,
+        pub account_id: String,
+        /// Unix time at which the server noticed the event.
+        pub created_at: i64,
+        #[serde(flatten)]
+        pub event: AccountEvent,
+    }
+
+    #[derive(Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct EventQuery {
+        /// Replay the events after this sequence number before streaming new ones. The
+        /// `Last-Event-ID` header takes precedence.
+        pub cursor: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize, ToSchema)]
     pub struct TransferRequest {
         pub account_id: String,
         pub recipient: String,
         pub amount: u64,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
     pub struct TransferResponse {
         pub account_id: String,
         pub recipient: String,
@@ -94,34 +991,425 @@ pub mod model {
         pub transaction_id: String,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Serialize, Deserialize, Clone, ToSchema)]
+    pub struct Payout {
+        pub recipient: String,
+        /// In sats.
+        pub amount: u64,
+    }
+
+    #[derive(Serialize, Deserialize, ToSchema)]
+    pub struct BatchTransferRequest {
+        pub account_id: String,
+        pub payouts: Vec<Payout>,
+    }
+
+    #[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum PayoutStatus {
+        /// Paid by the transaction in `transaction_id`.
+        Sent,
+        /// Turned down before anything was built, e.g. for an invalid address.
+        Rejected,
+        /// Its transaction could not be built or submitted.
+        Failed,
+    }
+
+    #[derive(Serialize, Debug, PartialEq, ToSchema)]
+    pub struct PayoutResult {
+        pub recipient: String,
+        pub amount: u64,
+        pub status: PayoutStatus,
+        pub transaction_id: Option<String>,
+        pub error: Option<ApiError>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct BatchTransferResponse {
+        pub account_id: String,
+        /// One per payout, in the order of the request.
+        pub results: Vec<PayoutResult>,
+        /// The transactions that were submitted.
+        pub transaction_ids: Vec<String>,
+    }
+
+    /// A VTXO that a previewed transfer would spend.
+    #[derive(Serialize, ToSchema)]
+    pub struct PreviewInput {
+        pub outpoint: String,
+        pub amount: u64,
+        /// Unix time at which the VTXO expires.
+        pub expire_at: i64,
+    }
+
+    /// What `POST /api/transfer` would do with the same request.
+    #[derive(Serialize, ToSchema)]
+    pub struct TransferPreview {
+        pub account_id: String,
+        pub recipient: String,
+        pub amount: u64,
+        pub inputs: Vec<PreviewInput>,
+        /// In sats, taken from the change, or from the amount sent if there is none.
+        pub fee: u64,
+        /// What the recipient would get.
+        pub amount_received: u64,
+        /// What would come back to the account's first address key, after the fee.
+        pub change: u64,
+        /// Whether the change is below the Ark server's dust limit.
+        pub change_is_dust: bool,
+        /// The unsigned redeem transaction, base64-encoded.
+        pub psbt: String,
+        /// Things worth knowing before sending, in plain words.
+        pub warnings: Vec<String>,
+    }
+
+    #[derive(Deserialize, ToSchema)]
     pub struct FundingRequest {
         pub chain_address: String,
-        pub amount: f64,
+        /// In sats.
+        pub amount: u64,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
     pub struct FundingResponse {
-        pub success: bool,
         pub recipient: String,
-        pub amount: f64,
-        pub transaction_id: Option<String>,
-        pub error_message: Option<String>,
-        pub command_output: String,
+        pub amount: u64,
+        pub transaction_id: String,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Serialize, Deserialize, ToSchema)]
     pub struct WithdrawalRequest {
         pub account_id: String,
         pub destination_address: Option<String>,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, ToSchema)]
     pub struct WithdrawalResponse {
         pub account_id: String,
-        pub success: bool,
-        pub transaction_id: Option<String>,
-        pub error_message: Option<String>,
+        pub transaction_id: String,
+        /// One round per address key that held funds; `transaction_id` is the first of them.
+        pub transaction_ids: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize, ToSchema)]
+    pub struct OffboardRequest {
+        /// On-chain address that receives the funds.
+        pub destination_address: String,
+        /// Amount in sats to send on-chain, from a single address key. Everything if omitted.
+        pub amount: Option<u64>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct OffboardResponse {
+        pub account_id: String,
+        pub destination_address: String,
+        pub transaction_id: String,
+        /// One round per address key that sent funds; `transaction_id` is the first of them.
+        pub transaction_ids: Vec<String>,
+        pub status: OffboardStatus,
+        /// Why the funds of the remaining address keys were not sent, if `status` is `partial`.
+        pub error: Option<ApiError>,
+    }
+
+    /// Where an off-boarding stands when `POST /api/accounts/{account_id}/offboard` answers.
+    /// The rounds are finalized but not confirmed yet; an `offboard_confirmed` event follows
+    /// for each of them once it is.
+    #[derive(Serialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum OffboardStatus {
+        /// Every address key holding funds sent them.
+        Pending,
+        /// Some address keys sent their funds before off-boarding failed for another one.
+        Partial,
+    }
+
+    /// The last check of a service the server depends on.
+    #[derive(Serialize, Clone, Debug, Default, PartialEq, ToSchema)]
+    pub struct ServiceHealth {
+        pub up: bool,
+        /// When the service was last checked, as a Unix time. `None` if it has not been yet.
+        pub checked_at: Option<i64>,
+        /// Why the last check failed.
+        pub error: Option<String>,
+    }
+
+    #[derive(Serialize, Debug, ToSchema)]
+    pub struct HealthReport {
+        /// Whether every service is up, so that requests can be served.
+        pub ready: bool,
+        pub ark_server: ServiceHealth,
+        pub esplora: ServiceHealth,
+        pub storage: ServiceHealth,
+    }
+
+    #[derive(Deserialize, ToSchema)]
+    pub struct ExitRequest {
+        /// On-chain address that receives the exited funds.
+        pub destination_address: String,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ExitStatus {
+        /// Publishing the VTXO tree branches and waiting for them to confirm.
+        Committing,
+        /// Waiting for the exit delay of the confirmed VTXOs to pass.
+        Waiting,
+        /// Publishing the sweep transactions and waiting for them to confirm.
+        Sweeping,
+        Completed,
+        /// An input of one of the transactions was spent by another transaction, so the exit can
+        /// never complete. See `error`.
+        Failed,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ExitTransactionKind {
+        /// A transaction of the VTXO tree that leads to one of the exited VTXOs.
+        Branch,
+        /// Spends the exited VTXOs of one address key to the destination address.
+        Sweep,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ExitTransactionStatus {
+        Pending,
+        /// Seen by the blockchain explorer, but not confirmed yet.
+        Broadcast,
+        Confirmed,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+    pub struct ExitTransaction {
+        pub txid: String,
+        pub kind: ExitTransactionKind,
+        pub status: ExitTransactionStatus,
+        /// Unix time of the block that confirmed the transaction.
+        pub confirmed_at: Option<u64>,
+        /// Why the last attempt to publish the transaction failed, until it is published.
+        pub last_error: Option<String>,
+        /// The hex-encoded signed transaction, which anyone can publish.
+        pub raw_tx: String,
+    }
+
+    /// A unilateral exit of the VTXOs of an account, as started by
+    /// `POST /api/accounts/{account_id}/exit`.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+    pub struct ExitJob {
+        pub id: String,
+        pub account_id: String,
+        pub destination_address: String,
+        pub status: ExitStatus,
+        /// The relative timelock that starts once the VTXOs are confirmed on-chain.
+        pub exit_delay_seconds: u64,
+        /// Unix time after which the sweep transactions become valid; known once every branch
+        /// transaction is confirmed.
+        pub spendable_at: Option<i64>,
+        /// Branch transactions in publishing order, followed by one sweep per address key.
+        pub transactions: Vec<ExitTransaction>,
+        /// Outpoints of the exited VTXOs.
+        #[serde(default)]
+        pub vtxos: Vec<String>,
+        /// Outpoints of the VTXOs that were left out because they are not settled in a round yet,
+        /// so they have no VTXO tree branch to publish. Settle them and start another exit.
+        #[serde(default)]
+        pub unsettled_vtxos: Vec<String>,
+        /// Why the job failed.
+        #[serde(default)]
+        pub error: Option<String>,
+        pub created_at: i64,
+        pub updated_at: i64,
+    }
+
+    /// What is needed to exit one settled VTXO without the Ark server, recorded while the server
+    /// is reachable.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct VtxoExitPath {
+        pub outpoint: String,
+        /// The address key that owns the VTXO.
+        pub key_index: u32,
+        /// The amount of the VTXO, in sats.
+        pub amount: u64,
+        /// The hex-encoded transactions of the VTXO tree branch that leads to the VTXO, in
+        /// publishing order.
+        pub branch_txs: Vec<String>,
+        /// The hex-encoded x-only public key of the Ark server.
+        pub server_pk: String,
+        /// The relative timelock on the exit path of the VTXO, as a sequence number.
+        pub exit_delay: u32,
+        pub network: String,
+        /// The VTXO descriptor template of the Ark server that the VTXO was built from. `None`
+        /// for VTXOs built without one.
+        #[serde(default)]
+        pub vtxo_template: Option<String>,
+        pub recorded_at: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum RenewalStatus {
+        Renewed,
+        Failed,
+        /// Not renewed because the account is locked, which was reported with an
+        /// [`AccountEvent::VtxosExpiring`].
+        Locked,
+    }
+
+    /// An attempt to renew the VTXOs of one address key by settling them in a new round.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+    pub struct RenewalRecord {
+        pub account_id: String,
+        pub key_index: u32,
+        pub status: RenewalStatus,
+        /// Unix time at which the earliest of the VTXOs would have expired.
+        pub expire_at: i64,
+        /// The total amount of the VTXOs, in sats.
+        pub amount: u64,
+        pub round_txid: Option<String>,
+        pub error: Option<String>,
+        pub created_at: i64,
+    }
+
+    /// Paging of `GET /api/accounts/{account_id}/renewals`.
+    #[derive(Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct RenewalQuery {
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct RenewalLog {
+        pub account_id: String,
+        /// Newest first.
+        pub renewals: Vec<RenewalRecord>,
+    }
+
+    /// A payment request made with an `Idempotency-Key`, kept to answer retries.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct IdempotencyRecord {
+        pub account_id: String,
+        pub key: String,
+        /// Hash of the endpoint and body of the request that first used the key.
+        pub fingerprint: String,
+        /// `None` while the request is still being handled.
+        pub response: Option<StoredResponse>,
+        /// The server process that claimed the key, to tell the claims of a process that is gone.
+        #[serde(default)]
+        pub owner: Option<String>,
+        /// Unix time at which the key was claimed.
+        pub created_at: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct StoredResponse {
+        pub status: u16,
+        pub content_type: Option<String>,
+        pub body: String,
+    }
+
+    /// A URL that is sent the events of an account, registered with
+    /// `POST /api/accounts/{account_id}/webhooks`.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct Webhook {
+        pub id: String,
+        pub account_id: String,
+        pub url: String,
+        /// Names of the events to deliver, or empty for all of them.
+        pub events: Vec<String>,
+        /// Key of the HMAC-SHA256 signature of every delivery.
+        pub secret: String,
+        pub created_at: i64,
+    }
+
+    #[derive(Deserialize, ToSchema)]
+    pub struct WebhookRequest {
+        pub url: String,
+        /// Names of the events to deliver. All events are delivered if this is left out.
+        #[serde(default)]
+        pub events: Vec<String>,
+    }
+
+    /// A [`Webhook`] without its secret.
+    #[derive(Serialize, ToSchema)]
+    pub struct WebhookDetails {
+        pub id: String,
+        pub url: String,
+        pub events: Vec<String>,
+        pub created_at: i64,
+    }
+
+    impl From<&Webhook> for WebhookDetails {
+        fn from(webhook: &Webhook) -> Self {
+            Self {
+                id: webhook.id.clone(),
+                url: webhook.url.clone(),
+                events: webhook.events.clone(),
+                created_at: webhook.created_at,
+            }
+        }
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct WebhookCreationResponse {
+        #[serde(flatten)]
+        pub webhook: WebhookDetails,
+        /// The signing secret. It is only ever returned here.
+        pub secret: String,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct WebhookList {
+        pub account_id: String,
+        pub webhooks: Vec<WebhookDetails>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum DeliveryStatus {
+        /// Waiting for its first or next attempt.
+        Pending,
+        Delivered,
+        /// Given up on after too many failed attempts.
+        Failed,
+    }
+
+    /// One event on its way to one webhook, kept in the outbox until it is delivered or given up
+    /// on, and afterwards as delivery history.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+    pub struct WebhookDelivery {
+        pub id: String,
+        pub webhook_id: String,
+        pub account_id: String,
+        /// Sequence number of the event in the account's event log.
+        pub event_seq: u64,
+        pub event_type: String,
+        /// The JSON body that is posted and signed.
+        pub payload: String,
+        pub status: DeliveryStatus,
+        pub attempts: u32,
+        /// Unix time of the next attempt, while the delivery is pending.
+        pub next_attempt_at: i64,
+        /// HTTP status of the last attempt, if the webhook answered.
+        pub last_status_code: Option<u16>,
+        pub last_error: Option<String>,
+        pub created_at: i64,
+        pub delivered_at: Option<i64>,
+    }
+
+    /// Paging of `GET /api/accounts/{account_id}/webhooks/{webhook_id}/deliveries`.
+    #[derive(Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct DeliveryQuery {
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Serialize, ToSchema)]
+    pub struct DeliveryLog {
+        pub webhook_id: String,
+        /// Newest first.
+        pub deliveries: Vec<WebhookDelivery>,
     }
 
     #[derive(Clone)]
@@ -136,15 +1424,42 @@ pub mod model {
             Ok(Self { client })
         }
 
-        pub async fn query_utxos(
+        /// The height of the chain tip.
+        pub async fn tip_height(&self) -> Result<u32, anyhow::Error> {
+            let height = self
+                .client
+                .get_height()
+                .await
+                .inspect_err(|_| metrics::esplora_error("get_height"))?;
+
+            Ok(height)
+        }
+
+        /// Whether any transaction has ever paid to or spent from `address`.
+        pub async fn has_history(&self, address: &bitcoin::Address) -> Result<bool, anyhow::Error> {
+            let transactions = self
+                .client
+                .scripthash_txs(&address.script_pubkey(), None)
+                .await
+                .inspect_err(|_| metrics::esplora_error("scripthash_txs"))?;
+
+            Ok(!transactions.is_empty())
+        }
+    }
+
+    /// Lets `ark_client` look up boarding outputs and VTXOs on-chain through esplora.
+    impl ark_client::Blockchain for BlockchainClient {
+        async fn find_outpoints(
             &self,
             address: &bitcoin::Address,
-        ) -> Result<Vec<ExplorerUtxo>, anyhow::Error> {
+        ) -> Result<Vec<ExplorerUtxo>, ark_client::Error> {
             let script_pubkey = address.script_pubkey();
             let transactions = self
                 .client
                 .scripthash_txs(&script_pubkey, None)
-                .await?;
+                .await
+                .inspect_err(|_| metrics::esplora_error("scripthash_txs"))
+                .map_err(ark_client::Error::wallet)?;
 
             let utxos = transactions
                 .into_iter()
@@ -173,7 +1488,9 @@ pub mod model {
                 let status = self
                     .client
                     .get_output_status(&outpoint.txid, outpoint.vout as u64)
-                    .await?;
+                    .await
+                    .inspect_err(|_| metrics::esplora_error("get_output_status"))
+                    .map_err(ark_client::Error::wallet)?;
 
                 match status {
                     Some(esplora_client::OutputStatus { spent: false, .. }) | None => {
@@ -190,49 +1507,154 @@ pub mod model {
 
             Ok(result)
         }
+
+        async fn find_tx(
+            &self,
+            txid: &Txid,
+        ) -> Result<Option<bitcoin::Transaction>, ark_client::Error> {
+            self.client
+                .get_tx(txid)
+                .await
+                .inspect_err(|_| metrics::esplora_error("get_tx"))
+                .map_err(ark_client::Error::wallet)
+        }
+
+        async fn get_output_status(
+            &self,
+            txid: &Txid,
+            vout: u32,
+        ) -> Result<SpendStatus, ark_client::Error> {
+            let status = self
+                .client
+                .get_output_status(txid, vout as u64)
+                .await
+                .inspect_err(|_| metrics::esplora_error("get_output_status"))
+                .map_err(ark_client::Error::wallet)?;
+
+            Ok(SpendStatus {
+                spend_txid: status.and_then(|status| status.txid),
+            })
+        }
+
+        async fn broadcast(&self, tx: &bitcoin::Transaction) -> Result<(), ark_client::Error> {
+            self.client
+                .broadcast(tx)
+                .await
+                .inspect_err(|_| metrics::esplora_error("broadcast"))
+                .map_err(ark_client::Error::wallet)
+        }
     }
 }
 
 pub mod logger {
-    pub fn setup_logger() {
-        tracing_subscriber::fmt()
-            .with_env_filter(
-                "debug,\
-                tower=info,\
-                hyper_util=info,\
-                hyper=info,\
-                h2=warn,\
-                reqwest=info,\
-                ark_core=info,\
-                rustls=info",
-            )
+    use tracing_subscriber::filter::{filter_fn, EnvFilter};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::{fmt, Layer};
+
+    use crate::core::config::LoggingConfig;
+    use crate::metrics::{RoundLayer, METRICS};
+
+    const DEFAULT_FILTER: &str = "debug,\
+        tower=info,\
+        hyper_util=info,\
+        hyper=info,\
+        h2=warn,\
+        reqwest=info,\
+        ark_core=info,\
+        rustls=info";
+
+    /// Log through `tracing`, and feed the round spans of `ark_client` into the metrics. The
+    /// filter only applies to logging, so that the metrics see rounds at any log level.
+    pub fn setup_logger(config: &LoggingConfig) {
+        let filter = EnvFilter::new(config.filter.as_deref().unwrap_or(DEFAULT_FILTER));
+
+        let logs = if config.json {
+            fmt::layer().json().boxed()
+        } else {
+            fmt::layer().boxed()
+        };
+
+        tracing_subscriber::registry()
+            .with(logs.with_filter(filter))
+            .with(RoundLayer::new(&METRICS).with_filter(filter_fn(RoundLayer::wants)))
             .init()
     }
 }
 
 pub mod server {
-    use actix_web::{App, HttpServer, web};
-    use anyhow::Result;
-    use std::fs;
-    use std::path::Path;
-    use std::collections::HashMap;
-    use std::sync::Mutex;
+    use actix_web::{App, HttpServer, middleware, web};
+    use anyhow::{anyhow, Context, Result};
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::{Arc, Mutex};
 
     use crate::api;
-    use crate::core::config::AppConfig;
+    use crate::auth;
+    use crate::clients::ClientCache;
+    use crate::core::config::{AppConfig, TlsConfig};
+    use crate::cors;
+    use crate::error;
+    use crate::events::EventHub;
+    use crate::exits;
+    use crate::faucet;
+    use crate::idempotency;
+    use crate::metrics::{self, METRICS};
+    use crate::network;
+    use crate::renewals;
+    use crate::webhooks;
+    use crate::storage;
+    use crate::supervisor::{self, Health};
     use crate::core::model::{ApplicationState, BlockchainClient};
+    use crate::keystore::{self, KeyCache};
 
     pub async fn connect_to_ark_network(config: AppConfig) -> Result<ark_core::server::Info> {
         let mut client = ark_grpc::Client::new(config.ark_server_url.clone());
-        client.connect().await?;
-        let network_info = client.get_info().await?;
+        let network_info = async {
+            client.connect().await?;
+            client.get_info().await
+        }
+        .await
+        .inspect_err(|_| METRICS.grpc_errors.inc())?;
         Ok(network_info)
     }
 
+    /// Load the certificate chain and private key for serving HTTPS.
+    pub fn load_tls(config: &TlsConfig) -> Result<rustls::ServerConfig> {
+        let open = |path: &std::path::Path| {
+            File::open(path)
+                .map(BufReader::new)
+                .with_context(|| format!("failed to open {}", path.display()))
+        };
+
+        let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid certificate in {}", config.cert_path.display()))?;
+        if certs.is_empty() {
+            return Err(anyhow!("no certificate in {}", config.cert_path.display()));
+        }
+
+        let key = rustls_pemfile::private_key(&mut open(&config.key_path)?)
+            .with_context(|| format!("invalid private key in {}", config.key_path.display()))?
+            .ok_or_else(|| anyhow!("no private key in {}", config.key_path.display()))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("certificate does not match the private key")?;
+
+        Ok(tls)
+    }
+
     pub async fn launch_api_server(config: AppConfig) -> std::io::Result<()> {
-        // Connect to ARK network
-        let server_connection = match connect_to_ark_network(config.clone()).await {
-            Ok(info) => Some(Mutex::new(info)),
+        // Connect to ARK network; the supervisor keeps trying if this fails
+        let health = Health::default();
+        let connection = connect_to_ark_network(config.clone()).await;
+        health.record_ark_server(&connection);
+        let server_connection = match connection {
+            Ok(info) => Some(info),
             Err(e) => {
                 eprintln!("Network connection error: {}", e);
                 None
@@ -241,48 +1663,143 @@ pub mod server {
 
         // Initialize blockchain client
         let blockchain_client = match BlockchainClient::initialize(&config.esplora_url) {
-            Ok(client) => Some(Mutex::new(client)),
+            Ok(client) => Some(client),
             Err(e) => {
                 eprintln!("Blockchain client initialization error: {}", e);
                 None
             }
         };
 
-        // Ensure data directory exists
-        if !Path::new("wallets").exists() {
-            fs::create_dir("wallets")?;
+        // Refuse to run against services on another network
+        let ark_network = server_connection.as_ref().map(|info| info.network);
+        let blockchain = blockchain_client.clone();
+        network::verify(&config, ark_network, blockchain.as_ref())
+            .await
+            .map_err(|e| {
+                eprintln!("Network check failed: {:#}", e);
+                std::io::Error::other("Configured network does not match the services")
+            })?;
+
+        // Load the TLS certificate before anything starts running in the background
+        let tls = config
+            .server
+            .tls
+            .as_ref()
+            .map(load_tls)
+            .transpose()
+            .map_err(|e| {
+                eprintln!("TLS configuration error: {:#}", e);
+                std::io::Error::other("Failed to load the TLS certificate")
+            })?;
+
+        // Set up the faucet
+        let faucet = faucet::open(&config.faucet, blockchain).map_err(|e| {
+            eprintln!("Faucet error: {:#}", e);
+            std::io::Error::other("Failed to set up the faucet")
+        })?;
+
+        // Open account storage
+        let accounts = storage::open(&config.storage).map_err(|e| {
+            eprintln!("Account storage error: {:#}", e);
+            std::io::Error::other("Failed to open account storage")
+        })?;
+
+        match accounts.list_accounts() {
+            Ok(existing) => {
+                println!("Loaded {} account(s) from storage", existing.len());
+
+                let legacy = existing.iter().filter(|account| keystore::needs_migration(account)).count();
+                if legacy > 0 {
+                    tracing::warn!(
+                        "{} account(s) still have an unencrypted key and cannot be unlocked until `migrate-keys` is run",
+                        legacy
+                    );
+                }
+            }
+            Err(e) => eprintln!("Failed to list stored accounts: {}", e),
         }
 
         // Initialize application state
         let app_state = web::Data::new(ApplicationState {
-            accounts: Mutex::new(HashMap::new()),
+            accounts,
+            unlocked_keys: KeyCache::default(),
+            clients: ClientCache::default(),
+            events: EventHub::default(),
             config: config.clone(),
-            server_connection,
-            blockchain_client,
+            server_connection: Mutex::new(server_connection),
+            blockchain_client: Mutex::new(blockchain_client),
+            faucet,
+            health,
         });
 
-        println!("Starting ARK-based Cryptocurrency Server on 127.0.0.1:8080");
+        // Reconnect to the Ark server when it goes away, and keep its info up to date
+        actix_web::rt::spawn(supervisor::run(app_state.clone()));
+
+        // Resume unfinished unilateral exits
+        actix_web::rt::spawn(exits::run(app_state.clone()));
+
+        // Record the exit paths of VTXOs while the Ark server is reachable
+        actix_web::rt::spawn(exits::record(app_state.clone()));
+
+        // Renew VTXOs of unlocked accounts before they expire
+        actix_web::rt::spawn(renewals::run(app_state.clone()));
+
+        // Watch accounts with webhooks and deliver their events
+        actix_web::rt::spawn(webhooks::run(app_state.clone()));
+
+        // Forget idempotency keys once retries are no longer expected
+        actix_web::rt::spawn(idempotency::run(app_state.clone()));
+
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        println!(
+            "Starting ARK-based Cryptocurrency Server on {}://{}",
+            scheme, config.server.bind
+        );
 
         // Start HTTP server
-        HttpServer::new(move || {
+        let server = HttpServer::new(move || {
             App::new()
                 .app_data(app_state.clone())
+                .app_data(web::JsonConfig::default().error_handler(error::json_error))
+                .app_data(web::QueryConfig::default().error_handler(error::query_error))
+                .wrap(middleware::from_fn(auth::authenticate))
+                .wrap(middleware::from_fn(cors::cors))
+                .wrap(middleware::from_fn(metrics::track))
                 .service(api::accounts::create_account)
+                .service(api::accounts::restore_account)
                 .service(api::accounts::get_account_addresses)
+                .service(api::accounts::add_account_address)
+                .service(api::accounts::unlock_account)
+                .service(api::accounts::lock_account)
+                .service(api::accounts::issue_token)
+                .service(api::accounts::register_webhook)
+                .service(api::accounts::list_webhooks)
+                .service(api::accounts::delete_webhook)
+                .service(api::accounts::get_webhook_deliveries)
                 .service(api::finance::get_account_balance)
+                .service(api::finance::get_account_transactions)
+                .service(api::finance::stream_account_events)
                 .service(api::finance::transfer_funds)
+                .service(api::finance::preview_transfer)
+                .service(api::finance::transfer_batch)
                 .service(api::finance::fund_account)
                 .service(api::finance::withdraw_funds)
-        })
-        .bind("127.0.0.1:8080")?
-        .run()
-        .await
-    }
-}
+                .service(api::finance::offboard_funds)
+                .service(api::finance::start_exit)
+                .service(api::finance::get_exit)
+                .service(api::finance::get_account_renewals)
+                .service(api::monitoring::get_metrics)
+                .service(api::monitoring::get_health)
+                .service(api::monitoring::get_readiness)
+                .service(api::docs::get_openapi)
+                .default_service(web::to(error::not_found))
+        });
 
-pub mod utils {
-    pub fn extract_transaction_id(command_output: &str) -> Option<String> {
-        let re = regex::Regex::new(r"[0-9a-f]{64}").ok()?;
-        re.find(command_output).map(|m| m.as_str().to_string())
+        let server = match tls {
+            Some(tls) => server.bind_rustls_0_23(&config.server.bind, tls)?,
+            None => server.bind(&config.server.bind)?,
+        };
+
+        server.run().await
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,164 @@
+//! CORS for browser clients served from other origins.
+//!
+//! Requests from an origin listed in `server.cors_origins` (or from anywhere, with `"*"`) get
+//! the headers that let the browser read the response, and their preflight requests are answered
+//! directly. Requests from other origins are served as usual, without CORS headers, so browsers
+//! keep the responses from the page that asked.
+
+use actix_web::body::{BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::http::header::{
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD,
+    HeaderValue, ORIGIN, VARY,
+};
+use actix_web::middleware::Next;
+use actix_web::{HttpResponse, web};
+
+use crate::core::model::ApplicationState;
+
+const ALLOWED_METHODS: &str = "GET, POST, DELETE, OPTIONS";
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type, Idempotency-Key, Last-Event-ID";
+const EXPOSED_HEADERS: &str = "Idempotent-Replayed";
+
+/// How long browsers may cache a preflight response, in seconds.
+const MAX_AGE: &str = "3600";
+
+/// Whether `origin` may call the API, given the configured `origins`.
+pub fn is_allowed(origins: &[String], origin: &str) -> bool {
+    origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed == origin)
+}
+
+pub async fn cors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody, BoxBody>>, actix_web::Error> {
+    let origin = req
+        .headers()
+        .get(ORIGIN)
+        .filter(|origin| {
+            let origins = req
+                .app_data::<web::Data<ApplicationState>>()
+                .map(|state| state.config.server.cors_origins.as_slice())
+                .unwrap_or_default();
+
+            origin
+                .to_str()
+                .is_ok_and(|origin| is_allowed(origins, origin))
+        })
+        .cloned();
+
+    let Some(origin) = origin else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    // Answer preflight requests here, as no route handles OPTIONS
+    if req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let response = HttpResponse::NoContent()
+            .insert_header((ACCESS_CONTROL_ALLOW_ORIGIN, origin))
+            .insert_header((ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS))
+            .insert_header((ACCESS_CONTROL_ALLOW_HEADERS, ALLOWED_HEADERS))
+            .insert_header((ACCESS_CONTROL_MAX_AGE, MAX_AGE))
+            .insert_header((VARY, "Origin"))
+            .finish();
+
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    // Errors of inner middleware, such as a rejected token, need the headers too, or the browser
+    // only reports a CORS failure.
+    let request = req.request().clone();
+    let mut response = match next.call(req).await {
+        Ok(response) => response.map_into_left_body(),
+        Err(e) => ServiceResponse::from_err(e, request).map_into_right_body(),
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{App, middleware};
+
+    use crate::api;
+    use crate::auth;
+    use crate::auth::tests::{add_account, config, state_from};
+
+    #[actix_web::test]
+    async fn listed_origins_get_cors_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(&dir);
+        config.server.cors_origins = vec!["https://wallet.example".to_string()];
+        let state = state_from(config);
+        let account_id = add_account(&state);
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(middleware::from_fn(auth::authenticate))
+                .wrap(middleware::from_fn(cors))
+                .service(api::accounts::get_account_addresses),
+        )
+        .await;
+        let uri = format!("/api/accounts/{account_id}/addresses");
+
+        let preflight = actix_web::test::TestRequest::with_uri(&uri)
+            .method(Method::OPTIONS)
+            .insert_header((ORIGIN, "https://wallet.example"))
+            .insert_header((ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, preflight).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://wallet.example"
+        );
+        assert_eq!(
+            resp.headers().get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(),
+            ALLOWED_HEADERS
+        );
+
+        // A rejected token still carries the headers, so the page can read the error.
+        let rejected = actix_web::test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((ORIGIN, "https://wallet.example"))
+            .insert_header(("Authorization", "Bearer ark_unknown"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, rejected).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://wallet.example"
+        );
+
+        let other = actix_web::test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((ORIGIN, "https://evil.example"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, other).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn wildcard_allows_any_origin() {
+        let origins = vec!["https://a.example".to_string()];
+        assert!(is_allowed(&origins, "https://a.example"));
+        assert!(!is_allowed(&origins, "https://b.example"));
+        assert!(!is_allowed(&[], "https://a.example"));
+        assert!(is_allowed(&["*".to_string()], "https://b.example"));
+    }
+}
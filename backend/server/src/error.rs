@@ -0,0 +1,270 @@
+//! Error responses of the HTTP API.
+//!
+//! Every error is answered with a JSON [`ApiError`], such as
+//! `{"code": "account_not_found", "message": "Account not found"}`. The `code` is stable, so that
+//! clients can act on it, while the `message` is meant for people and may change.
+//!
+//! Errors of `ark_client` and `ark_core` get the code of their kind. Errors that reach the
+//! handlers as [`anyhow::Error`] keep theirs by carrying an [`ApiError`] along, as
+//! [`crate::clients`] does.
+
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use ark_client::ErrorKind;
+use serde::Serialize;
+use std::fmt;
+use utoipa::ToSchema;
+
+use crate::clients::RoundFailed;
+
+/// What went wrong, in a form clients can act on.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request body, query or a header is malformed or out of range.
+    InvalidRequest,
+    /// An Ark or on-chain address could not be parsed, or is for another network.
+    InvalidAddress,
+    InvalidMnemonic,
+    /// The passphrase does not decrypt the account's keys.
+    InvalidPassphrase,
+    /// The API token is missing, malformed or unknown.
+    Unauthorized,
+    /// The API token belongs to another account, or its scope does not allow the operation.
+    Forbidden,
+    /// The faucet is disabled, or the server runs on mainnet.
+    FaucetUnavailable,
+    /// The faucet funded the same address too recently.
+    FaucetRateLimited,
+    /// No route matches the request.
+    NotFound,
+    AccountNotFound,
+    WebhookNotFound,
+    ExitJobNotFound,
+    /// The operation needs the account's keys; unlock it first.
+    AccountLocked,
+    /// The account's key is stored unencrypted and has to be migrated by the operator before the
+    /// account can be unlocked.
+    KeyMigrationRequired,
+    /// Not enough funds, in a single address where that matters.
+    InsufficientFunds,
+    TooManyWebhooks,
+    /// A new address would follow too many unused ones, which restoring the account would miss.
+    AddressGapLimit,
+    /// Some of the VTXOs are already being exited by an unfinished exit job.
+    ExitInProgress,
+    /// A request with the same `Idempotency-Key` is still being handled.
+    IdempotencyKeyInProgress,
+    /// The `Idempotency-Key` was already used for a different request.
+    IdempotencyKeyReused,
+    /// The Ark server is unreachable, or did not follow the protocol.
+    NetworkUnavailable,
+    /// The blockchain explorer is unreachable.
+    BlockchainUnavailable,
+    /// The Ark server gave up on a round the account had joined.
+    RoundFailed,
+    /// A round the account had joined was not finalized in time.
+    RoundTimeout,
+    /// The Ark server advertises a script policy that we cannot build outputs for.
+    UnsupportedServerPolicy,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest
+            | ErrorCode::InvalidAddress
+            | ErrorCode::InvalidMnemonic
+            | ErrorCode::InsufficientFunds => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidPassphrase | ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::FaucetUnavailable => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound
+            | ErrorCode::AccountNotFound
+            | ErrorCode::WebhookNotFound
+            | ErrorCode::ExitJobNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::TooManyWebhooks
+            | ErrorCode::AddressGapLimit
+            | ErrorCode::ExitInProgress
+            | ErrorCode::IdempotencyKeyInProgress
+            | ErrorCode::KeyMigrationRequired => StatusCode::CONFLICT,
+            ErrorCode::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::AccountLocked => StatusCode::LOCKED,
+            ErrorCode::FaucetRateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::RoundTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::RoundFailed | ErrorCode::UnsupportedServerPolicy => StatusCode::BAD_GATEWAY,
+            ErrorCode::NetworkUnavailable | ErrorCode::BlockchainUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The body of every error response.
+#[derive(Serialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message)
+    }
+
+    /// Something failed on our side while doing `what`.
+    pub fn internal(what: &str, e: impl fmt::Display) -> Self {
+        Self::new(ErrorCode::Internal, format!("{what}: {e}"))
+    }
+
+    pub fn account_not_found() -> Self {
+        Self::new(ErrorCode::AccountNotFound, "Account not found")
+    }
+
+    pub fn network_unavailable() -> Self {
+        Self::new(ErrorCode::NetworkUnavailable, "Network unavailable")
+    }
+
+    pub fn blockchain_unavailable() -> Self {
+        Self::new(ErrorCode::BlockchainUnavailable, "Blockchain client unavailable")
+    }
+
+    /// `e` failed while doing `what`. The code is the one `e` carries, if any, or `fallback`.
+    pub fn caused_by(what: &str, e: &anyhow::Error, fallback: ErrorCode) -> Self {
+        let code = if let Some(error) = e.downcast_ref::<ApiError>() {
+            error.code
+        } else if e.downcast_ref::<RoundFailed>().is_some() {
+            ErrorCode::RoundFailed
+        } else {
+            fallback
+        };
+
+        Self::new(code, format!("{what}: {e}"))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+impl From<ark_core::ErrorKind> for ErrorCode {
+    fn from(kind: ark_core::ErrorKind) -> Self {
+        match kind {
+            ark_core::ErrorKind::ArkAddress => ErrorCode::InvalidAddress,
+            ark_core::ErrorKind::CoinSelect => ErrorCode::InsufficientFunds,
+            ark_core::ErrorKind::AdHoc
+            | ark_core::ErrorKind::Crypto
+            | ark_core::ErrorKind::Transaction => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<ErrorKind> for ErrorCode {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::ArkServer => ErrorCode::NetworkUnavailable,
+            ErrorKind::Core(kind) => kind.into(),
+            ErrorKind::CoinSelect => ErrorCode::InsufficientFunds,
+            ErrorKind::Wallet => ErrorCode::BlockchainUnavailable,
+            ErrorKind::RoundFailed => ErrorCode::RoundFailed,
+            ErrorKind::RoundTimeout => ErrorCode::RoundTimeout,
+            ErrorKind::ServerPolicy => ErrorCode::UnsupportedServerPolicy,
+            ErrorKind::AdHoc => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<ark_core::Error> for ApiError {
+    fn from(e: ark_core::Error) -> Self {
+        Self::new(e.kind().into(), e.to_string())
+    }
+}
+
+impl From<&ark_client::Error> for ApiError {
+    fn from(e: &ark_client::Error) -> Self {
+        Self::new(e.kind().into(), e.to_string())
+    }
+}
+
+/// Answers malformed JSON bodies.
+pub fn json_error(e: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::invalid_request(format!("Invalid request body: {e}")).into()
+}
+
+/// Answers malformed query strings.
+pub fn query_error(e: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::invalid_request(format!("Invalid query: {e}")).into()
+}
+
+/// Answers requests that match no route.
+pub async fn not_found() -> HttpResponse {
+    ApiError::new(ErrorCode::NotFound, "No such route").error_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn codes_are_snake_case() {
+        let error = ApiError::account_not_found();
+        let body = serde_json::to_value(&error).unwrap();
+
+        assert_eq!(body["code"], "account_not_found");
+        assert_eq!(body["message"], "Account not found");
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn anyhow_errors_keep_their_code() {
+        let e = anyhow::Error::new(ApiError::new(ErrorCode::InsufficientFunds, "not enough"))
+            .context("sending");
+        let error = ApiError::caused_by("Failed to send", &e, ErrorCode::Internal);
+        assert_eq!(error.code, ErrorCode::InsufficientFunds);
+        assert_eq!(error.message, "Failed to send: sending");
+
+        let e = anyhow::Error::new(RoundFailed {
+            round_id: "r1".to_string(),
+            reason: "timeout".to_string(),
+        });
+        let error = ApiError::caused_by("Withdrawal failed", &e, ErrorCode::Internal);
+        assert_eq!(error.code, ErrorCode::RoundFailed);
+        assert_eq!(error.status_code(), StatusCode::BAD_GATEWAY);
+
+        let e = Err::<(), _>(std::io::Error::other("disk full"))
+            .context("storing")
+            .unwrap_err();
+        let error = ApiError::caused_by("Failed", &e, ErrorCode::NetworkUnavailable);
+        assert_eq!(error.code, ErrorCode::NetworkUnavailable);
+    }
+
+    #[test]
+    fn invalid_ark_addresses_are_reported_as_such() {
+        let e = ark_core::ArkAddress::decode("tark1notanaddress").unwrap_err();
+        assert_eq!(ApiError::from(e).code, ErrorCode::InvalidAddress);
+    }
+}
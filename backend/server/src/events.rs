@@ -0,0 +1,660 @@
+//! Live account events for `GET /api/accounts/{account_id}/events`.
+//!
+//! While an account has subscribers, a watcher task follows the Ark server's transaction stream
+//! and the explorer's `SubscribeForAddress` stream of each of its address keys. Whenever either
+//! reports something, and on a fixed tick, the watcher takes a snapshot of the account's VTXOs and
+//! boarding outputs and [`reconcile`]s it with what the account's event log already knows. New
+//! events are appended to the log before they are broadcast, so a client that reconnects with the
+//! sequence number of the last event it saw misses nothing, even across server restarts.
+//!
+//! The watcher reconnects when a stream fails, backing off as the connection to the Ark server
+//! does (see [`ConnectionConfig`](crate::core::config::ConnectionConfig)), and takes a full
+//! snapshot every time it connects, so events that happened while it was disconnected are picked
+//! up too. It remembers what the log has reported across reconnects, and reads the log in pages
+//! from where it left off. Accounts with webhooks are [pinned](EventHub::pin), which keeps their
+//! watcher running without subscribers.
+//!
+//! Handlers and background tasks report what only they see, such as completed transfers, failed
+//! rounds and VTXOs that locked accounts cannot renew, through [`publish`] as well, which also
+//! queues the events for the account's webhooks. Off-boarding rounds are followed on the explorer
+//! until they confirm by [`report_confirmations`].
+
+use actix_web::web;
+use anyhow::{anyhow, Result};
+use ark_client::{Blockchain, ExplorerUtxo};
+use ark_core::server::{TransactionEvent, VtxoOutPoint};
+use bitcoin::{OutPoint, Txid};
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::clients::RoundFailed;
+use crate::core::model::{AccountEvent, ApplicationState, StoredEvent};
+use crate::webhooks;
+
+/// How many events a slow subscriber may fall behind before it has to catch up from storage.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// How often the watcher takes a snapshot even if neither stream reported anything.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often [`report_confirmations`] asks the explorer about the rounds it follows.
+const CONFIRMATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long [`report_confirmations`] follows a round before giving up on it.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many events of the log the watcher reads at once.
+const LOG_PAGE: usize = 256;
+
+
+/// What the event log of an account has already reported.
+#[derive(Default)]
+pub struct KnownState {
+    received: HashSet<String>,
+    spent: HashSet<String>,
+    boarding: HashSet<String>,
+    rounds: HashSet<String>,
+}
+
+impl KnownState {
+    pub fn apply(&mut self, event: &AccountEvent) {
+        match event {
+            AccountEvent::VtxoReceived { outpoint, .. } => {
+                self.received.insert(outpoint.clone());
+            }
+            AccountEvent::VtxoSpent { outpoint, .. } => {
+                self.spent.insert(outpoint.clone());
+            }
+            AccountEvent::BoardingConfirmed { outpoint, .. } => {
+                self.boarding.insert(outpoint.clone());
+            }
+            AccountEvent::RoundCompleted { txid } => {
+                self.rounds.insert(txid.clone());
+            }
+            // Reported by handlers, not by the watcher.
+            AccountEvent::TransferCompleted { .. }
+            | AccountEvent::RoundFailed { .. }
+            | AccountEvent::OffboardConfirmed { .. }
+            | AccountEvent::VtxosExpiring { .. } => {}
+        }
+    }
+
+    fn has_received(&self, outpoint: &OutPoint) -> bool {
+        self.received.contains(&outpoint.to_string())
+    }
+
+    /// Apply the events of `account_id` logged after `seq`, moving `seq` to the last of them.
+    fn catch_up(
+        &mut self,
+        state: &ApplicationState,
+        account_id: &str,
+        seq: &mut u64,
+    ) -> Result<()> {
+        loop {
+            let page = state.accounts.events_after(account_id, *seq, LOG_PAGE)?;
+            let Some(last) = page.last() else {
+                return Ok(());
+            };
+            *seq = last.seq;
+
+            for stored in &page {
+                self.apply(&stored.event);
+            }
+        }
+    }
+}
+
+/// The VTXOs and boarding outputs of an account at one point in time, tagged with the index of
+/// the address key they belong to.
+#[derive(Default)]
+pub struct Snapshot {
+    pub vtxos: Vec<(u32, VtxoOutPoint)>,
+    pub boarding: Vec<(u32, ExplorerUtxo)>,
+}
+
+/// The events that explain how the account got from `known` to `snapshot`, in the order they
+/// should be reported. `known` is not updated.
+///
+/// A round is reported once a settled VTXO it created shows up. A VTXO that was received and
+/// spent in between two snapshots is reported as both.
+pub fn reconcile(known: &KnownState, snapshot: &Snapshot) -> Vec<AccountEvent> {
+    let mut events = Vec::new();
+    let mut rounds = HashSet::new();
+
+    for (key_index, vtxo) in &snapshot.vtxos {
+        let outpoint = vtxo.outpoint.to_string();
+
+        if !known.received.contains(&outpoint) {
+            events.push(AccountEvent::VtxoReceived {
+                outpoint: outpoint.clone(),
+                amount: vtxo.amount.to_sat(),
+                key_index: *key_index,
+            });
+        }
+
+        if (vtxo.spent || vtxo.spent_by.is_some()) && !known.spent.contains(&outpoint) {
+            events.push(AccountEvent::VtxoSpent {
+                outpoint,
+                amount: vtxo.amount.to_sat(),
+                spent_by: vtxo.spent_by.map(|txid| txid.to_string()),
+                key_index: *key_index,
+            });
+        }
+
+        let round_txid = vtxo.round_txid.to_string();
+        if !vtxo.is_pending && !known.rounds.contains(&round_txid) && rounds.insert(round_txid) {
+            events.push(AccountEvent::RoundCompleted {
+                txid: vtxo.round_txid.to_string(),
+            });
+        }
+    }
+
+    for (key_index, utxo) in &snapshot.boarding {
+        let outpoint = utxo.outpoint.to_string();
+
+        if let Some(confirmed_at) = utxo.confirmation_blocktime
+            && !known.boarding.contains(&outpoint)
+        {
+            events.push(AccountEvent::BoardingConfirmed {
+                outpoint,
+                amount: utxo.amount.to_sat(),
+                confirmed_at,
+                key_index: *key_index,
+            });
+        }
+    }
+
+    events
+}
+
+/// Append `events` to the event log of `account_id`, queue them for its webhooks and broadcast
+/// them to its subscribers. Returns the events as stored.
+pub fn publish(
+    state: &ApplicationState,
+    account_id: &str,
+    events: &[AccountEvent],
+) -> Result<Vec<StoredEvent>> {
+    let stored = state.accounts.append_events(account_id, events)?;
+
+    // The events are in the log either way, so a failure here only costs webhook deliveries.
+    if let Err(e) = webhooks::enqueue(state, account_id, &stored) {
+        tracing::error!(account_id, "Failed to queue webhook deliveries: {:#}", e);
+    }
+
+    if let Some(sender) = state.events.channels.lock().unwrap().get(account_id) {
+        for event in &stored {
+            // Nobody listening is fine: the events are in the log.
+            let _ = sender.send(event.clone());
+        }
+    }
+
+    Ok(stored)
+}
+
+/// [`publish`] a single event on the side of something else, only logging failures.
+pub fn report(state: &ApplicationState, account_id: &str, event: AccountEvent) {
+    if let Err(e) = publish(state, account_id, &[event]) {
+        tracing::error!(account_id, "Failed to publish account event: {:#}", e);
+    }
+}
+
+/// [`report`] the failed round behind `error`, if there is one.
+pub fn report_round_failure(state: &ApplicationState, account_id: &str, error: &anyhow::Error) {
+    if let Some(failed) = error.downcast_ref::<RoundFailed>() {
+        let event = AccountEvent::RoundFailed {
+            round_id: failed.round_id.clone(),
+            reason: failed.reason.clone(),
+        };
+        report(state, account_id, event);
+    }
+}
+
+/// Report an [`AccountEvent::OffboardConfirmed`] for each of the `txids` of the rounds
+/// `account_id` off-boarded with, once the explorer sees it confirmed.
+///
+/// The rounds are followed by a background task for up to [`CONFIRMATION_TIMEOUT`], and not
+/// across server restarts.
+pub fn report_confirmations(
+    state: web::Data<ApplicationState>,
+    account_id: String,
+    txids: Vec<Txid>,
+) {
+    actix_web::rt::spawn(async move {
+        let deadline = tokio::time::Instant::now() + CONFIRMATION_TIMEOUT;
+        let mut interval = tokio::time::interval(CONFIRMATION_INTERVAL);
+        let mut pending = txids;
+
+        while !pending.is_empty() && tokio::time::Instant::now() < deadline {
+            interval.tick().await;
+
+            let blockchain = match state.blockchain_client.lock().unwrap().clone() {
+                Some(client) => client,
+                None => continue,
+            };
+
+            let mut unconfirmed = Vec::new();
+            for txid in pending {
+                match blockchain.client.get_tx_status(&txid).await {
+                    Ok(status) if status.confirmed => {
+                        let event = AccountEvent::OffboardConfirmed {
+                            txid: txid.to_string(),
+                            confirmed_at: status.block_time.unwrap_or_default(),
+                        };
+                        report(&state, &account_id, event);
+                    }
+                    Ok(_) => unconfirmed.push(txid),
+                    Err(e) => {
+                        tracing::warn!(account_id, %txid, "Failed to look up round: {}", e);
+                        unconfirmed.push(txid);
+                    }
+                }
+            }
+            pending = unconfirmed;
+        }
+
+        if !pending.is_empty() {
+            tracing::warn!(
+                account_id,
+                ?pending,
+                "Gave up waiting for off-boarding rounds to confirm"
+            );
+        }
+    });
+}
+
+/// The broadcast channels of accounts with subscribers or pins, each fed by one watcher task.
+#[derive(Default)]
+pub struct EventHub {
+    channels: Mutex<HashMap<String, broadcast::Sender<StoredEvent>>>,
+    pinned: Mutex<HashSet<String>>,
+}
+
+impl EventHub {
+    /// Receive the events of `account_id` from now on, starting its watcher if it has none.
+    pub fn subscribe(
+        &self,
+        state: &web::Data<ApplicationState>,
+        account_id: &str,
+    ) -> broadcast::Receiver<StoredEvent> {
+        self.channel(state, account_id).subscribe()
+    }
+
+    /// Keep the watcher of `account_id` running even while it has no subscribers, starting it if
+    /// needed.
+    pub fn pin(&self, state: &web::Data<ApplicationState>, account_id: &str) {
+        self.pinned.lock().unwrap().insert(account_id.to_string());
+        self.channel(state, account_id);
+    }
+
+    /// Undo [`EventHub::pin`]. The watcher stops once it has no subscribers either.
+    pub fn unpin(&self, account_id: &str) {
+        self.pinned.lock().unwrap().remove(account_id);
+    }
+
+    fn channel(
+        &self,
+        state: &web::Data<ApplicationState>,
+        account_id: &str,
+    ) -> broadcast::Sender<StoredEvent> {
+        let mut channels = self.channels.lock().unwrap();
+
+        if let Some(sender) = channels.get(account_id) {
+            return sender.clone();
+        }
+
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        channels.insert(account_id.to_string(), sender.clone());
+
+        actix_web::rt::spawn(watch(state.clone(), account_id.to_string(), sender.clone()));
+
+        sender
+    }
+
+    /// Whether the watcher of `account_id` has nobody to report to.
+    fn is_idle(&self, account_id: &str, sender: &broadcast::Sender<StoredEvent>) -> bool {
+        sender.receiver_count() == 0 && !self.pinned.lock().unwrap().contains(account_id)
+    }
+
+    /// Forget the channel of `account_id` if nobody listens to it anymore. Returns whether it was
+    /// removed, in which case its watcher should stop.
+    fn close_if_idle(&self, account_id: &str) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+
+        let idle = channels
+            .get(account_id)
+            .is_none_or(|sender| self.is_idle(account_id, sender));
+        if idle {
+            channels.remove(account_id);
+        }
+
+        idle
+    }
+}
+
+/// Feed `sender` with the events of `account_id` until it has no receivers left and is not
+/// pinned.
+async fn watch(
+    state: web::Data<ApplicationState>,
+    account_id: String,
+    sender: broadcast::Sender<StoredEvent>,
+) {
+    let retry = state.config.connection.retry_policy();
+    let mut failures = 0;
+    let mut known = KnownState::default();
+    let mut seq = 0;
+
+    loop {
+        let watched = watch_once(&state, &account_id, &sender, &mut known, &mut seq, &mut failures);
+        if let Err(e) = watched.await {
+            failures = failures.saturating_add(1);
+            let delay = retry.delay(failures).max(1);
+            tracing::warn!(
+                account_id,
+                retry_in_seconds = delay,
+                "Account event watcher failed: {:#}",
+                e
+            );
+
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+        }
+
+        if state.events.close_if_idle(&account_id) {
+            tracing::debug!(account_id, "Stopped account event watcher");
+            return;
+        }
+    }
+}
+
+/// Connect to the Ark server and report events until the streams fail, the account hands out
+/// a new address key, or the watcher is idle.
+///
+/// `known` is what the event log of the account up to `seq` has reported, and is brought up to
+/// date first. `failures` is reset once the connection is up and the initial snapshot was taken.
+async fn watch_once(
+    state: &ApplicationState,
+    account_id: &str,
+    sender: &broadcast::Sender<StoredEvent>,
+    known: &mut KnownState,
+    seq: &mut u64,
+    failures: &mut u32,
+) -> Result<()> {
+    let account = state
+        .accounts
+        .get_account(account_id)?
+        .ok_or_else(|| anyhow!("account {} does not exist", account_id))?;
+
+    let network_info = match state.server_connection.lock().unwrap().clone() {
+        Some(info) => info,
+        None => return Err(anyhow!("network unavailable")),
+    };
+
+    let blockchain = match state.blockchain_client.lock().unwrap().clone() {
+        Some(client) => client,
+        None => return Err(anyhow!("blockchain client unavailable")),
+    };
+
+    let mut keys = Vec::new();
+    for index in 0..account.key_count.max(1) {
+        let (boarding_output, vtxo) = account.key_outputs(&network_info, index)?;
+        keys.push((
+            index,
+            vtxo.to_ark_address(),
+            boarding_output.address().clone(),
+        ));
+    }
+
+    let mut client = ark_grpc::Client::new(state.config.ark_server_url.clone());
+    client.connect().await?;
+
+    let mut tx_stream = client.get_tx_stream().await?;
+
+    let mut address_streams = Vec::new();
+    for (_, address, _) in &keys {
+        address_streams.push(client.subscribe_for_address(address).await?);
+    }
+    let mut address_stream = futures::stream::select_all(address_streams);
+
+    known.catch_up(state, account_id, seq)?;
+
+    let mut tick = tokio::time::interval_at(
+        tokio::time::Instant::now() + RECONCILE_INTERVAL,
+        RECONCILE_INTERVAL,
+    );
+    let mut connected = false;
+
+    loop {
+        if connected {
+            tokio::select! {
+                _ = tick.tick() => {
+                    // Restart with the new key if the account handed one out.
+                    let key_count = state
+                        .accounts
+                        .get_account(account_id)?
+                        .map(|account| account.key_count.max(1));
+                    if key_count != Some(keys.len() as u32) {
+                        return Ok(());
+                    }
+                }
+                event = tx_stream.next() => {
+                    match event.ok_or_else(|| anyhow!("transaction stream closed"))?? {
+                        TransactionEvent::Round(_) => {}
+                        // Only our own redeem transactions can change anything.
+                        TransactionEvent::Redeem(redeem) => {
+                            if !redeem
+                                .spent_vtxos
+                                .iter()
+                                .any(|vtxo| known.has_received(&vtxo.outpoint))
+                            {
+                                continue;
+                            }
+                        }
+                    }
+                }
+                event = address_stream.next() => {
+                    event.ok_or_else(|| anyhow!("address stream closed"))??;
+                }
+            }
+        }
+
+        if state.events.is_idle(account_id, sender) {
+            return Ok(());
+        }
+
+        let mut snapshot = Snapshot::default();
+        for (index, address, boarding_address) in &keys {
+            let vtxos = client.list_vtxos(address).await?;
+            snapshot.vtxos.extend(
+                vtxos
+                    .spendable
+                    .into_iter()
+                    .chain(vtxos.spent)
+                    .map(|vtxo| (*index, vtxo)),
+            );
+
+            let utxos = blockchain
+                .find_outpoints(boarding_address)
+                .await
+                .map_err(|e| anyhow!("failed to look up boarding outputs: {}", e))?;
+            snapshot
+                .boarding
+                .extend(utxos.into_iter().map(|utxo| (*index, utxo)));
+        }
+
+        let events = reconcile(known, &snapshot);
+        if !events.is_empty() {
+            for stored in publish(state, account_id, &events)? {
+                known.apply(&stored.event);
+            }
+        }
+
+        if !connected {
+            connected = true;
+            *failures = 0;
+            tracing::debug!(account_id, "Account event watcher connected");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, Txid};
+
+    fn known(events: &[AccountEvent]) -> KnownState {
+        let mut known = KnownState::default();
+        for event in events {
+            known.apply(event);
+        }
+
+        known
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    fn vtxo(n: u8, round: u8, spent_by: Option<Txid>) -> VtxoOutPoint {
+        VtxoOutPoint {
+            outpoint: OutPoint {
+                txid: txid(n),
+                vout: 0,
+            },
+            spent: spent_by.is_some(),
+            round_txid: txid(round),
+            spent_by,
+            created_at: 0,
+            expire_at: 0,
+            swept: false,
+            is_pending: false,
+            redeem_tx: None,
+            amount: Amount::from_sat(1_000),
+            pubkey: String::new(),
+        }
+    }
+
+    fn boarding(n: u8, confirmed_at: Option<u64>) -> ExplorerUtxo {
+        ExplorerUtxo {
+            outpoint: OutPoint {
+                txid: txid(n),
+                vout: 1,
+            },
+            amount: Amount::from_sat(5_000),
+            confirmation_blocktime: confirmed_at,
+            is_spent: false,
+        }
+    }
+
+    #[test]
+    fn new_vtxos_and_rounds_are_reported_once() {
+        let snapshot = Snapshot {
+            vtxos: vec![(0, vtxo(1, 9, None)), (1, vtxo(2, 9, None))],
+            boarding: vec![(0, boarding(3, None))],
+        };
+
+        let events = reconcile(&KnownState::default(), &snapshot);
+        let names = events.iter().map(AccountEvent::name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["vtxo_received", "round_completed", "vtxo_received"]
+        );
+        assert_eq!(
+            events[1],
+            AccountEvent::RoundCompleted {
+                txid: txid(9).to_string()
+            }
+        );
+
+        // Once the events are in the log, the same snapshot reports nothing.
+        let known = known(&events);
+        assert!(reconcile(&known, &snapshot).is_empty());
+    }
+
+    #[test]
+    fn spends_and_boarding_confirmations_are_reported() {
+        let before = Snapshot {
+            vtxos: vec![(0, vtxo(1, 9, None))],
+            boarding: vec![(2, boarding(3, None))],
+        };
+        let known = known(&reconcile(&KnownState::default(), &before));
+
+        let after = Snapshot {
+            vtxos: vec![(0, vtxo(1, 9, Some(txid(4))))],
+            boarding: vec![(2, boarding(3, Some(1_700_000_000)))],
+        };
+
+        assert_eq!(
+            reconcile(&known, &after),
+            vec![
+                AccountEvent::VtxoSpent {
+                    outpoint: format!("{}:0", txid(1)),
+                    amount: 1_000,
+                    spent_by: Some(txid(4).to_string()),
+                    key_index: 0,
+                },
+                AccountEvent::BoardingConfirmed {
+                    outpoint: format!("{}:1", txid(3)),
+                    amount: 5_000,
+                    confirmed_at: 1_700_000_000,
+                    key_index: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pending_vtxos_do_not_complete_rounds() {
+        let snapshot = Snapshot {
+            vtxos: vec![(
+                0,
+                VtxoOutPoint {
+                    is_pending: true,
+                    ..vtxo(1, 9, None)
+                },
+            )],
+            boarding: vec![],
+        };
+
+        let events = reconcile(&KnownState::default(), &snapshot);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name(), "vtxo_received");
+    }
+
+    #[test]
+    fn known_state_catches_up_from_where_it_left_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = crate::auth::tests::state(&dir);
+        let account_id = crate::auth::tests::add_account(&state);
+
+        // More than a page.
+        let received = (0..LOG_PAGE as u32 + 10)
+            .map(|n| AccountEvent::VtxoReceived {
+                outpoint: format!("{}:{}", txid(1), n),
+                amount: 1_000,
+                key_index: 0,
+            })
+            .collect::<Vec<_>>();
+        state.accounts.append_events(&account_id, &received).unwrap();
+
+        let mut known = KnownState::default();
+        let mut seq = 0;
+        known.catch_up(&state, &account_id, &mut seq).unwrap();
+        assert_eq!(seq, received.len() as u64);
+        assert_eq!(known.received.len(), received.len());
+
+        let spent = AccountEvent::VtxoSpent {
+            outpoint: format!("{}:0", txid(1)),
+            amount: 1_000,
+            spent_by: None,
+            key_index: 0,
+        };
+        state.accounts.append_events(&account_id, &[spent]).unwrap();
+
+        known.catch_up(&state, &account_id, &mut seq).unwrap();
+        assert_eq!(seq, received.len() as u64 + 1);
+        assert!(known.spent.contains(&format!("{}:0", txid(1))));
+    }
+}
@@ -0,0 +1,694 @@
+//! Unilateral exits for `POST /api/accounts/{account_id}/exit`.
+//!
+//! The exit path of every settled VTXO of an unlocked account, i.e. the VTXO tree branch that
+//! leads to it, is fetched from the Ark server shortly after the VTXO shows up and recorded as a
+//! [`VtxoExitPath`]. An exit can therefore be started while the Ark server is down: it only needs
+//! the account's keys, to sign a sweep transaction per address key up front, and the blockchain
+//! explorer, to estimate the fee of the sweeps. Everything is kept in an [`ExitJob`], so the exit
+//! can be completed after a restart, without the account's keys and without the Ark server.
+//!
+//! A background task then drives every unfinished job: it publishes the branch transactions in
+//! order, waits for them to confirm and for the exit delay to pass, and publishes the sweeps. A
+//! job fails for good once an input of one of its transactions is spent by another transaction.
+
+use actix_web::web;
+use anyhow::{Context, Result};
+use ark_client::UnilateralExit;
+use ark_core::Vtxo;
+use ark_core::descriptor::DescriptorTemplate;
+use ark_core::unilateral_exit::{VtxoInput, create_unilateral_exit_sweep};
+use bitcoin::consensus::encode::{deserialize_hex, serialize_hex};
+use bitcoin::key::{Keypair, Secp256k1};
+use bitcoin::{
+    Address, Amount, FeeRate, Network, OutPoint, Sequence, Transaction, Txid, XOnlyPublicKey,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use crate::clients::AccountClient;
+use crate::core::model::{
+    ApplicationState, BlockchainClient, ExitJob, ExitStatus, ExitTransaction, ExitTransactionKind,
+    ExitTransactionStatus, VtxoExitPath,
+};
+use crate::keystore::UnlockedKeys;
+
+/// How often unfinished jobs are checked against the blockchain.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the exit paths of the VTXOs of unlocked accounts are brought up to date.
+const RECORD_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The confirmation target, in blocks, of the fee rate the sweeps pay.
+const SWEEP_CONFIRMATION_TARGET: u16 = 6;
+
+/// The fee rate for sweeps, from the fee estimates of the blockchain explorer, in sat/vB by
+/// confirmation target.
+///
+/// Uses the estimate for the closest target that is not slower than
+/// [`SWEEP_CONFIRMATION_TARGET`], and never goes below the 1 sat/vB relay minimum.
+pub fn sweep_fee_rate(estimates: &HashMap<u16, f64>) -> FeeRate {
+    let sat_per_vb = estimates
+        .iter()
+        .filter(|(target, _)| **target <= SWEEP_CONFIRMATION_TARGET)
+        .max_by_key(|(target, _)| **target)
+        .or_else(|| estimates.iter().min_by_key(|(target, _)| **target))
+        .map_or(1.0, |(_, rate)| rate.max(1.0));
+
+    FeeRate::from_sat_per_kwu((sat_per_vb * 250.0).ceil() as u64)
+}
+
+/// Sign a sweep per address key of the VTXOs of `paths` to `destination`, paying `fee_rate`.
+pub fn prepare_exits(
+    keys: &UnlockedKeys,
+    paths: &[VtxoExitPath],
+    destination: &Address,
+    fee_rate: FeeRate,
+) -> Result<Vec<UnilateralExit>> {
+    let secp = Secp256k1::new();
+
+    let mut by_key: BTreeMap<u32, Vec<VtxoExitPath>> = BTreeMap::new();
+    for path in paths {
+        by_key.entry(path.key_index).or_default().push(path.clone());
+    }
+
+    by_key
+        .into_iter()
+        .map(|(index, paths)| {
+            let kp = Keypair::from_secret_key(&secp, &keys.secret_key(index)?);
+            prepare_exit(&kp, &paths, destination, fee_rate)
+                .with_context(|| format!("cannot sweep the VTXOs of address key {index}"))
+        })
+        .collect()
+}
+
+/// Sign a sweep of the VTXOs of `paths`, which must all belong to the address key `kp`, to
+/// `destination`, paying `fee_rate`.
+fn prepare_exit(
+    kp: &Keypair,
+    paths: &[VtxoExitPath],
+    destination: &Address,
+    fee_rate: FeeRate,
+) -> Result<UnilateralExit> {
+    let secp = Secp256k1::new();
+
+    let mut branch_txs = Vec::new();
+    let mut vtxo_inputs = Vec::new();
+    let mut exit_delay = Duration::ZERO;
+    for path in paths {
+        let server = path.server_pk.parse::<XOnlyPublicKey>()?;
+        let (owner, _) = kp.x_only_public_key();
+        let network = path.network.parse::<Network>()?;
+        let vtxo = match &path.vtxo_template {
+            Some(template) => {
+                let template = DescriptorTemplate::parse(template)?;
+                Vtxo::from_template(&secp, &template, server, owner, network)?
+            }
+            None => Vtxo::new(
+                &secp,
+                server,
+                owner,
+                vec![],
+                Sequence::from_consensus(path.exit_delay),
+                network,
+            )?,
+        };
+        exit_delay = exit_delay.max(vtxo.exit_delay_duration());
+
+        for tx in &path.branch_txs {
+            let tx: Transaction = deserialize_hex(tx)?;
+            if !branch_txs.contains(&tx) {
+                branch_txs.push(tx);
+            }
+        }
+
+        let outpoint = path.outpoint.parse::<OutPoint>()?;
+        vtxo_inputs.push(VtxoInput::new(
+            vtxo,
+            Amount::from_sat(path.amount),
+            outpoint,
+        ));
+    }
+
+    let sweep_tx = create_unilateral_exit_sweep(
+        kp,
+        destination.clone(),
+        fee_rate,
+        destination.script_pubkey().minimal_non_dust(),
+        &vtxo_inputs,
+    )?;
+
+    Ok(UnilateralExit {
+        branch_txs,
+        sweep_tx,
+        exit_delay,
+    })
+}
+
+/// Bring the recorded exit paths of `account_id` up to date with the VTXOs of its connected
+/// `client`, fetching the branches of new VTXOs from the Ark server.
+///
+/// Returns the paths, together with the outpoints of the VTXOs that are not settled in a round
+/// yet and so have no exit path.
+pub async fn record_exit_paths(
+    state: &ApplicationState,
+    account_id: &str,
+    client: &AccountClient,
+) -> Result<(Vec<VtxoExitPath>, Vec<String>)> {
+    let known = state.accounts.list_exit_paths(account_id)?;
+    let (mut paths, unsettled) = client.exit_paths(&known).await?;
+    paths.sort_by(|a, b| a.outpoint.cmp(&b.outpoint));
+
+    if paths != known {
+        state
+            .accounts
+            .replace_exit_paths(account_id, &paths)
+            .context("failed to store exit paths")?;
+    }
+
+    Ok((paths, unsettled))
+}
+
+fn pending(tx: &Transaction, kind: ExitTransactionKind) -> ExitTransaction {
+    ExitTransaction {
+        txid: tx.compute_txid().to_string(),
+        kind,
+        status: ExitTransactionStatus::Pending,
+        confirmed_at: None,
+        last_error: None,
+        raw_tx: serialize_hex(tx),
+    }
+}
+
+/// A new job for the prepared `exits` of the VTXOs `vtxos` of the address keys of `account_id`.
+/// The `unsettled` VTXOs that had to be left out are listed in the job.
+///
+/// Keys whose VTXOs come from the same round share branch transactions, which are only listed
+/// once.
+pub fn new_job(
+    account_id: &str,
+    destination: &Address,
+    exits: &[UnilateralExit],
+    vtxos: Vec<String>,
+    unsettled: Vec<String>,
+) -> ExitJob {
+    let now = jiff::Timestamp::now().as_second();
+
+    let mut seen = HashSet::new();
+    let branches = exits
+        .iter()
+        .flat_map(|exit| &exit.branch_txs)
+        .filter(|tx| seen.insert(tx.compute_txid()))
+        .map(|tx| pending(tx, ExitTransactionKind::Branch));
+    let sweeps = exits
+        .iter()
+        .map(|exit| pending(&exit.sweep_tx, ExitTransactionKind::Sweep));
+
+    let mut job = ExitJob {
+        id: uuid::Uuid::new_v4().to_string(),
+        account_id: account_id.to_string(),
+        destination_address: destination.to_string(),
+        status: ExitStatus::Committing,
+        exit_delay_seconds: exits
+            .iter()
+            .map(|exit| exit.exit_delay.as_secs())
+            .max()
+            .unwrap_or(0),
+        spendable_at: None,
+        transactions: branches.chain(sweeps).collect(),
+        vtxos,
+        unsettled_vtxos: unsettled,
+        error: None,
+        created_at: now,
+        updated_at: now,
+    };
+    refresh(&mut job);
+
+    job
+}
+
+/// Derive the status and `spendable_at` of `job` from the state of its transactions. A job with
+/// an `error` stays failed.
+pub fn refresh(job: &mut ExitJob) {
+    let (branches, sweeps): (Vec<_>, Vec<_>) = job
+        .transactions
+        .iter()
+        .partition(|tx| tx.kind == ExitTransactionKind::Branch);
+
+    let committed = branches
+        .iter()
+        .all(|tx| tx.status == ExitTransactionStatus::Confirmed);
+
+    // The exit delay runs from the confirmation of each VTXO, so the last branch to confirm
+    // decides when all sweeps become valid. Branches that were published before the job was
+    // created count as confirmed when it was.
+    job.spendable_at = committed.then(|| {
+        let last_confirmation = branches
+            .iter()
+            .filter_map(|tx| tx.confirmed_at)
+            .max()
+            .map_or(job.created_at, |confirmed_at| confirmed_at as i64);
+
+        last_confirmation + job.exit_delay_seconds as i64
+    });
+
+    job.status = if job.error.is_some() {
+        ExitStatus::Failed
+    } else if !committed {
+        ExitStatus::Committing
+    } else if sweeps
+        .iter()
+        .all(|tx| tx.status == ExitTransactionStatus::Confirmed)
+    {
+        ExitStatus::Completed
+    } else if sweeps
+        .iter()
+        .any(|tx| tx.status != ExitTransactionStatus::Pending)
+    {
+        ExitStatus::Sweeping
+    } else {
+        ExitStatus::Waiting
+    };
+}
+
+/// Whether transaction `index` of `job` should be published or checked for confirmation at Unix
+/// time `now`.
+pub fn is_due(job: &ExitJob, index: usize, now: i64) -> bool {
+    let tx = &job.transactions[index];
+
+    match (tx.status, tx.kind) {
+        (ExitTransactionStatus::Confirmed, _) => false,
+        (_, ExitTransactionKind::Branch) => true,
+        (_, ExitTransactionKind::Sweep) => job.spendable_at.is_some_and(|at| now >= at),
+    }
+}
+
+/// Publish the transactions of `job` that are due, and record which ones confirmed.
+///
+/// A transaction that cannot be published yet, e.g. because its parent is not in the mempool,
+/// keeps the error and is tried again on the next call. If it cannot be published because
+/// another transaction spent one of its inputs, it never will be, and the job fails.
+async fn advance(job: &mut ExitJob, blockchain: &BlockchainClient) -> Result<()> {
+    let now = jiff::Timestamp::now().as_second();
+
+    for index in 0..job.transactions.len() {
+        if !is_due(job, index, now) {
+            continue;
+        }
+
+        let tx = &mut job.transactions[index];
+        let txid: Txid = tx.txid.parse()?;
+
+        if blockchain.client.get_tx(&txid).await?.is_none() {
+            let transaction: Transaction = deserialize_hex(&tx.raw_tx)?;
+
+            match blockchain.client.broadcast(&transaction).await {
+                Ok(()) => {
+                    tracing::info!(job_id = job.id, %txid, "Broadcasted exit transaction");
+                    tx.last_error = None;
+                }
+                Err(e) => {
+                    tx.last_error = Some(e.to_string());
+
+                    if let Some((outpoint, spender)) =
+                        conflicting_spend(&transaction, blockchain).await?
+                    {
+                        tracing::warn!(
+                            job_id = job.id,
+                            %txid,
+                            %outpoint,
+                            %spender,
+                            "Exit transaction input was spent by another transaction"
+                        );
+
+                        job.error = Some(format!(
+                            "input {outpoint} of transaction {txid} was spent by transaction {spender}"
+                        ));
+                        refresh(job);
+                        break;
+                    }
+
+                    continue;
+                }
+            }
+        }
+
+        tx.status = ExitTransactionStatus::Broadcast;
+
+        let status = blockchain.client.get_tx_status(&txid).await?;
+        if status.confirmed {
+            tx.status = ExitTransactionStatus::Confirmed;
+            tx.confirmed_at = status.block_time;
+        }
+
+        // Sweeps may have become due now that the last branch confirmed.
+        refresh(job);
+    }
+
+    job.updated_at = now;
+
+    Ok(())
+}
+
+/// The first input of `tx` that was spent by another transaction, with the ID of that
+/// transaction.
+async fn conflicting_spend(
+    tx: &Transaction,
+    blockchain: &BlockchainClient,
+) -> Result<Option<(OutPoint, Txid)>> {
+    let txid = tx.compute_txid();
+
+    for input in &tx.input {
+        let outpoint = input.previous_output;
+        let status = blockchain
+            .client
+            .get_output_status(&outpoint.txid, outpoint.vout as u64)
+            .await?;
+
+        if let Some(spender) = status.and_then(|status| status.txid)
+            && spender != txid
+        {
+            return Ok(Some((outpoint, spender)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Drive every unfinished exit job, forever.
+pub async fn run(state: web::Data<ApplicationState>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let blockchain = match state.blockchain_client.lock().unwrap().clone() {
+            Some(client) => client,
+            None => continue,
+        };
+
+        let jobs = match state.accounts.list_exit_jobs() {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                tracing::error!("Failed to load exit jobs: {:#}", e);
+                continue;
+            }
+        };
+
+        for mut job in jobs
+            .into_iter()
+            .filter(|job| !matches!(job.status, ExitStatus::Completed | ExitStatus::Failed))
+        {
+            let before = job.clone();
+
+            if let Err(e) = advance(&mut job, &blockchain).await {
+                tracing::warn!(job_id = job.id, "Failed to advance exit job: {:#}", e);
+            }
+            // Keep what was learned before a failure, too.
+            refresh(&mut job);
+
+            if (job.transactions != before.transactions || job.error != before.error)
+                && let Err(e) = state.accounts.update_exit_job(&job)
+            {
+                tracing::error!(job_id = job.id, "Failed to store exit job: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Record the exit paths of the VTXOs of every unlocked account, forever, so that they are at
+/// hand if the Ark server goes away.
+pub async fn record(state: web::Data<ApplicationState>) {
+    let mut interval = tokio::time::interval(RECORD_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let network = match state.server_connection.lock().unwrap().as_ref() {
+            Some(info) => info.network,
+            None => continue,
+        };
+
+        let blockchain = match state.blockchain_client.lock().unwrap().clone() {
+            Some(client) => client,
+            None => continue,
+        };
+
+        let accounts = match state.accounts.list_accounts() {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                tracing::error!("Failed to load accounts: {:#}", e);
+                continue;
+            }
+        };
+
+        for account in accounts {
+            let Some(keys) = state.unlocked_keys.get(&account.id) else {
+                continue;
+            };
+
+            let recorded = async {
+                let client = state
+                    .clients
+                    .get_or_connect(&state.config, &blockchain, network, &account, &keys)
+                    .await?;
+
+                record_exit_paths(&state, &account.id, &client).await
+            };
+
+            if let Err(e) = recorded.await {
+                tracing::warn!(
+                    account_id = account.id,
+                    "Failed to record exit paths: {:#}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, TxIn, absolute, transaction};
+
+    fn tx(n: u8) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::from_byte_array([n; 32]),
+                    vout: 0,
+                },
+                ..Default::default()
+            }],
+            output: vec![],
+        }
+    }
+
+    fn job() -> ExitJob {
+        let destination = "bcrt1q6rz28mcfaxtmd6v789l9rrlrusdprr9pz3cppk"
+            .parse::<Address<_>>()
+            .unwrap()
+            .assume_checked();
+
+        let exits = vec![
+            UnilateralExit {
+                branch_txs: vec![tx(1), tx(2)],
+                sweep_tx: tx(10),
+                exit_delay: Duration::from_secs(600),
+            },
+            // Shares the root of the first key's branch.
+            UnilateralExit {
+                branch_txs: vec![tx(1), tx(3)],
+                sweep_tx: tx(11),
+                exit_delay: Duration::from_secs(600),
+            },
+        ];
+
+        new_job(
+            "account",
+            &destination,
+            &exits,
+            vec![format!("{}:0", tx(2).compute_txid())],
+            vec![format!("{}:1", tx(4).compute_txid())],
+        )
+    }
+
+    fn confirm(job: &mut ExitJob, index: usize, at: u64) {
+        job.transactions[index].status = ExitTransactionStatus::Confirmed;
+        job.transactions[index].confirmed_at = Some(at);
+        refresh(job);
+    }
+
+    #[test]
+    fn shared_branches_are_published_once() {
+        let job = job();
+
+        let kinds = job
+            .transactions
+            .iter()
+            .map(|tx| (tx.txid.clone(), tx.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    tx(1).compute_txid().to_string(),
+                    ExitTransactionKind::Branch
+                ),
+                (
+                    tx(2).compute_txid().to_string(),
+                    ExitTransactionKind::Branch
+                ),
+                (
+                    tx(3).compute_txid().to_string(),
+                    ExitTransactionKind::Branch
+                ),
+                (
+                    tx(10).compute_txid().to_string(),
+                    ExitTransactionKind::Sweep
+                ),
+                (
+                    tx(11).compute_txid().to_string(),
+                    ExitTransactionKind::Sweep
+                ),
+            ]
+        );
+        assert_eq!(job.status, ExitStatus::Committing);
+        assert_eq!(job.exit_delay_seconds, 600);
+
+        // The signed transactions survive a round trip through storage.
+        let raw: Transaction = deserialize_hex(&job.transactions[3].raw_tx).unwrap();
+        assert_eq!(raw, tx(10));
+    }
+
+    #[test]
+    fn sweeps_wait_for_every_branch_and_the_exit_delay() {
+        let mut job = job();
+
+        assert!(is_due(&job, 0, 0));
+        assert!(!is_due(&job, 3, i64::MAX));
+
+        confirm(&mut job, 0, 1_000);
+        confirm(&mut job, 1, 1_500);
+        assert_eq!(job.status, ExitStatus::Committing);
+        assert_eq!(job.spendable_at, None);
+
+        confirm(&mut job, 2, 1_200);
+        assert_eq!(job.status, ExitStatus::Waiting);
+        assert_eq!(job.spendable_at, Some(2_100));
+        assert!(!is_due(&job, 0, 2_100));
+        assert!(!is_due(&job, 3, 2_099));
+        assert!(is_due(&job, 3, 2_100));
+    }
+
+    #[test]
+    fn job_completes_once_every_sweep_confirms() {
+        let mut job = job();
+        for index in 0..3 {
+            confirm(&mut job, index, 1_000);
+        }
+
+        job.transactions[3].status = ExitTransactionStatus::Broadcast;
+        refresh(&mut job);
+        assert_eq!(job.status, ExitStatus::Sweeping);
+
+        confirm(&mut job, 3, 2_000);
+        assert_eq!(job.status, ExitStatus::Sweeping);
+
+        confirm(&mut job, 4, 2_000);
+        assert_eq!(job.status, ExitStatus::Completed);
+    }
+
+    #[test]
+    fn failed_jobs_stay_failed() {
+        let mut job = job();
+        assert_eq!(job.unsettled_vtxos.len(), 1);
+
+        job.error = Some("input was spent".to_string());
+        refresh(&mut job);
+        assert_eq!(job.status, ExitStatus::Failed);
+
+        for index in 0..5 {
+            confirm(&mut job, index, 1_000);
+        }
+        assert_eq!(job.status, ExitStatus::Failed);
+    }
+
+    #[test]
+    fn sweep_fee_rate_follows_the_estimates() {
+        let estimates = HashMap::from([(1, 20.0), (3, 10.5), (6, 5.2), (144, 1.5)]);
+        assert_eq!(sweep_fee_rate(&estimates), FeeRate::from_sat_per_kwu(1_300));
+
+        // Falls back to a faster target, then to the slowest known one.
+        let estimates = HashMap::from([(1, 20.0), (3, 10.5), (144, 1.5)]);
+        assert_eq!(sweep_fee_rate(&estimates), FeeRate::from_sat_per_kwu(2_625));
+
+        let estimates = HashMap::from([(144, 0.5), (504, 0.2)]);
+        assert_eq!(
+            sweep_fee_rate(&estimates),
+            FeeRate::from_sat_per_vb_unchecked(1)
+        );
+
+        assert_eq!(
+            sweep_fee_rate(&HashMap::new()),
+            FeeRate::from_sat_per_vb_unchecked(1)
+        );
+    }
+
+    #[test]
+    fn exits_are_prepared_from_recorded_paths() {
+        let secp = Secp256k1::new();
+        let kp = Keypair::new(&secp, &mut rand::thread_rng());
+        let server_pk = Keypair::new(&secp, &mut rand::thread_rng())
+            .x_only_public_key()
+            .0;
+
+        let path = |n: u8, branch: Vec<Transaction>| VtxoExitPath {
+            outpoint: format!("{}:0", tx(n).compute_txid()),
+            key_index: 0,
+            amount: 10_000,
+            branch_txs: branch.iter().map(serialize_hex).collect(),
+            server_pk: server_pk.to_string(),
+            exit_delay: 512 | (1 << 22),
+            network: "regtest".to_string(),
+            vtxo_template: None,
+            recorded_at: 0,
+        };
+        let paths = vec![path(5, vec![tx(1), tx(5)]), path(6, vec![tx(1), tx(6)])];
+
+        let destination = "bcrt1q6rz28mcfaxtmd6v789l9rrlrusdprr9pz3cppk"
+            .parse::<Address<_>>()
+            .unwrap()
+            .assume_checked();
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(2);
+
+        let exit = prepare_exit(&kp, &paths, &destination, fee_rate).unwrap();
+
+        assert_eq!(exit.branch_txs, vec![tx(1), tx(5), tx(6)]);
+        assert_eq!(exit.exit_delay, Duration::from_secs(512 * 512));
+        assert_eq!(exit.sweep_tx.input.len(), 2);
+        assert_eq!(exit.sweep_tx.output.len(), 1);
+        assert_eq!(
+            exit.sweep_tx.output[0].script_pubkey,
+            destination.script_pubkey()
+        );
+
+        let fee = Amount::from_sat(20_000) - exit.sweep_tx.output[0].value;
+        assert!(fee >= fee_rate.fee_vb(exit.sweep_tx.vsize() as u64).unwrap());
+
+        // VTXOs built from a template of the Ark server are rebuilt from it.
+        let template = format!(
+            "tr({}, {{ and(pk({server_pk}), pk(USER)), and(older({}), pk(USER)) }})",
+            ark_core::UNSPENDABLE_KEY,
+            512 | (1 << 22) | 2,
+        );
+        let mut templated = path(7, vec![tx(7)]);
+        templated.vtxo_template = Some(template);
+
+        let exit = prepare_exit(&kp, &[templated], &destination, fee_rate).unwrap();
+        assert_eq!(exit.exit_delay, Duration::from_secs(514 * 512));
+    }
+}
@@ -0,0 +1,564 @@
+//! On-chain funding for `POST /api/fund`.
+//!
+//! Every backend implements [`Faucet`], and the one to use is picked from the `[faucet]` section
+//! of `ark.config.toml`. The handler refuses to fund anything on mainnet, whichever backend is
+//! configured, and every backend is wrapped in a [`LimitedFaucet`] which caps what a request may
+//! ask for and how often an address may be funded.
+
+use anyhow::{anyhow, Context, Result};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::ecdsa;
+use bitcoin::key::{CompressedPublicKey, Secp256k1};
+use bitcoin::secp256k1::{Message, SecretKey};
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::{
+    Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    absolute, transaction,
+};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::core::config::{FaucetBackend, FaucetConfig};
+use crate::core::model::BlockchainClient;
+use crate::error::{ApiError, ErrorCode};
+
+pub trait Faucet: Send + Sync {
+    /// Pay `amount` to `address`, returning the ID of the funding transaction.
+    fn fund<'a>(&'a self, address: &'a Address, amount: Amount) -> BoxFuture<'a, Result<Txid>>;
+
+    /// Whether funding requests should be attempted at all.
+    fn is_enabled(&self) -> bool {
+        true
+    }
+}
+
+/// Open the faucet described by `config`. The esplora backend spends through `blockchain`.
+pub fn open(
+    config: &FaucetConfig,
+    blockchain: Option<BlockchainClient>,
+) -> Result<Box<dyn Faucet>> {
+    let faucet: Box<dyn Faucet> = match &config.backend {
+        FaucetBackend::Disabled => Box::new(DisabledFaucet),
+        FaucetBackend::Bitcoind {
+            url,
+            user,
+            password,
+        } => Box::new(BitcoindFaucet {
+            client: reqwest::Client::new(),
+            url: url.clone(),
+            user: user.clone(),
+            password: password.clone(),
+        }),
+        FaucetBackend::Esplora {
+            secret_key,
+            fee_rate,
+        } => {
+            let blockchain = blockchain
+                .ok_or_else(|| anyhow!("the esplora faucet needs a blockchain client"))?;
+            Box::new(EsploraFaucet::new(blockchain, secret_key, *fee_rate)?)
+        }
+    };
+
+    Ok(Box::new(LimitedFaucet::new(
+        faucet,
+        Amount::from_sat(config.max_amount_sats),
+        Duration::from_secs(config.address_cooldown_seconds),
+    )))
+}
+
+/// Wraps another faucet, refusing amounts above `max_amount` and funding the same address more
+/// than once per `cooldown`.
+///
+/// Refusals are [`ApiError`]s, so that the handler can answer them as they are.
+pub struct LimitedFaucet {
+    inner: Box<dyn Faucet>,
+    max_amount: Amount,
+    cooldown: Duration,
+    /// When each address was last funded, or is being funded.
+    last_funded: Mutex<HashMap<ScriptBuf, Instant>>,
+}
+
+impl LimitedFaucet {
+    pub fn new(inner: Box<dyn Faucet>, max_amount: Amount, cooldown: Duration) -> Self {
+        Self {
+            inner,
+            max_amount,
+            cooldown,
+            last_funded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Claim `script_pubkey` for funding now, unless it was funded within the cooldown.
+    fn claim(&self, script_pubkey: &ScriptBuf) -> Result<()> {
+        let now = Instant::now();
+        let mut last_funded = self.last_funded.lock().unwrap();
+
+        last_funded.retain(|_, funded_at| now.duration_since(*funded_at) < self.cooldown);
+        if last_funded.contains_key(script_pubkey) {
+            return Err(ApiError::new(
+                ErrorCode::FaucetRateLimited,
+                format!(
+                    "This address was funded less than {} seconds ago",
+                    self.cooldown.as_secs()
+                ),
+            )
+            .into());
+        }
+
+        last_funded.insert(script_pubkey.clone(), now);
+
+        Ok(())
+    }
+}
+
+impl Faucet for LimitedFaucet {
+    fn fund<'a>(&'a self, address: &'a Address, amount: Amount) -> BoxFuture<'a, Result<Txid>> {
+        Box::pin(async move {
+            if amount > self.max_amount {
+                return Err(ApiError::invalid_request(format!(
+                    "Amount must not exceed {} sats",
+                    self.max_amount.to_sat()
+                ))
+                .into());
+            }
+
+            let script_pubkey = address.script_pubkey();
+            self.claim(&script_pubkey)?;
+
+            let result = self.inner.fund(address, amount).await;
+            if result.is_err() {
+                // Nothing was sent, so the address may ask again.
+                self.last_funded.lock().unwrap().remove(&script_pubkey);
+            }
+
+            result
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.inner.is_enabled()
+    }
+}
+
+pub struct DisabledFaucet;
+
+impl Faucet for DisabledFaucet {
+    fn fund<'a>(&'a self, _: &'a Address, _: Amount) -> BoxFuture<'a, Result<Txid>> {
+        Box::pin(async { Err(anyhow!("faucet is disabled")) })
+    }
+
+    fn is_enabled(&self) -> bool {
+        false
+    }
+}
+
+/// Pays from the wallet of a bitcoind node, meant for regtest.
+pub struct BitcoindFaucet {
+    client: reqwest::Client,
+    url: String,
+    user: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    result: Option<Txid>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl Faucet for BitcoindFaucet {
+    fn fund<'a>(&'a self, address: &'a Address, amount: Amount) -> BoxFuture<'a, Result<Txid>> {
+        Box::pin(async move {
+            let request = serde_json::json!({
+                "jsonrpc": "1.0",
+                "id": "ark-faucet",
+                "method": "sendtoaddress",
+                "params": [address.to_string(), amount.to_btc()],
+            });
+
+            // bitcoind answers RPC errors with an error status and a JSON body, so the body is
+            // read regardless of the status.
+            let response: RpcResponse = self
+                .client
+                .post(&self.url)
+                .basic_auth(&self.user, Some(&self.password))
+                .json(&request)
+                .send()
+                .await
+                .context("bitcoind RPC request failed")?
+                .json()
+                .await
+                .context("invalid bitcoind RPC response")?;
+
+            match response {
+                RpcResponse {
+                    error: Some(error), ..
+                } => Err(anyhow!(
+                    "bitcoind RPC error {}: {}",
+                    error.code,
+                    error.message
+                )),
+                RpcResponse {
+                    result: Some(txid), ..
+                } => Ok(txid),
+                _ => Err(anyhow!("bitcoind RPC response has no result")),
+            }
+        })
+    }
+}
+
+/// Pays from a single-key P2WPKH hot wallet, using esplora to find and spend its coins.
+pub struct EsploraFaucet {
+    blockchain: BlockchainClient,
+    secret_key: SecretKey,
+    public_key: CompressedPublicKey,
+    fee_rate: u64,
+    /// Held from picking coins until the payment is broadcast, so that concurrent payments do
+    /// not pick the same coins. Holds the coins spent by our own payments which esplora may not
+    /// have seen yet.
+    spending: tokio::sync::Mutex<HashSet<OutPoint>>,
+}
+
+/// How many confirmed transactions esplora lists per page of an address's history.
+const ESPLORA_PAGE_SIZE: usize = 25;
+
+/// Estimated virtual size of a transaction with P2WPKH inputs, and room for taproot outputs.
+fn estimated_vsize(inputs: usize, outputs: usize) -> u64 {
+    11 + 68 * inputs as u64 + 43 * outputs as u64
+}
+
+/// Change below this is left to the fee rather than creating an uneconomical output.
+const DUST: Amount = Amount::from_sat(546);
+
+/// Pick coins, largest first, that pay `amount` plus the fee at `fee_rate` sats per vbyte.
+///
+/// Returns the coins and the change, which is zero if it would have been dust.
+pub fn select_coins(
+    mut coins: Vec<(OutPoint, Amount)>,
+    amount: Amount,
+    fee_rate: u64,
+) -> Result<(Vec<(OutPoint, Amount)>, Amount)> {
+    coins.sort_by_key(|(_, amount)| std::cmp::Reverse(*amount));
+
+    let mut selected = Vec::new();
+    let mut total = Amount::ZERO;
+    for coin in coins {
+        total += coin.1;
+        selected.push(coin);
+
+        let fee = Amount::from_sat(estimated_vsize(selected.len(), 2) * fee_rate);
+        if let Some(change) = total.checked_sub(amount + fee) {
+            let change = if change < DUST { Amount::ZERO } else { change };
+            return Ok((selected, change));
+        }
+    }
+
+    Err(anyhow!(
+        "insufficient faucet funds: {} available, {} needed before fees",
+        total,
+        amount
+    ))
+}
+
+/// The coins of `received` which are not in `spent`.
+fn unspent(
+    received: Vec<(OutPoint, Amount)>,
+    spent: &HashSet<OutPoint>,
+) -> Vec<(OutPoint, Amount)> {
+    received
+        .into_iter()
+        .filter(|(outpoint, _)| !spent.contains(outpoint))
+        .collect()
+}
+
+impl EsploraFaucet {
+    fn new(blockchain: BlockchainClient, secret_key: &str, fee_rate: u64) -> Result<Self> {
+        let secret_key: SecretKey = secret_key.parse().context("invalid faucet secret key")?;
+        let public_key = CompressedPublicKey(secret_key.public_key(&Secp256k1::new()));
+
+        Ok(Self {
+            blockchain,
+            secret_key,
+            public_key,
+            fee_rate,
+            spending: tokio::sync::Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn script_pubkey(&self) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&self.public_key.wpubkey_hash())
+    }
+
+    /// The unspent outputs of the hot wallet, including unconfirmed ones.
+    ///
+    /// Every transaction spending one of the wallet's outputs is in the wallet's history too, so
+    /// the whole history tells which outputs are spent without asking about each of them.
+    async fn coins(&self) -> Result<Vec<(OutPoint, Amount)>> {
+        let script_pubkey = self.script_pubkey();
+
+        let mut received = Vec::new();
+        let mut spent = HashSet::new();
+        let mut last_seen = None;
+        loop {
+            let transactions = self
+                .blockchain
+                .client
+                .scripthash_txs(&script_pubkey, last_seen)
+                .await?;
+            let count = transactions.len();
+            last_seen = transactions.last().map(|tx| tx.txid);
+
+            for tx in transactions {
+                spent.extend(tx.vin.iter().map(|input| OutPoint {
+                    txid: input.txid,
+                    vout: input.vout,
+                }));
+
+                for (vout, output) in tx.vout.iter().enumerate() {
+                    if output.scriptpubkey == script_pubkey {
+                        let outpoint = OutPoint {
+                            txid: tx.txid,
+                            vout: vout as u32,
+                        };
+                        received.push((outpoint, Amount::from_sat(output.value)));
+                    }
+                }
+            }
+
+            if count < ESPLORA_PAGE_SIZE {
+                break;
+            }
+        }
+
+        Ok(unspent(received, &spent))
+    }
+
+    fn sign(&self, coins: &[(OutPoint, Amount)], outputs: Vec<TxOut>) -> Result<Transaction> {
+        let secp = Secp256k1::new();
+        let script_pubkey = self.script_pubkey();
+
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: coins
+                .iter()
+                .map(|(outpoint, _)| TxIn {
+                    previous_output: *outpoint,
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs,
+        };
+
+        let mut cache = SighashCache::new(&mut tx);
+        for (i, (_, amount)) in coins.iter().enumerate() {
+            let sighash =
+                cache.p2wpkh_signature_hash(i, &script_pubkey, *amount, EcdsaSighashType::All)?;
+
+            let signature = ecdsa::Signature {
+                signature: secp.sign_ecdsa(&Message::from(sighash), &self.secret_key),
+                sighash_type: EcdsaSighashType::All,
+            };
+
+            *cache
+                .witness_mut(i)
+                .ok_or_else(|| anyhow!("missing input {}", i))? =
+                Witness::p2wpkh(&signature, &self.public_key.0);
+        }
+
+        Ok(tx)
+    }
+}
+
+impl Faucet for EsploraFaucet {
+    fn fund<'a>(&'a self, address: &'a Address, amount: Amount) -> BoxFuture<'a, Result<Txid>> {
+        Box::pin(async move {
+            let mut spending = self.spending.lock().await;
+
+            let coins = self.coins().await?;
+            // Coins esplora no longer lists as unspent need not be remembered.
+            spending.retain(|outpoint| coins.iter().any(|(coin, _)| coin == outpoint));
+            let coins = unspent(coins, &spending);
+
+            let (coins, change) = select_coins(coins, amount, self.fee_rate)?;
+
+            let mut outputs = vec![TxOut {
+                value: amount,
+                script_pubkey: address.script_pubkey(),
+            }];
+            if change > Amount::ZERO {
+                outputs.push(TxOut {
+                    value: change,
+                    script_pubkey: self.script_pubkey(),
+                });
+            }
+
+            let tx = self.sign(&coins, outputs)?;
+
+            self.blockchain
+                .client
+                .broadcast(&tx)
+                .await
+                .with_context(|| format!("failed to broadcast {}", serialize_hex(&tx)))?;
+
+            spending.extend(coins.iter().map(|(outpoint, _)| *outpoint));
+
+            Ok(tx.compute_txid())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    fn coin(n: u8, sats: u64) -> (OutPoint, Amount) {
+        let outpoint = OutPoint {
+            txid: Txid::from_byte_array([n; 32]),
+            vout: 0,
+        };
+        (outpoint, Amount::from_sat(sats))
+    }
+
+    #[test]
+    fn largest_coins_are_spent_first() {
+        let coins = vec![coin(1, 1_000), coin(2, 50_000), coin(3, 20_000)];
+
+        let (selected, change) = select_coins(coins, Amount::from_sat(30_000), 2).unwrap();
+
+        // One input and two outputs weigh 165 vbytes.
+        assert_eq!(selected, vec![coin(2, 50_000)]);
+        assert_eq!(change, Amount::from_sat(50_000 - 30_000 - 330));
+    }
+
+    #[test]
+    fn dust_change_goes_to_the_fee() {
+        let coins = vec![coin(1, 10_500)];
+
+        let (selected, change) = select_coins(coins, Amount::from_sat(10_000), 1).unwrap();
+
+        assert_eq!(selected.len(), 1);
+        assert_eq!(change, Amount::ZERO);
+    }
+
+    #[test]
+    fn fees_can_exhaust_the_wallet() {
+        let coins = vec![coin(1, 10_000), coin(2, 10_000)];
+
+        assert!(select_coins(coins.clone(), Amount::from_sat(19_000), 1).is_ok());
+        assert!(select_coins(coins, Amount::from_sat(19_900), 1).is_err());
+    }
+
+    /// Succeeds unless told to fail, counting the payments it made.
+    #[derive(Default)]
+    struct FakeFaucet {
+        fail: AtomicBool,
+        paid: AtomicUsize,
+    }
+
+    impl Faucet for Arc<FakeFaucet> {
+        fn fund<'a>(&'a self, _: &'a Address, _: Amount) -> BoxFuture<'a, Result<Txid>> {
+            Box::pin(async move {
+                if self.fail.load(Ordering::SeqCst) {
+                    return Err(anyhow!("failed to broadcast 0200..."));
+                }
+
+                self.paid.fetch_add(1, Ordering::SeqCst);
+                Ok(Txid::all_zeros())
+            })
+        }
+    }
+
+    fn address(n: u8) -> Address {
+        Address::p2wpkh(
+            &CompressedPublicKey(
+                SecretKey::from_slice(&[n; 32])
+                    .unwrap()
+                    .public_key(&Secp256k1::new()),
+            ),
+            bitcoin::Network::Regtest,
+        )
+    }
+
+    fn code(e: anyhow::Error) -> Option<ErrorCode> {
+        e.downcast_ref::<ApiError>().map(|e| e.code)
+    }
+
+    #[actix_web::test]
+    async fn limits_are_applied_before_paying() {
+        let inner = Arc::new(FakeFaucet::default());
+        let faucet = LimitedFaucet::new(
+            Box::new(inner.clone()),
+            Amount::from_sat(10_000),
+            Duration::from_secs(60),
+        );
+
+        let e = faucet
+            .fund(&address(1), Amount::from_sat(10_001))
+            .await
+            .unwrap_err();
+        assert_eq!(code(e), Some(ErrorCode::InvalidRequest));
+
+        faucet
+            .fund(&address(1), Amount::from_sat(10_000))
+            .await
+            .unwrap();
+        let e = faucet
+            .fund(&address(1), Amount::from_sat(1_000))
+            .await
+            .unwrap_err();
+        assert_eq!(code(e), Some(ErrorCode::FaucetRateLimited));
+
+        // Other addresses are not held back.
+        faucet
+            .fund(&address(2), Amount::from_sat(1_000))
+            .await
+            .unwrap();
+        assert_eq!(inner.paid.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn failed_payments_do_not_count_against_the_address() {
+        let inner = Arc::new(FakeFaucet::default());
+        let faucet = LimitedFaucet::new(
+            Box::new(inner.clone()),
+            Amount::from_sat(10_000),
+            Duration::from_secs(60),
+        );
+
+        inner.fail.store(true, Ordering::SeqCst);
+        let e = faucet
+            .fund(&address(1), Amount::from_sat(1_000))
+            .await
+            .unwrap_err();
+        assert_eq!(code(e), None);
+
+        inner.fail.store(false, Ordering::SeqCst);
+        faucet
+            .fund(&address(1), Amount::from_sat(1_000))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn coins_spent_by_the_history_are_left_out() {
+        let spent = HashSet::from([coin(2, 0).0]);
+
+        let coins = unspent(vec![coin(1, 1_000), coin(2, 2_000)], &spent);
+
+        assert_eq!(coins, vec![coin(1, 1_000)]);
+    }
+}
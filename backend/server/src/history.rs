@@ -0,0 +1,242 @@
+//! Account activity for `GET /api/accounts/{account_id}/transactions`.
+//!
+//! Every address key of an account has its own history, as reported by
+//! [`ark_client::Client::transaction_history`]. They are merged into a single list, newest first,
+//! which is then filtered by time and cut into pages. A page's cursor names the last entry on it,
+//! so pages stay stable when newer entries arrive in between requests.
+
+use anyhow::{anyhow, Result};
+use ark_core::ArkTransaction;
+use std::collections::HashMap;
+
+use crate::core::model::{TransactionEntry, TransactionKind, TransactionQuery};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 200;
+
+impl From<ArkTransaction> for TransactionEntry {
+    fn from(tx: ArkTransaction) -> Self {
+        match tx {
+            ArkTransaction::Boarding {
+                txid,
+                amount,
+                confirmed_at,
+            } => TransactionEntry {
+                txid: txid.to_string(),
+                kind: TransactionKind::Boarding,
+                amount: amount.to_sat() as i64,
+                settled: confirmed_at.is_some(),
+                created_at: confirmed_at,
+            },
+            ArkTransaction::Round {
+                txid,
+                amount,
+                created_at,
+            } => TransactionEntry {
+                txid: txid.to_string(),
+                kind: TransactionKind::Round,
+                amount: amount.to_sat(),
+                settled: true,
+                created_at: Some(created_at),
+            },
+            ArkTransaction::Redeem {
+                txid,
+                amount,
+                is_settled,
+                created_at,
+            } => TransactionEntry {
+                txid: txid.to_string(),
+                kind: TransactionKind::Redeem,
+                amount: amount.to_sat(),
+                settled: is_settled,
+                created_at: Some(created_at),
+            },
+        }
+    }
+}
+
+/// Where an entry sorts. Unconfirmed boarding transactions sort as if they were created in the
+/// future, like [`ArkTransaction::created_at`] does.
+fn sort_key(entry: &TransactionEntry) -> (i64, &str) {
+    (entry.created_at.unwrap_or(i64::MAX), &entry.txid)
+}
+
+/// Combine the histories of an account's address keys, newest first.
+///
+/// A transaction that touches several keys, such as a payment between two of them, shows up in
+/// each key's history. It is listed once, with the amounts added up.
+pub fn merge(histories: Vec<Vec<ArkTransaction>>) -> Vec<TransactionEntry> {
+    let mut merged: HashMap<(TransactionKind, String), TransactionEntry> = HashMap::new();
+
+    for tx in histories.into_iter().flatten() {
+        let entry = TransactionEntry::from(tx);
+
+        merged
+            .entry((entry.kind, entry.txid.clone()))
+            .and_modify(|known| {
+                known.amount += entry.amount;
+                known.settled &= entry.settled;
+            })
+            .or_insert(entry);
+    }
+
+    let mut entries = merged.into_values().collect::<Vec<_>>();
+    entries.sort_by(|a, b| sort_key(b).cmp(&sort_key(a)));
+
+    entries
+}
+
+fn encode_cursor(entry: &TransactionEntry) -> String {
+    let (created_at, txid) = sort_key(entry);
+    format!("{}.{}", created_at, txid)
+}
+
+fn decode_cursor(cursor: &str) -> Result<(i64, String)> {
+    let (created_at, txid) = cursor
+        .split_once('.')
+        .ok_or_else(|| anyhow!("invalid cursor"))?;
+    let created_at = created_at.parse().map_err(|_| anyhow!("invalid cursor"))?;
+
+    Ok((created_at, txid.to_string()))
+}
+
+/// The page of `entries`, sorted as by [`merge`], that `query` asks for, along with the cursor
+/// of the next page if there is one. Fails on a cursor that was not handed out by this function.
+pub fn page(
+    entries: Vec<TransactionEntry>,
+    query: &TransactionQuery,
+) -> Result<(Vec<TransactionEntry>, Option<String>)> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let after = query.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    let mut matching = entries.into_iter().filter(|entry| {
+        let (created_at, txid) = sort_key(entry);
+
+        query.from.is_none_or(|from| created_at >= from)
+            && query.to.is_none_or(|to| created_at <= to)
+            && after
+                .as_ref()
+                .is_none_or(|(cursor_at, cursor_txid)| (created_at, txid) < (*cursor_at, cursor_txid))
+    });
+
+    let page = matching.by_ref().take(limit).collect::<Vec<_>>();
+    let next_cursor = match (page.last(), matching.next()) {
+        (Some(last), Some(_)) => Some(encode_cursor(last)),
+        _ => None,
+    };
+
+    Ok((page, next_cursor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, SignedAmount, Txid};
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    fn redeem(n: u8, amount: i64, created_at: i64) -> ArkTransaction {
+        ArkTransaction::Redeem {
+            txid: txid(n),
+            amount: SignedAmount::from_sat(amount),
+            is_settled: false,
+            created_at,
+        }
+    }
+
+    fn query(cursor: Option<String>, limit: usize) -> TransactionQuery {
+        TransactionQuery {
+            cursor,
+            limit: Some(limit),
+            ..TransactionQuery::default()
+        }
+    }
+
+    #[test]
+    fn merge_sorts_newest_first_and_combines_keys() {
+        let pending_boarding = ArkTransaction::Boarding {
+            txid: txid(1),
+            amount: Amount::from_sat(5_000),
+            confirmed_at: None,
+        };
+
+        // Key 0 paid 1 000 sats to key 1 in redeem 3.
+        let key_0 = vec![redeem(2, 2_000, 100), redeem(3, -1_000, 300)];
+        let key_1 = vec![pending_boarding, redeem(3, 1_000, 300)];
+
+        let entries = merge(vec![key_0, key_1]);
+
+        let order = entries
+            .iter()
+            .map(|entry| (entry.txid.clone(), entry.amount))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                (txid(1).to_string(), 5_000),
+                (txid(3).to_string(), 0),
+                (txid(2).to_string(), 2_000),
+            ]
+        );
+        assert_eq!(entries[0].kind, TransactionKind::Boarding);
+        assert!(!entries[0].settled);
+        assert_eq!(entries[0].created_at, None);
+    }
+
+    #[test]
+    fn cursor_pages_through_every_entry_once() {
+        let entries = merge(vec![(1..=5).map(|n| redeem(n, 100, n as i64 * 10)).collect()]);
+
+        let (first, cursor) = page(entries.clone(), &query(None, 2)).unwrap();
+        let (second, cursor) = page(entries.clone(), &query(cursor, 2)).unwrap();
+        let (third, cursor) = page(entries.clone(), &query(cursor, 2)).unwrap();
+
+        assert_eq!(first, entries[0..2]);
+        assert_eq!(second, entries[2..4]);
+        assert_eq!(third, entries[4..5]);
+        assert_eq!(cursor, None);
+
+        // Entries that arrive later do not shift the pages that follow a cursor.
+        let (_, cursor) = page(entries.clone(), &query(None, 2)).unwrap();
+        let mut newer = entries.clone();
+        newer.insert(0, TransactionEntry::from(redeem(9, 100, 1_000)));
+        let (second_again, _) = page(newer, &query(cursor, 2)).unwrap();
+        assert_eq!(second_again, second);
+    }
+
+    #[test]
+    fn time_range_is_inclusive() {
+        let entries = merge(vec![(1..=5).map(|n| redeem(n, 100, n as i64 * 10)).collect()]);
+
+        let (filtered, cursor) = page(
+            entries,
+            &TransactionQuery {
+                from: Some(20),
+                to: Some(40),
+                ..TransactionQuery::default()
+            },
+        )
+        .unwrap();
+
+        let times = filtered
+            .iter()
+            .map(|entry| entry.created_at.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(times, vec![40, 30, 20]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn malformed_cursor_is_rejected() {
+        let entries = merge(vec![vec![redeem(1, 100, 10)]]);
+
+        assert!(page(entries.clone(), &query(Some("nope".to_string()), 2)).is_err());
+        assert!(page(entries, &query(Some("x.abc".to_string()), 2)).is_err());
+    }
+}
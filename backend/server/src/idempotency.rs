@@ -0,0 +1,418 @@
+//! `Idempotency-Key` handling for payment endpoints.
+//!
+//! A client that retries a payment after a timeout cannot tell whether the first attempt went
+//! through. By sending the same `Idempotency-Key` header with both attempts, it gets the outcome
+//! of the first one back instead of paying twice. Keys belong to an account and are stored with
+//! a fingerprint of the request that first used them, together with its final response.
+//!
+//! A key is claimed by the server process that handles the request. Once the claim is older than
+//! [`CLAIM_TIMEOUT`] without a response stored, e.g. because that process went away, the claim is
+//! stale and the next retry takes it over. Until then retries are turned away, even those reaching
+//! another process on the same storage, since the request may still be running there. Keys are
+//! forgotten after [`KEY_RETENTION`].
+
+use actix_web::body::to_bytes;
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{FromRequest, HttpRequest, HttpResponse, web};
+use bitcoin::hashes::{Hash, HashEngine, sha256};
+use serde::Serialize;
+use std::future::{Future, Ready, ready};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::core::model::{ApplicationState, IdempotencyRecord, StoredResponse};
+use crate::error::{ApiError, ErrorCode};
+
+pub const HEADER: &str = "Idempotency-Key";
+
+/// Set on responses that were stored by an earlier request with the same key.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LEN: usize = 255;
+
+/// How long a request may hold its key before retries may take it over.
+pub const CLAIM_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How long keys are kept to answer retries.
+pub const KEY_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often keys past [`KEY_RETENTION`] are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Recorded with the claims of this server process, to tell in the stored keys which process made
+/// them. Claims of other processes are not taken over any sooner: several processes may share
+/// the same storage, or overlap during a restart.
+static OWNER: LazyLock<String> = LazyLock::new(|| uuid::Uuid::new_v4().to_string());
+
+/// The `Idempotency-Key` of a request, if it has one.
+pub struct IdempotencyKey(pub Option<String>);
+
+impl FromRequest for IdempotencyKey {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let Some(header) = req.headers().get(HEADER) else {
+            return ready(Ok(IdempotencyKey(None)));
+        };
+
+        let key = header
+            .to_str()
+            .ok()
+            .map(str::trim)
+            .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+            .map(|key| IdempotencyKey(Some(key.to_string())))
+            .ok_or_else(|| {
+                ApiError::invalid_request(format!(
+                    "{HEADER} must be between 1 and {MAX_KEY_LEN} visible ASCII characters"
+                ))
+            });
+
+        ready(key)
+    }
+}
+
+/// Fingerprint of a request to `endpoint` with `body`, to tell a retry from a different request
+/// that reuses its key.
+pub fn fingerprint(endpoint: &str, body: &impl Serialize) -> String {
+    let mut engine = sha256::Hash::engine();
+    engine.input(endpoint.as_bytes());
+    engine.input(b"\n");
+    engine.input(&serde_json::to_vec(body).expect("request bodies serialize to JSON"));
+
+    sha256::Hash::from_engine(engine).to_string()
+}
+
+impl StoredResponse {
+    async fn capture(response: HttpResponse) -> Result<Self, ApiError> {
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let body = to_bytes(response.into_body())
+            .await
+            .ok()
+            .and_then(|body| String::from_utf8(body.to_vec()).ok())
+            .ok_or_else(|| ApiError::new(ErrorCode::Internal, "Unreadable response body"))?;
+
+        Ok(Self {
+            status,
+            content_type,
+            body,
+        })
+    }
+
+    fn to_response(&self, replayed: bool) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        let mut response = HttpResponse::build(status);
+        if let Some(content_type) = &self.content_type {
+            response.content_type(content_type.as_str());
+        }
+        if replayed {
+            response.insert_header((REPLAYED_HEADER, "true"));
+        }
+
+        response.body(self.body.clone())
+    }
+}
+
+/// Run `payment` for `account_id` at most once per idempotency key.
+///
+/// Without a key, `payment` simply runs. With one, a retry of a finished request gets the stored
+/// response back, marked with [`REPLAYED_HEADER`]. A key still held by a running request gets
+/// `409 Conflict`, and a key reused for a different request gets `422 Unprocessable Entity`. A
+/// retry of a request whose claim is stale runs `payment` again.
+///
+/// `payment` returns `Err` when it turns the request down before it had any effect, e.g. because
+/// the account is locked. The key is then released, so that the request can be retried. Every
+/// `Ok` response is stored, including failures, since funds may have moved by then. `payment`
+/// runs in a task of its own, so that its outcome is stored even if the client goes away.
+pub async fn run_once<F>(
+    state: &web::Data<ApplicationState>,
+    account_id: &str,
+    key: IdempotencyKey,
+    fingerprint: String,
+    payment: F,
+) -> Result<HttpResponse, ApiError>
+where
+    F: Future<Output = Result<HttpResponse, ApiError>> + 'static,
+{
+    let Some(key) = key.0 else {
+        return payment.await;
+    };
+
+    let now = jiff::Timestamp::now().as_second();
+    let record = IdempotencyRecord {
+        account_id: account_id.to_string(),
+        key: key.clone(),
+        fingerprint,
+        response: None,
+        owner: Some(OWNER.clone()),
+        created_at: now,
+    };
+
+    // Claim the key, or answer from the request that holds it
+    let stale_before = now.saturating_sub_unsigned(CLAIM_TIMEOUT.as_secs());
+    match state.accounts.claim_idempotency_key(&record, stale_before) {
+        Ok(None) => {}
+        Ok(Some(existing)) if existing.fingerprint != record.fingerprint => {
+            return Err(ApiError::new(
+                ErrorCode::IdempotencyKeyReused,
+                format!("{HEADER} was already used for a different request"),
+            ));
+        }
+        Ok(Some(IdempotencyRecord {
+            response: Some(response),
+            ..
+        })) => return Ok(response.to_response(true)),
+        Ok(Some(_)) => {
+            return Err(ApiError::new(
+                ErrorCode::IdempotencyKeyInProgress,
+                format!("A request with this {HEADER} is still in progress"),
+            ));
+        }
+        Err(e) => return Err(ApiError::internal(&format!("Failed to claim {HEADER}"), e)),
+    }
+
+    let state = state.clone();
+    let account_id = account_id.to_string();
+    let task = actix_web::rt::spawn(async move {
+        let response = match payment.await {
+            Ok(response) => response,
+            Err(error) => {
+                if let Err(e) = state.accounts.release_idempotency_key(&account_id, &key) {
+                    tracing::error!(%account_id, %key, "Failed to release idempotency key: {:#}", e);
+                }
+                return Err(error);
+            }
+        };
+
+        let stored = StoredResponse::capture(response).await?;
+
+        // If this fails the key stays claimed, and retries are told the request is in progress
+        // rather than paying again.
+        if let Err(e) = state
+            .accounts
+            .complete_idempotency_key(&account_id, &key, &stored)
+        {
+            tracing::error!(%account_id, %key, "Failed to store idempotent response: {:#}", e);
+        }
+
+        Ok(stored.to_response(false))
+    });
+
+    task.await
+        .unwrap_or_else(|e| Err(ApiError::internal("Payment task failed", e)))
+}
+
+/// Remove keys past [`KEY_RETENTION`], forever.
+pub async fn run(state: web::Data<ApplicationState>) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let before = jiff::Timestamp::now()
+            .as_second()
+            .saturating_sub_unsigned(KEY_RETENTION.as_secs());
+        match state.accounts.prune_idempotency_keys(before) {
+            Ok(0) => {}
+            Ok(pruned) => tracing::debug!(pruned, "Pruned expired idempotency keys"),
+            Err(e) => tracing::error!("Failed to prune idempotency keys: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, post, test};
+    use serde_json::{Value, json};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use crate::auth::tests::{add_account, state};
+
+    #[derive(Default)]
+    struct Payments {
+        made: AtomicUsize,
+        reject: AtomicBool,
+    }
+
+    #[post("/pay/{account_id}")]
+    async fn pay(
+        account_id: web::Path<String>,
+        key: IdempotencyKey,
+        state: web::Data<ApplicationState>,
+        payments: web::Data<Payments>,
+        req: web::Json<Value>,
+    ) -> Result<HttpResponse, ApiError> {
+        let fingerprint = fingerprint("pay", &*req);
+
+        run_once(&state, &account_id, key, fingerprint, async move {
+            if payments.reject.load(Ordering::SeqCst) {
+                return Err(ApiError::invalid_request("Rejected"));
+            }
+
+            let made = payments.made.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(HttpResponse::Ok().json(json!({ "payment": made })))
+        })
+        .await
+    }
+
+    fn request(account_id: &str, key: Option<&str>, amount: u64) -> test::TestRequest {
+        let req = test::TestRequest::post()
+            .uri(&format!("/pay/{account_id}"))
+            .set_json(json!({ "amount": amount }));
+
+        match key {
+            Some(key) => req.insert_header((HEADER, key)),
+            None => req,
+        }
+    }
+
+    #[actix_web::test]
+    async fn retries_get_the_first_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let account_id = add_account(&state);
+        let payments = web::Data::new(Payments::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(payments.clone())
+                .service(pay),
+        )
+        .await;
+
+        let first =
+            test::call_service(&app, request(&account_id, Some("k1"), 5).to_request()).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(first.headers().get(REPLAYED_HEADER).is_none());
+        let first: Value = test::read_body_json(first).await;
+
+        let retry =
+            test::call_service(&app, request(&account_id, Some("k1"), 5).to_request()).await;
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.headers().get(REPLAYED_HEADER).unwrap(), "true");
+        let retry: Value = test::read_body_json(retry).await;
+
+        assert_eq!(first, retry);
+        assert_eq!(payments.made.load(Ordering::SeqCst), 1);
+
+        // The same key with another body is a different request.
+        let reused =
+            test::call_service(&app, request(&account_id, Some("k1"), 6).to_request()).await;
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let reused: Value = test::read_body_json(reused).await;
+        assert_eq!(reused["code"], "idempotency_key_reused");
+        assert_eq!(payments.made.load(Ordering::SeqCst), 1);
+
+        // Requests without a key are not deduplicated.
+        for _ in 0..2 {
+            let resp = test::call_service(&app, request(&account_id, None, 5).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        assert_eq!(payments.made.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn turned_down_requests_release_their_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let account_id = add_account(&state);
+        let payments = web::Data::new(Payments::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(payments.clone())
+                .service(pay),
+        )
+        .await;
+
+        payments.reject.store(true, Ordering::SeqCst);
+        let rejected =
+            test::call_service(&app, request(&account_id, Some("k2"), 5).to_request()).await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+
+        payments.reject.store(false, Ordering::SeqCst);
+        let retry =
+            test::call_service(&app, request(&account_id, Some("k2"), 5).to_request()).await;
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert!(retry.headers().get(REPLAYED_HEADER).is_none());
+        assert_eq!(payments.made.load(Ordering::SeqCst), 1);
+
+        let record = IdempotencyRecord {
+            account_id: account_id.clone(),
+            key: "k3".to_string(),
+            fingerprint: fingerprint("pay", &json!({ "amount": 5 })),
+            response: None,
+            owner: Some(OWNER.clone()),
+            created_at: jiff::Timestamp::now().as_second(),
+        };
+        state.accounts.claim_idempotency_key(&record, 0).unwrap();
+        let in_progress =
+            test::call_service(&app, request(&account_id, Some("k3"), 5).to_request()).await;
+        assert_eq!(in_progress.status(), StatusCode::CONFLICT);
+        assert_eq!(payments.made.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn stale_claims_are_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let account_id = add_account(&state);
+        let payments = web::Data::new(Payments::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(payments.clone())
+                .service(pay),
+        )
+        .await;
+
+        let now = jiff::Timestamp::now().as_second();
+        let claim = |key: &str, owner: &str, created_at| IdempotencyRecord {
+            account_id: account_id.clone(),
+            key: key.to_string(),
+            fingerprint: fingerprint("pay", &json!({ "amount": 5 })),
+            response: None,
+            owner: Some(owner.to_string()),
+            created_at,
+        };
+
+        let timed_out_at = now - CLAIM_TIMEOUT.as_secs() as i64 - 1;
+
+        // Claimed by another process, which may still be running the request.
+        let running = claim("k4", "other", now);
+        state.accounts.claim_idempotency_key(&running, 0).unwrap();
+        let in_progress =
+            test::call_service(&app, request(&account_id, Some("k4"), 5).to_request()).await;
+        assert_eq!(in_progress.status(), StatusCode::CONFLICT);
+
+        // Claimed by a process that crashed before storing the response, long enough ago.
+        let crashed = claim("k5", "gone", timed_out_at);
+        state.accounts.claim_idempotency_key(&crashed, 0).unwrap();
+
+        // Claimed by this process, but for longer than a request may take.
+        let timed_out = claim("k6", &OWNER, timed_out_at);
+        state.accounts.claim_idempotency_key(&timed_out, 0).unwrap();
+
+        for key in ["k5", "k6"] {
+            let retry =
+                test::call_service(&app, request(&account_id, Some(key), 5).to_request()).await;
+            assert_eq!(retry.status(), StatusCode::OK);
+            assert!(retry.headers().get(REPLAYED_HEADER).is_none());
+
+            let replayed =
+                test::call_service(&app, request(&account_id, Some(key), 5).to_request()).await;
+            assert_eq!(replayed.headers().get(REPLAYED_HEADER).unwrap(), "true");
+        }
+        assert_eq!(payments.made.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,373 @@
+//! Passphrase protection and derivation of account private keys.
+//!
+//! Accounts are backed by a BIP39 mnemonic. Its seed is stored encrypted with ChaCha20-Poly1305
+//! under a key derived from the account passphrase with Argon2id, and every address key is derived
+//! from it with BIP32. Decrypted keys only live in the [`KeyCache`], and only until their unlock
+//! TTL runs out or the account is locked again.
+//!
+//! Accounts created before keys were encrypted at rest still hold a plaintext key. They cannot be
+//! unlocked until the operator encrypts it with [`migrate_legacy_keys`], through the
+//! `migrate-keys` command.
+
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use bip39::Mnemonic;
+use bitcoin::bip32::{ChildNumber, DerivationPath, Xpriv, Xpub};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::NetworkKind;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::core::model::UserAccount;
+use crate::storage::AccountStore;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Passphrases shorter than this are rejected.
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+
+/// Entropy of a freshly generated mnemonic, i.e. 12 words.
+const MNEMONIC_ENTROPY_LEN: usize = 16;
+
+/// Secret bytes (a BIP39 seed, or a single secret key for older accounts), encrypted together
+/// with everything needed to decrypt them given the passphrase.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncryptedKey {
+    /// Argon2id memory cost in KiB.
+    pub m_cost: u32,
+    /// Argon2id iterations.
+    pub t_cost: u32,
+    /// Argon2id parallelism.
+    pub p_cost: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl EncryptedKey {
+    pub fn encrypt(secret: &[u8], passphrase: &str) -> Result<Self> {
+        let mut rng = rand::thread_rng();
+
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        let params = Params::default();
+        let cipher = cipher(passphrase, &salt, &params)?;
+
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), secret)
+            .map_err(|_| anyhow!("failed to encrypt private key"))?;
+
+        Ok(Self {
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Decrypt the secret. A wrong passphrase fails authentication and yields an error.
+    pub fn decrypt(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let salt = hex::decode(&self.salt)?;
+        let nonce = hex::decode(&self.nonce)?;
+        let ciphertext = hex::decode(&self.ciphertext)?;
+
+        if nonce.len() != NONCE_LEN {
+            return Err(anyhow!("invalid nonce length"));
+        }
+
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|e| anyhow!("invalid key derivation parameters: {}", e))?;
+        let cipher = cipher(passphrase, &salt, &params)?;
+
+        cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow!("wrong passphrase"))
+    }
+}
+
+fn cipher(passphrase: &str, salt: &[u8], params: &Params) -> Result<ChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("failed to derive encryption key: {}", e))?;
+
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Encrypt the plaintext keys of the accounts created before keys were encrypted at rest under
+/// `passphrase`: every such account, or only `account_id`. Returns the IDs of the migrated
+/// accounts.
+pub fn migrate_legacy_keys(
+    store: &dyn AccountStore,
+    account_id: Option<&str>,
+    passphrase: &str,
+) -> Result<Vec<String>> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(anyhow!(
+            "passphrase must be at least {} characters",
+            MIN_PASSPHRASE_LEN
+        ));
+    }
+
+    let accounts = match account_id {
+        Some(account_id) => vec![store
+            .get_account(account_id)?
+            .ok_or_else(|| anyhow!("account {} not found", account_id))?],
+        None => store.list_accounts()?,
+    };
+
+    let secp = Secp256k1::new();
+    let mut migrated = Vec::new();
+    for mut account in accounts {
+        let (None, Some(private_key)) = (&account.encrypted_key, account.private_key.take()) else {
+            continue;
+        };
+
+        let secret_key: SecretKey = private_key
+            .parse()
+            .map_err(|e| anyhow!("invalid private key of account {}: {}", account.id, e))?;
+
+        account.public_key = Some(secret_key.public_key(&secp).to_string());
+        account.encrypted_key = Some(EncryptedKey::encrypt(&secret_key.secret_bytes(), passphrase)?);
+        store.update_account(&account)?;
+
+        migrated.push(account.id);
+    }
+
+    Ok(migrated)
+}
+
+/// Whether `account` still holds a plaintext key that [`migrate_legacy_keys`] has to encrypt.
+pub fn needs_migration(account: &UserAccount) -> bool {
+    account.encrypted_key.is_none() && account.private_key.is_some()
+}
+
+pub fn generate_mnemonic() -> Mnemonic {
+    let mut entropy = [0u8; MNEMONIC_ENTROPY_LEN];
+    rand::thread_rng().fill_bytes(&mut entropy);
+
+    Mnemonic::from_entropy(&entropy).expect("valid entropy length")
+}
+
+/// The BIP32 path of the account-level key, `m/86'/coin'/0'`. Address key `i` lives at `0/i`
+/// below it, so that it can be derived from the account [`Xpub`] alone.
+pub fn account_path(network: NetworkKind) -> DerivationPath {
+    let coin_type = match network {
+        NetworkKind::Main => 0,
+        NetworkKind::Test => 1,
+    };
+
+    DerivationPath::from(vec![
+        ChildNumber::Hardened { index: 86 },
+        ChildNumber::Hardened { index: coin_type },
+        ChildNumber::Hardened { index: 0 },
+    ])
+}
+
+/// The account-level extended private key for a BIP39 `seed`.
+pub fn account_xpriv(seed: &[u8], network: NetworkKind) -> Result<Xpriv> {
+    let secp = Secp256k1::new();
+
+    let master = Xpriv::new_master(network, seed)?;
+    Ok(master.derive_priv(&secp, &account_path(network))?)
+}
+
+fn address_path(index: u32) -> Result<[ChildNumber; 2]> {
+    Ok([
+        ChildNumber::from_normal_idx(0)?,
+        ChildNumber::from_normal_idx(index)?,
+    ])
+}
+
+/// The public key of address key `index`, derived without access to any secret.
+pub fn derive_public_key(account_xpub: &Xpub, index: u32) -> Result<PublicKey> {
+    let secp = Secp256k1::verification_only();
+
+    Ok(account_xpub
+        .derive_pub(&secp, &address_path(index)?)?
+        .public_key)
+}
+
+/// The decrypted key material of an unlocked account.
+#[derive(Clone, Copy)]
+pub enum UnlockedKeys {
+    /// A single key, for accounts created before mnemonics were introduced.
+    Single(SecretKey),
+    /// The account-level extended private key.
+    Hd(Xpriv),
+}
+
+impl UnlockedKeys {
+    /// The secret key of address key `index`.
+    pub fn secret_key(&self, index: u32) -> Result<SecretKey> {
+        match self {
+            UnlockedKeys::Single(secret_key) if index == 0 => Ok(*secret_key),
+            UnlockedKeys::Single(_) => Err(anyhow!("single-key account has no key {}", index)),
+            UnlockedKeys::Hd(account_xpriv) => {
+                let secp = Secp256k1::new();
+                Ok(account_xpriv
+                    .derive_priv(&secp, &address_path(index)?)?
+                    .private_key)
+            }
+        }
+    }
+}
+
+/// Decrypted keys of unlocked accounts, each with its own expiry.
+#[derive(Default)]
+pub struct KeyCache {
+    keys: Mutex<HashMap<String, (UnlockedKeys, Instant)>>,
+}
+
+impl KeyCache {
+    pub fn unlock(&self, account_id: &str, keys: UnlockedKeys, ttl: Duration) -> Instant {
+        let expires_at = Instant::now() + ttl;
+
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(account_id.to_string(), (keys, expires_at));
+
+        expires_at
+    }
+
+    /// Forget the keys for `account_id`. Returns whether the account was unlocked.
+    pub fn lock(&self, account_id: &str) -> bool {
+        self.keys.lock().unwrap().remove(account_id).is_some()
+    }
+
+    /// The decrypted keys for `account_id`, if the account is unlocked and its TTL has not
+    /// run out.
+    pub fn get(&self, account_id: &str) -> Option<UnlockedKeys> {
+        let mut keys = self.keys.lock().unwrap();
+
+        match keys.get(account_id) {
+            Some((unlocked, expires_at)) if *expires_at > Instant::now() => Some(*unlocked),
+            Some(_) => {
+                keys.remove(account_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// How many accounts are unlocked and within their TTL.
+    pub fn unlocked_count(&self) -> usize {
+        let now = Instant::now();
+
+        self.keys
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn secret_key() -> SecretKey {
+        SecretKey::new(&mut rand::thread_rng())
+    }
+
+    #[test]
+    fn encrypted_key_roundtrip() {
+        let key = secret_key();
+
+        let encrypted = EncryptedKey::encrypt(&key.secret_bytes(), "correct horse").unwrap();
+
+        assert_eq!(
+            encrypted.decrypt("correct horse").unwrap(),
+            key.secret_bytes()
+        );
+        assert!(encrypted.decrypt("battery staple").is_err());
+    }
+
+    #[test]
+    fn ciphertext_does_not_contain_key() {
+        let key = secret_key();
+
+        let encrypted = EncryptedKey::encrypt(&key.secret_bytes(), "passphrase").unwrap();
+
+        assert!(!encrypted
+            .ciphertext
+            .contains(&key.display_secret().to_string()));
+    }
+
+    #[test]
+    fn public_and_private_derivation_agree() {
+        let secp = Secp256k1::new();
+        let seed = generate_mnemonic().to_seed("");
+
+        let account_xpriv = account_xpriv(&seed, NetworkKind::Test).unwrap();
+        let account_xpub = Xpub::from_priv(&secp, &account_xpriv);
+        let unlocked = UnlockedKeys::Hd(account_xpriv);
+
+        for index in 0..5 {
+            assert_eq!(
+                unlocked.secret_key(index).unwrap().public_key(&secp),
+                derive_public_key(&account_xpub, index).unwrap()
+            );
+        }
+
+        assert_ne!(
+            derive_public_key(&account_xpub, 0).unwrap(),
+            derive_public_key(&account_xpub, 1).unwrap()
+        );
+    }
+
+    // BIP86 test vector: https://github.com/bitcoin/bips/blob/master/bip-0086.mediawiki#test-vectors
+    #[test]
+    fn derivation_matches_bip86() {
+        let mnemonic = Mnemonic::from_str(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        )
+        .unwrap();
+
+        let account_xpriv = account_xpriv(&mnemonic.to_seed(""), NetworkKind::Main).unwrap();
+        let secp = Secp256k1::new();
+        let account_xpub = Xpub::from_priv(&secp, &account_xpriv);
+
+        assert_eq!(
+            account_xpub.to_string(),
+            "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ"
+        );
+
+        let public_key = derive_public_key(&account_xpub, 0).unwrap();
+        assert_eq!(
+            public_key.x_only_public_key().0.to_string(),
+            "cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115"
+        );
+    }
+
+    #[test]
+    fn key_cache_expires_and_locks() {
+        let cache = KeyCache::default();
+        let key = secret_key();
+
+        cache.unlock("a", UnlockedKeys::Single(key), Duration::from_secs(60));
+        cache.unlock("b", UnlockedKeys::Single(key), Duration::ZERO);
+
+        assert_eq!(cache.get("a").unwrap().secret_key(0).unwrap(), key);
+        assert!(cache.get("b").is_none());
+
+        assert!(cache.lock("a"));
+        assert!(cache.get("a").is_none());
+        assert!(!cache.lock("a"));
+    }
+}
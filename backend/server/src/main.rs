@@ -1,25 +1,91 @@
 mod core;
 mod api;
+mod auth;
+mod clients;
+mod cors;
+mod error;
+mod events;
+mod exits;
+mod faucet;
+mod history;
+mod idempotency;
+mod keystore;
+mod metrics;
+mod network;
+mod openapi;
+mod payouts;
+mod preview;
+mod renewals;
+mod storage;
+mod supervisor;
+mod webhooks;
 
-use std::fs;
+use clap::{Parser, Subcommand};
 use std::io;
-use toml;
+use std::path::PathBuf;
+
+/// Environment variable the `migrate-keys` passphrase is read from, before falling back to
+/// standard input.
+const MIGRATION_PASSPHRASE_ENV: &str = "ARK_MIGRATION_PASSPHRASE";
+
+#[derive(Parser)]
+#[command(about = "ARK-based cryptocurrency server")]
+struct Args {
+    /// Configuration file to use instead of `ark.config.toml` in the working directory
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Encrypt the plaintext keys of accounts created before keys were encrypted at rest, so
+    /// that they can be unlocked again. The passphrase is read from `ARK_MIGRATION_PASSPHRASE`,
+    /// or else from standard input, and has to be handed to the owners of the accounts
+    MigrateKeys {
+        /// Migrate only this account
+        #[arg(long, value_name = "ACCOUNT_ID")]
+        account: Option<String>,
+    },
+    /// Issue a full-access API token to every account that has none, such as accounts created
+    /// before API tokens existed, and print them. The tokens have to be handed to the owners of
+    /// the accounts
+    IssueTokens {
+        /// Issue a token to this account only
+        #[arg(long, value_name = "ACCOUNT_ID")]
+        account: Option<String>,
+    },
+}
 
 fn main() -> io::Result<()> {
-    // Initialize logging
-    core::logger::setup_logger();
+    let args = Args::parse();
 
-    // Read and parse configuration file
-    let config_content = fs::read_to_string("ark.config.toml").map_err(|e| {
-        eprintln!("Configuration file error: {}", e);
-        io::Error::new(io::ErrorKind::Other, "Failed to read configuration file")
-    })?;
+    // Read the configuration file and apply environment overrides
+    let path = args
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(core::config::DEFAULT_PATH));
 
-    let app_config: core::config::AppConfig = toml::from_str(&config_content).map_err(|e| {
-        eprintln!("Configuration parsing error: {}", e);
-        io::Error::new(io::ErrorKind::Other, "Invalid configuration format")
+    let app_config = core::config::load(&path, args.config.is_some()).map_err(|e| {
+        eprintln!("Configuration error: {:#}", e);
+        io::Error::other("Invalid configuration")
     })?;
 
+    match args.command {
+        Some(Command::MigrateKeys { account }) => {
+            return migrate_keys(&app_config, account.as_deref());
+        }
+        Some(Command::IssueTokens { account }) => {
+            return issue_tokens(&app_config, account.as_deref());
+        }
+        None => {}
+    }
+
+    // Initialize logging
+    core::logger::setup_logger(&app_config.logging);
+
     // Launch server in async runtime
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -29,3 +95,52 @@ fn main() -> io::Result<()> {
             core::server::launch_api_server(app_config).await
         })
 }
+
+fn migrate_keys(config: &core::config::AppConfig, account_id: Option<&str>) -> io::Result<()> {
+    let passphrase = match std::env::var(MIGRATION_PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            eprintln!("Passphrase for the migrated keys:");
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+
+    let accounts = storage::open(&config.storage).map_err(|e| {
+        eprintln!("Account storage error: {:#}", e);
+        io::Error::other("Failed to open account storage")
+    })?;
+
+    let migrated = keystore::migrate_legacy_keys(accounts.as_ref(), account_id, &passphrase)
+        .map_err(|e| {
+            eprintln!("Key migration error: {:#}", e);
+            io::Error::other("Failed to migrate keys")
+        })?;
+
+    for account_id in &migrated {
+        println!("Encrypted the key of account {account_id}");
+    }
+    println!("Migrated {} account(s)", migrated.len());
+
+    Ok(())
+}
+
+fn issue_tokens(config: &core::config::AppConfig, account_id: Option<&str>) -> io::Result<()> {
+    let accounts = storage::open(&config.storage).map_err(|e| {
+        eprintln!("Account storage error: {:#}", e);
+        io::Error::other("Failed to open account storage")
+    })?;
+
+    let issued = auth::bootstrap_tokens(accounts.as_ref(), account_id).map_err(|e| {
+        eprintln!("Token error: {:#}", e);
+        io::Error::other("Failed to issue API tokens")
+    })?;
+
+    for (account_id, token) in &issued {
+        println!("{account_id} {token}");
+    }
+    eprintln!("Issued tokens to {} account(s)", issued.len());
+
+    Ok(())
+}
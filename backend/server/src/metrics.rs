@@ -0,0 +1,364 @@
+//! Prometheus metrics, exported at `GET /metrics`.
+//!
+//! HTTP requests are counted and timed per route by the [`track`] middleware. Rounds are timed
+//! from the `ark_round` and `ark_round_step` spans that `ark_client` opens while taking part in
+//! one, which the [`RoundLayer`] turns into metrics as they close. Errors from the Ark server and
+//! esplora are counted where the server sees them, and account totals are read from storage when
+//! the metrics are scraped.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Metadata, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// The metrics of this server.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Spans opened by `ark_client` for a round and for each step of it.
+const ROUND_SPAN: &str = "ark_round";
+const STEP_SPAN: &str = "ark_round_step";
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_duration: HistogramVec,
+    pub rounds: IntCounterVec,
+    pub round_duration: HistogramVec,
+    pub round_step_duration: HistogramVec,
+    pub grpc_errors: IntCounter,
+    pub esplora_errors: IntCounterVec,
+    pub accounts: IntGauge,
+    pub unlocked_accounts: IntGauge,
+    pub vtxos_near_expiry: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("ark_http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ark_http_request_duration_seconds",
+                "Time taken to answer HTTP requests",
+            ),
+            &["method", "route"],
+        )
+        .unwrap();
+        let rounds = IntCounterVec::new(
+            Opts::new(
+                "ark_rounds_total",
+                "Rounds taken part in, by outcome: finalized, failed, error or cancelled",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let round_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ark_round_duration_seconds",
+                "Time from registering for a round until it ended",
+            )
+            .buckets(vec![1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+            &["outcome"],
+        )
+        .unwrap();
+        let round_step_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ark_round_step_duration_seconds",
+                "Time taken by each step of the round protocol",
+            )
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["step"],
+        )
+        .unwrap();
+        let grpc_errors =
+            IntCounter::new("ark_grpc_errors_total", "Failed calls to the Ark server").unwrap();
+        let esplora_errors = IntCounterVec::new(
+            Opts::new("ark_esplora_errors_total", "Failed calls to esplora"),
+            &["operation"],
+        )
+        .unwrap();
+        let accounts = IntGauge::new("ark_accounts", "Stored accounts").unwrap();
+        let unlocked_accounts =
+            IntGauge::new("ark_unlocked_accounts", "Accounts whose keys are unlocked").unwrap();
+        let vtxos_near_expiry = IntGauge::new(
+            "ark_vtxos_near_expiry_sats",
+            "Value of the VTXOs of unlocked accounts that are due for renewal",
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(rounds.clone())).unwrap();
+        registry.register(Box::new(round_duration.clone())).unwrap();
+        registry
+            .register(Box::new(round_step_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(grpc_errors.clone())).unwrap();
+        registry.register(Box::new(esplora_errors.clone())).unwrap();
+        registry.register(Box::new(accounts.clone())).unwrap();
+        registry
+            .register(Box::new(unlocked_accounts.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(vtxos_near_expiry.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_duration,
+            rounds,
+            round_duration,
+            round_step_duration,
+            grpc_errors,
+            esplora_errors,
+            accounts,
+            unlocked_accounts,
+            vtxos_near_expiry,
+        }
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics encode to text");
+
+        String::from_utf8(buffer).expect("metrics are UTF-8")
+    }
+}
+
+/// Count a failed call to esplora.
+pub fn esplora_error(operation: &str) {
+    METRICS.esplora_errors.with_label_values(&[operation]).inc();
+}
+
+/// Count and time requests by route pattern, so that account IDs do not end up in labels.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let result = next.call(req).await;
+
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, status.as_str()])
+        .inc();
+    METRICS
+        .http_duration
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+
+    result
+}
+
+/// Turns the round spans of `ark_client` into metrics.
+pub struct RoundLayer {
+    metrics: &'static Metrics,
+}
+
+impl RoundLayer {
+    pub fn new(metrics: &'static Metrics) -> Self {
+        Self { metrics }
+    }
+
+    /// Whether the layer needs to see the spans of `metadata`.
+    pub fn wants(metadata: &Metadata<'_>) -> bool {
+        metadata.is_span()
+            && metadata.target().starts_with("ark_client")
+            && matches!(metadata.name(), ROUND_SPAN | STEP_SPAN)
+    }
+}
+
+/// When a round or step span was opened, and its `outcome` or `step` field.
+struct SpanTiming {
+    started: Instant,
+    label: Option<String>,
+}
+
+impl Visit for SpanTiming {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if matches!(field.name(), "outcome" | "step") {
+            self.label = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+}
+
+impl<S> Layer<S> for RoundLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !Self::wants(attrs.metadata()) {
+            return;
+        }
+
+        let mut timing = SpanTiming {
+            started: Instant::now(),
+            label: None,
+        };
+        attrs.record(&mut timing);
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(timing);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+            values.record(timing);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(timing) = span.extensions_mut().remove::<SpanTiming>() else {
+            return;
+        };
+
+        let elapsed = timing.started.elapsed().as_secs_f64();
+
+        if span.name() == ROUND_SPAN {
+            // A round span without an outcome was dropped midway, e.g. because the request that
+            // started it went away.
+            let outcome = timing.label.as_deref().unwrap_or("cancelled");
+
+            self.metrics.rounds.with_label_values(&[outcome]).inc();
+            self.metrics
+                .round_duration
+                .with_label_values(&[outcome])
+                .observe(elapsed);
+        } else {
+            let step = timing.label.as_deref().unwrap_or("unknown");
+
+            self.metrics
+                .round_step_duration
+                .with_label_values(&[step])
+                .observe(elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, middleware};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::api;
+    use crate::auth::tests::{add_account, state};
+
+    #[test]
+    fn round_spans_feed_round_metrics() {
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        let subscriber = tracing_subscriber::registry().with(RoundLayer::new(metrics));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let round = tracing::info_span!(
+                target: "ark_client::round",
+                "ark_round",
+                outcome = tracing::field::Empty
+            );
+            let _entered = round.enter();
+
+            for step in ["registration", "nonces", "signing"] {
+                let _step =
+                    tracing::info_span!(target: "ark_client::round", "ark_round_step", step);
+            }
+
+            round.record("outcome", "failed");
+
+            // Spans of other crates are left alone.
+            let _ = tracing::info_span!("ark_round", outcome = "finalized");
+        });
+
+        let steps = &metrics.round_step_duration;
+        assert_eq!(steps.with_label_values(&["nonces"]).get_sample_count(), 1);
+        assert_eq!(steps.with_label_values(&["signing"]).get_sample_count(), 1);
+        assert_eq!(
+            steps
+                .with_label_values(&["finalization"])
+                .get_sample_count(),
+            0
+        );
+
+        assert_eq!(metrics.rounds.with_label_values(&["failed"]).get(), 1);
+        assert_eq!(metrics.rounds.with_label_values(&["finalized"]).get(), 0);
+        assert_eq!(
+            metrics
+                .round_duration
+                .with_label_values(&["failed"])
+                .get_sample_count(),
+            1
+        );
+    }
+
+    #[actix_web::test]
+    async fn requests_are_counted_by_route() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        let account_id = add_account(&state);
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(middleware::from_fn(track))
+                .service(api::accounts::get_account_addresses)
+                .service(api::monitoring::get_metrics),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/api/accounts/{account_id}/addresses"))
+            .to_request();
+        actix_web::test::call_service(&app, req).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/metrics")
+            .to_request();
+        let body = actix_web::test::call_and_read_body(&app, req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.contains(
+            r#"ark_http_requests_total{method="GET",route="/api/accounts/{account_id}/addresses",status="401"}"#
+        ));
+        assert!(!body.contains(&account_id));
+        assert!(body.contains("ark_accounts 1"));
+        assert!(body.contains("ark_unlocked_accounts 0"));
+    }
+}
@@ -0,0 +1,146 @@
+//! Startup checks that the Ark server and esplora are on the network the server is configured
+//! for.
+//!
+//! A wallet talking to an Ark server on one network and an explorer on another would hand out
+//! addresses that nobody can pay, or report balances from the wrong chain, so the server refuses
+//! to start instead. Esplora is identified by the hash of its genesis block. Services that are
+//! unreachable at startup are checked by [`crate::supervisor`] once they are reached.
+
+use anyhow::{anyhow, Result};
+use bitcoin::constants::genesis_block;
+use bitcoin::{BlockHash, Network};
+
+use crate::core::config::AppConfig;
+use crate::core::model::BlockchainClient;
+
+/// Networks whose genesis block esplora may report.
+const KNOWN_NETWORKS: [Network; 5] = [
+    Network::Bitcoin,
+    Network::Testnet,
+    Network::Testnet4,
+    Network::Signet,
+    Network::Regtest,
+];
+
+/// The network whose chain starts with `genesis`, if it is a known one.
+///
+/// All signets share the genesis block of the default signet, so a custom signet such as
+/// Mutinynet is reported as [`Network::Signet`].
+pub fn chain_of(genesis: BlockHash) -> Option<Network> {
+    KNOWN_NETWORKS
+        .into_iter()
+        .find(|network| genesis_block(*network).block_hash() == genesis)
+}
+
+/// Check the network of the Ark server, `ark_network`, and the genesis block of esplora,
+/// `esplora_genesis`, against `expected`. Either may be unknown, e.g. because the service was
+/// unreachable, in which case it is not checked.
+pub fn check(
+    config: &AppConfig,
+    expected: Network,
+    ark_network: Option<Network>,
+    esplora_genesis: Option<BlockHash>,
+) -> Result<()> {
+    if let Some(network) = ark_network.filter(|network| *network != expected) {
+        return Err(anyhow!(
+            "the Ark server at {} runs on {}, but {} is expected",
+            config.ark_server_url,
+            network,
+            expected
+        ));
+    }
+
+    if let Some(genesis) = esplora_genesis.filter(|genesis| chain_of(*genesis) != Some(expected)) {
+        let chain = match chain_of(genesis) {
+            Some(network) => network.to_string(),
+            None => format!("an unknown chain with genesis block {genesis}"),
+        };
+
+        return Err(anyhow!(
+            "esplora at {} follows {}, but {} is expected",
+            config.esplora_url,
+            chain,
+            expected
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check the Ark server and esplora against the configured network, or esplora against the Ark
+/// server's network if none is configured.
+pub async fn verify(
+    config: &AppConfig,
+    ark_network: Option<Network>,
+    blockchain: Option<&BlockchainClient>,
+) -> Result<()> {
+    let Some(expected) = config.network.or(ark_network) else {
+        tracing::warn!("No network configured and the Ark server is unreachable; skipping checks");
+        return Ok(());
+    };
+
+    let esplora_genesis = esplora_genesis(blockchain).await;
+    check(config, expected, ark_network, esplora_genesis)
+}
+
+/// The genesis block of esplora, or `None` if there is no client or it cannot be reached.
+pub async fn esplora_genesis(blockchain: Option<&BlockchainClient>) -> Option<BlockHash> {
+    match blockchain?.client.get_block_hash(0).await {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            tracing::warn!("Could not ask esplora for its genesis block: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    use crate::core::config;
+
+    fn genesis(network: Network) -> BlockHash {
+        genesis_block(network).block_hash()
+    }
+
+    #[test]
+    fn chains_are_told_apart_by_genesis_block() {
+        for network in KNOWN_NETWORKS {
+            assert_eq!(chain_of(genesis(network)), Some(network));
+        }
+
+        assert_eq!(chain_of(BlockHash::all_zeros()), None);
+    }
+
+    #[test]
+    fn mismatched_networks_fail() {
+        let config = config::parse(
+            r#"
+                ark_server_url = "http://localhost:7070"
+                esplora_url = "http://localhost:30000"
+            "#,
+            Vec::new(),
+        )
+        .unwrap();
+        let regtest = Network::Regtest;
+
+        assert!(check(&config, regtest, Some(regtest), Some(genesis(regtest))).is_ok());
+        assert!(check(&config, regtest, None, None).is_ok());
+
+        let error = check(&config, regtest, Some(Network::Signet), None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the Ark server at http://localhost:7070 runs on signet, but regtest is expected"
+        );
+
+        let error = check(&config, regtest, None, Some(genesis(Network::Bitcoin))).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "esplora at http://localhost:30000 follows bitcoin, but regtest is expected"
+        );
+
+        assert!(check(&config, regtest, None, Some(BlockHash::all_zeros())).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! The OpenAPI 3 document of the HTTP API, served at `GET /api/openapi.json`.
+//!
+//! It is generated from the `#[utoipa::path]` attributes of the handlers and the schemas of the
+//! types they exchange, so it cannot drift from the code.
+
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::api::{accounts, docs, finance, monitoring};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Ark wallet server",
+        description = "Custodial Ark wallets. Every error is answered with an `ApiError`, whose \
+                       `code` is stable."
+    ),
+    paths(
+        accounts::create_account,
+        accounts::restore_account,
+        accounts::unlock_account,
+        accounts::lock_account,
+        accounts::issue_token,
+        accounts::get_account_addresses,
+        accounts::add_account_address,
+        accounts::register_webhook,
+        accounts::list_webhooks,
+        accounts::delete_webhook,
+        accounts::get_webhook_deliveries,
+        finance::get_account_balance,
+        finance::get_account_transactions,
+        finance::stream_account_events,
+        finance::transfer_funds,
+        finance::preview_transfer,
+        finance::transfer_batch,
+        finance::fund_account,
+        finance::withdraw_funds,
+        finance::offboard_funds,
+        finance::start_exit,
+        finance::get_exit,
+        finance::get_account_renewals,
+        monitoring::get_metrics,
+        monitoring::get_health,
+        monitoring::get_readiness,
+        docs::get_openapi,
+    ),
+    modifiers(&ApiTokenAuth),
+    tags(
+        (name = "accounts", description = "Accounts, their keys and API tokens"),
+        (name = "finance", description = "Balances, payments and exits"),
+        (name = "webhooks", description = "Event delivery to account-owned URLs"),
+        (name = "monitoring", description = "Metrics, health checks and this document"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Declares the `Authorization: Bearer <token>` scheme that account-scoped routes require.
+struct ApiTokenAuth;
+
+impl Modify for ApiTokenAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_token",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("An API token issued to the account"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_route_is_documented() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = spec["paths"].as_object().unwrap();
+
+        for (path, method) in [
+            ("/api/accounts", "post"),
+            ("/api/accounts/{account_id}/unlock", "post"),
+            ("/api/accounts/{account_id}/webhooks/{webhook_id}", "delete"),
+            ("/api/accounts/{account_id}/transactions", "get"),
+            ("/api/transfer", "post"),
+            ("/api/transfer/preview", "post"),
+            ("/api/transfer/batch", "post"),
+            ("/api/withdraw", "post"),
+            ("/api/accounts/{account_id}/offboard", "post"),
+            ("/api/exits/{job_id}", "get"),
+            ("/api/openapi.json", "get"),
+            ("/metrics", "get"),
+            ("/healthz", "get"),
+            ("/readyz", "get"),
+        ] {
+            assert!(paths[path][method].is_object(), "{method} {path} is missing");
+        }
+        assert_eq!(paths.len(), 25);
+
+        let schemas = &spec["components"]["schemas"];
+        assert!(schemas["ApiError"].is_object());
+        assert!(schemas["ErrorCode"]["enum"]
+            .as_array()
+            .unwrap()
+            .contains(&"insufficient_funds".into()));
+        assert!(schemas["TransferRequest"].is_object());
+        assert!(spec["components"]["securitySchemes"]["api_token"].is_object());
+
+        let transfer = &paths["/api/transfer"]["post"];
+        assert_eq!(
+            transfer["responses"]["400"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ApiError"
+        );
+    }
+}
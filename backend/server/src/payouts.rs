@@ -0,0 +1,172 @@
+//! Batch payouts for `POST /api/transfer/batch`.
+//!
+//! Every payout is checked on its own before anything is built, so that one bad recipient does
+//! not hold up the others. The valid ones are paid with
+//! [`crate::clients::AccountClient::send_vtxos`], which packs them into as few redeem
+//! transactions as the limits on inputs and outputs allow, and each payout is then reported with
+//! the outcome of the transaction that carried it.
+
+use anyhow::Result;
+use ark_core::ArkAddress;
+use bitcoin::{Amount, Network, Txid};
+
+use crate::core::model::{Payout, PayoutResult, PayoutStatus};
+use crate::error::{ApiError, ErrorCode};
+
+/// The most payouts a single request may make.
+pub const MAX_PAYOUTS: usize = 500;
+
+/// Check every payout on its own, giving its parsed address if it can be paid on `network`.
+pub fn validate(
+    payouts: &[Payout],
+    network: Network,
+    dust: Amount,
+) -> Vec<Result<ArkAddress, ApiError>> {
+    payouts
+        .iter()
+        .map(|payout| {
+            let address =
+                ArkAddress::decode_for_network(&payout.recipient, network).map_err(|e| {
+                    ApiError::new(
+                        ErrorCode::InvalidAddress,
+                        format!("Invalid recipient address: {e}"),
+                    )
+                })?;
+
+            if payout.amount < dust.to_sat() {
+                return Err(ApiError::invalid_request(format!(
+                    "Amount is below the dust limit of {} sats",
+                    dust.to_sat()
+                )));
+            }
+
+            Ok(address)
+        })
+        .collect()
+}
+
+/// One result per payout, in order. Payouts that failed validation are rejected; the others
+/// take the outcome of their transaction from `outcomes`, whose positions count the valid
+/// payouts only, as they were passed on to be paid.
+pub fn report(
+    payouts: &[Payout],
+    validated: Vec<Result<ArkAddress, ApiError>>,
+    outcomes: &[(Vec<usize>, Result<Txid>)],
+) -> Vec<PayoutResult> {
+    let mut results = Vec::with_capacity(payouts.len());
+    let mut paid = Vec::new();
+    for (i, (payout, validated)) in payouts.iter().zip(validated).enumerate() {
+        let (status, error) = match validated {
+            Ok(_) => {
+                paid.push(i);
+                (PayoutStatus::Failed, None)
+            }
+            Err(e) => (PayoutStatus::Rejected, Some(e)),
+        };
+
+        results.push(PayoutResult {
+            recipient: payout.recipient.clone(),
+            amount: payout.amount,
+            status,
+            transaction_id: None,
+            error,
+        });
+    }
+
+    for (positions, outcome) in outcomes {
+        for position in positions {
+            let result = &mut results[paid[*position]];
+
+            match outcome {
+                Ok(txid) => {
+                    result.status = PayoutStatus::Sent;
+                    result.transaction_id = Some(txid.to_string());
+                }
+                Err(e) => {
+                    result.error = Some(ApiError::caused_by(
+                        "Payment failed",
+                        e,
+                        ErrorCode::Internal,
+                    ));
+                }
+            }
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use bitcoin::hashes::Hash;
+
+    const ADDRESS: &str = "tark1x0lm8hhr2wc6n6lyemtyh9rz8rg2ftpkfun46aca56kjg3ws0tsztfpuanaquxc6faedvjk3tax0575y6perapg3e95654pk8r4fjecs5fyd2";
+
+    fn payout(recipient: &str, amount: u64) -> Payout {
+        Payout {
+            recipient: recipient.to_string(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn payouts_are_validated_one_by_one() {
+        let payouts = [
+            payout(ADDRESS, 1_000),
+            payout("tark1notanaddress", 1_000),
+            payout(ADDRESS, 100),
+            payout(ADDRESS, 1_000),
+        ];
+
+        let validated = validate(&payouts, Network::Regtest, Amount::from_sat(330));
+
+        assert!(validated[0].is_ok());
+        assert_eq!(
+            validated[1].as_ref().unwrap_err().code,
+            ErrorCode::InvalidAddress
+        );
+        assert_eq!(
+            validated[2].as_ref().unwrap_err().code,
+            ErrorCode::InvalidRequest
+        );
+
+        // A testnet address cannot be paid on mainnet.
+        let validated = validate(&payouts[3..], Network::Bitcoin, Amount::from_sat(330));
+        assert_eq!(
+            validated[0].as_ref().unwrap_err().code,
+            ErrorCode::InvalidAddress
+        );
+    }
+
+    #[test]
+    fn outcomes_are_reported_per_payout() {
+        let payouts = [
+            payout(ADDRESS, 1_000),
+            payout("tark1notanaddress", 1_000),
+            payout(ADDRESS, 2_000),
+            payout(ADDRESS, 3_000),
+        ];
+        let validated = validate(&payouts, Network::Regtest, Amount::from_sat(330));
+        let txid = Txid::all_zeros();
+
+        // The valid payouts are 0, 2 and 3; the first two went into one transaction.
+        let outcomes = [
+            (vec![0, 1], Ok(txid)),
+            (vec![2], Err(anyhow!("connection reset"))),
+        ];
+
+        let results = report(&payouts, validated, &outcomes);
+
+        assert_eq!(results[0].status, PayoutStatus::Sent);
+        assert_eq!(results[0].transaction_id, Some(txid.to_string()));
+        assert_eq!(results[1].status, PayoutStatus::Rejected);
+        assert_eq!(results[2].status, PayoutStatus::Sent);
+        assert_eq!(results[3].status, PayoutStatus::Failed);
+        assert_eq!(
+            results[3].error.as_ref().unwrap().message,
+            "Payment failed: connection reset"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Dry runs of `POST /api/transfer`, for `POST /api/transfer/preview`.
+//!
+//! The account selects VTXOs and builds the redeem transaction exactly as it would for the
+//! transfer, but nothing is signed or submitted. The outcome is described along
+//! with warnings about anything the user may not expect, such as spending VTXOs that are about to
+//! expire or getting change back that is too small to spend.
+
+use ark_client::SendPreview;
+use bitcoin::Amount;
+
+use crate::core::model::{PreviewInput, TransferPreview, TransferRequest};
+
+/// Selected VTXOs expiring within this many seconds get a warning.
+pub const EXPIRY_WARNING_SECONDS: i64 = 24 * 60 * 60;
+
+/// Describe what a transfer of `req` would do, as of Unix time `now`.
+pub fn describe(
+    req: &TransferRequest,
+    preview: &SendPreview,
+    now: i64,
+) -> TransferPreview {
+    let change_is_dust = preview.change > Amount::ZERO && preview.change < preview.dust;
+
+    TransferPreview {
+        account_id: req.account_id.clone(),
+        recipient: req.recipient.clone(),
+        amount: req.amount,
+        inputs: preview
+            .selected
+            .iter()
+            .map(|vtxo| PreviewInput {
+                outpoint: vtxo.outpoint.to_string(),
+                amount: vtxo.amount.to_sat(),
+                expire_at: vtxo.expire_at,
+            })
+            .collect(),
+        fee: preview.fee.to_sat(),
+        amount_received: preview.sent.to_sat(),
+        change: preview.change.to_sat(),
+        change_is_dust,
+        psbt: preview.psbt.to_string(),
+        warnings: warnings(req, preview, change_is_dust, now),
+    }
+}
+
+fn warnings(
+    req: &TransferRequest,
+    preview: &SendPreview,
+    change_is_dust: bool,
+    now: i64,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    for vtxo in &preview.selected {
+        if vtxo.expire_at <= now {
+            warnings.push(format!("selected VTXO {} has expired", vtxo.outpoint));
+        } else if vtxo.expire_at - now < EXPIRY_WARNING_SECONDS {
+            warnings.push(format!(
+                "selected VTXO {} expires in less than 1 day",
+                vtxo.outpoint
+            ));
+        }
+    }
+
+    if change_is_dust {
+        warnings.push(format!(
+            "change of {} sats is below the dust limit of {} sats",
+            preview.change.to_sat(),
+            preview.dust.to_sat()
+        ));
+    }
+
+    if preview.sent.to_sat() < req.amount {
+        warnings.push(format!(
+            "there is no change to pay the fee from, so the recipient gets {} sats",
+            preview.sent.to_sat()
+        ));
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_core::coin_select::VtxoOutPoint;
+    use bitcoin::{OutPoint, Psbt, Transaction, absolute, transaction};
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn preview(expiries: &[i64], sent: u64, change: u64) -> SendPreview {
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+
+        SendPreview {
+            selected: expiries
+                .iter()
+                .map(|expire_at| VtxoOutPoint {
+                    outpoint: OutPoint::null(),
+                    expire_at: *expire_at,
+                    amount: Amount::from_sat(5_000),
+                })
+                .collect(),
+            psbt: Psbt::from_unsigned_tx(tx).unwrap(),
+            fee: Amount::from_sat(200),
+            sent: Amount::from_sat(sent),
+            change: Amount::from_sat(change),
+            dust: Amount::from_sat(330),
+        }
+    }
+
+    fn request(amount: u64) -> TransferRequest {
+        TransferRequest {
+            account_id: "account".to_string(),
+            recipient: "tark1recipient".to_string(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn plain_transfers_have_no_warnings() {
+        let described = describe(
+            &request(4_000),
+            &preview(&[NOW + 7 * 86_400], 4_000, 800),
+            NOW,
+        );
+
+        assert_eq!(described.inputs.len(), 1);
+        assert_eq!(described.inputs[0].expire_at, NOW + 7 * 86_400);
+        assert_eq!(described.fee, 200);
+        assert_eq!(described.change, 800);
+        assert!(!described.change_is_dust);
+        assert!(described.warnings.is_empty());
+    }
+
+    #[test]
+    fn expiring_inputs_and_dust_change_are_flagged() {
+        let described = describe(
+            &request(4_500),
+            &preview(&[NOW + 3_600, NOW - 1], 4_500, 300),
+            NOW,
+        );
+
+        assert!(described.change_is_dust);
+        assert_eq!(described.warnings.len(), 3);
+        assert!(described.warnings[0].ends_with("expires in less than 1 day"));
+        assert!(described.warnings[1].ends_with("has expired"));
+        assert_eq!(
+            described.warnings[2],
+            "change of 300 sats is below the dust limit of 330 sats"
+        );
+    }
+
+    #[test]
+    fn fee_taken_from_the_amount_sent_is_flagged() {
+        let described = describe(
+            &request(5_000),
+            &preview(&[NOW + 7 * 86_400], 4_800, 0),
+            NOW,
+        );
+
+        assert!(!described.change_is_dust);
+        assert_eq!(described.amount_received, 4_800);
+        assert_eq!(
+            described.warnings,
+            ["there is no change to pay the fee from, so the recipient gets 4800 sats"]
+        );
+    }
+}
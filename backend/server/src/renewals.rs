@@ -0,0 +1,382 @@
+//! Automatic renewal of VTXOs before they expire.
+//!
+//! A VTXO expires together with the VTXO tree it belongs to, after which the Ark server may sweep
+//! it. A background task checks every account on a fixed interval, and settles the funds of any
+//! address key whose earliest VTXO has less than the configured fraction of the tree lifetime left
+//! back to the key's own off-chain address, which gives them a fresh expiry. Renewing holds the
+//! account's [payment lock](crate::clients::ClientCache::lock_payments), so it never races a
+//! transfer or withdrawal for the same VTXOs.
+//!
+//! Renewing signs with the account's keys, which the server only holds while the account is
+//! unlocked. The VTXOs of locked accounts are looked up with their addresses instead, and an
+//! [`AccountEvent::VtxosExpiring`] is reported once for every expiry, which also reaches the
+//! account's webhooks, so that someone can unlock the account in time.
+//!
+//! Every attempt is added to the account's renewal log, which also drives the retry policy: failed
+//! renewals of the same VTXOs are retried with exponential backoff, up to a maximum number of
+//! attempts.
+//!
+//! The value of the VTXOs found due for renewal on each pass is exported as a metric, whether or
+//! not renewing them succeeds.
+
+use actix_web::web;
+use anyhow::Result;
+use bitcoin::{Amount, Network, Sequence, relative};
+use rand::thread_rng;
+use std::time::Duration;
+
+use crate::core::config::{RenewalConfig, RetryPolicy};
+use crate::core::model::{
+    AccountEvent, ApplicationState, BlockchainClient, RenewalRecord, RenewalStatus, UserAccount,
+};
+use crate::events;
+use crate::keystore::UnlockedKeys;
+use crate::metrics::METRICS;
+
+/// The lifetime of a VTXO tree whose outputs the Ark server can sweep after `expiry`, in seconds.
+/// Block-based expiries assume ten minutes per block.
+pub fn tree_lifetime(expiry: Sequence) -> Option<u64> {
+    match expiry.to_relative_lock_time()? {
+        relative::LockTime::Time(time) => Some(time.value() as u64 * 512),
+        relative::LockTime::Blocks(height) => Some(height.value() as u64 * 600),
+    }
+}
+
+/// Whether VTXOs expiring at `expire_at` have less than `threshold` of a tree `lifetime` left at
+/// Unix time `now`.
+pub fn needs_renewal(expire_at: i64, now: i64, lifetime: u64, threshold: f64) -> bool {
+    let left = expire_at.saturating_sub(now);
+
+    (left as f64) < lifetime as f64 * threshold
+}
+
+/// When the VTXOs of an address key expiring at `expire_at` may be renewed next, given the
+/// renewal log of the key, newest first. `None` if `policy` gives up on them.
+pub fn next_attempt_at(policy: &RetryPolicy, log: &[RenewalRecord], expire_at: i64) -> Option<i64> {
+    let failures = log
+        .iter()
+        .take_while(|record| {
+            record.status == RenewalStatus::Failed && record.expire_at == expire_at
+        })
+        .collect::<Vec<_>>();
+
+    let Some(last) = failures.first() else {
+        return Some(i64::MIN);
+    };
+
+    if failures.len() >= policy.max_attempts as usize {
+        return None;
+    }
+
+    let delay = policy.delay(failures.len() as u32);
+
+    Some(last.created_at.saturating_add_unsigned(delay))
+}
+
+/// Whether the VTXOs of an address key expiring at `expire_at` were already reported as expiring,
+/// given the renewal log of the key, newest first.
+pub fn expiry_reported(log: &[RenewalRecord], expire_at: i64) -> bool {
+    log.first().is_some_and(|record| {
+        record.status == RenewalStatus::Locked && record.expire_at == expire_at
+    })
+}
+
+/// Renew the VTXOs of every account that need it, forever.
+pub async fn run(state: web::Data<ApplicationState>) {
+    let config = state.config.renewal.clone();
+    if !config.enabled {
+        return;
+    }
+
+    if !(config.threshold > 0.0 && config.threshold <= 1.0) {
+        tracing::error!(
+            threshold = config.threshold,
+            "Renewal threshold must be above 0 and at most 1; VTXO renewal is disabled"
+        );
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_seconds.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        let network_info = match state.server_connection.lock().unwrap().clone() {
+            Some(info) => info,
+            None => continue,
+        };
+
+        let blockchain = match state.blockchain_client.lock().unwrap().clone() {
+            Some(client) => client,
+            None => continue,
+        };
+
+        let Some(lifetime) = tree_lifetime(network_info.vtxo_tree_expiry) else {
+            tracing::warn!(
+                expiry = %network_info.vtxo_tree_expiry,
+                "VTXO tree expiry is not a relative timelock; cannot renew VTXOs"
+            );
+            continue;
+        };
+
+        let accounts = match state.accounts.list_accounts() {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                tracing::error!("Failed to load accounts: {:#}", e);
+                continue;
+            }
+        };
+
+        let mut near_expiry = Amount::ZERO;
+
+        for account in accounts {
+            let renewed = match state.unlocked_keys.get(&account.id) {
+                Some(keys) => {
+                    renew_account(
+                        &state,
+                        &config,
+                        &blockchain,
+                        network_info.network,
+                        lifetime,
+                        &account,
+                        &keys,
+                    )
+                    .await
+                }
+                None => report_expiring(&state, &config, &network_info, lifetime, &account).await,
+            };
+
+            match renewed {
+                Ok(amount) => near_expiry += amount,
+                Err(e) => {
+                    tracing::warn!(account_id = account.id, "Failed to renew VTXOs: {:#}", e);
+                }
+            }
+        }
+
+        METRICS.vtxos_near_expiry.set(near_expiry.to_sat() as i64);
+    }
+}
+
+/// Renew the VTXOs of `account` that need it, returning the value of those that did.
+async fn renew_account(
+    state: &ApplicationState,
+    config: &RenewalConfig,
+    blockchain: &BlockchainClient,
+    network: Network,
+    lifetime: u64,
+    account: &UserAccount,
+    keys: &UnlockedKeys,
+) -> Result<Amount> {
+    let client = state
+        .clients
+        .get_or_connect(&state.config, blockchain, network, account, keys)
+        .await?;
+
+    let now = jiff::Timestamp::now().as_second();
+    let mut near_expiry = Amount::ZERO;
+
+    for (index, expiry) in client.vtxo_expiries().await?.into_iter().enumerate() {
+        let Some((expire_at, amount)) = expiry else {
+            continue;
+        };
+
+        if !needs_renewal(expire_at, now, lifetime, config.threshold) {
+            continue;
+        }
+
+        near_expiry += amount;
+
+        let log = state.accounts.list_renewals(
+            &account.id,
+            Some(index as u32),
+            config.retry.max_attempts as usize,
+        )?;
+        if next_attempt_at(&config.retry, &log, expire_at).is_none_or(|at| at > now) {
+            continue;
+        }
+
+        let mut record = RenewalRecord {
+            account_id: account.id.clone(),
+            key_index: index as u32,
+            status: RenewalStatus::Renewed,
+            expire_at,
+            amount: amount.to_sat(),
+            round_txid: None,
+            error: None,
+            created_at: now,
+        };
+
+        let _payment = state.clients.lock_payments(&account.id).await;
+
+        match client.renew(&mut thread_rng(), index).await {
+            Ok(Some(txid)) => {
+                tracing::info!(account_id = account.id, index, %txid, "Renewed VTXOs");
+                record.round_txid = Some(txid.to_string());
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::warn!(
+                    account_id = account.id,
+                    index,
+                    "Failed to renew VTXOs: {:#}",
+                    e
+                );
+                events::report_round_failure(state, &account.id, &e);
+
+                record.status = RenewalStatus::Failed;
+                record.error = Some(e.to_string());
+            }
+        }
+
+        state.accounts.append_renewal(&record)?;
+    }
+
+    Ok(near_expiry)
+}
+
+/// Report the VTXOs of the locked `account` that need renewing, returning their value.
+async fn report_expiring(
+    state: &ApplicationState,
+    config: &RenewalConfig,
+    network_info: &ark_core::server::Info,
+    lifetime: u64,
+    account: &UserAccount,
+) -> Result<Amount> {
+    let mut client = ark_grpc::Client::new(state.config.ark_server_url.clone());
+    client.connect().await?;
+
+    let now = jiff::Timestamp::now().as_second();
+    let mut near_expiry = Amount::ZERO;
+
+    for index in 0..account.key_count.max(1) {
+        let (_, vtxo) = account.key_outputs(network_info, index)?;
+        let spendable = client.list_vtxos(&vtxo.to_ark_address()).await?.spendable;
+
+        let Some(expire_at) = spendable.iter().map(|vtxo| vtxo.expire_at).min() else {
+            continue;
+        };
+
+        if !needs_renewal(expire_at, now, lifetime, config.threshold) {
+            continue;
+        }
+
+        let amount = spendable.iter().map(|vtxo| vtxo.amount).sum::<Amount>();
+        near_expiry += amount;
+
+        let log = state.accounts.list_renewals(&account.id, Some(index), 1)?;
+        if expiry_reported(&log, expire_at) {
+            continue;
+        }
+
+        tracing::warn!(
+            account_id = account.id,
+            index,
+            expire_at,
+            "VTXOs of a locked account are due for renewal"
+        );
+
+        let expiring = AccountEvent::VtxosExpiring {
+            key_index: index,
+            amount: amount.to_sat(),
+            expire_at,
+        };
+        events::report(state, &account.id, expiring);
+
+        state.accounts.append_renewal(&RenewalRecord {
+            account_id: account.id.clone(),
+            key_index: index,
+            status: RenewalStatus::Locked,
+            expire_at,
+            amount: amount.to_sat(),
+            round_txid: None,
+            error: Some("account is locked".to_string()),
+            created_at: now,
+        })?;
+    }
+
+    Ok(near_expiry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(expire_at: i64, created_at: i64) -> RenewalRecord {
+        RenewalRecord {
+            account_id: "account".to_string(),
+            key_index: 0,
+            status: RenewalStatus::Failed,
+            expire_at,
+            amount: 10_000,
+            round_txid: None,
+            error: Some("round failed".to_string()),
+            created_at,
+        }
+    }
+
+    #[test]
+    fn lifetime_follows_the_timelock_unit() {
+        assert_eq!(
+            tree_lifetime(Sequence::from_512_second_intervals(2)),
+            Some(1024)
+        );
+        assert_eq!(tree_lifetime(Sequence::from_height(144)), Some(86_400));
+        assert_eq!(tree_lifetime(Sequence::MAX), None);
+    }
+
+    #[test]
+    fn renewal_starts_below_the_threshold() {
+        // 20% of a 10_000 second lifetime is 2_000 seconds.
+        assert!(!needs_renewal(12_000, 10_000, 10_000, 0.2));
+        assert!(needs_renewal(11_999, 10_000, 10_000, 0.2));
+        assert!(needs_renewal(5_000, 10_000, 10_000, 0.2));
+    }
+
+    #[test]
+    fn failures_back_off_until_the_policy_gives_up() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff_seconds: 60,
+            max_backoff_seconds: 100,
+        };
+
+        assert_eq!(next_attempt_at(&policy, &[], 5_000), Some(i64::MIN));
+
+        let log = vec![failure(5_000, 1_000)];
+        assert_eq!(next_attempt_at(&policy, &log, 5_000), Some(1_060));
+
+        let log = vec![failure(5_000, 1_060), failure(5_000, 1_000)];
+        assert_eq!(next_attempt_at(&policy, &log, 5_000), Some(1_160));
+
+        let log = vec![
+            failure(5_000, 1_160),
+            failure(5_000, 1_060),
+            failure(5_000, 1_000),
+        ];
+        assert_eq!(next_attempt_at(&policy, &log, 5_000), None);
+
+        // Failures of other VTXOs do not count.
+        assert_eq!(next_attempt_at(&policy, &log, 9_000), Some(i64::MIN));
+    }
+
+    #[test]
+    fn locked_accounts_hear_of_each_expiry_once() {
+        let locked = RenewalRecord {
+            status: RenewalStatus::Locked,
+            error: None,
+            ..failure(5_000, 1_000)
+        };
+
+        assert!(!expiry_reported(&[], 5_000));
+        let log = vec![locked];
+        assert!(expiry_reported(&log, 5_000));
+
+        // VTXOs renewed or received since expire at another time.
+        assert!(!expiry_reported(&log, 9_000));
+
+        // The account was unlocked and locked again before the VTXOs could be renewed.
+        let log = vec![failure(5_000, 1_100), log[0].clone()];
+        assert!(!expiry_reported(&log, 5_000));
+    }
+}
@@ -0,0 +1,2760 @@
+//! Durable storage for server accounts.
+//!
+//! Every backend implements [`AccountStore`], so that [`ApplicationState`] does not need to know
+//! where accounts are kept. The backend is picked from the `[storage]` section of
+//! `ark.config.toml`.
+//!
+//! [`ApplicationState`]: crate::core::model::ApplicationState
+
+use anyhow::Result;
+
+use crate::core::config::StorageConfig;
+use crate::core::model::{
+    AccountEvent, ApiToken, ExitJob, IdempotencyRecord, RenewalRecord, StoredEvent, StoredResponse,
+    UserAccount, VtxoExitPath, Webhook, WebhookDelivery,
+};
+
+pub trait AccountStore: Send + Sync {
+    /// Persist a new account. Fails if an account with the same ID already exists.
+    fn insert_account(&self, account: &UserAccount) -> Result<()>;
+
+    /// Replace the stored record of an existing account.
+    fn update_account(&self, account: &UserAccount) -> Result<()>;
+
+    fn get_account(&self, account_id: &str) -> Result<Option<UserAccount>>;
+
+    /// Hand out the next address key of `account_id`, by raising its key count from `key_count`
+    /// to `key_count + 1`. Returns `false`, and changes nothing, if the key count is no longer
+    /// `key_count`.
+    fn add_address_key(&self, account_id: &str, key_count: u32) -> Result<bool>;
+
+    fn list_accounts(&self) -> Result<Vec<UserAccount>>;
+
+    /// How many accounts there are, without loading them.
+    fn count_accounts(&self) -> Result<usize>;
+
+    /// Persist a new API token for an existing account.
+    fn insert_token(&self, token: &ApiToken) -> Result<()>;
+
+    /// Look up an API token by the hash of its secret.
+    fn get_token(&self, token_hash: &str) -> Result<Option<ApiToken>>;
+
+    /// How many API tokens `account_id` has.
+    fn count_tokens(&self, account_id: &str) -> Result<usize>;
+
+    /// Append `events` to the event log of `account_id`, returning them with the sequence
+    /// numbers they were given.
+    fn append_events(&self, account_id: &str, events: &[AccountEvent]) -> Result<Vec<StoredEvent>>;
+
+    /// Up to `limit` events of `account_id` with a sequence number above `after`, oldest first.
+    fn events_after(&self, account_id: &str, after: u64, limit: usize) -> Result<Vec<StoredEvent>>;
+
+    /// Persist a new exit job for an existing account.
+    fn insert_exit_job(&self, job: &ExitJob) -> Result<()>;
+
+    /// Replace the stored record of an existing exit job.
+    fn update_exit_job(&self, job: &ExitJob) -> Result<()>;
+
+    fn get_exit_job(&self, job_id: &str) -> Result<Option<ExitJob>>;
+
+    /// All exit jobs, oldest first.
+    fn list_exit_jobs(&self) -> Result<Vec<ExitJob>>;
+
+    /// Replace the recorded exit paths of the VTXOs of an existing account with `paths`.
+    fn replace_exit_paths(&self, account_id: &str, paths: &[VtxoExitPath]) -> Result<()>;
+
+    /// The recorded exit paths of the VTXOs of `account_id`, ordered by outpoint.
+    fn list_exit_paths(&self, account_id: &str) -> Result<Vec<VtxoExitPath>>;
+
+    /// Claim `record.key` for `record.account_id`, unless it is taken. Returns `None` if the key
+    /// was claimed, or the record that already holds it.
+    ///
+    /// A claim for the same request that has no response yet is taken over if it is stale: if it
+    /// was made before Unix time `stale_before`, whichever owner made it.
+    fn claim_idempotency_key(
+        &self,
+        record: &IdempotencyRecord,
+        stale_before: i64,
+    ) -> Result<Option<IdempotencyRecord>>;
+
+    /// Store the final response of the request holding `key`.
+    fn complete_idempotency_key(
+        &self,
+        account_id: &str,
+        key: &str,
+        response: &StoredResponse,
+    ) -> Result<()>;
+
+    /// Free `key` again, for a request that was turned down before it had any effect.
+    fn release_idempotency_key(&self, account_id: &str, key: &str) -> Result<()>;
+
+    /// Remove the idempotency keys claimed before Unix time `before`. Returns how many were
+    /// removed.
+    fn prune_idempotency_keys(&self, before: i64) -> Result<usize>;
+
+    /// Add `record` to the renewal log of its account.
+    fn append_renewal(&self, record: &RenewalRecord) -> Result<()>;
+
+    /// Up to `limit` entries of the renewal log of `account_id`, newest first, optionally only
+    /// those of address key `key_index`.
+    fn list_renewals(
+        &self,
+        account_id: &str,
+        key_index: Option<u32>,
+        limit: usize,
+    ) -> Result<Vec<RenewalRecord>>;
+
+    /// Persist a new webhook for an existing account.
+    fn insert_webhook(&self, webhook: &Webhook) -> Result<()>;
+
+    /// The webhooks of `account_id`, oldest first.
+    fn list_webhooks(&self, account_id: &str) -> Result<Vec<Webhook>>;
+
+    /// Remove webhook `webhook_id` of `account_id`. Returns whether it existed. Its delivery
+    /// history is kept.
+    fn delete_webhook(&self, account_id: &str, webhook_id: &str) -> Result<bool>;
+
+    /// Add `deliveries` to the outbox.
+    fn insert_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<()>;
+
+    /// Replace the stored record of an existing delivery.
+    fn update_delivery(&self, delivery: &WebhookDelivery) -> Result<()>;
+
+    /// Up to `limit` pending deliveries whose next attempt is due at Unix time `now`, most
+    /// overdue first.
+    fn due_deliveries(&self, now: i64, limit: usize) -> Result<Vec<WebhookDelivery>>;
+
+    /// Up to `limit` deliveries to webhook `webhook_id` of `account_id`, newest first.
+    fn list_deliveries(
+        &self,
+        account_id: &str,
+        webhook_id: &str,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>>;
+
+    /// Remove the delivered and given-up deliveries last attempted before Unix time `before`.
+    /// Returns how many were removed.
+    fn prune_deliveries(&self, before: i64) -> Result<usize>;
+
+    /// Fail unless the store can be read, for the readiness probe.
+    fn check(&self) -> Result<()>;
+}
+
+/// Open the account store described by `config`, running any pending migrations.
+pub fn open(config: &StorageConfig) -> Result<Box<dyn AccountStore>> {
+    let store: Box<dyn AccountStore> = match config {
+        StorageConfig::Sqlite { path } => Box::new(sqlite::SqliteStore::open(path)?),
+        StorageConfig::File { path } => Box::new(file::FileStore::open(path)?),
+    };
+
+    Ok(store)
+}
+
+pub mod sqlite {
+    use anyhow::{Context, Result};
+    use rusqlite::{params, Connection, OptionalExtension, Row};
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use super::AccountStore;
+    use crate::core::model::{
+        AccountEvent, ApiToken, DeliveryStatus, ExitJob, IdempotencyRecord, RenewalRecord,
+        StoredEvent, StoredResponse, UserAccount, VtxoExitPath, Webhook, WebhookDelivery,
+    };
+
+    /// Schema migrations, applied in order. The index of a migration plus one is the schema
+    /// version it produces, tracked through SQLite's `user_version` pragma.
+    ///
+    /// Never edit a migration that has been released; append a new one instead.
+    const MIGRATIONS: &[&str] = &[
+        // 1: accounts.
+        "CREATE TABLE accounts (
+            id TEXT PRIMARY KEY NOT NULL,
+            private_key TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );",
+        // 2: encrypted keys. The plaintext `private_key` column is kept, nullable, for accounts
+        // created before version 2 until the `migrate-keys` command encrypts their keys.
+        "CREATE TABLE accounts_v2 (
+            id TEXT PRIMARY KEY NOT NULL,
+            public_key TEXT,
+            encrypted_key TEXT,
+            private_key TEXT,
+            created_at INTEGER NOT NULL
+        );
+        INSERT INTO accounts_v2 (id, private_key, created_at)
+            SELECT id, private_key, created_at FROM accounts;
+        DROP TABLE accounts;
+        ALTER TABLE accounts_v2 RENAME TO accounts;",
+        // 3: mnemonic-backed accounts with derived address keys.
+        "ALTER TABLE accounts ADD COLUMN xpub TEXT;
+        ALTER TABLE accounts ADD COLUMN key_count INTEGER NOT NULL DEFAULT 1;",
+        // 4: API tokens.
+        "CREATE TABLE api_tokens (
+            token_hash TEXT PRIMARY KEY NOT NULL,
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            scope TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );",
+        // 5: account event log.
+        "CREATE TABLE account_events (
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            seq INTEGER NOT NULL,
+            event TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (account_id, seq)
+        );",
+        // 6: unilateral exit jobs.
+        "CREATE TABLE exit_jobs (
+            id TEXT PRIMARY KEY NOT NULL,
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            job TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );",
+        // 7: idempotency keys of payment requests.
+        "CREATE TABLE idempotency_keys (
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            key TEXT NOT NULL,
+            fingerprint TEXT NOT NULL,
+            response TEXT,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (account_id, key)
+        );",
+        // 8: VTXO renewal log.
+        "CREATE TABLE renewals (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            key_index INTEGER NOT NULL,
+            record TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE INDEX renewals_by_account ON renewals (account_id, key_index);",
+        // 9: webhooks and their delivery outbox. Deliveries outlive the webhook they were for.
+        "CREATE TABLE webhooks (
+            id TEXT PRIMARY KEY NOT NULL,
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            webhook TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE webhook_deliveries (
+            id TEXT PRIMARY KEY NOT NULL,
+            webhook_id TEXT NOT NULL,
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            status TEXT NOT NULL,
+            next_attempt_at INTEGER NOT NULL,
+            delivery TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE INDEX webhook_deliveries_by_status ON webhook_deliveries (status, next_attempt_at);
+        CREATE INDEX webhook_deliveries_by_webhook ON webhook_deliveries (webhook_id, created_at);",
+        // 10: exit paths of settled VTXOs, recorded ahead of a unilateral exit.
+        "CREATE TABLE exit_paths (
+            account_id TEXT NOT NULL REFERENCES accounts (id),
+            outpoint TEXT NOT NULL,
+            path TEXT NOT NULL,
+            PRIMARY KEY (account_id, outpoint)
+        );",
+        // 11: owners of idempotency key claims, and expiry of old keys.
+        "ALTER TABLE idempotency_keys ADD COLUMN owner TEXT;
+        CREATE INDEX idempotency_keys_by_age ON idempotency_keys (created_at);",
+    ];
+
+    const ACCOUNT_COLUMNS: &str = "id, xpub, key_count, public_key, encrypted_key, private_key";
+
+    type AccountRow = (
+        String,
+        Option<String>,
+        u32,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+    );
+
+    fn read_account_row(row: &Row) -> rusqlite::Result<AccountRow> {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+        ))
+    }
+
+    fn account_from_row(
+        (id, xpub, key_count, public_key, encrypted_key, private_key): AccountRow,
+    ) -> Result<UserAccount> {
+        let encrypted_key = encrypted_key
+            .map(|e| serde_json::from_str(&e))
+            .transpose()
+            .with_context(|| format!("corrupt encrypted key for account {id}"))?;
+
+        Ok(UserAccount {
+            id,
+            xpub,
+            key_count,
+            public_key,
+            encrypted_key,
+            private_key,
+        })
+    }
+
+    pub struct SqliteStore {
+        connection: Mutex<Connection>,
+    }
+
+    impl SqliteStore {
+        pub fn open(path: &Path) -> Result<Self> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create {}", parent.display()))?;
+            }
+
+            let connection = Connection::open(path)
+                .with_context(|| format!("failed to open database {}", path.display()))?;
+
+            Self::from_connection(connection)
+        }
+
+        pub fn from_connection(mut connection: Connection) -> Result<Self> {
+            connection.pragma_update(None, "foreign_keys", true)?;
+            migrate(&mut connection)?;
+
+            Ok(Self {
+                connection: Mutex::new(connection),
+            })
+        }
+    }
+
+    fn migrate(connection: &mut Connection) -> Result<()> {
+        let version: usize =
+            connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "database schema version {version} is newer than supported version {}",
+                MIGRATIONS.len()
+            );
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = connection.transaction()?;
+            tx.execute_batch(migration)
+                .with_context(|| format!("failed to apply migration {}", i + 1))?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+
+            tracing::info!(version = i + 1, "Applied storage migration");
+        }
+
+        Ok(())
+    }
+
+    impl AccountStore for SqliteStore {
+        fn insert_account(&self, account: &UserAccount) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+            let created_at = jiff::Timestamp::now().as_second();
+
+            let encrypted_key = account
+                .encrypted_key
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?;
+
+            connection
+                .execute(
+                    "INSERT INTO accounts
+                        (id, xpub, key_count, public_key, encrypted_key, private_key, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        account.id,
+                        account.xpub,
+                        account.key_count,
+                        account.public_key,
+                        encrypted_key,
+                        account.private_key,
+                        created_at
+                    ],
+                )
+                .context("failed to insert account")?;
+
+            Ok(())
+        }
+
+        fn update_account(&self, account: &UserAccount) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            let encrypted_key = account
+                .encrypted_key
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?;
+
+            let updated = connection
+                .execute(
+                    "UPDATE accounts SET xpub = ?2, key_count = ?3, public_key = ?4,
+                        encrypted_key = ?5, private_key = ?6
+                     WHERE id = ?1",
+                    params![
+                        account.id,
+                        account.xpub,
+                        account.key_count,
+                        account.public_key,
+                        encrypted_key,
+                        account.private_key
+                    ],
+                )
+                .context("failed to update account")?;
+
+            if updated == 0 {
+                anyhow::bail!("account {} does not exist", account.id);
+            }
+
+            Ok(())
+        }
+
+        fn add_address_key(&self, account_id: &str, key_count: u32) -> Result<bool> {
+            let connection = self.connection.lock().unwrap();
+
+            let updated = connection
+                .execute(
+                    "UPDATE accounts SET key_count = key_count + 1 WHERE id = ?1 AND key_count = ?2",
+                    params![account_id, key_count],
+                )
+                .context("failed to add address key")?;
+
+            Ok(updated == 1)
+        }
+
+        fn get_account(&self, account_id: &str) -> Result<Option<UserAccount>> {
+            let connection = self.connection.lock().unwrap();
+
+            let row = connection
+                .query_row(
+                    &format!("SELECT {ACCOUNT_COLUMNS} FROM accounts WHERE id = ?1"),
+                    params![account_id],
+                    read_account_row,
+                )
+                .optional()?;
+
+            row.map(account_from_row).transpose()
+        }
+
+        fn list_accounts(&self) -> Result<Vec<UserAccount>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement = connection.prepare(&format!(
+                "SELECT {ACCOUNT_COLUMNS} FROM accounts ORDER BY created_at"
+            ))?;
+            let rows = statement
+                .query_map([], read_account_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            rows.into_iter().map(account_from_row).collect()
+        }
+
+        fn count_accounts(&self) -> Result<usize> {
+            let connection = self.connection.lock().unwrap();
+
+            let count = connection.query_row("SELECT COUNT(*) FROM accounts", [], |row| row.get(0))?;
+
+            Ok(count)
+        }
+
+        fn insert_token(&self, token: &ApiToken) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+            let created_at = jiff::Timestamp::now().as_second();
+
+            connection
+                .execute(
+                    "INSERT INTO api_tokens (token_hash, account_id, scope, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        token.token_hash,
+                        token.account_id,
+                        serde_json::to_string(&token.scope)?,
+                        created_at
+                    ],
+                )
+                .context("failed to insert API token")?;
+
+            Ok(())
+        }
+
+        fn get_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+            let connection = self.connection.lock().unwrap();
+
+            let row: Option<(String, String, String)> = connection
+                .query_row(
+                    "SELECT token_hash, account_id, scope FROM api_tokens WHERE token_hash = ?1",
+                    params![token_hash],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .optional()?;
+
+            row.map(|(token_hash, account_id, scope)| {
+                Ok(ApiToken {
+                    scope: serde_json::from_str(&scope)
+                        .with_context(|| format!("corrupt scope for API token of {account_id}"))?,
+                    token_hash,
+                    account_id,
+                })
+            })
+            .transpose()
+        }
+
+        fn count_tokens(&self, account_id: &str) -> Result<usize> {
+            let connection = self.connection.lock().unwrap();
+
+            let count = connection.query_row(
+                "SELECT COUNT(*) FROM api_tokens WHERE account_id = ?1",
+                params![account_id],
+                |row| row.get(0),
+            )?;
+
+            Ok(count)
+        }
+
+        fn append_events(
+            &self,
+            account_id: &str,
+            events: &[AccountEvent],
+        ) -> Result<Vec<StoredEvent>> {
+            let mut connection = self.connection.lock().unwrap();
+            let created_at = jiff::Timestamp::now().as_second();
+
+            let tx = connection.transaction()?;
+            let last_seq: u64 = tx.query_row(
+                "SELECT COALESCE(MAX(seq), 0) FROM account_events WHERE account_id = ?1",
+                params![account_id],
+                |row| row.get(0),
+            )?;
+
+            let mut stored = Vec::new();
+            for (i, event) in events.iter().enumerate() {
+                let event = StoredEvent {
+                    seq: last_seq + i as u64 + 1,
+                    account_id: account_id.to_string(),
+                    created_at,
+                    event: event.clone(),
+                };
+
+                tx.execute(
+                    "INSERT INTO account_events (account_id, seq, event, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        account_id,
+                        event.seq,
+                        serde_json::to_string(&event.event)?,
+                        created_at
+                    ],
+                )
+                .context("failed to insert account event")?;
+
+                stored.push(event);
+            }
+            tx.commit()?;
+
+            Ok(stored)
+        }
+
+        fn events_after(
+            &self,
+            account_id: &str,
+            after: u64,
+            limit: usize,
+        ) -> Result<Vec<StoredEvent>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement = connection.prepare(
+                "SELECT seq, event, created_at FROM account_events
+                 WHERE account_id = ?1 AND seq > ?2
+                 ORDER BY seq LIMIT ?3",
+            )?;
+            let rows = statement
+                .query_map(
+                    params![account_id, after, limit.min(i64::MAX as usize) as i64],
+                    |row| Ok((row.get(0)?, row.get::<_, String>(1)?, row.get(2)?)),
+                )?
+                .collect::<Result<Vec<(u64, String, i64)>, _>>()?;
+
+            rows.into_iter()
+                .map(|(seq, event, created_at)| {
+                    Ok(StoredEvent {
+                        seq,
+                        account_id: account_id.to_string(),
+                        created_at,
+                        event: serde_json::from_str(&event).with_context(|| {
+                            format!("corrupt event {seq} of account {account_id}")
+                        })?,
+                    })
+                })
+                .collect()
+        }
+
+        fn insert_exit_job(&self, job: &ExitJob) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            connection
+                .execute(
+                    "INSERT INTO exit_jobs (id, account_id, job, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        job.id,
+                        job.account_id,
+                        serde_json::to_string(job)?,
+                        job.created_at
+                    ],
+                )
+                .context("failed to insert exit job")?;
+
+            Ok(())
+        }
+
+        fn update_exit_job(&self, job: &ExitJob) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            let updated = connection
+                .execute(
+                    "UPDATE exit_jobs SET job = ?2 WHERE id = ?1",
+                    params![job.id, serde_json::to_string(job)?],
+                )
+                .context("failed to update exit job")?;
+
+            if updated == 0 {
+                anyhow::bail!("exit job {} does not exist", job.id);
+            }
+
+            Ok(())
+        }
+
+        fn get_exit_job(&self, job_id: &str) -> Result<Option<ExitJob>> {
+            let connection = self.connection.lock().unwrap();
+
+            let job: Option<String> = connection
+                .query_row(
+                    "SELECT job FROM exit_jobs WHERE id = ?1",
+                    params![job_id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            job.map(|job| {
+                serde_json::from_str(&job).with_context(|| format!("corrupt exit job {job_id}"))
+            })
+            .transpose()
+        }
+
+        fn list_exit_jobs(&self) -> Result<Vec<ExitJob>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement =
+                connection.prepare("SELECT id, job FROM exit_jobs ORDER BY created_at")?;
+            let rows = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+
+            rows.into_iter()
+                .map(|(id, job)| {
+                    serde_json::from_str(&job).with_context(|| format!("corrupt exit job {id}"))
+                })
+                .collect()
+        }
+
+        fn replace_exit_paths(&self, account_id: &str, paths: &[VtxoExitPath]) -> Result<()> {
+            let mut connection = self.connection.lock().unwrap();
+            let transaction = connection.transaction()?;
+
+            transaction.execute(
+                "DELETE FROM exit_paths WHERE account_id = ?1",
+                params![account_id],
+            )?;
+
+            for path in paths {
+                transaction
+                    .execute(
+                        "INSERT INTO exit_paths (account_id, outpoint, path) VALUES (?1, ?2, ?3)",
+                        params![account_id, path.outpoint, serde_json::to_string(path)?],
+                    )
+                    .context("failed to insert exit path")?;
+            }
+
+            transaction.commit()?;
+
+            Ok(())
+        }
+
+        fn list_exit_paths(&self, account_id: &str) -> Result<Vec<VtxoExitPath>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement = connection.prepare(
+                "SELECT outpoint, path FROM exit_paths WHERE account_id = ?1 ORDER BY outpoint",
+            )?;
+            let rows = statement
+                .query_map(params![account_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+
+            rows.into_iter()
+                .map(|(outpoint, path)| {
+                    serde_json::from_str(&path)
+                        .with_context(|| format!("corrupt exit path of {outpoint}"))
+                })
+                .collect()
+        }
+
+        fn claim_idempotency_key(
+            &self,
+            record: &IdempotencyRecord,
+            stale_before: i64,
+        ) -> Result<Option<IdempotencyRecord>> {
+            let connection = self.connection.lock().unwrap();
+
+            let claimed = connection
+                .execute(
+                    "INSERT INTO idempotency_keys
+                     (account_id, key, fingerprint, response, owner, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (account_id, key) DO UPDATE
+                     SET owner = excluded.owner, created_at = excluded.created_at
+                     WHERE idempotency_keys.response IS NULL
+                       AND idempotency_keys.fingerprint = excluded.fingerprint
+                       AND idempotency_keys.created_at < ?7",
+                    params![
+                        record.account_id,
+                        record.key,
+                        record.fingerprint,
+                        record
+                            .response
+                            .as_ref()
+                            .map(serde_json::to_string)
+                            .transpose()?,
+                        record.owner,
+                        record.created_at,
+                        stale_before
+                    ],
+                )
+                .context("failed to claim idempotency key")?;
+
+            if claimed == 1 {
+                return Ok(None);
+            }
+
+            let (fingerprint, response, owner, created_at): (
+                String,
+                Option<String>,
+                Option<String>,
+                i64,
+            ) = connection.query_row(
+                "SELECT fingerprint, response, owner, created_at FROM idempotency_keys
+                 WHERE account_id = ?1 AND key = ?2",
+                params![record.account_id, record.key],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+
+            let response = response
+                .map(|response| serde_json::from_str(&response))
+                .transpose()
+                .with_context(|| format!("corrupt response for idempotency key {}", record.key))?;
+
+            Ok(Some(IdempotencyRecord {
+                account_id: record.account_id.clone(),
+                key: record.key.clone(),
+                fingerprint,
+                response,
+                owner,
+                created_at,
+            }))
+        }
+
+        fn complete_idempotency_key(
+            &self,
+            account_id: &str,
+            key: &str,
+            response: &StoredResponse,
+        ) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            let updated = connection
+                .execute(
+                    "UPDATE idempotency_keys SET response = ?3 WHERE account_id = ?1 AND key = ?2",
+                    params![account_id, key, serde_json::to_string(response)?],
+                )
+                .context("failed to complete idempotency key")?;
+
+            if updated == 0 {
+                anyhow::bail!("idempotency key {key} of account {account_id} is not claimed");
+            }
+
+            Ok(())
+        }
+
+        fn release_idempotency_key(&self, account_id: &str, key: &str) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            connection
+                .execute(
+                    "DELETE FROM idempotency_keys WHERE account_id = ?1 AND key = ?2",
+                    params![account_id, key],
+                )
+                .context("failed to release idempotency key")?;
+
+            Ok(())
+        }
+
+        fn prune_idempotency_keys(&self, before: i64) -> Result<usize> {
+            let connection = self.connection.lock().unwrap();
+
+            let pruned = connection
+                .execute(
+                    "DELETE FROM idempotency_keys WHERE created_at < ?1",
+                    params![before],
+                )
+                .context("failed to prune idempotency keys")?;
+
+            Ok(pruned)
+        }
+
+        fn append_renewal(&self, record: &RenewalRecord) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            connection
+                .execute(
+                    "INSERT INTO renewals (account_id, key_index, record, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        record.account_id,
+                        record.key_index,
+                        serde_json::to_string(record)?,
+                        record.created_at
+                    ],
+                )
+                .context("failed to insert renewal")?;
+
+            Ok(())
+        }
+
+        fn list_renewals(
+            &self,
+            account_id: &str,
+            key_index: Option<u32>,
+            limit: usize,
+        ) -> Result<Vec<RenewalRecord>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement = connection.prepare(
+                "SELECT record FROM renewals
+                 WHERE account_id = ?1 AND (?2 IS NULL OR key_index = ?2)
+                 ORDER BY id DESC LIMIT ?3",
+            )?;
+            let rows = statement
+                .query_map(
+                    params![account_id, key_index, limit.min(i64::MAX as usize) as i64],
+                    |row| row.get(0),
+                )?
+                .collect::<Result<Vec<String>, _>>()?;
+
+            rows.into_iter()
+                .map(|record| {
+                    serde_json::from_str(&record)
+                        .with_context(|| format!("corrupt renewal of account {account_id}"))
+                })
+                .collect()
+        }
+
+        fn insert_webhook(&self, webhook: &Webhook) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            connection
+                .execute(
+                    "INSERT INTO webhooks (id, account_id, webhook, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        webhook.id,
+                        webhook.account_id,
+                        serde_json::to_string(webhook)?,
+                        webhook.created_at
+                    ],
+                )
+                .context("failed to insert webhook")?;
+
+            Ok(())
+        }
+
+        fn list_webhooks(&self, account_id: &str) -> Result<Vec<Webhook>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement = connection.prepare(
+                "SELECT id, webhook FROM webhooks WHERE account_id = ?1 ORDER BY created_at, id",
+            )?;
+            let rows = statement
+                .query_map(params![account_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+
+            rows.into_iter()
+                .map(|(id, webhook)| {
+                    serde_json::from_str(&webhook).with_context(|| format!("corrupt webhook {id}"))
+                })
+                .collect()
+        }
+
+        fn delete_webhook(&self, account_id: &str, webhook_id: &str) -> Result<bool> {
+            let connection = self.connection.lock().unwrap();
+
+            let deleted = connection
+                .execute(
+                    "DELETE FROM webhooks WHERE id = ?1 AND account_id = ?2",
+                    params![webhook_id, account_id],
+                )
+                .context("failed to delete webhook")?;
+
+            Ok(deleted > 0)
+        }
+
+        fn insert_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<()> {
+            let mut connection = self.connection.lock().unwrap();
+            let transaction = connection.transaction()?;
+
+            for delivery in deliveries {
+                transaction
+                    .execute(
+                        "INSERT INTO webhook_deliveries
+                         (id, webhook_id, account_id, status, next_attempt_at, delivery, created_at)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                        params![
+                            delivery.id,
+                            delivery.webhook_id,
+                            delivery.account_id,
+                            status_column(delivery.status),
+                            delivery.next_attempt_at,
+                            serde_json::to_string(delivery)?,
+                            delivery.created_at
+                        ],
+                    )
+                    .context("failed to insert webhook delivery")?;
+            }
+
+            transaction.commit()?;
+
+            Ok(())
+        }
+
+        fn update_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+
+            let updated = connection
+                .execute(
+                    "UPDATE webhook_deliveries SET status = ?2, next_attempt_at = ?3, delivery = ?4
+                     WHERE id = ?1",
+                    params![
+                        delivery.id,
+                        status_column(delivery.status),
+                        delivery.next_attempt_at,
+                        serde_json::to_string(delivery)?
+                    ],
+                )
+                .context("failed to update webhook delivery")?;
+
+            if updated == 0 {
+                anyhow::bail!("webhook delivery {} does not exist", delivery.id);
+            }
+
+            Ok(())
+        }
+
+        fn due_deliveries(&self, now: i64, limit: usize) -> Result<Vec<WebhookDelivery>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement = connection.prepare(
+                "SELECT id, delivery FROM webhook_deliveries
+                 WHERE status = ?1 AND next_attempt_at <= ?2
+                 ORDER BY next_attempt_at LIMIT ?3",
+            )?;
+            let rows = statement
+                .query_map(
+                    params![
+                        status_column(DeliveryStatus::Pending),
+                        now,
+                        limit.min(i64::MAX as usize) as i64
+                    ],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+
+            rows.into_iter()
+                .map(|(id, delivery)| {
+                    serde_json::from_str(&delivery)
+                        .with_context(|| format!("corrupt webhook delivery {id}"))
+                })
+                .collect()
+        }
+
+        fn list_deliveries(
+            &self,
+            account_id: &str,
+            webhook_id: &str,
+            limit: usize,
+        ) -> Result<Vec<WebhookDelivery>> {
+            let connection = self.connection.lock().unwrap();
+
+            let mut statement = connection.prepare(
+                "SELECT id, delivery FROM webhook_deliveries
+                 WHERE account_id = ?1 AND webhook_id = ?2
+                 ORDER BY created_at DESC, rowid DESC LIMIT ?3",
+            )?;
+            let rows = statement
+                .query_map(
+                    params![account_id, webhook_id, limit.min(i64::MAX as usize) as i64],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+
+            rows.into_iter()
+                .map(|(id, delivery)| {
+                    serde_json::from_str(&delivery)
+                        .with_context(|| format!("corrupt webhook delivery {id}"))
+                })
+                .collect()
+        }
+
+        fn prune_deliveries(&self, before: i64) -> Result<usize> {
+            let connection = self.connection.lock().unwrap();
+
+            let deleted = connection
+                .execute(
+                    "DELETE FROM webhook_deliveries WHERE status != ?1 AND next_attempt_at < ?2",
+                    params![status_column(DeliveryStatus::Pending), before],
+                )
+                .context("failed to prune webhook deliveries")?;
+
+            Ok(deleted)
+        }
+
+        fn check(&self) -> Result<()> {
+            let connection = self.connection.lock().unwrap();
+            connection.prepare("SELECT 1 FROM accounts LIMIT 1")?.exists([])?;
+
+            Ok(())
+        }
+    }
+
+    /// The `status` column of a delivery, which the outbox is polled by.
+    fn status_column(status: DeliveryStatus) -> &'static str {
+        match status {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+pub mod file {
+    use anyhow::{Context, Result};
+    use bitcoin::hashes::{sha256, Hash};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    use super::AccountStore;
+    use crate::core::model::{
+        AccountEvent, ApiToken, DeliveryStatus, ExitJob, IdempotencyRecord, RenewalRecord,
+        StoredEvent, StoredResponse, UserAccount, VtxoExitPath, Webhook, WebhookDelivery,
+    };
+
+    /// Version of the on-disk layout, stored in a `VERSION` file next to the accounts.
+    ///
+    /// - 1: accounts.
+    /// - 2: API tokens in a `tokens` subdirectory.
+    /// - 3: account event logs in an `events` subdirectory.
+    /// - 4: exit jobs in an `exits` subdirectory.
+    /// - 5: idempotency keys in an `idempotency` subdirectory.
+    /// - 6: renewal logs in a `renewals` subdirectory.
+    /// - 7: webhooks and webhook deliveries in `webhooks` and `deliveries` subdirectories.
+    /// - 8: finished webhook deliveries in `deliveries/done`.
+    /// - 9: VTXO exit paths in an `exit_paths` subdirectory.
+    const LAYOUT_VERSION: u32 = 9;
+
+    /// Stores every account as `<account_id>.json` inside a directory, every API token as
+    /// `tokens/<token_hash>.json`, and the event log of every account as one JSON line per event
+    /// in `events/<account_id>.jsonl`. Exit jobs are kept as `exits/<job_id>.json`, and
+    /// idempotency keys as `idempotency/<account_id>-<key_hash>.json`, where `key_hash` is the
+    /// SHA-256 hash of the key. The renewal log of every account is kept like its event log, in
+    /// `renewals/<account_id>.jsonl`. Webhooks are kept as `webhooks/<webhook_id>.json`, and
+    /// their pending deliveries as `deliveries/<delivery_id>.json`. Deliveries move to
+    /// `deliveries/done` once delivered or given up on, so that polling the outbox only reads
+    /// the pending ones. The recorded exit paths of the VTXOs of every account are kept together
+    /// in `exit_paths/<account_id>.json`.
+    ///
+    /// Every event log is read once per process to build its [`EventIndex`]. After that, reading
+    /// a page of events parses at most [`INDEX_STRIDE`] events besides the page itself, and
+    /// appending does not read the log at all.
+    pub struct FileStore {
+        dir: PathBuf,
+        // Serializes writers so that two requests cannot create the same account file at once.
+        write_lock: Mutex<()>,
+        event_indexes: Mutex<HashMap<String, EventIndex>>,
+    }
+
+    /// How many events apart the entries of an [`EventIndex`] are.
+    const INDEX_STRIDE: u64 = 256;
+
+    /// Where some of the events of an event log start, so that the log can be read from any
+    /// sequence number without parsing everything before it.
+    #[derive(Default)]
+    struct EventIndex {
+        last_seq: u64,
+        /// The length of the log up to the end of event `last_seq`. Anything after it is being
+        /// written.
+        len: u64,
+        /// The sequence number and offset of every event whose sequence number is one more than
+        /// a multiple of [`INDEX_STRIDE`], in order.
+        offsets: Vec<(u64, u64)>,
+    }
+
+    impl EventIndex {
+        /// Note that event `seq` starts at `offset` and ends at `end`.
+        fn record(&mut self, seq: u64, offset: u64, end: u64) {
+            if (seq - 1).is_multiple_of(INDEX_STRIDE) {
+                self.offsets.push((seq, offset));
+            }
+
+            self.last_seq = seq;
+            self.len = end;
+        }
+
+        /// The offset of the nearest indexed event at or before event `after + 1`.
+        fn offset_before(&self, after: u64) -> u64 {
+            let indexed = self.offsets.partition_point(|(seq, _)| *seq <= after + 1);
+
+            indexed.checked_sub(1).map_or(0, |i| self.offsets[i].1)
+        }
+    }
+
+    impl FileStore {
+        pub fn open(dir: &Path) -> Result<Self> {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+
+            migrate(dir)?;
+
+            Ok(Self {
+                dir: dir.to_path_buf(),
+                write_lock: Mutex::new(()),
+                event_indexes: Mutex::new(HashMap::new()),
+            })
+        }
+
+        /// The file for `account_id`, or `None` if the ID cannot name an account file.
+        ///
+        /// Account IDs are UUIDs, so anything else is rejected before it can escape `dir`.
+        fn account_path(&self, account_id: &str) -> Option<PathBuf> {
+            uuid::Uuid::parse_str(account_id)
+                .ok()
+                .map(|id| self.dir.join(format!("{}.json", id.hyphenated())))
+        }
+
+        /// The file for the token with `token_hash`, or `None` if it is not a SHA-256 hex digest.
+        fn token_path(&self, token_hash: &str) -> Option<PathBuf> {
+            let is_digest = token_hash.len() == 64
+                && token_hash
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+
+            is_digest.then(|| self.dir.join("tokens").join(format!("{token_hash}.json")))
+        }
+
+        /// The event log of `account_id`, or `None` if the ID cannot name an account file.
+        fn events_path(&self, account_id: &str) -> Option<PathBuf> {
+            uuid::Uuid::parse_str(account_id).ok().map(|id| {
+                self.dir
+                    .join("events")
+                    .join(format!("{}.jsonl", id.hyphenated()))
+            })
+        }
+
+        /// The renewal log of `account_id`, or `None` if the ID cannot name an account file.
+        fn renewals_path(&self, account_id: &str) -> Option<PathBuf> {
+            uuid::Uuid::parse_str(account_id).ok().map(|id| {
+                self.dir
+                    .join("renewals")
+                    .join(format!("{}.jsonl", id.hyphenated()))
+            })
+        }
+
+        /// The file for the exit job `job_id`, or `None` if the ID cannot name an exit job file.
+        fn exit_job_path(&self, job_id: &str) -> Option<PathBuf> {
+            uuid::Uuid::parse_str(job_id).ok().map(|id| {
+                self.dir
+                    .join("exits")
+                    .join(format!("{}.json", id.hyphenated()))
+            })
+        }
+
+        /// The exit paths of `account_id`, or `None` if the ID cannot name an account file.
+        fn exit_paths_path(&self, account_id: &str) -> Option<PathBuf> {
+            uuid::Uuid::parse_str(account_id).ok().map(|id| {
+                self.dir
+                    .join("exit_paths")
+                    .join(format!("{}.json", id.hyphenated()))
+            })
+        }
+
+        /// The file for webhook `webhook_id`, or `None` if the ID cannot name a webhook file.
+        fn webhook_path(&self, webhook_id: &str) -> Option<PathBuf> {
+            uuid::Uuid::parse_str(webhook_id).ok().map(|id| {
+                self.dir
+                    .join("webhooks")
+                    .join(format!("{}.json", id.hyphenated()))
+            })
+        }
+
+        /// The file for delivery `delivery_id` while it has `status`, or `None` if the ID cannot
+        /// name a delivery file.
+        fn delivery_path(&self, delivery_id: &str, status: DeliveryStatus) -> Option<PathBuf> {
+            let dir = match status {
+                DeliveryStatus::Pending => self.dir.join("deliveries"),
+                DeliveryStatus::Delivered | DeliveryStatus::Failed => {
+                    self.dir.join("deliveries").join("done")
+                }
+            };
+
+            uuid::Uuid::parse_str(delivery_id)
+                .ok()
+                .map(|id| dir.join(format!("{}.json", id.hyphenated())))
+        }
+
+        /// Every file in subdirectory `name`, parsed.
+        fn read_all<T: serde::de::DeserializeOwned>(&self, name: &str) -> Result<Vec<T>> {
+            let mut items = Vec::new();
+            for entry in fs::read_dir(self.dir.join(name))? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+
+                let contents = fs::read(&path)?;
+                let item = serde_json::from_slice(&contents)
+                    .with_context(|| format!("corrupt file {}", path.display()))?;
+
+                items.push(item);
+            }
+
+            Ok(items)
+        }
+
+        /// The file for `key` of `account_id`, or `None` if the ID cannot name an account file.
+        ///
+        /// Keys are chosen by clients, so the file is named after their hash.
+        fn idempotency_path(&self, account_id: &str, key: &str) -> Option<PathBuf> {
+            uuid::Uuid::parse_str(account_id).ok().map(|id| {
+                let key_hash = sha256::Hash::hash(key.as_bytes());
+                self.dir
+                    .join("idempotency")
+                    .join(format!("{}-{key_hash}.json", id.hyphenated()))
+            })
+        }
+
+        /// The index of the event log of `account_id` at `path`, read from the log the first time.
+        fn event_index<'a>(
+            &self,
+            indexes: &'a mut HashMap<String, EventIndex>,
+            account_id: &str,
+            path: &Path,
+        ) -> Result<&'a mut EventIndex> {
+            if !indexes.contains_key(account_id) {
+                let index = self.index_events(path)?;
+                indexes.insert(account_id.to_string(), index);
+            }
+
+            Ok(indexes.get_mut(account_id).expect("just indexed"))
+        }
+
+        fn index_events(&self, path: &Path) -> Result<EventIndex> {
+            let mut index = EventIndex::default();
+
+            let file = match fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(index),
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut reader = BufReader::new(file);
+            let mut offset = 0;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let read = reader
+                    .read_line(&mut line)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                if read == 0 {
+                    return Ok(index);
+                }
+
+                let end = offset + read as u64;
+                if !line.trim().is_empty() {
+                    let event: StoredEvent = serde_json::from_str(&line)
+                        .with_context(|| format!("corrupt event log {}", path.display()))?;
+                    index.record(event.seq, offset, end);
+                }
+                offset = end;
+            }
+        }
+    }
+
+    fn migrate(dir: &Path) -> Result<()> {
+        let version_path = dir.join("VERSION");
+
+        let version = match fs::read_to_string(&version_path) {
+            Ok(version) => version
+                .trim()
+                .parse::<u32>()
+                .with_context(|| format!("invalid {}", version_path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        if version > LAYOUT_VERSION {
+            anyhow::bail!(
+                "account directory layout version {version} is newer than supported version {LAYOUT_VERSION}"
+            );
+        }
+
+        // Version 0 is an empty (or pre-versioning) directory, which already matches version 1.
+        if version < 2 {
+            fs::create_dir_all(dir.join("tokens"))
+                .with_context(|| format!("failed to create {}", dir.join("tokens").display()))?;
+        }
+
+        if version < 3 {
+            fs::create_dir_all(dir.join("events"))
+                .with_context(|| format!("failed to create {}", dir.join("events").display()))?;
+        }
+
+        if version < 4 {
+            fs::create_dir_all(dir.join("exits"))
+                .with_context(|| format!("failed to create {}", dir.join("exits").display()))?;
+        }
+
+        if version < 5 {
+            fs::create_dir_all(dir.join("idempotency")).with_context(|| {
+                format!("failed to create {}", dir.join("idempotency").display())
+            })?;
+        }
+
+        if version < 6 {
+            fs::create_dir_all(dir.join("renewals"))
+                .with_context(|| format!("failed to create {}", dir.join("renewals").display()))?;
+        }
+
+        if version < 7 {
+            for name in ["webhooks", "deliveries"] {
+                fs::create_dir_all(dir.join(name))
+                    .with_context(|| format!("failed to create {}", dir.join(name).display()))?;
+            }
+        }
+
+        if version < 8 {
+            let done = dir.join("deliveries").join("done");
+            fs::create_dir_all(&done)
+                .with_context(|| format!("failed to create {}", done.display()))?;
+
+            for entry in fs::read_dir(dir.join("deliveries"))? {
+                let entry = entry?;
+                let path = entry.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+
+                let delivery: WebhookDelivery = serde_json::from_slice(&fs::read(&path)?)
+                    .with_context(|| format!("corrupt file {}", path.display()))?;
+                if delivery.status == DeliveryStatus::Pending {
+                    continue;
+                }
+
+                let target = done.join(entry.file_name());
+                fs::rename(&path, &target)
+                    .with_context(|| format!("failed to move {}", path.display()))?;
+            }
+        }
+
+        if version < 9 {
+            fs::create_dir_all(dir.join("exit_paths")).with_context(|| {
+                format!("failed to create {}", dir.join("exit_paths").display())
+            })?;
+        }
+
+        if version < LAYOUT_VERSION {
+            write_atomically(&version_path, LAYOUT_VERSION.to_string().as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Write to a temporary file first and rename it, so that a crash never leaves a
+    /// half-written file behind.
+    fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+
+        fs::write(&tmp_path, contents)
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to move {} into place", path.display()))?;
+
+        Ok(())
+    }
+
+    impl AccountStore for FileStore {
+        fn insert_account(&self, account: &UserAccount) -> Result<()> {
+            let path = self
+                .account_path(&account.id)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {}", account.id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            if path.exists() {
+                anyhow::bail!("account {} already exists", account.id);
+            }
+
+            let contents = serde_json::to_vec_pretty(account)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn update_account(&self, account: &UserAccount) -> Result<()> {
+            let path = self
+                .account_path(&account.id)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {}", account.id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            if !path.exists() {
+                anyhow::bail!("account {} does not exist", account.id);
+            }
+
+            let contents = serde_json::to_vec_pretty(account)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn add_address_key(&self, account_id: &str, key_count: u32) -> Result<bool> {
+            let _guard = self.write_lock.lock().unwrap();
+
+            let Some(mut account) = self.get_account(account_id)? else {
+                return Ok(false);
+            };
+            if account.key_count != key_count {
+                return Ok(false);
+            }
+
+            account.key_count += 1;
+
+            let path = self
+                .account_path(account_id)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {account_id}"))?;
+            let contents = serde_json::to_vec_pretty(&account)?;
+            write_atomically(&path, &contents)?;
+
+            Ok(true)
+        }
+
+        fn get_account(&self, account_id: &str) -> Result<Option<UserAccount>> {
+            let Some(path) = self.account_path(account_id) else {
+                return Ok(None);
+            };
+
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let account = serde_json::from_slice(&contents)
+                .with_context(|| format!("corrupt account file {}", path.display()))?;
+
+            Ok(Some(account))
+        }
+
+        fn list_accounts(&self) -> Result<Vec<UserAccount>> {
+            let mut accounts = Vec::new();
+            for entry in fs::read_dir(&self.dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+
+                let contents = fs::read(&path)?;
+                let account = serde_json::from_slice(&contents)
+                    .with_context(|| format!("corrupt account file {}", path.display()))?;
+
+                accounts.push(account);
+            }
+
+            Ok(accounts)
+        }
+
+        fn count_accounts(&self) -> Result<usize> {
+            let mut count = 0;
+            for entry in fs::read_dir(&self.dir)? {
+                if entry?.path().extension().and_then(|e| e.to_str()) == Some("json") {
+                    count += 1;
+                }
+            }
+
+            Ok(count)
+        }
+
+        fn insert_token(&self, token: &ApiToken) -> Result<()> {
+            let path = self
+                .token_path(&token.token_hash)
+                .ok_or_else(|| anyhow::anyhow!("invalid API token hash"))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            if path.exists() {
+                anyhow::bail!("API token already exists");
+            }
+
+            let account_exists = self
+                .account_path(&token.account_id)
+                .is_some_and(|path| path.exists());
+            if !account_exists {
+                anyhow::bail!("account {} does not exist", token.account_id);
+            }
+
+            let contents = serde_json::to_vec_pretty(token)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn get_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+            let Some(path) = self.token_path(token_hash) else {
+                return Ok(None);
+            };
+
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let token = serde_json::from_slice(&contents)
+                .with_context(|| format!("corrupt API token file {}", path.display()))?;
+
+            Ok(Some(token))
+        }
+
+        fn count_tokens(&self, account_id: &str) -> Result<usize> {
+            let tokens: Vec<ApiToken> = self.read_all("tokens")?;
+
+            Ok(tokens
+                .iter()
+                .filter(|token| token.account_id == account_id)
+                .count())
+        }
+
+        fn append_events(
+            &self,
+            account_id: &str,
+            events: &[AccountEvent],
+        ) -> Result<Vec<StoredEvent>> {
+            let path = self
+                .events_path(account_id)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {}", account_id))?;
+            let created_at = jiff::Timestamp::now().as_second();
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            let mut indexes = self.event_indexes.lock().unwrap();
+            let index = self.event_index(&mut indexes, account_id, &path)?;
+            let last_seq = index.last_seq;
+
+            let stored = events
+                .iter()
+                .enumerate()
+                .map(|(i, event)| StoredEvent {
+                    seq: last_seq + i as u64 + 1,
+                    account_id: account_id.to_string(),
+                    created_at,
+                    event: event.clone(),
+                })
+                .collect::<Vec<_>>();
+
+            let mut lines = Vec::new();
+            let mut ends = Vec::new();
+            for event in &stored {
+                serde_json::to_writer(&mut lines, event)?;
+                lines.push(b'\n');
+                ends.push(lines.len() as u64);
+            }
+
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            let start = file.metadata()?.len();
+            file.write_all(&lines)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+
+            let mut offset = start;
+            for (event, end) in stored.iter().zip(ends) {
+                index.record(event.seq, offset, start + end);
+                offset = start + end;
+            }
+
+            Ok(stored)
+        }
+
+        fn events_after(
+            &self,
+            account_id: &str,
+            after: u64,
+            limit: usize,
+        ) -> Result<Vec<StoredEvent>> {
+            let Some(path) = self.events_path(account_id) else {
+                return Ok(Vec::new());
+            };
+
+            let (offset, len) = {
+                let mut indexes = self.event_indexes.lock().unwrap();
+                let index = self.event_index(&mut indexes, account_id, &path)?;
+                (index.offset_before(after), index.len)
+            };
+            if offset >= len {
+                return Ok(Vec::new());
+            }
+
+            let mut file = fs::File::open(&path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            file.seek(SeekFrom::Start(offset))?;
+
+            // Start at the nearest indexed event and stop once the page is full, leaving out
+            // events that are still being written
+            let mut events = Vec::new();
+            for line in BufReader::new(file.take(len - offset)).lines() {
+                if events.len() >= limit {
+                    break;
+                }
+
+                let line = line.with_context(|| format!("failed to read {}", path.display()))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let event: StoredEvent = serde_json::from_str(&line)
+                    .with_context(|| format!("corrupt event log {}", path.display()))?;
+                if event.seq > after {
+                    events.push(event);
+                }
+            }
+
+            Ok(events)
+        }
+
+        fn insert_exit_job(&self, job: &ExitJob) -> Result<()> {
+            let path = self
+                .exit_job_path(&job.id)
+                .ok_or_else(|| anyhow::anyhow!("invalid exit job ID {}", job.id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            if path.exists() {
+                anyhow::bail!("exit job {} already exists", job.id);
+            }
+
+            let account_exists = self
+                .account_path(&job.account_id)
+                .is_some_and(|path| path.exists());
+            if !account_exists {
+                anyhow::bail!("account {} does not exist", job.account_id);
+            }
+
+            let contents = serde_json::to_vec_pretty(job)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn update_exit_job(&self, job: &ExitJob) -> Result<()> {
+            let path = self
+                .exit_job_path(&job.id)
+                .ok_or_else(|| anyhow::anyhow!("invalid exit job ID {}", job.id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            if !path.exists() {
+                anyhow::bail!("exit job {} does not exist", job.id);
+            }
+
+            let contents = serde_json::to_vec_pretty(job)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn get_exit_job(&self, job_id: &str) -> Result<Option<ExitJob>> {
+            let Some(path) = self.exit_job_path(job_id) else {
+                return Ok(None);
+            };
+
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let job = serde_json::from_slice(&contents)
+                .with_context(|| format!("corrupt exit job file {}", path.display()))?;
+
+            Ok(Some(job))
+        }
+
+        fn list_exit_jobs(&self) -> Result<Vec<ExitJob>> {
+            let mut jobs: Vec<ExitJob> = Vec::new();
+            for entry in fs::read_dir(self.dir.join("exits"))? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+
+                let contents = fs::read(&path)?;
+                let job = serde_json::from_slice(&contents)
+                    .with_context(|| format!("corrupt exit job file {}", path.display()))?;
+
+                jobs.push(job);
+            }
+
+            jobs.sort_by_key(|job| job.created_at);
+
+            Ok(jobs)
+        }
+
+        fn replace_exit_paths(&self, account_id: &str, paths: &[VtxoExitPath]) -> Result<()> {
+            let path = self
+                .exit_paths_path(account_id)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {account_id}"))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            let account_exists = self
+                .account_path(account_id)
+                .is_some_and(|path| path.exists());
+            if !account_exists {
+                anyhow::bail!("account {account_id} does not exist");
+            }
+
+            let mut paths = paths.to_vec();
+            paths.sort_by(|a, b| a.outpoint.cmp(&b.outpoint));
+
+            let contents = serde_json::to_vec_pretty(&paths)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn list_exit_paths(&self, account_id: &str) -> Result<Vec<VtxoExitPath>> {
+            let Some(path) = self.exit_paths_path(account_id) else {
+                return Ok(Vec::new());
+            };
+
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+
+            serde_json::from_slice(&contents)
+                .with_context(|| format!("corrupt exit paths file {}", path.display()))
+        }
+
+        fn claim_idempotency_key(
+            &self,
+            record: &IdempotencyRecord,
+            stale_before: i64,
+        ) -> Result<Option<IdempotencyRecord>> {
+            let path = self
+                .idempotency_path(&record.account_id, &record.key)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {}", record.account_id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            match fs::read(&path) {
+                Ok(contents) => {
+                    let existing: IdempotencyRecord = serde_json::from_slice(&contents)
+                        .with_context(|| {
+                            format!("corrupt idempotency key file {}", path.display())
+                        })?;
+
+                    let stale = existing.response.is_none()
+                        && existing.fingerprint == record.fingerprint
+                        && existing.created_at < stale_before;
+                    if !stale {
+                        return Ok(Some(existing));
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let account_exists = self
+                .account_path(&record.account_id)
+                .is_some_and(|path| path.exists());
+            if !account_exists {
+                anyhow::bail!("account {} does not exist", record.account_id);
+            }
+
+            let contents = serde_json::to_vec_pretty(record)?;
+            write_atomically(&path, &contents)?;
+
+            Ok(None)
+        }
+
+        fn complete_idempotency_key(
+            &self,
+            account_id: &str,
+            key: &str,
+            response: &StoredResponse,
+        ) -> Result<()> {
+            let path = self
+                .idempotency_path(account_id, key)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {}", account_id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    anyhow::bail!("idempotency key {key} of account {account_id} is not claimed")
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut record: IdempotencyRecord = serde_json::from_slice(&contents)
+                .with_context(|| format!("corrupt idempotency key file {}", path.display()))?;
+            record.response = Some(response.clone());
+
+            let contents = serde_json::to_vec_pretty(&record)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn release_idempotency_key(&self, account_id: &str, key: &str) -> Result<()> {
+            let Some(path) = self.idempotency_path(account_id, key) else {
+                return Ok(());
+            };
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            match fs::remove_file(&path) {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        fn prune_idempotency_keys(&self, before: i64) -> Result<usize> {
+            let _guard = self.write_lock.lock().unwrap();
+
+            let mut pruned = 0;
+            for entry in fs::read_dir(self.dir.join("idempotency"))? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+
+                let record: IdempotencyRecord = serde_json::from_slice(&fs::read(&path)?)
+                    .with_context(|| format!("corrupt idempotency key file {}", path.display()))?;
+                if record.created_at < before {
+                    fs::remove_file(&path)?;
+                    pruned += 1;
+                }
+            }
+
+            Ok(pruned)
+        }
+
+        fn append_renewal(&self, record: &RenewalRecord) -> Result<()> {
+            let path = self
+                .renewals_path(&record.account_id)
+                .ok_or_else(|| anyhow::anyhow!("invalid account ID {}", record.account_id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            let account_exists = self
+                .account_path(&record.account_id)
+                .is_some_and(|path| path.exists());
+            if !account_exists {
+                anyhow::bail!("account {} does not exist", record.account_id);
+            }
+
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            file.write_all(&line)
+                .with_context(|| format!("failed to write {}", path.display()))
+        }
+
+        fn list_renewals(
+            &self,
+            account_id: &str,
+            key_index: Option<u32>,
+            limit: usize,
+        ) -> Result<Vec<RenewalRecord>> {
+            let Some(path) = self.renewals_path(account_id) else {
+                return Ok(Vec::new());
+            };
+
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+
+            let records = contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    serde_json::from_str::<RenewalRecord>(line)
+                        .with_context(|| format!("corrupt renewal log {}", path.display()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(records
+                .into_iter()
+                .rev()
+                .filter(|record| key_index.is_none_or(|index| record.key_index == index))
+                .take(limit)
+                .collect())
+        }
+
+        fn insert_webhook(&self, webhook: &Webhook) -> Result<()> {
+            let path = self
+                .webhook_path(&webhook.id)
+                .ok_or_else(|| anyhow::anyhow!("invalid webhook ID {}", webhook.id))?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            if path.exists() {
+                anyhow::bail!("webhook {} already exists", webhook.id);
+            }
+
+            let account_exists = self
+                .account_path(&webhook.account_id)
+                .is_some_and(|path| path.exists());
+            if !account_exists {
+                anyhow::bail!("account {} does not exist", webhook.account_id);
+            }
+
+            let contents = serde_json::to_vec_pretty(webhook)?;
+            write_atomically(&path, &contents)
+        }
+
+        fn list_webhooks(&self, account_id: &str) -> Result<Vec<Webhook>> {
+            let mut webhooks = self
+                .read_all::<Webhook>("webhooks")?
+                .into_iter()
+                .filter(|webhook| webhook.account_id == account_id)
+                .collect::<Vec<_>>();
+
+            webhooks.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+
+            Ok(webhooks)
+        }
+
+        fn delete_webhook(&self, account_id: &str, webhook_id: &str) -> Result<bool> {
+            let Some(path) = self.webhook_path(webhook_id) else {
+                return Ok(false);
+            };
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+            let webhook: Webhook = serde_json::from_slice(&contents)
+                .with_context(|| format!("corrupt webhook file {}", path.display()))?;
+            if webhook.account_id != account_id {
+                return Ok(false);
+            }
+
+            fs::remove_file(&path)
+                .with_context(|| format!("failed to remove {}", path.display()))?;
+
+            Ok(true)
+        }
+
+        fn insert_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<()> {
+            let _guard = self.write_lock.lock().unwrap();
+
+            for delivery in deliveries {
+                let path = self
+                    .delivery_path(&delivery.id, delivery.status)
+                    .ok_or_else(|| anyhow::anyhow!("invalid delivery ID {}", delivery.id))?;
+
+                let account_exists = self
+                    .account_path(&delivery.account_id)
+                    .is_some_and(|path| path.exists());
+                if !account_exists {
+                    anyhow::bail!("account {} does not exist", delivery.account_id);
+                }
+
+                let contents = serde_json::to_vec_pretty(delivery)?;
+                write_atomically(&path, &contents)?;
+            }
+
+            Ok(())
+        }
+
+        fn update_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+            let invalid = || anyhow::anyhow!("invalid delivery ID {}", delivery.id);
+            let pending = self
+                .delivery_path(&delivery.id, DeliveryStatus::Pending)
+                .ok_or_else(invalid)?;
+            let done = self
+                .delivery_path(&delivery.id, DeliveryStatus::Delivered)
+                .ok_or_else(invalid)?;
+
+            let _guard = self.write_lock.lock().unwrap();
+
+            if !pending.exists() && !done.exists() {
+                anyhow::bail!("webhook delivery {} does not exist", delivery.id);
+            }
+
+            let (path, stale) = match delivery.status {
+                DeliveryStatus::Pending => (pending, done),
+                DeliveryStatus::Delivered | DeliveryStatus::Failed => (done, pending),
+            };
+
+            let contents = serde_json::to_vec_pretty(delivery)?;
+            write_atomically(&path, &contents)?;
+
+            match fs::remove_file(&stale) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e)
+                    .with_context(|| format!("failed to remove {}", stale.display())),
+                _ => Ok(()),
+            }
+        }
+
+        fn due_deliveries(&self, now: i64, limit: usize) -> Result<Vec<WebhookDelivery>> {
+            let mut deliveries = self
+                .read_all::<WebhookDelivery>("deliveries")?
+                .into_iter()
+                .filter(|delivery| {
+                    delivery.status == DeliveryStatus::Pending && delivery.next_attempt_at <= now
+                })
+                .collect::<Vec<_>>();
+
+            deliveries.sort_by_key(|delivery| delivery.next_attempt_at);
+            deliveries.truncate(limit);
+
+            Ok(deliveries)
+        }
+
+        fn list_deliveries(
+            &self,
+            account_id: &str,
+            webhook_id: &str,
+            limit: usize,
+        ) -> Result<Vec<WebhookDelivery>> {
+            let mut deliveries = self.read_all::<WebhookDelivery>("deliveries")?;
+            deliveries.extend(self.read_all::<WebhookDelivery>("deliveries/done")?);
+            deliveries.retain(|delivery| {
+                delivery.account_id == account_id && delivery.webhook_id == webhook_id
+            });
+
+            deliveries.sort_by_key(|delivery| {
+                std::cmp::Reverse((delivery.created_at, delivery.event_seq))
+            });
+            deliveries.truncate(limit);
+
+            Ok(deliveries)
+        }
+
+        fn prune_deliveries(&self, before: i64) -> Result<usize> {
+            let _guard = self.write_lock.lock().unwrap();
+
+            let mut pruned = 0;
+            for delivery in self.read_all::<WebhookDelivery>("deliveries/done")? {
+                if delivery.next_attempt_at >= before {
+                    continue;
+                }
+
+                if let Some(path) = self.delivery_path(&delivery.id, delivery.status) {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("failed to remove {}", path.display()))?;
+                    pruned += 1;
+                }
+            }
+
+            Ok(pruned)
+        }
+
+        fn check(&self) -> Result<()> {
+            let version_path = self.dir.join("VERSION");
+            fs::read_to_string(&version_path)
+                .with_context(|| format!("failed to read {}", version_path.display()))?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::{self, EncryptedKey};
+    use bitcoin::bip32::Xpub;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::NetworkKind;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    const SECRET_KEY: &str = "7f9a3c1c53d9e1a3bb3b8d2b6b0cdb3cf9b6a5b0d3fbc96f3a8bfc4e0e2e4f11";
+
+    fn account(id: &str) -> UserAccount {
+        let seed = [7u8; 64];
+        let account_xpriv = keystore::account_xpriv(&seed, NetworkKind::Test).unwrap();
+
+        UserAccount {
+            id: id.to_string(),
+            xpub: Some(Xpub::from_priv(&Secp256k1::new(), &account_xpriv).to_string()),
+            key_count: 3,
+            public_key: None,
+            encrypted_key: Some(EncryptedKey::encrypt(&seed, "passphrase").unwrap()),
+            private_key: None,
+        }
+    }
+
+    fn single_key_account(id: &str) -> UserAccount {
+        let secret_key = SecretKey::from_str(SECRET_KEY).unwrap();
+
+        UserAccount {
+            id: id.to_string(),
+            xpub: None,
+            key_count: 1,
+            public_key: Some(secret_key.public_key(&Secp256k1::new()).to_string()),
+            encrypted_key: None,
+            private_key: None,
+        }
+    }
+
+    fn configs(dir: &tempfile::TempDir) -> Vec<StorageConfig> {
+        vec![
+            StorageConfig::Sqlite {
+                path: dir.path().join("ark.db"),
+            },
+            StorageConfig::File {
+                path: dir.path().join("accounts"),
+            },
+        ]
+    }
+
+    #[test]
+    fn accounts_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let id = uuid::Uuid::new_v4().to_string();
+
+            {
+                let store = open(&config).unwrap();
+                store.insert_account(&account(&id)).unwrap();
+            }
+
+            // Reopening the store is what happens when the server restarts.
+            let store = open(&config).unwrap();
+            let restored = store.get_account(&id).unwrap().unwrap();
+
+            assert_eq!(restored.id, id);
+            assert_eq!(restored.xpub, account(&id).xpub);
+            assert_eq!(restored.key_count, 3);
+            assert_eq!(
+                restored.public_keys().unwrap(),
+                account(&id).public_keys().unwrap()
+            );
+            assert_eq!(
+                restored
+                    .encrypted_key
+                    .unwrap()
+                    .decrypt("passphrase")
+                    .unwrap(),
+                [7u8; 64]
+            );
+            assert_eq!(store.list_accounts().unwrap().len(), 1);
+            assert_eq!(store.count_accounts().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn address_keys_are_added_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let store = open(&config).unwrap();
+            let id = uuid::Uuid::new_v4().to_string();
+            store.insert_account(&account(&id)).unwrap();
+
+            assert!(store.add_address_key(&id, 3).unwrap());
+            // A request that read the old key count loses the race.
+            assert!(!store.add_address_key(&id, 3).unwrap());
+            assert!(store.add_address_key(&id, 4).unwrap());
+            assert_eq!(store.get_account(&id).unwrap().unwrap().key_count, 5);
+
+            assert!(!store.add_address_key(&uuid::Uuid::new_v4().to_string(), 1).unwrap());
+        }
+    }
+
+    #[test]
+    fn duplicate_account_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let store = open(&config).unwrap();
+            let id = uuid::Uuid::new_v4().to_string();
+
+            store.insert_account(&account(&id)).unwrap();
+            assert!(store.insert_account(&account(&id)).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_account_is_none() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let store = open(&config).unwrap();
+
+            assert!(store.get_account("../../etc/passwd").unwrap().is_none());
+            assert!(store
+                .get_account(&uuid::Uuid::new_v4().to_string())
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn check_fails_once_the_store_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let store = open(&config).unwrap();
+            store.check().unwrap();
+        }
+
+        // The directory of the file store goes away underneath it.
+        let path = dir.path().join("accounts");
+        let store = open(&StorageConfig::File { path: path.clone() }).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert!(store.check().is_err());
+    }
+
+    #[test]
+    fn update_replaces_legacy_plaintext_key() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let store = open(&config).unwrap();
+            let id = uuid::Uuid::new_v4().to_string();
+
+            let legacy = UserAccount {
+                public_key: None,
+                private_key: Some(SECRET_KEY.to_string()),
+                ..single_key_account(&id)
+            };
+            store.insert_account(&legacy).unwrap();
+
+            let secret_key = SecretKey::from_str(SECRET_KEY).unwrap();
+            let encrypted = UserAccount {
+                encrypted_key: Some(
+                    EncryptedKey::encrypt(&secret_key.secret_bytes(), "passphrase").unwrap(),
+                ),
+                ..single_key_account(&id)
+            };
+            store.update_account(&encrypted).unwrap();
+
+            let updated = store.get_account(&id).unwrap().unwrap();
+            assert!(updated.private_key.is_none());
+            assert!(updated.encrypted_key.is_some());
+            assert!(!updated.is_hd());
+        }
+    }
+
+    #[test]
+    fn tokens_belong_to_existing_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let store = open(&config).unwrap();
+            let id = uuid::Uuid::new_v4().to_string();
+            let token = ApiToken {
+                token_hash: "ab".repeat(32),
+                account_id: id.clone(),
+                scope: crate::core::model::TokenScope::Read,
+            };
+
+            assert!(store.insert_token(&token).is_err());
+
+            store.insert_account(&account(&id)).unwrap();
+            assert_eq!(store.count_tokens(&id).unwrap(), 0);
+            store.insert_token(&token).unwrap();
+            assert_eq!(store.count_tokens(&id).unwrap(), 1);
+
+            let stored = store.get_token(&token.token_hash).unwrap().unwrap();
+            assert_eq!(stored.account_id, id);
+            assert_eq!(stored.scope, token.scope);
+            assert!(store.get_token(&"cd".repeat(32)).unwrap().is_none());
+            assert!(store.get_token("../VERSION").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn event_log_is_numbered_per_account() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let first = uuid::Uuid::new_v4().to_string();
+            let second = uuid::Uuid::new_v4().to_string();
+            let event = |n: u64| AccountEvent::VtxoReceived {
+                outpoint: format!("{}:0", "ab".repeat(32)),
+                amount: n,
+                key_index: 0,
+            };
+
+            {
+                let store = open(&config).unwrap();
+                store.insert_account(&account(&first)).unwrap();
+                store.insert_account(&account(&second)).unwrap();
+
+                let stored = store.append_events(&first, &[event(1), event(2)]).unwrap();
+                assert_eq!(stored.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2]);
+                store.append_events(&second, &[event(3)]).unwrap();
+            }
+
+            // Sequence numbers carry on after a restart.
+            let store = open(&config).unwrap();
+            let stored = store.append_events(&first, &[event(4)]).unwrap();
+            assert_eq!(stored[0].seq, 3);
+
+            let after_first = store.events_after(&first, 1, 10).unwrap();
+            assert_eq!(
+                after_first.iter().map(|e| e.seq).collect::<Vec<_>>(),
+                vec![2, 3]
+            );
+            assert_eq!(after_first[1].event, event(4));
+            assert_eq!(store.events_after(&first, 0, 1).unwrap().len(), 1);
+
+            let of_second = store.events_after(&second, 0, 10).unwrap();
+            assert_eq!(of_second.len(), 1);
+            assert_eq!(of_second[0].seq, 1);
+            assert_eq!(of_second[0].account_id, second);
+
+            assert!(store.events_after("../VERSION", 0, 10).unwrap().is_empty());
+
+            let stored = store.append_events(&first, &[event(5)]).unwrap();
+            assert_eq!(stored[0].seq, 4);
+
+            // Pages of a long log start where they should, before and after a restart.
+            let long = uuid::Uuid::new_v4().to_string();
+            store.insert_account(&account(&long)).unwrap();
+            for n in 0..3 {
+                let events = (0..250).map(|i| event(n * 250 + i + 1)).collect::<Vec<_>>();
+                store.append_events(&long, &events).unwrap();
+            }
+
+            let check = |store: &dyn AccountStore| {
+                let page = store.events_after(&long, 600, 3).unwrap();
+                assert_eq!(page.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![601, 602, 603]);
+                assert_eq!(page[0].event, event(601));
+
+                assert_eq!(store.events_after(&long, 256, 1).unwrap()[0].seq, 257);
+                assert_eq!(store.events_after(&long, 0, 1).unwrap()[0].seq, 1);
+                assert!(store.events_after(&long, 750, 10).unwrap().is_empty());
+            };
+            check(store.as_ref());
+            drop(store);
+            check(open(&config).unwrap().as_ref());
+        }
+    }
+
+    #[test]
+    fn exit_jobs_survive_a_restart() {
+        use crate::core::model::{
+            ExitStatus, ExitTransaction, ExitTransactionKind, ExitTransactionStatus,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let account_id = uuid::Uuid::new_v4().to_string();
+            let mut job = ExitJob {
+                id: uuid::Uuid::new_v4().to_string(),
+                account_id: account_id.clone(),
+                destination_address: "bcrt1q6rz28mcfaxtmd6v789l9rrlrusdprr9pz3cppk".to_string(),
+                status: ExitStatus::Committing,
+                exit_delay_seconds: 600,
+                spendable_at: None,
+                transactions: vec![ExitTransaction {
+                    txid: "ab".repeat(32),
+                    kind: ExitTransactionKind::Branch,
+                    status: ExitTransactionStatus::Pending,
+                    confirmed_at: None,
+                    last_error: None,
+                    raw_tx: "00".to_string(),
+                }],
+                vtxos: vec![format!("{}:0", "cd".repeat(32))],
+                unsettled_vtxos: Vec::new(),
+                error: None,
+                created_at: 0,
+                updated_at: 0,
+            };
+
+            {
+                let store = open(&config).unwrap();
+                assert!(store.insert_exit_job(&job).is_err());
+
+                store.insert_account(&account(&account_id)).unwrap();
+                store.insert_exit_job(&job).unwrap();
+                assert!(store.insert_exit_job(&job).is_err());
+
+                job.transactions[0].status = ExitTransactionStatus::Confirmed;
+                job.status = ExitStatus::Waiting;
+                store.update_exit_job(&job).unwrap();
+            }
+
+            let store = open(&config).unwrap();
+            assert_eq!(store.get_exit_job(&job.id).unwrap(), Some(job.clone()));
+            assert_eq!(store.list_exit_jobs().unwrap(), vec![job.clone()]);
+            assert!(store.get_exit_job("../VERSION").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn exit_paths_are_replaced_as_a_whole() {
+        use crate::core::model::VtxoExitPath;
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = |n: u8| VtxoExitPath {
+            outpoint: format!("{}:0", format!("{n:02x}").repeat(32)),
+            key_index: 0,
+            amount: 10_000,
+            branch_txs: vec!["00".to_string()],
+            server_pk: "ab".repeat(32),
+            exit_delay: 144,
+            network: "regtest".to_string(),
+            vtxo_template: None,
+            recorded_at: 0,
+        };
+
+        for config in configs(&dir) {
+            let account_id = uuid::Uuid::new_v4().to_string();
+
+            {
+                let store = open(&config).unwrap();
+                assert!(store.replace_exit_paths(&account_id, &[path(1)]).is_err());
+
+                store.insert_account(&account(&account_id)).unwrap();
+                store
+                    .replace_exit_paths(&account_id, &[path(2), path(1)])
+                    .unwrap();
+                assert_eq!(
+                    store.list_exit_paths(&account_id).unwrap(),
+                    vec![path(1), path(2)]
+                );
+
+                store
+                    .replace_exit_paths(&account_id, &[path(3), path(2)])
+                    .unwrap();
+            }
+
+            let store = open(&config).unwrap();
+            assert_eq!(
+                store.list_exit_paths(&account_id).unwrap(),
+                vec![path(2), path(3)]
+            );
+            assert!(store.list_exit_paths("../VERSION").unwrap().is_empty());
+            assert!(store
+                .list_exit_paths(&uuid::Uuid::new_v4().to_string())
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn idempotency_keys_are_claimed_once() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let account_id = uuid::Uuid::new_v4().to_string();
+            let record = IdempotencyRecord {
+                account_id: account_id.clone(),
+                key: "retry/../me".to_string(),
+                fingerprint: "ab".repeat(32),
+                response: None,
+                owner: Some("first".to_string()),
+                created_at: 10,
+            };
+            let response = StoredResponse {
+                status: 200,
+                content_type: Some("application/json".to_string()),
+                body: "{}".to_string(),
+            };
+
+            {
+                let store = open(&config).unwrap();
+                assert!(store.claim_idempotency_key(&record, 0).is_err());
+
+                store.insert_account(&account(&account_id)).unwrap();
+                assert_eq!(store.claim_idempotency_key(&record, 0).unwrap(), None);
+                assert_eq!(
+                    store.claim_idempotency_key(&record, 0).unwrap(),
+                    Some(record.clone())
+                );
+
+                // A claim is taken over by a retry once it timed out, whichever process made it,
+                // but never by a different request.
+                let retry = IdempotencyRecord {
+                    owner: Some("second".to_string()),
+                    created_at: 20,
+                    ..record.clone()
+                };
+                let other = IdempotencyRecord {
+                    fingerprint: "cd".repeat(32),
+                    ..retry.clone()
+                };
+                assert_eq!(
+                    store.claim_idempotency_key(&other, 11).unwrap(),
+                    Some(record.clone())
+                );
+                assert_eq!(
+                    store.claim_idempotency_key(&retry, 10).unwrap(),
+                    Some(record.clone())
+                );
+                assert_eq!(store.claim_idempotency_key(&retry, 11).unwrap(), None);
+                assert_eq!(
+                    store.claim_idempotency_key(&retry, 20).unwrap(),
+                    Some(retry.clone())
+                );
+                assert_eq!(store.claim_idempotency_key(&retry, 21).unwrap(), None);
+
+                store
+                    .complete_idempotency_key(&account_id, &record.key, &response)
+                    .unwrap();
+                assert!(store
+                    .complete_idempotency_key(&account_id, "unclaimed", &response)
+                    .is_err());
+            }
+
+            let store = open(&config).unwrap();
+            let completed = IdempotencyRecord {
+                response: Some(response.clone()),
+                owner: Some("second".to_string()),
+                created_at: 20,
+                ..record.clone()
+            };
+            // Completed keys are never taken over.
+            assert_eq!(
+                store.claim_idempotency_key(&record, i64::MAX).unwrap(),
+                Some(completed)
+            );
+
+            store
+                .release_idempotency_key(&account_id, &record.key)
+                .unwrap();
+            assert_eq!(store.claim_idempotency_key(&record, 0).unwrap(), None);
+
+            let newer = IdempotencyRecord {
+                key: "newer".to_string(),
+                created_at: 30,
+                ..record.clone()
+            };
+            assert_eq!(store.claim_idempotency_key(&newer, 0).unwrap(), None);
+            assert_eq!(store.prune_idempotency_keys(30).unwrap(), 1);
+            assert_eq!(store.claim_idempotency_key(&record, 0).unwrap(), None);
+            assert_eq!(
+                store.claim_idempotency_key(&newer, 0).unwrap(),
+                Some(newer.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn renewal_log_lists_newest_first() {
+        use crate::core::model::RenewalStatus;
+
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let account_id = uuid::Uuid::new_v4().to_string();
+            let record = |key_index, created_at| RenewalRecord {
+                account_id: account_id.clone(),
+                key_index,
+                status: RenewalStatus::Failed,
+                expire_at: 1_000,
+                amount: 5_000,
+                round_txid: None,
+                error: Some("round failed".to_string()),
+                created_at,
+            };
+
+            {
+                let store = open(&config).unwrap();
+                assert!(store.append_renewal(&record(0, 1)).is_err());
+
+                store.insert_account(&account(&account_id)).unwrap();
+                for (key_index, created_at) in [(0, 1), (1, 2), (0, 3)] {
+                    store
+                        .append_renewal(&record(key_index, created_at))
+                        .unwrap();
+                }
+            }
+
+            let store = open(&config).unwrap();
+            assert_eq!(
+                store.list_renewals(&account_id, None, 10).unwrap(),
+                vec![record(0, 3), record(1, 2), record(0, 1)]
+            );
+            assert_eq!(
+                store.list_renewals(&account_id, Some(0), 1).unwrap(),
+                vec![record(0, 3)]
+            );
+            assert!(store
+                .list_renewals("../VERSION", None, 10)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn webhook_outbox_hands_out_due_deliveries() {
+        use crate::core::model::{DeliveryStatus, Webhook, WebhookDelivery};
+
+        let dir = tempfile::tempdir().unwrap();
+
+        for config in configs(&dir) {
+            let account_id = uuid::Uuid::new_v4().to_string();
+            let webhook = Webhook {
+                id: uuid::Uuid::new_v4().to_string(),
+                account_id: account_id.clone(),
+                url: "https://example.com/hook".to_string(),
+                events: vec!["vtxo_received".to_string()],
+                secret: "whsec_test".to_string(),
+                created_at: 1,
+            };
+            let delivery = |event_seq, next_attempt_at| WebhookDelivery {
+                id: uuid::Uuid::new_v4().to_string(),
+                webhook_id: webhook.id.clone(),
+                account_id: account_id.clone(),
+                event_seq,
+                event_type: "vtxo_received".to_string(),
+                payload: "{}".to_string(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                next_attempt_at,
+                last_status_code: None,
+                last_error: None,
+                created_at: event_seq as i64,
+                delivered_at: None,
+            };
+            let first = delivery(1, 20);
+            let mut second = delivery(2, 10);
+            let later = delivery(3, 50);
+
+            {
+                let store = open(&config).unwrap();
+                assert!(store.insert_webhook(&webhook).is_err());
+
+                store.insert_account(&account(&account_id)).unwrap();
+                store.insert_webhook(&webhook).unwrap();
+                store
+                    .insert_deliveries(&[first.clone(), second.clone(), later.clone()])
+                    .unwrap();
+            }
+
+            let store = open(&config).unwrap();
+            assert_eq!(
+                store.list_webhooks(&account_id).unwrap(),
+                vec![webhook.clone()]
+            );
+            assert_eq!(
+                store.due_deliveries(30, 10).unwrap(),
+                vec![second.clone(), first.clone()]
+            );
+
+            second.status = DeliveryStatus::Delivered;
+            second.attempts = 1;
+            second.delivered_at = Some(30);
+            store.update_delivery(&second).unwrap();
+            assert_eq!(store.due_deliveries(30, 10).unwrap(), vec![first.clone()]);
+
+            // Deleting the webhook keeps its history.
+            assert!(!store.delete_webhook("other", &webhook.id).unwrap());
+            assert!(store.delete_webhook(&account_id, &webhook.id).unwrap());
+            assert!(!store.delete_webhook(&account_id, &webhook.id).unwrap());
+            assert!(store.list_webhooks(&account_id).unwrap().is_empty());
+            assert_eq!(
+                store.list_deliveries(&account_id, &webhook.id, 2).unwrap(),
+                vec![later.clone(), second]
+            );
+            assert!(store
+                .list_deliveries("other", &webhook.id, 2)
+                .unwrap()
+                .is_empty());
+
+            // Only finished deliveries are pruned.
+            assert_eq!(store.prune_deliveries(10).unwrap(), 0);
+            assert_eq!(store.prune_deliveries(100).unwrap(), 1);
+            assert_eq!(
+                store.list_deliveries(&account_id, &webhook.id, 10).unwrap(),
+                vec![later, first]
+            );
+        }
+    }
+
+    #[test]
+    fn sqlite_migrates_version_1_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path: PathBuf = dir.path().join("ark.db");
+        let id = uuid::Uuid::new_v4().to_string();
+
+        {
+            let connection = rusqlite::Connection::open(&path).unwrap();
+            connection
+                .execute_batch(&format!(
+                    "CREATE TABLE accounts (
+                        id TEXT PRIMARY KEY NOT NULL,
+                        private_key TEXT NOT NULL,
+                        created_at INTEGER NOT NULL
+                    );
+                    INSERT INTO accounts VALUES ('{id}', '{SECRET_KEY}', 0);
+                    PRAGMA user_version = 1;"
+                ))
+                .unwrap();
+        }
+
+        let store = sqlite::SqliteStore::open(&path).unwrap();
+        let migrated = store.get_account(&id).unwrap().unwrap();
+
+        assert_eq!(migrated.private_key.as_deref(), Some(SECRET_KEY));
+        assert_eq!(migrated.key_count, 1);
+        assert_eq!(
+            migrated.public_keys().unwrap(),
+            single_key_account(&id).public_keys().unwrap()
+        );
+    }
+
+    #[test]
+    fn sqlite_migrations_are_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path: PathBuf = dir.path().join("ark.db");
+
+        sqlite::SqliteStore::open(&path).unwrap();
+        sqlite::SqliteStore::open(&path).unwrap();
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        let version: usize = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+
+        assert!(version > 0);
+    }
+}
@@ -0,0 +1,249 @@
+//! Keeps the connection to the Ark server up, and tracks the health of the services the server
+//! depends on for `GET /healthz` and `GET /readyz`.
+//!
+//! The Ark server is reached once at startup, but it may be down at the time, restart later, or
+//! change its info, such as the dust limit or the exit delay. A background task therefore fetches
+//! its info again on a fixed interval, and reconnects with exponential backoff while it is
+//! unreachable. Connected clients cache the info they were connected with, so they are dropped
+//! whenever it changes, and connected again on their next use.
+//!
+//! Esplora is probed on the same schedule, and its client built again if that failed at startup.
+//! Every time the Ark server is reached, it and esplora are checked against the expected network
+//! as at startup, so that services which were down then are checked too. Storage is checked
+//! whenever health is reported.
+
+use actix_web::web;
+use anyhow::{anyhow, Result};
+use ark_core::server::Info;
+use jiff::Timestamp;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::core::model::{ApplicationState, BlockchainClient, HealthReport, ServiceHealth};
+use crate::core::server::connect_to_ark_network;
+use crate::network;
+
+/// The last checks of the Ark server and esplora.
+#[derive(Default)]
+pub struct Health {
+    ark_server: Mutex<ServiceHealth>,
+    esplora: Mutex<ServiceHealth>,
+}
+
+impl Health {
+    /// Record the outcome of reaching the Ark server.
+    pub fn record_ark_server<T>(&self, result: &Result<T>) {
+        *self.ark_server.lock().unwrap() = checked(result);
+    }
+
+    /// Record the outcome of probing esplora.
+    pub fn record_esplora<T>(&self, result: &Result<T>) {
+        *self.esplora.lock().unwrap() = checked(result);
+    }
+}
+
+fn checked<T>(result: &Result<T>) -> ServiceHealth {
+    ServiceHealth {
+        up: result.is_ok(),
+        checked_at: Some(Timestamp::now().as_second()),
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+    }
+}
+
+/// The health of every service, checking storage on the way.
+pub fn report(state: &ApplicationState) -> HealthReport {
+    let ark_server = state.health.ark_server.lock().unwrap().clone();
+    let esplora = state.health.esplora.lock().unwrap().clone();
+    let storage = checked(&state.accounts.check());
+
+    HealthReport {
+        ready: ark_server.up && esplora.up && storage.up,
+        ark_server,
+        esplora,
+        storage,
+    }
+}
+
+/// Refresh the Ark server's info and probe esplora, forever.
+pub async fn run(state: web::Data<ApplicationState>) {
+    let config = state.config.connection.clone();
+    let retry = config.retry_policy();
+
+    let mut failures = match state.server_connection.lock().unwrap().as_ref() {
+        Some(_) => 0,
+        None => 1,
+    };
+
+    loop {
+        let result = probe_esplora(&state).await;
+        state.health.record_esplora(&result);
+
+        let delay = match failures {
+            0 => config.refresh_interval_seconds,
+            failures => retry.delay(failures),
+        };
+        tokio::time::sleep(Duration::from_secs(delay.max(1))).await;
+
+        let result = refresh(&state).await;
+        state.health.record_ark_server(&result);
+
+        match result {
+            Ok(()) => {
+                if failures > 0 {
+                    tracing::info!("Reconnected to the Ark server");
+                }
+                failures = 0;
+            }
+            Err(e) => {
+                failures = failures.saturating_add(1);
+                tracing::warn!(
+                    failures,
+                    retry_in_seconds = retry.delay(failures),
+                    "Failed to reach the Ark server: {:#}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Fetch the Ark server's info and replace the cached one with it.
+async fn refresh(state: &ApplicationState) -> Result<()> {
+    let info = connect_to_ark_network(state.config.clone()).await?;
+
+    let previous = state.server_connection.lock().unwrap().clone();
+
+    // An Ark server that moved to another network, or that is on another network than esplora,
+    // must not be used at all. Without a configured network, the first Ark server reached sets
+    // the network, as it does at startup.
+    let expected = state
+        .config
+        .network
+        .or(previous.as_ref().map(|info| info.network))
+        .unwrap_or(info.network);
+    let blockchain = state.blockchain_client.lock().unwrap().clone();
+    let esplora_genesis = network::esplora_genesis(blockchain.as_ref()).await;
+    if let Err(e) = network::check(&state.config, expected, Some(info.network), esplora_genesis) {
+        *state.server_connection.lock().unwrap() = None;
+        state.clients.clear();
+        return Err(e);
+    }
+
+    if previous.as_ref() != Some(&info) {
+        if let Some(previous) = &previous {
+            log_changes(previous, &info);
+        }
+
+        *state.server_connection.lock().unwrap() = Some(info);
+        state.clients.clear();
+    }
+
+    Ok(())
+}
+
+fn log_changes(previous: &Info, info: &Info) {
+    tracing::info!(
+        dust = %info.dust,
+        previous_dust = %previous.dust,
+        unilateral_exit_delay = %info.unilateral_exit_delay,
+        previous_unilateral_exit_delay = %previous.unilateral_exit_delay,
+        vtxo_tree_expiry = %info.vtxo_tree_expiry,
+        previous_vtxo_tree_expiry = %previous.vtxo_tree_expiry,
+        "The Ark server's info changed; reconnecting clients"
+    );
+}
+
+async fn probe_esplora(state: &ApplicationState) -> Result<u32> {
+    blockchain_client(state)?.tip_height().await
+}
+
+/// The esplora client, built again if that failed before.
+fn blockchain_client(state: &ApplicationState) -> Result<BlockchainClient> {
+    let mut client = state.blockchain_client.lock().unwrap();
+
+    if client.is_none() {
+        let built = BlockchainClient::initialize(&state.config.esplora_url)
+            .map_err(|e| anyhow!("blockchain client failed to initialize: {:#}", e))?;
+        tracing::info!("Built the blockchain client");
+
+        *client = Some(built);
+    }
+
+    Ok(client.clone().expect("just built"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+
+    use crate::api;
+    use crate::auth::tests::state;
+
+    #[test]
+    fn failures_are_recorded_with_their_cause() {
+        let health = Health::default();
+        assert_eq!(*health.esplora.lock().unwrap(), ServiceHealth::default());
+
+        health.record_esplora(&Err::<(), _>(anyhow!("connection refused")));
+        let esplora = health.esplora.lock().unwrap().clone();
+        assert!(!esplora.up);
+        assert!(esplora.checked_at.is_some());
+        assert_eq!(esplora.error.as_deref(), Some("connection refused"));
+
+        health.record_esplora(&Ok(840_000));
+        let esplora = health.esplora.lock().unwrap().clone();
+        assert!(esplora.up);
+        assert_eq!(esplora.error, None);
+    }
+
+    #[test]
+    fn blockchain_client_is_built_once_it_can_be() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        assert!(state.blockchain_client.lock().unwrap().is_none());
+
+        blockchain_client(&state).unwrap();
+        assert!(state.blockchain_client.lock().unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn readiness_needs_every_service() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(&dir);
+        state.health.record_esplora(&Ok(840_000));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(api::monitoring::get_health)
+                .service(api::monitoring::get_readiness),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/healthz")
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The Ark server has not been reached yet
+        let req = actix_web::test::TestRequest::get()
+            .uri("/readyz")
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["ark_server"]["up"], false);
+        assert_eq!(body["esplora"]["up"], true);
+        assert_eq!(body["storage"]["up"], true);
+
+        state.health.record_ark_server(&Ok(()));
+        let req = actix_web::test::TestRequest::get()
+            .uri("/readyz")
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
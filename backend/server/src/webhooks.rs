@@ -0,0 +1,561 @@
+//! Signed webhooks for account events.
+//!
+//! An account registers a URL with `POST /api/accounts/{account_id}/webhooks`, optionally for
+//! some event names only. Every event that goes into the account's event log through
+//! [`events::publish`] is then queued in a persistent outbox, once per webhook that wants it, and
+//! a background task posts the queued deliveries. Failed deliveries are retried with exponential
+//! backoff until they succeed or run out of attempts, and stay around as delivery history.
+//!
+//! The body of a delivery is the [`StoredEvent`], as also sent over the event stream. Its
+//! [`SIGNATURE_HEADER`] reads `t=<timestamp>,v1=<signature>`, where the signature is the hex
+//! HMAC-SHA256 of `<timestamp>.<body>` under the webhook's secret. Receivers should compute it
+//! themselves, compare, and reject old timestamps to stop replays.
+//!
+//! The events of accounts with webhooks are watched even while nobody subscribes to their
+//! event stream.
+//!
+//! Webhooks are posted from inside the operator's network, so their URLs must resolve to public
+//! addresses only, both when they are registered and on every delivery, and redirects are not
+//! followed. Finished deliveries are kept as history for [`DELIVERY_RETENTION`].
+//!
+//! [`events::publish`]: crate::events::publish
+//! [`StoredEvent`]: crate::core::model::StoredEvent
+
+use actix_web::web;
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::{Hash, HashEngine, hmac, sha256};
+use futures::StreamExt;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::core::config::RetryPolicy;
+use crate::core::model::{
+    AccountEvent, ApplicationState, DeliveryStatus, StoredEvent, Webhook, WebhookDelivery,
+    WebhookRequest,
+};
+
+/// Signature of a delivery, as `t=<timestamp>,v1=<signature>`.
+pub const SIGNATURE_HEADER: &str = "Ark-Signature";
+/// Name of the delivered event.
+pub const EVENT_HEADER: &str = "Ark-Event";
+/// ID of the delivery, the same for every attempt.
+pub const DELIVERY_HEADER: &str = "Ark-Delivery";
+
+/// How many webhooks an account may register.
+pub const MAX_WEBHOOKS: usize = 10;
+
+const SECRET_PREFIX: &str = "whsec_";
+const SECRET_LEN: usize = 32;
+
+/// How often the outbox is checked for due deliveries.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: usize = 100;
+const CONCURRENCY: usize = 8;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long delivered and given-up deliveries are kept as history.
+pub const DELIVERY_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often deliveries past [`DELIVERY_RETENTION`] are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Retries of failed deliveries span about half a day before they are given up on.
+const RETRY: RetryPolicy = RetryPolicy {
+    max_attempts: 12,
+    backoff_seconds: 30,
+    max_backoff_seconds: 4 * 60 * 60,
+};
+
+/// Check that `request` names a usable URL and known events.
+pub fn validate(request: &WebhookRequest) -> Result<()> {
+    let url = reqwest::Url::parse(&request.url).map_err(|e| anyhow!("invalid URL: {}", e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(anyhow!("webhook URL must be an http or https URL"));
+    }
+
+    if let Some(name) = request
+        .events
+        .iter()
+        .find(|name| !AccountEvent::NAMES.contains(&name.as_str()))
+    {
+        return Err(anyhow!(
+            "unknown event {:?}, expected one of: {}",
+            name,
+            AccountEvent::NAMES.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Resolve the host of webhook URL `url`, failing unless every address it resolves to is
+/// public. Returns the host and the address to post to, so that the host cannot resolve to
+/// another address between the check and the request.
+pub async fn resolve_destination(url: &str) -> Result<(String, SocketAddr)> {
+    let url = reqwest::Url::parse(url).map_err(|e| anyhow!("invalid URL: {}", e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("webhook URL has no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("webhook URL has no port"))?;
+
+    let addresses = tokio::net::lookup_host(format!("{host}:{port}"))
+        .await
+        .map_err(|e| anyhow!("failed to resolve {}: {}", host, e))?
+        .collect::<Vec<_>>();
+
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        return Err(anyhow!(
+            "webhook host {} resolves to non-public address {}",
+            host,
+            address.ip()
+        ));
+    }
+
+    let address = addresses
+        .first()
+        .ok_or_else(|| anyhow!("{} does not resolve to any address", host))?;
+
+    Ok((host.to_string(), *address))
+}
+
+/// Whether `ip` is reachable on the internet, rather than loopback, private, link-local (which
+/// includes the cloud metadata address 169.254.169.254) or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Shared address space (RFC 6598), where some clouds put their metadata services.
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Create and store a webhook for `account_id`, and start watching the account's events.
+pub fn register(
+    state: &web::Data<ApplicationState>,
+    account_id: &str,
+    request: WebhookRequest,
+) -> Result<Webhook> {
+    let mut bytes = [0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    let mut events = request.events;
+    events.sort();
+    events.dedup();
+
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().to_string(),
+        account_id: account_id.to_string(),
+        url: request.url,
+        events,
+        secret: format!("{SECRET_PREFIX}{}", hex::encode(bytes)),
+        created_at: jiff::Timestamp::now().as_second(),
+    };
+
+    state.accounts.insert_webhook(&webhook)?;
+    state.events.pin(state, account_id);
+
+    Ok(webhook)
+}
+
+/// Remove webhook `webhook_id` of `account_id`. Returns whether it existed.
+pub fn unregister(state: &ApplicationState, account_id: &str, webhook_id: &str) -> Result<bool> {
+    if !state.accounts.delete_webhook(account_id, webhook_id)? {
+        return Ok(false);
+    }
+
+    if state.accounts.list_webhooks(account_id)?.is_empty() {
+        state.events.unpin(account_id);
+    }
+
+    Ok(true)
+}
+
+/// Whether `webhook` wants to be told about `event`.
+pub fn subscribes(webhook: &Webhook, event: &AccountEvent) -> bool {
+    webhook.events.is_empty() || webhook.events.iter().any(|name| name == event.name())
+}
+
+/// Queue `events` of `account_id` for every webhook that wants them.
+pub fn enqueue(state: &ApplicationState, account_id: &str, events: &[StoredEvent]) -> Result<()> {
+    let webhooks = state.accounts.list_webhooks(account_id)?;
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let now = jiff::Timestamp::now().as_second();
+
+    let mut deliveries = Vec::new();
+    for event in events {
+        let payload = serde_json::to_string(event)?;
+
+        for webhook in webhooks
+            .iter()
+            .filter(|webhook| subscribes(webhook, &event.event))
+        {
+            deliveries.push(WebhookDelivery {
+                id: uuid::Uuid::new_v4().to_string(),
+                webhook_id: webhook.id.clone(),
+                account_id: account_id.to_string(),
+                event_seq: event.seq,
+                event_type: event.event.name().to_string(),
+                payload: payload.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                next_attempt_at: now,
+                last_status_code: None,
+                last_error: None,
+                created_at: now,
+                delivered_at: None,
+            });
+        }
+    }
+
+    state.accounts.insert_deliveries(&deliveries)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+
+    hex::encode(hmac::Hmac::from_engine(engine).to_byte_array())
+}
+
+/// The [`SIGNATURE_HEADER`] of `body`, sent at Unix time `timestamp`.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let signature = hmac_sha256(secret.as_bytes(), format!("{timestamp}.{body}").as_bytes());
+
+    format!("t={timestamp},v1={signature}")
+}
+
+/// Record an attempt of `delivery` at Unix time `now`. `error` is `None` if it was delivered.
+pub fn record_attempt(
+    delivery: &mut WebhookDelivery,
+    now: i64,
+    status_code: Option<u16>,
+    error: Option<String>,
+) {
+    delivery.attempts += 1;
+    delivery.last_status_code = status_code;
+
+    match error {
+        None => {
+            delivery.status = DeliveryStatus::Delivered;
+            delivery.delivered_at = Some(now);
+            delivery.last_error = None;
+        }
+        Some(error) => {
+            delivery.last_error = Some(error);
+
+            if delivery.attempts >= RETRY.max_attempts {
+                delivery.status = DeliveryStatus::Failed;
+            } else {
+                delivery.next_attempt_at =
+                    now.saturating_add_unsigned(RETRY.delay(delivery.attempts));
+            }
+        }
+    }
+}
+
+/// Watch the accounts with webhooks, and deliver the outbox, forever.
+pub async fn run(state: web::Data<ApplicationState>) {
+    match state.accounts.list_accounts() {
+        Ok(accounts) => {
+            for account in accounts {
+                match state.accounts.list_webhooks(&account.id) {
+                    Ok(webhooks) if !webhooks.is_empty() => state.events.pin(&state, &account.id),
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::error!(account_id = account.id, "Failed to load webhooks: {:#}", e)
+                    }
+                }
+            }
+        }
+        Err(e) => tracing::error!("Failed to load accounts: {:#}", e),
+    }
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut last_pruned: Option<Instant> = None;
+
+    loop {
+        interval.tick().await;
+
+        let now = jiff::Timestamp::now().as_second();
+
+        if last_pruned.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+            last_pruned = Some(Instant::now());
+
+            let before = now.saturating_sub_unsigned(DELIVERY_RETENTION.as_secs());
+            match state.accounts.prune_deliveries(before) {
+                Ok(0) => {}
+                Ok(pruned) => tracing::debug!(pruned, "Pruned finished webhook deliveries"),
+                Err(e) => tracing::error!("Failed to prune webhook deliveries: {:#}", e),
+            }
+        }
+
+        let deliveries = match state.accounts.due_deliveries(now, BATCH_SIZE) {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                tracing::error!("Failed to load webhook deliveries: {:#}", e);
+                continue;
+            }
+        };
+
+        let mut webhooks = HashMap::new();
+        for delivery in &deliveries {
+            if !webhooks.contains_key(&delivery.account_id) {
+                match state.accounts.list_webhooks(&delivery.account_id) {
+                    Ok(list) => {
+                        webhooks.insert(delivery.account_id.clone(), list);
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            account_id = delivery.account_id,
+                            "Failed to load webhooks: {:#}",
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        let (state, webhooks) = (&state, &webhooks);
+        futures::stream::iter(deliveries)
+            .for_each_concurrent(CONCURRENCY, |mut delivery| async move {
+                // Try again on the next tick if the account's webhooks failed to load.
+                let Some(account_webhooks) = webhooks.get(&delivery.account_id) else {
+                    return;
+                };
+
+                match account_webhooks
+                    .iter()
+                    .find(|webhook| webhook.id == delivery.webhook_id)
+                {
+                    Some(webhook) => {
+                        let (status_code, error) = deliver(webhook, &delivery).await;
+                        let now = jiff::Timestamp::now().as_second();
+                        record_attempt(&mut delivery, now, status_code, error);
+                    }
+                    // Without its webhook the delivery can neither be signed nor sent.
+                    None => {
+                        delivery.status = DeliveryStatus::Failed;
+                        delivery.last_error = Some("webhook was deleted".to_string());
+                    }
+                }
+
+                if let Err(e) = state.accounts.update_delivery(&delivery) {
+                    tracing::error!(
+                        delivery_id = delivery.id,
+                        "Failed to update webhook delivery: {:#}",
+                        e
+                    );
+                }
+            })
+            .await;
+    }
+}
+
+/// Post `delivery` to `webhook`, returning the HTTP status, if any, and the error, if it failed.
+async fn deliver(webhook: &Webhook, delivery: &WebhookDelivery) -> (Option<u16>, Option<String>) {
+    // The host is resolved again on every delivery, as its addresses may have changed since the
+    // webhook was registered, and the client is pinned to the checked address.
+    let client = match resolve_destination(&webhook.url).await.and_then(|(host, address)| {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve(&host, address)
+            .build()
+            .map_err(anyhow::Error::from)
+    }) {
+        Ok(client) => client,
+        Err(e) => return (None, Some(e.to_string())),
+    };
+
+    let timestamp = jiff::Timestamp::now().as_second();
+
+    let response = client
+        .post(&webhook.url)
+        .timeout(REQUEST_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .header(
+            SIGNATURE_HEADER,
+            sign(&webhook.secret, timestamp, &delivery.payload),
+        )
+        .header(EVENT_HEADER, delivery.event_type.as_str())
+        .header(DELIVERY_HEADER, delivery.id.as_str())
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+        Ok(response) => (
+            Some(response.status().as_u16()),
+            Some(format!("webhook answered {}", response.status())),
+        ),
+        Err(e) => {
+            tracing::debug!(
+                delivery_id = delivery.id,
+                url = webhook.url,
+                "Webhook delivery failed: {}",
+                e
+            );
+            (None, Some(e.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery() -> WebhookDelivery {
+        WebhookDelivery {
+            id: "delivery".to_string(),
+            webhook_id: "webhook".to_string(),
+            account_id: "account".to_string(),
+            event_seq: 1,
+            event_type: "vtxo_received".to_string(),
+            payload: "{}".to_string(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: 0,
+            last_status_code: None,
+            last_error: None,
+            created_at: 0,
+            delivered_at: None,
+        }
+    }
+
+    #[test]
+    fn signatures_are_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let header = sign("whsec_test", 1_700_000_000, r#"{"seq":1}"#);
+        let expected = hmac_sha256(b"whsec_test", br#"1700000000.{"seq":1}"#);
+        assert_eq!(header, format!("t=1700000000,v1={expected}"));
+    }
+
+    #[test]
+    fn webhooks_filter_events_by_name() {
+        let mut webhook = Webhook {
+            id: "webhook".to_string(),
+            account_id: "account".to_string(),
+            url: "https://example.com/hook".to_string(),
+            events: vec![],
+            secret: "whsec_test".to_string(),
+            created_at: 0,
+        };
+        let completed = AccountEvent::RoundCompleted {
+            txid: "ab".repeat(32),
+        };
+        let failed = AccountEvent::RoundFailed {
+            round_id: "round".to_string(),
+            reason: "timeout".to_string(),
+        };
+
+        assert!(subscribes(&webhook, &completed));
+        assert!(subscribes(&webhook, &failed));
+
+        webhook.events = vec!["round_failed".to_string()];
+        assert!(!subscribes(&webhook, &completed));
+        assert!(subscribes(&webhook, &failed));
+    }
+
+    #[test]
+    fn only_public_addresses_are_accepted() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} is not public");
+        }
+
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+    }
+
+    #[actix_web::test]
+    async fn internal_destinations_are_rejected() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "https://10.0.0.1/hook",
+        ] {
+            assert!(resolve_destination(url).await.is_err(), "{url} is rejected");
+        }
+
+        let (host, address) = resolve_destination("https://93.184.216.34/hook")
+            .await
+            .unwrap();
+        assert_eq!(host, "93.184.216.34");
+        assert_eq!(address, "93.184.216.34:443".parse().unwrap());
+    }
+
+    #[test]
+    fn failed_deliveries_back_off_until_given_up() {
+        let mut delivery = delivery();
+
+        record_attempt(&mut delivery, 1_000, Some(500), Some("boom".to_string()));
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.next_attempt_at, 1_030);
+
+        record_attempt(&mut delivery, 1_030, None, Some("timeout".to_string()));
+        assert_eq!(delivery.next_attempt_at, 1_090);
+        assert_eq!(delivery.last_status_code, None);
+
+        while delivery.status == DeliveryStatus::Pending {
+            record_attempt(&mut delivery, 2_000, None, Some("timeout".to_string()));
+        }
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, RETRY.max_attempts);
+
+        let mut delivery = self::delivery();
+        record_attempt(&mut delivery, 1_000, Some(500), Some("boom".to_string()));
+        record_attempt(&mut delivery, 1_030, Some(204), None);
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.delivered_at, Some(1_030));
+        assert_eq!(delivery.last_error, None);
+    }
+}